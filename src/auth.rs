@@ -0,0 +1,420 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::server::conn::AddrStream;
+use ring::{
+    digest::{digest, SHA256},
+    pbkdf2,
+};
+use tokio::{net::TcpStream, sync::Semaphore};
+use tokio_rustls::server::TlsStream;
+
+/// Users of the basic authentication, with their hashed password, when
+/// not given with `--basic-auth-users`
+const USERS_VARIABLE: &str = "TXNE_BASIC_AUTH_USERS";
+
+/// Bearer token, when not given with `--bearer-token-file`
+const TOKEN_VARIABLE: &str = "TXNE_BEARER_TOKEN";
+
+/// Cost of the bcrypt hashes made by `txne hash-password`
+const BCRYPT_COST: u32 = 10;
+
+/// Verified credentials kept, so that each scrape does not pay for the
+/// hashing
+const VERIFIED_CACHE: usize = 64;
+
+/// Password verifications run at once, the other checks waiting for
+/// their turn rather than taking more of the blocking threads
+const CONCURRENT_VERIFICATIONS: usize = 2;
+
+/// Longest wait for a turn to verify a password, after which the check
+/// gives up rather than queuing behind the wrong passwords of a client
+const VERIFICATION_WAIT: Duration = Duration::from_secs(1);
+
+/// Credentials accepted by the HTTP API
+#[derive(Debug)]
+pub struct Auth {
+    users: HashMap<String, Hash>,
+    token: Option<String>,
+    /// SHA-256 of the user and password of the last successful checks
+    verified: Mutex<HashSet<Vec<u8>>>,
+    verifications: Semaphore,
+}
+
+/// Outcome of the check of the credentials of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Valid,
+    Invalid,
+    /// Too many passwords being verified to verify this one in time
+    Busy,
+}
+
+/// Salted hash of a password
+#[derive(Debug, Clone)]
+enum Hash {
+    /// `$2a$`, `$2b$` or `$2y$` hash, as made by `htpasswd -B` and
+    /// accepted by the web configuration of the Prometheus exporters
+    Bcrypt(String),
+    /// PBKDF2-HMAC-SHA256
+    Pbkdf2 {
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl Hash {
+    /// Parse a bcrypt hash, or `pbkdf2$ITERATIONS$SALT$HASH` with the
+    /// salt and hash in base64
+    fn parse(text: &str) -> Option<Self> {
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| text.starts_with(prefix))
+        {
+            text.parse::<bcrypt::HashParts>().ok()?;
+            return Some(Hash::Bcrypt(text.to_string()));
+        }
+        let mut parts = text.split('$');
+        if parts.next()? != "pbkdf2" {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok()?;
+        let salt = STANDARD.decode(parts.next()?).ok()?;
+        let hash = STANDARD.decode(parts.next()?).ok()?;
+        if parts.next().is_some() || salt.is_empty() || hash.is_empty() {
+            return None;
+        }
+        Some(Hash::Pbkdf2 {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Pbkdf2 {
+                iterations,
+                salt,
+                hash,
+            } => {
+                let algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+                pbkdf2::verify(algorithm, *iterations, salt, password.as_bytes(), hash).is_ok()
+            }
+        }
+    }
+}
+
+/// Hash a password for `--basic-auth-users`, with bcrypt
+pub fn hash_password(password: &str) -> String {
+    bcrypt::hash(password, BCRYPT_COST).unwrap()
+}
+
+impl Auth {
+    /// Read the credentials from the files, or else from the
+    /// environment, `None` when there are none
+    pub fn load(users: Option<&Path>, token: Option<&Path>) -> Result<Option<Arc<Self>>, String> {
+        let users = match users {
+            Some(path) => Some(read(path)?),
+            None => std::env::var(USERS_VARIABLE).ok(),
+        };
+        let token = match token {
+            Some(path) => Some(read(path)?),
+            None => std::env::var(TOKEN_VARIABLE).ok(),
+        };
+        let mut auth = Auth {
+            users: HashMap::new(),
+            token: None,
+            verified: Mutex::default(),
+            verifications: Semaphore::new(CONCURRENT_VERIFICATIONS),
+        };
+        // Separated by new lines in files, or by commas in the environment
+        for line in users.iter().flat_map(|users| users.split(['\n', ','])) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let hash = line
+                .split_once(':')
+                .filter(|(user, _)| !user.is_empty())
+                .and_then(|(user, hash)| Some((user, Hash::parse(hash.trim())?)));
+            let Some((user, hash)) = hash else {
+                let user = line.split(':').next().unwrap_or_default();
+                return Err(format!(
+                    "Invalid basic authentication user {user:?} (expected USER:HASH, \
+                     the bcrypt HASH being given by txne hash-password or htpasswd -B)"
+                ));
+            };
+            auth.users.insert(user.to_string(), hash);
+        }
+        if let Some(token) = token {
+            let token = token.trim();
+            if token.is_empty() {
+                return Err("Empty bearer token".to_string());
+            }
+            auth.token = Some(token.to_string());
+        }
+        if auth.users.is_empty() && auth.token.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Arc::new(auth)))
+    }
+
+    /// Whether the `Authorization` header (or gRPC metadata) holds
+    /// valid credentials
+    ///
+    /// The passwords that are not in the cache are verified on a
+    /// blocking thread, since hashing them takes a while, a few at a
+    /// time: the checks that wait too long for their turn are `Busy`.
+    pub async fn check(&self, authorization: Option<&str>) -> Check {
+        let Some(authorization) = authorization else {
+            return Check::Invalid;
+        };
+        let (scheme, credentials) = authorization.split_once(' ').unwrap_or_default();
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let valid = self
+                .token
+                .as_ref()
+                .is_some_and(|token| equal(token.as_bytes(), credentials.as_bytes()));
+            return if valid { Check::Valid } else { Check::Invalid };
+        }
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return Check::Invalid;
+        }
+        let Some(decoded) = STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+        else {
+            return Check::Invalid;
+        };
+        let Some((user, password)) = decoded.split_once(':') else {
+            return Check::Invalid;
+        };
+        let Some(hash) = self.users.get(user) else {
+            return Check::Invalid;
+        };
+        let key = digest(&SHA256, decoded.as_bytes()).as_ref().to_vec();
+        if self.verified.lock().unwrap().contains(&key) {
+            return Check::Valid;
+        }
+        let permit = tokio::time::timeout(VERIFICATION_WAIT, self.verifications.acquire()).await;
+        let Ok(Ok(_permit)) = permit else {
+            return Check::Busy;
+        };
+        // Verified by another check while waiting
+        if self.verified.lock().unwrap().contains(&key) {
+            return Check::Valid;
+        }
+        let hash = hash.clone();
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || hash.verify(&password)).await;
+        if !valid.unwrap_or(false) {
+            return Check::Invalid;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= VERIFIED_CACHE {
+            verified.clear();
+        }
+        verified.insert(key);
+        Check::Valid
+    }
+}
+
+/// Reject the requests without valid credentials
+pub async fn require<B>(
+    State(auth): State<Arc<Auth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // The probes of the orchestrators come without credentials
+    let probe = matches!(request.uri().path(), "/healthz" | "/readyz");
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if probe {
+        return next.run(request).await;
+    }
+    match auth.check(authorization).await {
+        Check::Valid => return next.run(request).await,
+        Check::Invalid => {}
+        Check::Busy => {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                "Too many authentications in progress\n",
+            )
+                .into_response()
+        }
+    }
+    let challenge = if auth.users.is_empty() {
+        "Bearer"
+    } else {
+        "Basic realm=\"txne\""
+    };
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+        "Unauthorized\n",
+    )
+        .into_response()
+}
+
+/// Address of the client of a connection, plain or TLS
+#[derive(Debug, Clone, Copy)]
+pub struct Peer(SocketAddr);
+
+impl Connected<&AddrStream> for Peer {
+    fn connect_info(target: &AddrStream) -> Self {
+        Peer(target.remote_addr())
+    }
+}
+
+impl Connected<&TlsStream<TcpStream>> for Peer {
+    fn connect_info(target: &TlsStream<TcpStream>) -> Self {
+        let addr = target.get_ref().0.peer_addr();
+        Peer(addr.unwrap_or_else(|_| SocketAddr::from(([0; 4], 0))))
+    }
+}
+
+/// Subnets of the clients allowed to use the HTTP API
+pub type Allowlist = Arc<Vec<(u32, u32)>>;
+
+/// Whether a client is within the allowlist
+pub fn is_allowed(allowed: &Allowlist, peer: IpAddr) -> bool {
+    // IPv4 clients of a "::" listener are seen as IPv4-mapped addresses
+    let ip = match peer {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+    };
+    let ip = ip.map(|ip| u32::from_be_bytes(ip.octets()));
+    ip.is_some_and(|ip| allowed.iter().any(|(addr, mask)| ip & mask == *addr))
+}
+
+/// Reject the requests of the clients outside the allowlist
+pub async fn allow<B>(
+    State(allowed): State<Allowlist>,
+    ConnectInfo(Peer(peer)): ConnectInfo<Peer>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if is_allowed(&allowed, peer.ip()) {
+        return next.run(request).await;
+    }
+    (StatusCode::FORBIDDEN, "Forbidden\n").into_response()
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|err| format!("Unable to read {}: {err}", path.display()))
+}
+
+/// Compare in constant time (for a given length), so that the time
+/// taken does not tell how much of a secret was guessed
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+    }
+
+    /// Hash of "secret", as given by `htpasswd -B`
+    const BCRYPT: &str = "$2y$05$J1Ib2ucnPJbxUjfrCjLODeBcz1uqShNnd.WLklt.o5L7AWJdMPXJC";
+
+    /// Hash of "secret", in 1000 iterations
+    const PBKDF2: &str =
+        "pbkdf2$1000$dHhuZS10ZXN0LXNhbHQhIQ==$s3N5/M/XdPuz8/5HkldayFd3l0YG911z6Hy8/Oed86w=";
+
+    #[test]
+    fn hashes() {
+        for text in [BCRYPT, &BCRYPT.replace("$2y$", "$2a$"), PBKDF2] {
+            let hash = Hash::parse(text).unwrap();
+            assert!(hash.verify("secret"));
+            assert!(!hash.verify("guess"));
+        }
+        assert!(Hash::parse(&BCRYPT.replace("$2y$", "$2x$")).is_none());
+        assert!(Hash::parse("$2y$05$short").is_none());
+        assert!(Hash::parse("secret").is_none());
+    }
+
+    /// The checks verifying a password at the same time each get their
+    /// answer, the valid credentials being cached
+    #[tokio::test]
+    async fn concurrent_checks() {
+        let hash = Hash::parse(BCRYPT).unwrap();
+        let auth = Arc::new(Auth {
+            users: HashMap::from([("admin".to_string(), hash)]),
+            token: Some("token".to_string()),
+            verified: Mutex::default(),
+            verifications: Semaphore::new(CONCURRENT_VERIFICATIONS),
+        });
+        let checks = (0..8).map(|n| {
+            let auth = auth.clone();
+            let password = if n % 2 == 0 { "secret" } else { "guess" };
+            let authorization = basic("admin", password);
+            tokio::spawn(async move { auth.check(Some(&authorization)).await == Check::Valid })
+        });
+        let mut results = Vec::new();
+        for check in checks.collect::<Vec<_>>() {
+            results.push(check.await.unwrap());
+        }
+        assert_eq!(results, [true, false].repeat(4));
+        assert_eq!(auth.verified.lock().unwrap().len(), 1);
+        assert_eq!(auth.check(Some("Bearer token")).await, Check::Valid);
+        assert_eq!(
+            auth.check(Some(&basic("root", "secret"))).await,
+            Check::Invalid
+        );
+        assert_eq!(auth.check(None).await, Check::Invalid);
+    }
+
+    /// The checks waiting too long for a verification give up, those
+    /// of the cached credentials not waiting
+    #[tokio::test]
+    async fn busy_verifications() {
+        let auth = Auth {
+            users: HashMap::from([("admin".to_string(), Hash::parse(BCRYPT).unwrap())]),
+            token: None,
+            verified: Mutex::default(),
+            verifications: Semaphore::new(CONCURRENT_VERIFICATIONS),
+        };
+        assert_eq!(
+            auth.check(Some(&basic("admin", "secret"))).await,
+            Check::Valid
+        );
+        let _permits = auth
+            .verifications
+            .acquire_many(CONCURRENT_VERIFICATIONS as u32)
+            .await;
+        assert_eq!(
+            auth.check(Some(&basic("admin", "guess"))).await,
+            Check::Busy
+        );
+        assert_eq!(
+            auth.check(Some(&basic("admin", "secret"))).await,
+            Check::Valid
+        );
+    }
+}
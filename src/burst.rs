@@ -0,0 +1,403 @@
+//! 95th percentile of the throughput, for burstable billing
+//!
+//! The average throughput of each IP is sampled every 5 minutes. The
+//! highest 5% of the samples are discarded, and the highest remaining
+//! one is the percentile.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::report;
+
+const DAY: u64 = 86400;
+
+/// Interval between two samples, in seconds
+pub const INTERVAL: u64 = 300;
+
+/// Window of the rolling percentile
+const ROLLING: u64 = 30 * DAY;
+
+/// How long the samples are kept, to cover a calendar month
+const RETENTION: u64 = 31 * DAY;
+
+const PERCENTILE: f64 = 0.95;
+
+const FILE: &str = "percentiles.json";
+
+/// End of the interval (seconds since the UNIX epoch), and inbound and
+/// outbound throughput (Mbps)
+type Sample = (u64, f32, f32);
+
+/// Inbound and outbound bytes of each IP
+pub type Totals = HashMap<Option<u32>, (u64, u64)>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Percentile {
+    pub inbound_mbps: f64,
+    pub outbound_mbps: f64,
+    /// Number of samples the percentile is computed from
+    pub samples: usize,
+}
+
+impl Percentile {
+    fn of<'a>(samples: impl Iterator<Item = &'a Sample>) -> Self {
+        let (mut inbound, mut outbound): (Vec<_>, Vec<_>) = samples
+            .map(|(_, inbound, outbound)| (*inbound, *outbound))
+            .unzip();
+        let pick = |values: &mut Vec<f32>| {
+            if values.is_empty() {
+                return 0.0;
+            }
+            values.sort_by(f32::total_cmp);
+            let index = (values.len() as f64 * PERCENTILE).ceil() as usize - 1;
+            values[index] as f64
+        };
+        Self {
+            inbound_mbps: pick(&mut inbound),
+            outbound_mbps: pick(&mut outbound),
+            samples: inbound.len(),
+        }
+    }
+
+    /// Billed throughput, the highest of both directions
+    pub fn billable_mbps(&self) -> f64 {
+        self.inbound_mbps.max(self.outbound_mbps)
+    }
+}
+
+/// Percentiles of a calendar month
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Month {
+    /// First second of the month
+    pub start: u64,
+    pub percentiles: Vec<(Option<u32>, Percentile)>,
+}
+
+/// Samples of the tracked IPs (`None` for the overflow entry)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Samples {
+    samples: Vec<(Option<u32>, VecDeque<Sample>)>,
+    /// Percentiles of the previous month, once it is over
+    previous: Option<Month>,
+    /// Time and inbound and outbound bytes of the previous sample
+    #[serde(skip)]
+    last: Option<(u64, Totals)>,
+    /// Percentiles over the rolling window, as of the last sample
+    #[serde(skip)]
+    rolling: Vec<(Option<u32>, Percentile)>,
+}
+
+impl Samples {
+    /// Load the samples persisted in a state directory
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content =
+            fs::read(&path).map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+        let mut samples: Samples = serde_json::from_slice(&content)
+            .map_err(|err| format!("Invalid samples {}: {err}", path.display()))?;
+        samples.update_rolling();
+        Ok(samples)
+    }
+
+    /// Persist the samples in a state directory
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        let path = dir.join(FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self).unwrap())
+            .and_then(|()| fs::rename(&tmp, &path))
+            .map_err(|err| format!("Unable to write {}: {err}", path.display()))
+    }
+
+    /// Add a sample of the throughput since the previous call, from the
+    /// current inbound and outbound bytes of each IP
+    ///
+    /// The first call (and the first one for a new IP) only records the
+    /// bytes. A call for an interval already sampled (by the capture of
+    /// another stream) is ignored.
+    pub fn record(&mut self, time: u64, totals: Totals) {
+        if self.last.as_ref().is_some_and(|(last, _)| time <= *last) {
+            return;
+        }
+        // Keep the percentiles of the month that is over
+        if let Some(latest) = self.latest() {
+            let start = report::month_start(latest);
+            let month = report::month_start(time);
+            if start != month {
+                self.previous = Some(Month {
+                    start,
+                    percentiles: self.percentiles(start, month),
+                });
+            }
+        }
+        let mut samples = std::mem::take(&mut self.samples)
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        if let Some((last_time, last)) = &self.last {
+            let elapsed = time.saturating_sub(*last_time);
+            for (ip, (inbound, outbound)) in &totals {
+                let Some((last_inbound, last_outbound)) = last.get(ip) else {
+                    continue;
+                };
+                if elapsed == 0 {
+                    continue;
+                }
+                let mbps = |bytes: u64| (bytes as f64 * 8.0 / elapsed as f64 / 1e6) as f32;
+                samples.entry(*ip).or_insert_with(VecDeque::new).push_back((
+                    time,
+                    mbps(inbound.saturating_sub(*last_inbound)),
+                    mbps(outbound.saturating_sub(*last_outbound)),
+                ));
+            }
+        }
+        let oldest = time.saturating_sub(RETENTION);
+        samples.retain(|_, samples| {
+            while samples.front().is_some_and(|(time, _, _)| *time <= oldest) {
+                samples.pop_front();
+            }
+            !samples.is_empty()
+        });
+        self.samples = samples.into_iter().collect();
+        self.samples.sort_by_key(|(ip, _)| *ip);
+        self.last = Some((time, totals));
+        self.update_rolling();
+    }
+
+    /// Forget the bytes of the previous call, after a jump of the wall
+    /// clock, so that the next call only records the bytes again
+    pub fn rebaseline(&mut self) {
+        self.last = None;
+    }
+
+    /// Time of the latest sample
+    fn latest(&self) -> Option<u64> {
+        self.samples
+            .iter()
+            .filter_map(|(_, samples)| samples.back())
+            .map(|(time, _, _)| *time)
+            .max()
+    }
+
+    fn update_rolling(&mut self) {
+        let Some(end) = self.latest() else {
+            self.rolling.clear();
+            return;
+        };
+        self.rolling = self.percentiles(end.saturating_sub(ROLLING) + 1, end.saturating_add(1));
+    }
+
+    /// Percentiles of the samples whose interval ends in `[start, end)`
+    fn percentiles(&self, start: u64, end: u64) -> Vec<(Option<u32>, Percentile)> {
+        self.samples
+            .iter()
+            .map(|(ip, samples)| {
+                let samples = samples
+                    .iter()
+                    .filter(|(time, _, _)| (start..end).contains(time));
+                (*ip, Percentile::of(samples))
+            })
+            .filter(|(_, percentile)| percentile.samples > 0)
+            .collect()
+    }
+
+    /// Percentiles over the last 30 days
+    pub fn rolling(&self) -> &[(Option<u32>, Percentile)] {
+        &self.rolling
+    }
+
+    /// Percentiles over the last 30 days of groups of IPs, from the sums
+    /// of the samples of their members (`group` giving the group of an
+    /// IP, or `None` to leave it out)
+    pub fn rolling_groups(
+        &self,
+        group: impl Fn(Option<u32>) -> Option<Option<u32>>,
+    ) -> Vec<(Option<u32>, Percentile)> {
+        let Some(end) = self.latest() else {
+            return Vec::new();
+        };
+        let start = end.saturating_sub(ROLLING) + 1;
+        let mut sums = BTreeMap::<Option<u32>, BTreeMap<u64, (f32, f32)>>::new();
+        for (ip, samples) in &self.samples {
+            let Some(group) = group(*ip) else {
+                continue;
+            };
+            let sums = sums.entry(group).or_default();
+            for (time, inbound, outbound) in samples {
+                if *time >= start {
+                    let sum = sums.entry(*time).or_default();
+                    sum.0 += inbound;
+                    sum.1 += outbound;
+                }
+            }
+        }
+        sums.into_iter()
+            .map(|(group, sums)| {
+                let samples = sums
+                    .into_iter()
+                    .map(|(time, (inbound, outbound))| (time, inbound, outbound))
+                    .collect::<Vec<_>>();
+                (group, Percentile::of(samples.iter()))
+            })
+            .filter(|(_, percentile)| percentile.samples > 0)
+            .collect()
+    }
+
+    /// Percentiles of the current month, so far
+    pub fn current_month(&self, now: u64) -> Month {
+        let start = report::month_start(now);
+        Month {
+            start,
+            percentiles: self.percentiles(start, u64::MAX),
+        }
+    }
+
+    /// Percentiles of the previous month, if it was sampled
+    pub fn previous_month(&self, now: u64) -> Option<&Month> {
+        let start = report::month_start(report::month_start(now).saturating_sub(1));
+        self.previous.as_ref().filter(|month| month.start == start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-31 12:00 UTC, a multiple of the interval
+    const JANUARY: u64 = 1_706_702_400;
+
+    /// 2024-02-01 00:00 UTC
+    const FEBRUARY: u64 = 1_706_745_600;
+
+    /// Bytes of 1 Mbps over an interval
+    const MBIT: u64 = INTERVAL * 1_000_000 / 8;
+
+    fn samples(values: &[f32]) -> Vec<Sample> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| (index as u64, *value, *value / 2.0))
+            .collect()
+    }
+
+    fn totals(ips: &[(Option<u32>, u64)]) -> Totals {
+        ips.iter()
+            .map(|(ip, bytes)| (*ip, (*bytes, *bytes / 2)))
+            .collect()
+    }
+
+    #[test]
+    fn percentile() {
+        assert_eq!(Percentile::of([].iter()), Percentile::default());
+        let one = Percentile::of(samples(&[4.0]).iter());
+        assert_eq!(
+            (one.inbound_mbps, one.outbound_mbps, one.samples),
+            (4.0, 2.0, 1)
+        );
+        // 20 samples: the highest one is discarded
+        let values = (1..=20).rev().map(|value| value as f32).collect::<Vec<_>>();
+        let percentile = Percentile::of(samples(&values).iter());
+        assert_eq!((percentile.inbound_mbps, percentile.samples), (19.0, 20));
+        assert_eq!(percentile.billable_mbps(), 19.0);
+        // 21 samples: the highest one still is, 5% being 1.05 samples
+        let values = (1..=21).map(|value| value as f32).collect::<Vec<_>>();
+        assert_eq!(Percentile::of(samples(&values).iter()).inbound_mbps, 20.0);
+        // 19 samples: none is
+        let values = (1..=19).map(|value| value as f32).collect::<Vec<_>>();
+        assert_eq!(Percentile::of(samples(&values).iter()).inbound_mbps, 19.0);
+    }
+
+    #[test]
+    fn record() {
+        let ip = Some(1);
+        let mut samples = Samples::default();
+        // The first call only records the bytes
+        samples.record(JANUARY, totals(&[(ip, 0)]));
+        assert!(samples.rolling().is_empty());
+        samples.record(JANUARY + INTERVAL, totals(&[(ip, MBIT), (Some(2), 0)]));
+        assert_eq!(samples.rolling().len(), 1);
+        let (_, percentile) = samples.rolling()[0];
+        assert_eq!(
+            (percentile.inbound_mbps, percentile.outbound_mbps),
+            (1.0, 0.5)
+        );
+        // An interval already sampled is ignored
+        samples.record(JANUARY + INTERVAL, totals(&[(ip, 9 * MBIT)]));
+        assert_eq!(samples.rolling()[0].1.samples, 1);
+        // A new IP is sampled from its second call
+        samples.record(
+            JANUARY + 2 * INTERVAL,
+            totals(&[(ip, 3 * MBIT), (Some(2), MBIT)]),
+        );
+        let rolling = samples.rolling();
+        assert_eq!(rolling.len(), 2);
+        assert_eq!((rolling[0].0, rolling[0].1.inbound_mbps), (ip, 2.0));
+        assert_eq!((rolling[1].0, rolling[1].1.inbound_mbps), (Some(2), 1.0));
+
+        // Beyond the retention, the samples are forgotten
+        let later = JANUARY + 2 * INTERVAL + RETENTION;
+        samples.record(later, totals(&[(ip, 3 * MBIT)]));
+        assert_eq!(samples.samples.len(), 1);
+        assert_eq!(samples.samples[0].1.len(), 1);
+        samples.record(later + INTERVAL, totals(&[(ip, 3 * MBIT)]));
+        assert_eq!(samples.samples[0].1.len(), 2);
+    }
+
+    #[test]
+    fn months() {
+        let mut samples = Samples::default();
+        samples.record(FEBRUARY - 2 * INTERVAL, totals(&[(None, 0)]));
+        samples.record(FEBRUARY - INTERVAL, totals(&[(None, MBIT)]));
+        assert!(samples.previous_month(FEBRUARY - INTERVAL).is_none());
+        let current = samples.current_month(FEBRUARY - INTERVAL);
+        assert_eq!(current.start, FEBRUARY - 31 * DAY);
+        assert_eq!(current.percentiles[0].1.samples, 1);
+
+        // The first sample of February (its interval ending on February
+        // 1 at 00:00) closes January
+        samples.record(FEBRUARY, totals(&[(None, 3 * MBIT)]));
+        let previous = samples.previous_month(FEBRUARY).unwrap();
+        assert_eq!(previous.start, FEBRUARY - 31 * DAY);
+        assert_eq!(previous.percentiles[0].1.samples, 1);
+        assert_eq!(previous.percentiles[0].1.inbound_mbps, 1.0);
+        let current = samples.current_month(FEBRUARY);
+        assert_eq!(current.start, FEBRUARY);
+        assert_eq!(current.percentiles[0].1.inbound_mbps, 2.0);
+        // Only while it is the previous month
+        assert!(samples.previous_month(FEBRUARY + 29 * DAY).is_none());
+        assert!(samples.previous_month(0).is_none());
+    }
+
+    #[test]
+    fn rebaseline() {
+        let mut samples = Samples::default();
+        samples.record(JANUARY, totals(&[(None, 0)]));
+        samples.rebaseline();
+        // After a jump, the bytes since the previous call are not sampled
+        samples.record(JANUARY + 10 * INTERVAL, totals(&[(None, 100 * MBIT)]));
+        assert!(samples.rolling().is_empty());
+        samples.record(JANUARY + 11 * INTERVAL, totals(&[(None, 101 * MBIT)]));
+        assert_eq!(samples.rolling()[0].1.inbound_mbps, 1.0);
+        assert_eq!(samples.rolling()[0].1.samples, 1);
+    }
+
+    #[test]
+    fn groups() {
+        let mut samples = Samples::default();
+        samples.record(JANUARY, totals(&[(Some(1), 0), (Some(2), 0), (None, 0)]));
+        let bytes = [(Some(1), MBIT), (Some(2), 2 * MBIT), (None, 4 * MBIT)];
+        samples.record(JANUARY + INTERVAL, totals(&bytes));
+        let groups = samples.rolling_groups(|ip| ip.map(|_| Some(1)));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].0, Some(1));
+        assert_eq!(groups[0].1.inbound_mbps, 3.0);
+        assert_eq!(groups[0].1.outbound_mbps, 1.5);
+        assert_eq!(groups[0].1.samples, 1);
+    }
+}
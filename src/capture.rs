@@ -0,0 +1,768 @@
+//! Capture loop, over an interface or a stream
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "pcap")]
+use pcap::{Active, Capture};
+
+use crate::{
+    burst,
+    category::{Flows, Rules},
+    clock::{Clock, Jump},
+    discovery,
+    enrich::SharedEnricher,
+    events::{Bus, Event},
+    geoip::{self, Geo},
+    hook::{Hook, Meta, Verdict},
+    icmp::{self, EchoTracker},
+    laptop,
+    link::{self, Count, Framing},
+    mirror::Mirror,
+    packet::{self, Linktype},
+    poll::PollMode,
+    rawsock::{self, RawSocket},
+    server::{CaptureHealth, SharedHealth},
+    service::Ports,
+    shard::Shard,
+    ssdp::{self, SharedDevices},
+    stats::{record_named, throughput_totals, Key, Stats},
+    stream,
+    wifi::SharedWifi,
+    window::{LocalTime, Schedule},
+};
+
+/// Reason of the fallbacks when built without libpcap
+#[cfg(not(feature = "pcap"))]
+pub const NO_PCAP: &str = "built without libpcap";
+
+/// Linux pseudo-device capturing on every interface
+pub const ANY_DEVICE: &str = "any";
+
+/// How often the default route is checked in laptop mode
+pub const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Subnets of a capture, and limit of the tracked IPs, which are
+/// reloaded on SIGHUP
+#[derive(Debug, Clone, Default)]
+pub struct Networks {
+    pub local: Vec<(u32, u32)>,
+    pub excluded: Vec<(u32, u32)>,
+    pub max_tracking: usize,
+}
+
+impl Networks {
+    pub fn is_local(&self, ip: u32) -> bool {
+        self.local.iter().any(|(addr, mask)| ip & mask == *addr)
+    }
+
+    pub fn is_excluded(&self, ip: u32) -> bool {
+        self.excluded.iter().any(|(addr, mask)| ip & mask == *addr)
+    }
+}
+
+pub type SharedNetworks = Arc<Mutex<Arc<Networks>>>;
+
+/// Settings of the capture loop
+pub struct RunOptions<'a> {
+    /// Interface in use when following the default route
+    pub follow: Option<&'a str>,
+    /// Current wireless association, when labeling by SSID/BSSID
+    pub wifi: Option<&'a SharedWifi>,
+    /// Measure the RTT of ICMP echo requests
+    pub icmp_rtt: bool,
+    /// Learn the names of local hosts from mDNS and NetBIOS
+    pub discover_names: bool,
+    /// Inventory of the local devices announcing themselves over SSDP
+    pub ssdp: Option<&'a SharedDevices>,
+    /// Executor for the lookups triggered by the capture
+    pub enricher: &'a SharedEnricher,
+    /// Part of the local IPs to track
+    pub shard: Option<Shard>,
+    /// VLANs to count, all of them when empty
+    pub vlans: &'a [u16],
+    /// Track the counters per VLAN
+    pub vlan_label: bool,
+    /// Copy of the captured packets to another tool
+    pub mirror: Option<&'a Mirror>,
+    /// Value of the "interface" label, when capturing on several ones
+    pub interface: Option<Arc<str>>,
+    /// Addresses of this machine, when capturing on the "any" device:
+    /// forwarded packets are seen both when received and sent, so only
+    /// the packets sent from these addresses are counted when sent
+    pub own_addresses: Option<&'a [u32]>,
+    /// Categories of traffic, when defined
+    pub categories: Option<&'a Rules>,
+    /// Classification hook, when given
+    pub hook: Option<&'a Hook>,
+    /// Accounting periods, when defined
+    pub schedule: Option<&'a Schedule>,
+    /// Ports to break the traffic down by, when enabled
+    pub ports: Option<&'a Ports>,
+    /// Countries and autonomous systems, when databases are given
+    pub geo: Option<&'a Geo>,
+    /// Bytes counted for each packet
+    pub count: Count,
+    /// Interval between the flushes of the counters to the shared
+    /// statistics
+    pub flush_interval: Duration,
+    /// Health of the captures, where this one reports under its name
+    pub health: (&'a SharedHealth, &'a str),
+    pub events: &'a Bus,
+    /// Throughput samples, when computing the percentiles
+    pub samples: Option<&'a Mutex<burst::Samples>>,
+    /// Sample the throughput at the timestamps of the packets (streams)
+    /// rather than on the clock of the exporter
+    pub packet_clock: bool,
+}
+
+/// Packets of an interface, from libpcap or else a raw socket, or of a
+/// stream
+pub enum PacketSource {
+    #[cfg(feature = "pcap")]
+    Pcap(Capture<Active>),
+    Raw(RawSocket),
+    Stream(stream::Reader),
+}
+
+impl PacketSource {
+    /// Link type of the packets, unless given for each one (pcapng)
+    fn datalink(&self) -> Option<Linktype> {
+        match self {
+            #[cfg(feature = "pcap")]
+            PacketSource::Pcap(cap) => Some(cap.get_datalink()),
+            PacketSource::Raw(socket) => Some(socket.datalink()),
+            PacketSource::Stream(reader) => reader.datalink(),
+        }
+    }
+
+    /// Packets dropped by the kernel since the capture was opened,
+    /// unknown for streams
+    fn dropped(&mut self) -> Option<u64> {
+        match self {
+            #[cfg(feature = "pcap")]
+            PacketSource::Pcap(cap) => cap.stats().ok().map(|stats| stats.dropped as u64),
+            PacketSource::Raw(socket) => socket.dropped().ok(),
+            PacketSource::Stream(_) => None,
+        }
+    }
+
+    /// Wait for the next packet, with the interface it was captured on
+    /// for streams
+    fn next_packet(
+        &mut self,
+    ) -> Result<(packet::Packet<'_>, Option<&stream::Interface>), packet::Error> {
+        match self {
+            #[cfg(feature = "pcap")]
+            PacketSource::Pcap(cap) => cap.next_packet().map(|pkt| (pkt, None)),
+            PacketSource::Raw(socket) => socket.next_packet().map(|pkt| (pkt, None)),
+            PacketSource::Stream(reader) => reader
+                .next_packet()
+                .map(|(pkt, interface)| (pkt, Some(interface))),
+        }
+    }
+}
+
+/// Capture packets and update the shared statistics
+///
+/// When following the default route (`follow` is the name of the
+/// interface currently in use), the capture stops on errors, after a
+/// suspend, or when the default route moves to another interface, so
+/// that the caller can reopen the right one. The capture of a stream
+/// stops at its end. Otherwise it never returns.
+pub fn run(
+    mut cap: PacketSource,
+    networks: &SharedNetworks,
+    out_stats: Arc<Mutex<Stats>>,
+    options: RunOptions,
+) {
+    let RunOptions {
+        follow,
+        wifi: shared_wifi,
+        icmp_rtt,
+        discover_names,
+        ssdp,
+        enricher,
+        shard,
+        vlans,
+        vlan_label,
+        mirror,
+        interface,
+        own_addresses,
+        categories,
+        hook,
+        schedule,
+        ports,
+        geo,
+        count,
+        flush_interval,
+        health: (health, name),
+        events,
+        samples,
+        packet_clock,
+    } = options;
+    // The default route is checked when flushing
+    let flush_interval = match follow {
+        Some(_) => flush_interval.min(ROUTE_CHECK_INTERVAL),
+        None => flush_interval,
+    };
+    // Given for each packet by pcapng streams
+    let linktype = cap.datalink().unwrap_or(Linktype::ETHERNET);
+    let framing = Framing::new(linktype);
+    let mut echo_tracker = EchoTracker::default();
+    let mut flows = Flows::default();
+    let mut classifier = match hook.map(Hook::instantiate).transpose() {
+        Ok(classifier) => classifier,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
+    let mut origins = geoip::Cache::default();
+    let mut last_ts = Duration::ZERO;
+    // End of the current interval of the throughput samples, following
+    // the timestamps of the packets
+    let mut next_sample = None;
+    // Statistics since the last sync
+    let mut stats = Stats::default();
+    let mut next_flush = Instant::now();
+    let mut unparsed = 0;
+    let mut lag = None;
+    let mut sample_lag = false;
+    let mut clock = Clock::new();
+    let mut since_route_check = Duration::ZERO;
+    let mut wifi = None;
+    let mut current = networks.lock().unwrap().clone();
+    let mut local_time = LocalTime::default();
+    loop {
+        if Instant::now() >= next_flush {
+            current = networks.lock().unwrap().clone();
+            out_stats
+                .lock()
+                .unwrap()
+                .merge(std::mem::take(&mut stats), current.max_tracking);
+            next_flush = Instant::now() + flush_interval;
+            if let Some(shared_wifi) = shared_wifi {
+                wifi = shared_wifi.lock().unwrap().clone();
+            }
+            if icmp_rtt {
+                echo_tracker.expire(last_ts);
+            }
+            let (elapsed, jump) = clock.tick();
+            let dropped = cap.dropped();
+            health.lock().unwrap().insert(
+                name.to_string(),
+                CaptureHealth {
+                    flush_interval: elapsed,
+                    dropped,
+                    unparsed,
+                    lag,
+                },
+            );
+            sample_lag = true;
+            match jump {
+                Some(Jump::Forward(delta)) => {
+                    events.emit(Event::ClockJump {
+                        seconds: delta.as_secs_f64(),
+                    });
+                    if follow.is_some() {
+                        return;
+                    }
+                }
+                Some(Jump::Backward(delta)) => {
+                    events.emit(Event::ClockJump {
+                        seconds: -delta.as_secs_f64(),
+                    });
+                }
+                None => {}
+            }
+            // The timestamps before the jump cannot be compared with the
+            // ones after it
+            if jump.is_some() {
+                echo_tracker.clear();
+                if let Some(samples) = samples.filter(|_| !packet_clock) {
+                    samples.lock().unwrap().rebaseline();
+                }
+            }
+            if let Some(follow) = follow {
+                since_route_check += elapsed;
+                if since_route_check >= ROUTE_CHECK_INTERVAL {
+                    since_route_check = Duration::ZERO;
+                    if laptop::default_route_interface().as_deref() != Some(follow) {
+                        println!("Default route moved away from {follow}");
+                        return;
+                    }
+                }
+            }
+        }
+
+        let pkt = match cap.next_packet() {
+            Ok(pkt) => Some(pkt),
+            Err(packet::Error::TimeoutExpired) => continue,
+            Err(err) if follow.is_some() => {
+                println!("Capture failed: {err}");
+                out_stats.lock().unwrap().merge(stats, current.max_tracking);
+                return;
+            }
+            Err(packet::Error::NoMorePackets) => {
+                out_stats.lock().unwrap().merge(stats, current.max_tracking);
+                return;
+            }
+            Err(_) => None,
+        };
+        if let Some((pkt, source)) = pkt {
+            let (linktype, framing) = match source {
+                Some(source) => (source.linktype, source.framing),
+                None => (linktype, framing),
+            };
+            // The packets before the epoch (shifted back by the offset
+            // of a pcapng interface) are left out of the samples
+            let time = u64::try_from(pkt.header.ts.tv_sec).ok();
+            if let Some((samples, time)) = samples.filter(|_| packet_clock).zip(time) {
+                let end = (time / burst::INTERVAL + 1).saturating_mul(burst::INTERVAL);
+                match next_sample {
+                    Some(next) if time >= next => {
+                        // The counters as of the end of the interval, the
+                        // packet being counted in the next one
+                        let mut out_stats = out_stats.lock().unwrap();
+                        out_stats.merge(std::mem::take(&mut stats), current.max_tracking);
+                        let totals = throughput_totals(&out_stats);
+                        drop(out_stats);
+                        samples.lock().unwrap().record(next, totals);
+                        next_sample = Some(end);
+                    }
+                    Some(_) => {}
+                    None => next_sample = Some(end),
+                }
+            }
+            if sample_lag && source.is_none() {
+                sample_lag = false;
+                let ts = &pkt.header.ts;
+                let captured =
+                    UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000);
+                lag = Some(
+                    SystemTime::now()
+                        .duration_since(captured)
+                        .unwrap_or_default(),
+                );
+            }
+            let Some(framing) = framing else {
+                unparsed += 1;
+                continue;
+            };
+            if let Some(mirror) = mirror {
+                mirror.send(linktype, &pkt);
+            }
+            // Labeled with the original interface, when named
+            let interface = source
+                .and_then(|source| source.name.clone())
+                .or_else(|| interface.clone());
+            if let Some((vlan, ip)) = framing.parse(pkt.data) {
+                if !vlans.is_empty() && !vlans.contains(&vlan) {
+                    continue;
+                }
+                let transport = &ip[link::header_len(ip)..];
+                let bytes = match count {
+                    Count::Wire => pkt.header.len,
+                    Count::Ip => match link::total_len(ip) {
+                        // Offloaded, from the length on the wire instead
+                        0 => pkt
+                            .header
+                            .len
+                            .saturating_sub((pkt.data.len() - ip.len()) as u32),
+                        total => total as u32,
+                    },
+                };
+                let ip_proto = ip[9];
+                let ip_source = u32::from_be_bytes(ip[12..16].try_into().unwrap());
+                let ip_dest = u32::from_be_bytes(ip[16..20].try_into().unwrap());
+                if let Some(own_addresses) = own_addresses {
+                    if framing.is_outgoing(pkt.data) && !own_addresses.contains(&ip_source) {
+                        continue;
+                    }
+                }
+                if ip_proto == 17 && current.is_local(ip_source) && transport.len() >= 8 {
+                    let udp = transport;
+                    let source_port = u16::from_be_bytes([udp[0], udp[1]]);
+                    let dest_port = u16::from_be_bytes([udp[2], udp[3]]);
+                    if discover_names {
+                        let discovered = match source_port {
+                            discovery::MDNS_PORT => discovery::parse_mdns(&udp[8..]),
+                            discovery::NETBIOS_NS_PORT => discovery::parse_netbios(&udp[8..]),
+                            _ => Vec::new(),
+                        };
+                        let discovered = discovered
+                            .into_iter()
+                            .filter(|(ip, _)| current.is_local(*ip))
+                            .collect();
+                        discovery::record(&mut stats.names, discovered, current.max_tracking);
+                    }
+                    if let Some(devices) = ssdp {
+                        if source_port == ssdp::SSDP_PORT || dest_port == ssdp::SSDP_PORT {
+                            if let Some((server, location)) = ssdp::parse(&udp[8..]) {
+                                ssdp::record(
+                                    enricher,
+                                    events,
+                                    devices,
+                                    ip_source,
+                                    server,
+                                    location,
+                                    current.max_tracking,
+                                );
+                            }
+                        }
+                    }
+                }
+                if current.is_excluded(ip_source) || current.is_excluded(ip_dest) {
+                    continue;
+                }
+                let from_local = current.is_local(ip_source);
+                let to_local = current.is_local(ip_dest);
+                if from_local != to_local {
+                    let ip_entry = if from_local { ip_source } else { ip_dest };
+                    let remote = if from_local { ip_dest } else { ip_source };
+                    if let Some(shard) = shard {
+                        if !shard.owns(ip_entry) {
+                            continue;
+                        }
+                    }
+                    let verdict = classifier.as_mut().map(|classifier| {
+                        let meta =
+                            Meta::new(ip_proto, transport, ip_entry, remote, from_local, bytes);
+                        classifier.classify(&meta)
+                    });
+                    if verdict == Some(Verdict::Drop) {
+                        continue;
+                    }
+                    let key = Key {
+                        ip: Some(ip_entry),
+                        wifi: wifi.clone(),
+                        vlan: vlan_label.then_some(vlan),
+                        interface,
+                        port: ports.map(|ports| ports.label(ip_proto, transport, from_local)),
+                    };
+                    if icmp_rtt && ip_proto == 1 {
+                        let ts = &pkt.header.ts;
+                        last_ts = Duration::new(ts.tv_sec as u64, ts.tv_usec as u32 * 1000);
+                        if let Some((peer, rtt)) =
+                            echo_tracker.process(transport, remote, from_local, last_ts)
+                        {
+                            icmp::record(&mut stats.latencies, peer, rtt, current.max_tracking);
+                        }
+                    }
+                    // The rules follow the TLS connections even when the
+                    // hook decides
+                    let category = categories.and_then(|rules| {
+                        rules.classify(
+                            &mut flows, ip_proto, transport, ip_entry, remote, from_local,
+                        )
+                    });
+                    let category = match verdict {
+                        Some(Verdict::Category(name)) => Some(name),
+                        _ => category,
+                    };
+                    if let Some(name) = category {
+                        record_named(&mut stats.categories, ip_entry, name, from_local, bytes);
+                    }
+                    if let Some(schedule) = schedule {
+                        let time = local_time.shift(pkt.header.ts.tv_sec);
+                        if let Some(period) = schedule.period(time) {
+                            record_named(&mut stats.periods, ip_entry, period, from_local, bytes);
+                        }
+                    }
+                    if let Some(geo) = geo {
+                        let origin = origins.origin(geo, remote);
+                        if let Some(country) = origin.country {
+                            record_named(
+                                &mut stats.countries,
+                                ip_entry,
+                                country,
+                                from_local,
+                                bytes,
+                            );
+                        }
+                        if let Some(asn) = origin.asn {
+                            record_named(&mut stats.asns, ip_entry, asn, from_local, bytes);
+                        }
+                    }
+                    let entry = stats.counters.entry(key);
+                    let entry = entry.or_default();
+                    let item = match ip_proto {
+                        1 => &mut entry.icmp,
+                        6 => &mut entry.tcp,
+                        17 => &mut entry.udp,
+                        _ => &mut entry.other,
+                    };
+                    let item = if from_local {
+                        &mut item.outbound
+                    } else {
+                        &mut item.inbound
+                    };
+                    item.pkts += 1;
+                    item.bytes += bytes as u64;
+                }
+            } else {
+                unparsed += 1;
+            }
+        }
+    }
+}
+
+/// Open a capture on the named interface, keeping only the first
+/// `snaplen` bytes of each packet
+///
+/// Unless blocking, the reads time out (or do not wait at all when
+/// busy polling), so that the capture loop flushes its counters (and
+/// checks the default route, when following it) even when no traffic
+/// is seen.
+///
+/// When libpcap is not able to list or open the interfaces, or when
+/// built without it, a raw socket is used instead.
+pub fn open_capture(
+    interface: &str,
+    snaplen: i32,
+    promisc: bool,
+    poll: PollMode,
+    flush_interval: Duration,
+) -> Result<(packet::Device, PacketSource), String> {
+    let timeout = poll.timeout(flush_interval);
+    let (devices, fallback) = match pcap_devices() {
+        Ok(devices) => (devices, None),
+        Err(err) => (
+            rawsock::devices().map_err(|_| format!("Device lookup failed: {err}"))?,
+            Some(err),
+        ),
+    };
+    let device = devices
+        .into_iter()
+        .find(|dev| dev.name == interface)
+        .or_else(|| (interface == ANY_DEVICE).then(|| packet::Device::from(ANY_DEVICE)))
+        .ok_or_else(|| format!("Device {interface:?} not found"))?;
+    println!("Using device {}", device.name);
+
+    let opened = match fallback {
+        Some(err) => Err(err),
+        None => open_pcap(&device, snaplen, promisc, poll, timeout),
+    };
+    let cap = match opened {
+        Ok(cap) => cap,
+        Err(err) => {
+            let socket = RawSocket::open(interface, snaplen, promisc, timeout)
+                .and_then(|socket| match poll {
+                    PollMode::Busy => socket.set_nonblocking().map(|_| socket),
+                    _ => Ok(socket),
+                })
+                .map_err(|raw| {
+                    format!("Unable to open {interface:?}: {err} (raw socket: {raw})")
+                })?;
+            if cfg!(feature = "pcap") {
+                println!("Unable to open {interface:?} with libpcap ({err}), using a raw socket");
+            }
+            PacketSource::Raw(socket)
+        }
+    };
+
+    let link = cap.datalink().unwrap();
+    if Framing::new(link).is_none() {
+        return Err(format!(
+            "Interface not supported. The link type of {interface:?} is {}.",
+            link.get_name().unwrap_or_else(|_| link.0.to_string())
+        ));
+    }
+    Ok((device, cap))
+}
+
+/// Interfaces listed by libpcap
+#[cfg(feature = "pcap")]
+pub fn pcap_devices() -> Result<Vec<packet::Device>, String> {
+    pcap::Device::list().map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "pcap"))]
+pub fn pcap_devices() -> Result<Vec<packet::Device>, String> {
+    Err(NO_PCAP.to_string())
+}
+
+/// Open a capture with libpcap
+#[cfg(feature = "pcap")]
+pub fn open_pcap(
+    device: &packet::Device,
+    snaplen: i32,
+    promisc: bool,
+    poll: PollMode,
+    timeout: Option<Duration>,
+) -> Result<PacketSource, String> {
+    let mut cap = pcap::Capture::from_device(device.clone())
+        .unwrap()
+        .immediate_mode(!matches!(poll, PollMode::Timeout(_)))
+        .promisc(promisc)
+        .snaplen(snaplen);
+    if let Some(timeout) = timeout {
+        cap = cap.timeout(timeout.as_millis().clamp(1, i32::MAX as u128) as i32);
+    }
+    let cap = cap.open().map_err(|err| err.to_string());
+    let cap = match poll {
+        PollMode::Busy => cap.and_then(|cap| cap.setnonblock().map_err(|err| err.to_string())),
+        _ => cap,
+    };
+    cap.map(PacketSource::Pcap)
+}
+
+#[cfg(not(feature = "pcap"))]
+pub fn open_pcap(
+    _device: &packet::Device,
+    _snaplen: i32,
+    _promisc: bool,
+    _poll: PollMode,
+    _timeout: Option<Duration>,
+) -> Result<PacketSource, String> {
+    Err(NO_PCAP.to_string())
+}
+
+/// Whether an interface is a pcap or pcapng stream ("-" for the
+/// standard input, or a path)
+pub fn is_stream(interface: &str) -> bool {
+    interface == "-" || interface.contains('/')
+}
+
+/// Open a pcap or pcapng stream, waiting for a writer in the case of
+/// a named pipe
+pub fn open_stream(path: &str) -> Result<PacketSource, String> {
+    let reader = stream::Reader::open(path)?;
+    if let Some(link) = reader
+        .datalink()
+        .filter(|link| Framing::new(*link).is_none())
+    {
+        return Err(format!(
+            "Stream not supported. The link type of {path:?} is {}.",
+            link.get_name().unwrap_or_else(|_| link.0.to_string())
+        ));
+    }
+    Ok(PacketSource::Stream(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{enrich::Enricher, stats::DEFAULT_MAX};
+
+    fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        body.resize(body.len().div_ceil(4) * 4, 0);
+        let len = (12 + body.len() as u32).to_le_bytes();
+        let mut block = kind.to_le_bytes().to_vec();
+        block.extend(len);
+        block.extend(body);
+        block.extend(len);
+        block
+    }
+
+    /// Raw IP interface, its timestamps shifted by `offset` seconds
+    fn interface(offset: i64) -> Vec<u8> {
+        let mut body = 101u16.to_le_bytes().to_vec();
+        body.extend([0, 0]);
+        body.extend(65535u32.to_le_bytes());
+        // if_tsoffset, then the end of the options
+        body.extend([14, 0, 8, 0]);
+        body.extend(offset.to_le_bytes());
+        body.extend([0; 4]);
+        block(1, &body)
+    }
+
+    /// Packet from 10.0.0.1 to 192.0.2.1 at a time (in seconds)
+    fn packet(interface: u32, time: u64) -> Vec<u8> {
+        let mut data = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 255, 0, 0];
+        data.extend([10, 0, 0, 1, 192, 0, 2, 1]);
+        let ts = time * 1_000_000;
+        let mut body = interface.to_le_bytes().to_vec();
+        body.extend(((ts >> 32) as u32).to_le_bytes());
+        body.extend((ts as u32).to_le_bytes());
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(data);
+        block(6, &body)
+    }
+
+    /// The packets before the epoch are counted but not sampled, the
+    /// ones after it are
+    #[tokio::test]
+    async fn packet_clock_before_the_epoch() {
+        let mut section = 0x1a2b3c4du32.to_le_bytes().to_vec();
+        section.extend([1, 0, 0, 0]);
+        section.extend([0xff; 8]);
+        let mut stream = block(0x0a0d0d0a, &section);
+        stream.extend(interface(-2_000_000_000));
+        stream.extend(interface(0));
+        // Up to the last second before the epoch, where the end of its
+        // interval would overflow
+        for time in [1_000_000_000, 1_999_999_999] {
+            stream.extend(packet(0, time));
+        }
+        for time in [1_600_000_000, 1_600_000_600, 1_600_001_200] {
+            stream.extend(packet(1, time));
+        }
+        let path = std::env::temp_dir().join(format!("txne-{}.pcapng", std::process::id()));
+        fs::write(&path, stream).unwrap();
+        let cap = PacketSource::Stream(stream::Reader::open(path.to_str().unwrap()).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let networks = Arc::new(Mutex::new(Arc::new(Networks {
+            local: vec![(0x0a00_0000, 0xff00_0000)],
+            excluded: Vec::new(),
+            max_tracking: DEFAULT_MAX,
+        })));
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let samples = Mutex::new(burst::Samples::default());
+        let enricher = Enricher::new(tokio::runtime::Handle::current());
+        let health = SharedHealth::default();
+        run(
+            cap,
+            &networks,
+            stats.clone(),
+            RunOptions {
+                follow: None,
+                wifi: None,
+                icmp_rtt: false,
+                discover_names: false,
+                ssdp: None,
+                enricher: &enricher,
+                shard: None,
+                vlans: &[],
+                vlan_label: false,
+                mirror: None,
+                interface: None,
+                own_addresses: None,
+                categories: None,
+                hook: None,
+                schedule: None,
+                ports: None,
+                geo: None,
+                count: Count::Wire,
+                flush_interval: Duration::from_secs(1),
+                health: (&health, "test"),
+                events: &Bus::new(),
+                samples: Some(&samples),
+                packet_clock: true,
+            },
+        );
+
+        let stats = stats.lock().unwrap();
+        let packets: u64 = stats
+            .counters
+            .values()
+            .map(|counters| counters.get(None).outbound.pkts)
+            .sum();
+        assert_eq!(packets, 5);
+        // Bytes recorded at the end of the first interval after the
+        // epoch, then a sample at the end of the next one
+        let samples = samples.lock().unwrap();
+        let rolling = samples.rolling();
+        assert_eq!(rolling.len(), 1);
+        assert_eq!(rolling[0].0, Some(0x0a00_0001));
+        assert_eq!(rolling[0].1.samples, 1);
+    }
+}
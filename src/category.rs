@@ -0,0 +1,377 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{hosts::parse_subnets, stats::Protocol};
+
+/// TCP connections whose server name is remembered, beyond which they
+/// are all forgotten
+const MAX_FLOWS: usize = 65536;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_RST: u8 = 0x04;
+
+/// A category of traffic (configuration file only)
+///
+/// The traffic matches when every given criterion matches, the remote
+/// side being the peer outside of the network. The first matching
+/// category is used.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Category {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<Protocol>,
+    /// Remote ports (TCP and UDP)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<Ports>,
+    /// Remote subnets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<String>,
+    /// Files listing remote subnets, one per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefix_lists: Vec<PathBuf>,
+    /// Server names of the TLS connections, `*.` matching the domain
+    /// and its subdomains
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sni: Vec<String>,
+}
+
+/// A port, or a range of ports (`FIRST-LAST`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "PortsValue", into = "PortsValue")]
+pub struct Ports {
+    first: u16,
+    last: u16,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum PortsValue {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortsValue> for Ports {
+    type Error = String;
+
+    fn try_from(value: PortsValue) -> Result<Self, Self::Error> {
+        match value {
+            PortsValue::Port(port) => Ok(Ports {
+                first: port,
+                last: port,
+            }),
+            PortsValue::Range(range) => {
+                let parse = |port: &str| port.trim().parse::<u16>().ok();
+                let (first, last) = range.split_once('-').unwrap_or((&range, &range));
+                match (parse(first), parse(last)) {
+                    (Some(first), Some(last)) if first <= last => Ok(Ports { first, last }),
+                    _ => Err(format!("Invalid port range {range:?}")),
+                }
+            }
+        }
+    }
+}
+
+impl From<Ports> for PortsValue {
+    fn from(ports: Ports) -> Self {
+        if ports.first == ports.last {
+            PortsValue::Port(ports.first)
+        } else {
+            PortsValue::Range(format!("{}-{}", ports.first, ports.last))
+        }
+    }
+}
+
+impl Category {
+    /// Check the category, returning its subnets
+    fn subnets(&self) -> Result<Vec<(u32, u32)>, String> {
+        if self.name.is_empty() {
+            return Err("Categories need a \"name\"".to_string());
+        }
+        if self.protocols.is_empty()
+            && self.ports.is_empty()
+            && self.prefixes.is_empty()
+            && self.prefix_lists.is_empty()
+            && self.sni.is_empty()
+        {
+            return Err(format!("The category {:?} matches everything", self.name));
+        }
+        let mut subnets = Vec::new();
+        for prefix in &self.prefixes {
+            subnets
+                .extend(parse_subnets(prefix).ok_or_else(|| format!("Invalid prefix {prefix:?}"))?);
+        }
+        for path in &self.prefix_lists {
+            let content = fs::read_to_string(path)
+                .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+            for line in content.lines() {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                subnets.extend(
+                    parse_subnets(line)
+                        .ok_or_else(|| format!("Invalid prefix {line:?} in {}", path.display()))?,
+                );
+            }
+        }
+        Ok(subnets)
+    }
+}
+
+struct Rule {
+    name: Arc<str>,
+    protocols: Vec<Protocol>,
+    ports: Vec<Ports>,
+    subnets: Vec<(u32, u32)>,
+    sni: Vec<String>,
+}
+
+impl Rule {
+    fn matches(
+        &self,
+        protocol: Protocol,
+        remote: u32,
+        port: Option<u16>,
+        sni: Option<&str>,
+    ) -> bool {
+        (self.protocols.is_empty() || self.protocols.contains(&protocol))
+            && (self.ports.is_empty()
+                || port.is_some_and(|port| {
+                    self.ports
+                        .iter()
+                        .any(|ports| (ports.first..=ports.last).contains(&port))
+                }))
+            && (self.subnets.is_empty()
+                || self
+                    .subnets
+                    .iter()
+                    .any(|(addr, mask)| remote & mask == *addr))
+            && (self.sni.is_empty()
+                || sni.is_some_and(|sni| self.sni.iter().any(|pattern| matches_name(pattern, sni))))
+    }
+}
+
+/// Whether a server name matches a pattern (case insensitive)
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            name == domain
+                || name
+                    .strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        }
+        None => name == pattern,
+    }
+}
+
+/// The categories, ready to classify packets
+pub struct Rules {
+    rules: Vec<Rule>,
+    /// Whether a server name is needed
+    sni: bool,
+}
+
+impl Rules {
+    pub fn new(categories: &[Category]) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for category in categories {
+            rules.push(Rule {
+                name: Arc::from(category.name.as_str()),
+                protocols: category.protocols.clone(),
+                ports: category.ports.clone(),
+                subnets: category.subnets()?,
+                sni: category.sni.clone(),
+            });
+        }
+        Ok(Self {
+            sni: rules.iter().any(|rule| !rule.sni.is_empty()),
+            rules,
+        })
+    }
+
+    /// Whether the payload of the packets is needed (for the server
+    /// names)
+    pub fn needs_payload(&self) -> bool {
+        self.sni
+    }
+
+    /// Category of a packet, from its IP protocol and its transport
+    /// header (and payload)
+    ///
+    /// The server name of a TLS connection is learned from the
+    /// ClientHello, and remembered until the connection is closed.
+    pub fn classify(
+        &self,
+        flows: &mut Flows,
+        ip_proto: u8,
+        transport: &[u8],
+        local: u32,
+        remote: u32,
+        outbound: bool,
+    ) -> Option<Arc<str>> {
+        let protocol = Protocol::from_number(ip_proto);
+        let ports = matches!(protocol, Protocol::Tcp | Protocol::Udp)
+            .then(|| transport.get(..4))
+            .flatten()
+            .map(|ports| {
+                let source = u16::from_be_bytes([ports[0], ports[1]]);
+                let dest = u16::from_be_bytes([ports[2], ports[3]]);
+                if outbound {
+                    (source, dest)
+                } else {
+                    (dest, source)
+                }
+            });
+        let find = |sni: Option<&str>| {
+            self.rules.iter().position(|rule| {
+                rule.matches(protocol, remote, ports.map(|(_, remote)| remote), sni)
+            })
+        };
+        let index = match ports {
+            Some((local_port, remote_port)) if self.sni && ip_proto == 6 => {
+                let key = (local, local_port, remote, remote_port);
+                if outbound {
+                    let offset = (*transport.get(12)? >> 4) as usize * 4;
+                    if let Some(name) = transport.get(offset..).and_then(parse_sni) {
+                        if flows.entries.len() >= MAX_FLOWS {
+                            flows.entries.clear();
+                        }
+                        flows.entries.insert(key, find(Some(&name)));
+                    }
+                }
+                let known = flows.entries.get(&key).copied();
+                if transport
+                    .get(13)
+                    .is_some_and(|flags| flags & (TCP_FIN | TCP_RST) != 0)
+                {
+                    flows.entries.remove(&key);
+                }
+                known.unwrap_or_else(|| find(None))
+            }
+            _ => find(None),
+        };
+        index.map(|index| self.rules[index].name.clone())
+    }
+}
+
+/// Categories of the TLS connections seen by a capture, by local IP
+/// and port, and remote IP and port
+#[derive(Default)]
+pub struct Flows {
+    entries: HashMap<(u32, u16, u32, u16), Option<usize>>,
+}
+
+/// Server name of a TLS ClientHello
+fn parse_sni(payload: &[u8]) -> Option<String> {
+    let u16_at = |offset: usize| -> Option<usize> {
+        Some(u16::from_be_bytes(payload.get(offset..offset + 2)?.try_into().unwrap()) as usize)
+    };
+    // Handshake record, with a ClientHello
+    if *payload.first()? != 0x16 || *payload.get(5)? != 1 {
+        return None;
+    }
+    // Skip the versions and random, then the session ID, cipher suites
+    // and compression methods
+    let mut offset = 43;
+    offset += 1 + *payload.get(offset)? as usize;
+    offset += 2 + u16_at(offset)?;
+    offset += 1 + *payload.get(offset)? as usize;
+    let end = (offset + 2 + u16_at(offset)?).min(payload.len());
+    offset += 2;
+    while offset + 4 <= end {
+        let kind = u16_at(offset)?;
+        let len = u16_at(offset + 2)?;
+        offset += 4;
+        if kind == 0 {
+            // Server name list, with a host name first
+            if *payload.get(offset + 2)? != 0 {
+                return None;
+            }
+            let len = u16_at(offset + 3)?;
+            let name = payload.get(offset + 5..offset + 5 + len)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        offset += len;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TLS record with a ClientHello, with a padding extension before the
+    /// server name one
+    fn client_hello(name: &[u8]) -> Vec<u8> {
+        let mut server_name = vec![0, 0];
+        server_name.extend((name.len() as u16 + 5).to_be_bytes());
+        server_name.extend((name.len() as u16 + 3).to_be_bytes());
+        server_name.push(0);
+        server_name.extend((name.len() as u16).to_be_bytes());
+        server_name.extend(name);
+        let mut extensions = vec![0, 0x15, 0, 2, 0, 0];
+        extensions.extend(server_name);
+
+        let mut hello = vec![3, 3];
+        hello.extend([0x5a; 32]);
+        // Session ID, cipher suites and compression methods
+        hello.extend([1, 0xaa, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend((extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![1, 0];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn sni() {
+        let hello = client_hello(b"www.example.com");
+        assert_eq!(parse_sni(&hello).as_deref(), Some("www.example.com"));
+    }
+
+    #[test]
+    fn sni_truncated() {
+        let hello = client_hello(b"www.example.com");
+        for len in 0..hello.len() {
+            assert_eq!(parse_sni(&hello[..len]), None, "{len}");
+        }
+    }
+
+    #[test]
+    fn sni_malformed() {
+        let hello = client_hello(b"www.example.com");
+        // Not a handshake, or not a ClientHello
+        let mut payload = hello.clone();
+        payload[0] = 0x17;
+        assert_eq!(parse_sni(&payload), None);
+        let mut payload = hello.clone();
+        payload[5] = 2;
+        assert_eq!(parse_sni(&payload), None);
+        // Lengths past the end: session ID, cipher suites, compression
+        // methods, padding and server name
+        for offset in [43, 45, 49, 55, 66] {
+            let mut payload = hello.clone();
+            payload[offset] = 0xff;
+            assert_eq!(parse_sni(&payload), None, "{offset}");
+        }
+        // Not a host name
+        let mut payload = hello.clone();
+        payload[65] = 1;
+        assert_eq!(parse_sni(&payload), None);
+        // Invalid UTF-8
+        assert_eq!(parse_sni(&client_hello(b"www.\xff.com")), None);
+        // Without extensions
+        let mut payload = hello[..51].to_vec();
+        payload.extend([0, 0]);
+        assert_eq!(parse_sni(&payload), None);
+    }
+}
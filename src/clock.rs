@@ -0,0 +1,53 @@
+use std::time::{Duration, Instant, SystemTime};
+
+/// Wall clock drift beyond which a jump is reported
+const JUMP_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Monotonic clock that detects wall clock jumps
+///
+/// Everything time related (rates, expiry, ...) must be computed from
+/// the monotonic `Instant`. The wall clock is only tracked to notice
+/// NTP steps and suspend/resume cycles (the monotonic clock does not
+/// advance while the machine is suspended), so that time based logic
+/// can re-baseline instead of seeing a huge or negative interval.
+pub struct Clock {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+/// A detected discrepancy between the wall clock and the monotonic clock
+#[derive(Debug, Clone, Copy)]
+pub enum Jump {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            instant: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// Advance the clock, returning the monotonic elapsed time since the
+    /// previous tick and any wall clock jump observed in the meantime
+    pub fn tick(&mut self) -> (Duration, Option<Jump>) {
+        let instant = Instant::now();
+        let wall = SystemTime::now();
+        let elapsed = instant.duration_since(self.instant);
+        let jump = match wall.duration_since(self.wall) {
+            Ok(wall_elapsed) if wall_elapsed > elapsed + JUMP_THRESHOLD => {
+                Some(Jump::Forward(wall_elapsed - elapsed))
+            }
+            Ok(wall_elapsed) if wall_elapsed + JUMP_THRESHOLD < elapsed => {
+                Some(Jump::Backward(elapsed - wall_elapsed))
+            }
+            Ok(_) => None,
+            Err(err) => Some(Jump::Backward(elapsed + err.duration())),
+        };
+        self.instant = instant;
+        self.wall = wall;
+        (elapsed, jump)
+    }
+}
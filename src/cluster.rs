@@ -0,0 +1,60 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often a standby instance tries to take the lead
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether this instance is currently the leader
+pub type Leadership = Arc<AtomicBool>;
+
+/// Take part in the election of a leader among the instances sharing
+/// the given lease file
+///
+/// The leader is the instance holding an exclusive lock on the file,
+/// and it keeps it until it exits. Standby instances retry
+/// periodically, so that one of them takes over when the leader goes
+/// away. The file must be on a file system supporting locks shared by
+/// every instance (local, or NFS with lock support).
+pub fn spawn(path: PathBuf, id: String) -> Result<Leadership, String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|err| format!("Unable to open lease file {}: {err}", path.display()))?;
+    let leader = Leadership::default();
+    let result = leader.clone();
+    thread::spawn(move || loop {
+        match file.try_lock() {
+            Ok(()) => {
+                println!("Elected leader ({})", path.display());
+                write_owner(&file, &id);
+                leader.store(true, Ordering::Relaxed);
+                // The lock is held for as long as the file stays open
+                loop {
+                    thread::park();
+                }
+            }
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(err)) => {
+                println!("Unable to lock {}: {err}", path.display());
+            }
+        }
+        thread::sleep(RETRY_INTERVAL);
+    });
+    Ok(result)
+}
+
+/// Record the identity of the leader in the lease file, for operators
+fn write_owner(mut file: &File, id: &str) {
+    let _ = file.set_len(0).and_then(|()| writeln!(file, "{id}"));
+}
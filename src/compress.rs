@@ -0,0 +1,118 @@
+use std::io::Write;
+
+use axum::{
+    body::{self, Full},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+/// Smaller responses are sent as is
+const MIN_SIZE: usize = 1024;
+
+/// Content encoding accepted by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Encoding with the highest weight in an `Accept-Encoding` header, gzip
+/// on a tie
+pub fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let weight = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let encoding = if name.eq_ignore_ascii_case("gzip") || name == "*" {
+            Encoding::Gzip
+        } else if name.eq_ignore_ascii_case("deflate") {
+            Encoding::Deflate
+        } else {
+            continue;
+        };
+        if weight <= 0.0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((current, current_weight)) => {
+                weight > current_weight
+                    || (weight == current_weight
+                        && encoding == Encoding::Gzip
+                        && current != encoding)
+            }
+        };
+        if better {
+            best = Some((encoding, weight));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compress the responses with the encoding negotiated with the client
+pub async fn compress<B>(request: Request<B>, next: Next<B>) -> Response {
+    let encoding = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate);
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = encoding else {
+        return response;
+    };
+    if response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(data) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    if data.len() < MIN_SIZE {
+        return Response::from_parts(parts, body::boxed(Full::from(data)));
+    }
+    let Ok(encoded) = encoding.encode(&data) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    Response::from_parts(parts, body::boxed(Full::from(encoded)))
+}
@@ -0,0 +1,185 @@
+use std::{fs, path::Path, path::PathBuf};
+
+use clap::{parser::ValueSource, ArgMatches};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    category::Category, events::Subscriber, link::Count, mirror, naming::Source,
+    notify::Notification, poll::PollMode, probe::Target, profile::Profile, service::Service,
+    shard::Shard, window::Window, Args, Compat,
+};
+
+/// A command line setting that can also come from the configuration
+/// file, whether it is optional on the command line or not
+trait Setting<T> {
+    fn set(&mut self, value: T);
+    fn get(&self) -> Option<T>;
+}
+
+impl<T: Clone> Setting<T> for T {
+    fn set(&mut self, value: T) {
+        *self = value;
+    }
+
+    fn get(&self) -> Option<T> {
+        Some(self.clone())
+    }
+}
+
+impl<T: Clone> Setting<T> for Option<T> {
+    fn set(&mut self, value: T) {
+        *self = Some(value);
+    }
+
+    fn get(&self) -> Option<T> {
+        self.clone()
+    }
+}
+
+/// Whether the argument was given explicitly on the command line
+///
+/// Settings only available in the configuration file are never given.
+pub fn given_on_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.ids().any(|known| known.as_str() == id)
+        && matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// Declare the settings available in the configuration file
+///
+/// Each key is named after the command line option (`dhcp-leases`
+/// for `--dhcp-leases`) and has the same type.
+macro_rules! config {
+    ($($field:ident: $ty:ty,)*) => {
+        /// Content of the configuration file
+        #[derive(Debug, Default, Deserialize, Serialize)]
+        #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+        pub struct Config {
+            $(
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub $field: Option<$ty>,
+            )*
+        }
+
+        impl Config {
+            /// Apply the settings of the file to the arguments, except
+            /// the ones given explicitly on the command line
+            pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+                $(
+                    if let Some(value) = self.$field {
+                        if !given_on_command_line(matches, stringify!($field)) {
+                            Setting::<$ty>::set(&mut args.$field, value);
+                        }
+                    }
+                )*
+            }
+
+            /// Configuration equivalent to the given arguments
+            pub fn effective(args: &Args) -> Self {
+                Self {
+                    $($field: Setting::<$ty>::get(&args.$field),)*
+                }
+            }
+        }
+    };
+}
+
+config! {
+    profile: Profile,
+    interface: Vec<String>,
+    bind: String,
+    port: u16,
+    bind_unix: PathBuf,
+    tls_cert: PathBuf,
+    tls_key: PathBuf,
+    tls_client_ca: PathBuf,
+    basic_auth_users: PathBuf,
+    bearer_token_file: PathBuf,
+    allow_scrape_from: String,
+    subnets: String,
+    exclude: String,
+    max: usize,
+    promisc: bool,
+    flush_interval: u64,
+    poll_mode: PollMode,
+    host_label: String,
+    namespace: String,
+    legacy_names: bool,
+    compat: Compat,
+    laptop: bool,
+    wifi: bool,
+    icmp_rtt: bool,
+    probe: Vec<Target>,
+    probe_interval: u64,
+    dhcp_leases: Vec<PathBuf>,
+    discover_names: bool,
+    ssdp: bool,
+    hosts_file: PathBuf,
+    name_sources: Vec<Source>,
+    reverse_dns: bool,
+    kubernetes: bool,
+    docker: bool,
+    source_labels: bool,
+    vm_import_url: String,
+    vm_import_interval: u64,
+    leader_lease: PathBuf,
+    leader_id: String,
+    shard: Shard,
+    mirror: mirror::Target,
+    mirror_filter: String,
+    vlan: Vec<u16>,
+    vlan_label: bool,
+    geoip_db: PathBuf,
+    asn_db: PathBuf,
+    count: Count,
+    ports: Vec<Service>,
+    wasm_hook: PathBuf,
+    shm_file: PathBuf,
+    grpc_port: u16,
+    state_dir: PathBuf,
+    percentiles: bool,
+    notify: Vec<Notification>,
+    subscribe: Vec<Subscriber>,
+    category: Vec<Category>,
+    script: PathBuf,
+    window: Vec<Window>,
+}
+
+/// Format of a configuration file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Format of a file, from its extension (TOML by default)
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Toml,
+        }
+    }
+}
+
+/// Load a TOML or YAML configuration file
+///
+/// Unknown keys are rejected, and errors point to the offending line
+/// and key.
+pub fn load(path: &Path) -> Result<Config, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    let result = match Format::of(path) {
+        Format::Toml => toml::from_str(&content).map_err(|err| err.to_string()),
+        Format::Yaml => serde_yaml::from_str(&content).map_err(|err| err.to_string()),
+    };
+    result.map_err(|err| format!("Invalid configuration {}:\n{err}", path.display()))
+}
+
+/// Render the configuration
+pub fn print(config: &Config, format: Format) -> String {
+    match format {
+        Format::Toml => toml::to_string(config).unwrap(),
+        Format::Yaml => serde_yaml::to_string(config).unwrap(),
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{enrich::SharedEnricher, naming::Resolver, report};
+
+/// How often the lease files are read again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct Lease {
+    pub name: Option<String>,
+    pub mac: Option<String>,
+}
+
+/// Leases by IPv4 address
+pub type Leases = HashMap<u32, Lease>;
+
+pub type SharedLeases = Arc<Mutex<Leases>>;
+
+impl Resolver for SharedLeases {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        self.lock().unwrap().get(&ip)?.name.clone()
+    }
+}
+
+/// Parse a lease file, guessing its format (ISC dhcpd, Kea CSV or
+/// dnsmasq)
+///
+/// The leases that are released or expired at `now` (seconds since the
+/// UNIX epoch) are left out, the files of Kea and ISC dhcpd keeping them
+/// until they are cleaned up.
+pub fn parse(content: &str, now: u64) -> Leases {
+    if content.starts_with("address,") {
+        parse_kea(content, now)
+    } else if content
+        .lines()
+        .any(|line| line.trim_start().starts_with("lease "))
+    {
+        parse_isc(content, now)
+    } else {
+        parse_dnsmasq(content)
+    }
+}
+
+fn parse_ip(ip: &str) -> Option<u32> {
+    let ip: Ipv4Addr = ip.parse().ok()?;
+    Some(u32::from_be_bytes(ip.octets()))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim().trim_matches('"');
+    (!value.is_empty() && value != "*").then(|| value.to_string())
+}
+
+/// `EXPIRY MAC IP HOSTNAME CLIENT-ID` (with `*` for unknown values)
+fn parse_dnsmasq(content: &str) -> Leases {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let ip = parse_ip(fields.get(2)?)?;
+            let lease = Lease {
+                name: fields.get(3).and_then(|name| non_empty(name)),
+                mac: fields.get(1).and_then(|mac| non_empty(mac)),
+            };
+            Some((ip, lease))
+        })
+        .collect()
+}
+
+/// CSV with a header line naming the columns
+///
+/// The file is appended to until it is cleaned up (LFC), the last row of
+/// an address wins. Only the rows in the default state (0) that are not
+/// expired are valid leases.
+fn parse_kea(content: &str, now: u64) -> Leases {
+    let mut lines = content.lines();
+    let header = lines
+        .next()
+        .unwrap_or_default()
+        .split(',')
+        .collect::<Vec<_>>();
+    let column = |name| header.iter().position(|column| *column == name);
+    let (Some(address), hwaddr, hostname, expire, state) = (
+        column("address"),
+        column("hwaddr"),
+        column("hostname"),
+        column("expire"),
+        column("state"),
+    ) else {
+        return Leases::new();
+    };
+    let mut leases = Leases::new();
+    for line in lines {
+        let fields = line.split(',').collect::<Vec<_>>();
+        let Some(ip) = fields.get(address).and_then(|ip| parse_ip(ip)) else {
+            continue;
+        };
+        let field = |index: Option<usize>| non_empty(fields.get(index?)?);
+        let expired = field(expire)
+            .and_then(|expire| expire.parse::<u64>().ok())
+            .is_some_and(|expire| expire <= now);
+        let valid = field(state).is_none_or(|state| state == "0");
+        if expired || !valid {
+            leases.remove(&ip);
+            continue;
+        }
+        let lease = Lease {
+            name: field(hostname),
+            mac: field(hwaddr),
+        };
+        leases.insert(ip, lease);
+    }
+    leases
+}
+
+/// End of an ISC dhcpd lease (`ends`): `W YYYY/MM/DD HH:MM:SS` (UTC),
+/// `epoch SECONDS`, or `never` (`None`)
+fn parse_isc_end(value: &str) -> Option<u64> {
+    let mut fields = value.split_whitespace();
+    match fields.next()? {
+        "never" => None,
+        "epoch" => fields.next()?.parse().ok(),
+        _ => {
+            let (date, time) = (fields.next()?, fields.next()?);
+            let date = date
+                .split('/')
+                .map(|field| field.parse::<u64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let time = time
+                .split(':')
+                .map(|field| field.parse::<u64>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let (&[year, month, day], &[hours, minutes, seconds]) =
+                (date.as_slice(), time.as_slice())
+            else {
+                return None;
+            };
+            if !(1970..10000).contains(&year) || !(1..=12).contains(&month) {
+                return None;
+            }
+            let days = report::days_from_civil(year, month, day);
+            Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+        }
+    }
+}
+
+/// `lease IP { ... }` blocks, the last block of an address wins
+///
+/// Only the active leases that did not end are valid, the file keeping
+/// the free and expired ones until it is rewritten.
+fn parse_isc(content: &str, now: u64) -> Leases {
+    let mut leases = Leases::new();
+    let mut current = None;
+    let mut active = false;
+    let mut ends = None;
+    for line in content.lines() {
+        // Before the comment following `ends epoch SECONDS;`
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if let Some(rest) = line.strip_prefix("lease ") {
+            let ip = rest.trim_end_matches('{').trim();
+            current = parse_ip(ip).map(|ip| (ip, Lease::default()));
+            active = false;
+            ends = None;
+        } else if line == "}" {
+            if let Some((ip, lease)) = current.take() {
+                if active && ends.is_none_or(|ends| ends > now) {
+                    leases.insert(ip, lease);
+                } else {
+                    leases.remove(&ip);
+                }
+            }
+        } else if let Some((_, lease)) = &mut current {
+            if let Some(mac) = line.strip_prefix("hardware ethernet ") {
+                lease.mac = non_empty(mac);
+            } else if let Some(name) = line.strip_prefix("client-hostname ") {
+                lease.name = non_empty(name);
+            } else if let Some(state) = line.strip_prefix("binding state ") {
+                active = state.trim() == "active";
+            } else if let Some(end) = line.strip_prefix("ends ") {
+                ends = parse_isc_end(end);
+            }
+        }
+    }
+    leases
+}
+
+/// Read the lease files periodically in the background
+///
+/// When an address appears in several files, the last file wins.
+pub fn spawn_watcher(enricher: SharedEnricher, files: Vec<PathBuf>, leases: SharedLeases) {
+    thread::spawn(move || loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut result = Leases::new();
+        for file in &files {
+            match fs::read_to_string(file) {
+                Ok(content) => {
+                    result.extend(parse(&content, now));
+                    enricher.report("dhcp", true);
+                }
+                Err(err) => {
+                    println!("Unable to read {}: {err}", file.display());
+                    enricher.report("dhcp", false);
+                }
+            }
+        }
+        *leases.lock().unwrap() = result;
+        thread::sleep(REFRESH_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-10-09 08:53:20 UTC
+    const NOW: u64 = 1_760_000_000;
+
+    #[test]
+    fn kea_state_and_expiry() {
+        let content = concat!(
+            "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,",
+            "hostname,state,user_context\n",
+            "10.0.0.1,00:11:22:33:44:01,,3600,1760003600,1,0,0,laptop,0,\n",
+            "10.0.0.2,00:11:22:33:44:02,,3600,1759990000,1,0,0,expired,0,\n",
+            "10.0.0.3,00:11:22:33:44:03,,3600,1760003600,1,0,0,declined,1,\n",
+            "10.0.0.4,00:11:22:33:44:04,,3600,1760003600,1,0,0,phone,0,\n",
+            // Released later, in the same file
+            "10.0.0.4,00:11:22:33:44:04,,0,1760003600,1,0,0,phone,2,\n",
+        );
+        let leases = parse(content, NOW);
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[&0x0a00_0001].name.as_deref(), Some("laptop"));
+    }
+
+    #[test]
+    fn isc_state_and_expiry() {
+        let content = "\
+lease 10.0.0.1 {
+  starts 4 2025/10/09 08:00:00;
+  ends 4 2025/10/09 10:00:00;
+  binding state active;
+  next binding state free;
+  hardware ethernet 00:11:22:33:44:01;
+  client-hostname \"laptop\";
+}
+lease 10.0.0.2 {
+  ends never;
+  binding state free;
+  client-hostname \"freed\";
+}
+lease 10.0.0.3 {
+  ends 1 2025/10/06 10:00:00;
+  binding state active;
+  client-hostname \"expired\";
+}
+lease 10.0.0.4 {
+  ends epoch 1760003600; # Thu Oct 09 09:53:20 2025
+  binding state active;
+  client-hostname \"phone\";
+}
+lease 10.0.0.1 {
+  ends never;
+  binding state active;
+  hardware ethernet 00:11:22:33:44:01;
+  client-hostname \"laptop\";
+}
+";
+        let leases = parse(content, NOW);
+        let mut names = leases
+            .values()
+            .filter_map(|lease| lease.name.as_deref())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["laptop", "phone"]);
+        assert_eq!(parse_isc_end("4 2025/10/09 10:00:00"), Some(1_760_004_000));
+        assert_eq!(parse(content, 1_760_004_000).len(), 1);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{naming::Resolver, stats::Stats};
+
+pub const MDNS_PORT: u16 = 5353;
+pub const NETBIOS_NS_PORT: u16 = 137;
+
+const TYPE_A: u16 = 1;
+const TYPE_NB: u16 = 0x20;
+
+/// Names discovered from announcements, by IPv4 address
+pub type Names = HashMap<u32, String>;
+
+impl Resolver for Arc<Mutex<Stats>> {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        self.lock().unwrap().names.get(&ip).cloned()
+    }
+}
+
+/// Read a (possibly compressed) DNS name starting at `offset`,
+/// returning its labels and the offset following it
+fn read_name(msg: &[u8], mut offset: usize) -> Option<(Vec<&[u8]>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bound the number of jumps to survive pointer loops
+    for _ in 0..32 {
+        let len = *msg.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let pointer = (len & 0x3f) << 8 | *msg.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+        } else if len == 0 {
+            return Some((labels, end.unwrap_or(offset + 1)));
+        } else {
+            labels.push(msg.get(offset + 1..offset + 1 + len)?);
+            offset += 1 + len;
+        }
+    }
+    None
+}
+
+/// Iterate over the resource records of a DNS message, calling `f`
+/// with the name, type and data of each one
+fn for_each_record<'a>(msg: &'a [u8], mut f: impl FnMut(Vec<&'a [u8]>, u16, &'a [u8])) {
+    let count = |offset: usize| -> usize {
+        msg.get(offset..offset + 2)
+            .map(|count| u16::from_be_bytes([count[0], count[1]]) as usize)
+            .unwrap_or(0)
+    };
+    let questions = count(4);
+    let records = count(6) + count(8) + count(10);
+    let mut offset = 12;
+    for _ in 0..questions {
+        let Some((_, end)) = read_name(msg, offset) else {
+            return;
+        };
+        offset = end + 4;
+    }
+    for _ in 0..records {
+        let Some((name, end)) = read_name(msg, offset) else {
+            return;
+        };
+        let Some(header) = msg.get(end..end + 10) else {
+            return;
+        };
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let Some(rdata) = msg.get(end + 10..end + 10 + rdlength) else {
+            return;
+        };
+        f(name, rtype, rdata);
+        offset = end + 10 + rdlength;
+    }
+}
+
+/// Extract the host names announced by a mDNS response
+///
+/// Only the A records are considered, and the `.local` suffix is
+/// removed.
+pub fn parse_mdns(msg: &[u8]) -> Vec<(u32, String)> {
+    let mut result = Vec::new();
+    for_each_record(msg, |name, rtype, rdata| {
+        if rtype != TYPE_A || rdata.len() != 4 {
+            return;
+        }
+        let mut labels = name
+            .iter()
+            .map(|label| String::from_utf8_lossy(label))
+            .collect::<Vec<_>>();
+        if labels
+            .last()
+            .map(|label| label.eq_ignore_ascii_case("local"))
+            == Some(true)
+        {
+            labels.pop();
+        }
+        if !labels.is_empty() {
+            let ip = u32::from_be_bytes(rdata.try_into().unwrap());
+            result.push((ip, labels.join(".")));
+        }
+    });
+    result
+}
+
+/// Decode a NetBIOS first-level encoded name, returning the name and
+/// its suffix
+fn decode_netbios(label: &[u8]) -> Option<(String, u8)> {
+    if label.len() != 32 {
+        return None;
+    }
+    let bytes = label
+        .chunks(2)
+        .map(|pair| {
+            let high = pair[0].checked_sub(b'A')?;
+            let low = pair[1].checked_sub(b'A')?;
+            (high < 16 && low < 16).then_some(high << 4 | low)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let name = String::from_utf8_lossy(&bytes[..15]).trim_end().to_string();
+    Some((name, bytes[15]))
+}
+
+/// Extract the workstation names from NetBIOS name registrations and
+/// query responses
+pub fn parse_netbios(msg: &[u8]) -> Vec<(u32, String)> {
+    let mut result = Vec::new();
+    for_each_record(msg, |name, rtype, rdata| {
+        if rtype != TYPE_NB || rdata.len() < 6 {
+            return;
+        }
+        let Some((name, suffix)) = name.first().and_then(|label| decode_netbios(label)) else {
+            return;
+        };
+        // Workstation and server services only (not group names)
+        let group = rdata[0] & 0x80 != 0;
+        if (suffix == 0x00 || suffix == 0x20) && !group && !name.is_empty() {
+            let ip = u32::from_be_bytes(rdata[2..6].try_into().unwrap());
+            result.push((ip, name));
+        }
+    });
+    result
+}
+
+/// Record discovered names, bounding the number of entries
+pub fn record(names: &mut Names, discovered: Vec<(u32, String)>, max_tracking: usize) {
+    for (ip, name) in discovered {
+        if names.contains_key(&ip) || names.len() < max_tracking {
+            names.insert(ip, name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DNS header of a response with the given number of questions and
+    /// answers
+    fn header(questions: u16, answers: u16) -> Vec<u8> {
+        let mut msg = vec![0, 0, 0x84, 0];
+        msg.extend(questions.to_be_bytes());
+        msg.extend(answers.to_be_bytes());
+        msg.extend([0, 0, 0, 0]);
+        msg
+    }
+
+    /// Resource record of the given name (encoded), type and data
+    fn record(msg: &mut Vec<u8>, name: &[u8], rtype: u16, rdata: &[u8]) {
+        msg.extend(name);
+        msg.extend(rtype.to_be_bytes());
+        msg.extend([0x80, 0x01, 0, 0, 0, 120]);
+        msg.extend((rdata.len() as u16).to_be_bytes());
+        msg.extend(rdata);
+    }
+
+    /// mDNS response announcing `laptop.local`, then `printer.local`
+    /// with a compressed suffix
+    fn mdns_response() -> Vec<u8> {
+        let mut msg = header(0, 2);
+        record(&mut msg, b"\x06laptop\x05local\x00", TYPE_A, &[10, 0, 0, 5]);
+        // Pointer to "local", after the header and the first label
+        record(&mut msg, b"\x07printer\xc0\x13", TYPE_A, &[10, 0, 0, 6]);
+        msg
+    }
+
+    #[test]
+    fn mdns_a_records() {
+        assert_eq!(
+            parse_mdns(&mdns_response()),
+            [
+                (0x0a00_0005, "laptop".to_string()),
+                (0x0a00_0006, "printer".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn mdns_truncated() {
+        let msg = mdns_response();
+        // The first record ends after 12 + 28 bytes
+        for len in 0..msg.len() {
+            let names = parse_mdns(&msg[..len]);
+            assert_eq!(names.len(), usize::from(len >= 40), "{len}");
+        }
+    }
+
+    #[test]
+    fn mdns_malformed() {
+        // Pointer to itself
+        let mut msg = header(0, 1);
+        record(&mut msg, b"\xc0\x0c", TYPE_A, &[10, 0, 0, 5]);
+        assert!(parse_mdns(&msg).is_empty());
+        // Pointers to each other
+        let mut msg = header(0, 1);
+        record(&mut msg, b"\x01a\xc0\x0e\xc0\x0c", TYPE_A, &[10, 0, 0, 5]);
+        assert!(parse_mdns(&msg).is_empty());
+        // Pointer past the end
+        let mut msg = header(0, 1);
+        record(&mut msg, b"\xff\xff", TYPE_A, &[10, 0, 0, 5]);
+        assert!(parse_mdns(&msg).is_empty());
+        // Label past the end
+        let mut msg = header(0, 1);
+        msg.extend(b"\x3flaptop");
+        assert!(parse_mdns(&msg).is_empty());
+        // Data longer than the message
+        let mut msg = header(0, 1);
+        record(&mut msg, b"\x06laptop\x00", TYPE_A, &[10, 0, 0, 5]);
+        msg.truncate(msg.len() - 1);
+        assert!(parse_mdns(&msg).is_empty());
+        // More records than given, and not an address
+        let mut msg = header(0, 0xffff);
+        record(&mut msg, b"\x06laptop\x00", TYPE_A, &[10, 0, 0]);
+        assert!(parse_mdns(&msg).is_empty());
+        // Only the suffix
+        let mut msg = header(0, 1);
+        record(&mut msg, b"\x05local\x00", TYPE_A, &[10, 0, 0, 5]);
+        assert!(parse_mdns(&msg).is_empty());
+    }
+
+    /// First-level encoding of a NetBIOS name with its suffix
+    fn encode_netbios(name: &str, suffix: u8) -> Vec<u8> {
+        let mut bytes = format!("{name:15}").into_bytes();
+        bytes.push(suffix);
+        let mut label = vec![32];
+        for byte in bytes {
+            label.extend([b'A' + (byte >> 4), b'A' + (byte & 0xf)]);
+        }
+        label.push(0);
+        label
+    }
+
+    #[test]
+    fn netbios_registration() {
+        // The name in the question, the record pointing to it
+        let mut msg = header(1, 0);
+        msg[11] = 1;
+        msg.extend(encode_netbios("DESKTOP", 0x00));
+        msg.extend([0, 0x20, 0, 1]);
+        record(&mut msg, b"\xc0\x0c", TYPE_NB, &[0, 0, 10, 0, 0, 7]);
+        assert_eq!(parse_netbios(&msg), [(0x0a00_0007, "DESKTOP".to_string())]);
+
+        // A group name
+        let mut msg = header(0, 1);
+        let name = encode_netbios("WORKGROUP", 0x00);
+        record(&mut msg, &name, TYPE_NB, &[0x80, 0, 10, 0, 0, 7]);
+        assert!(parse_netbios(&msg).is_empty());
+
+        // Not encoded
+        let mut msg = header(0, 1);
+        let mut name = vec![32];
+        name.extend([b'z'; 32]);
+        name.push(0);
+        record(&mut msg, &name, TYPE_NB, &[0, 0, 10, 0, 0, 7]);
+        assert!(parse_netbios(&msg).is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+//! Containers of the local Docker daemon, a source of names (and of
+//! the container_name and image labels with --source-labels)
+
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use hyper::{body, client::conn, header, Body, Request};
+use serde::Deserialize;
+use tokio::net::UnixStream;
+
+use crate::{enrich::SharedEnricher, naming::Resolver};
+
+/// Socket of the daemon, unless `DOCKER_HOST` gives another one
+const SOCKET: &str = "/var/run/docker.sock";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Container {
+    pub name: String,
+    pub image: String,
+}
+
+pub type SharedContainers = Arc<Mutex<HashMap<u32, Container>>>;
+
+impl Resolver for SharedContainers {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        Some(self.lock().unwrap().get(&ip)?.name.clone())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Summary {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    image: String,
+    #[serde(default)]
+    network_settings: NetworkSettings,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: HashMap<String, Endpoint>,
+}
+
+#[derive(Deserialize)]
+struct Endpoint {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+/// Path of the socket of the daemon
+fn socket() -> String {
+    std::env::var("DOCKER_HOST")
+        .ok()
+        .and_then(|host| host.strip_prefix("unix://").map(str::to_string))
+        .unwrap_or_else(|| SOCKET.to_string())
+}
+
+/// The running containers, by IP (on every network they are attached
+/// to, bridge or macvlan)
+async fn list(socket: &str) -> Result<HashMap<u32, Container>, String> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .map_err(|err| format!("{socket}: {err}"))?;
+    let (mut sender, connection) = conn::handshake(stream)
+        .await
+        .map_err(|err| err.to_string())?;
+    tokio::spawn(connection);
+    let request = Request::get("/containers/json")
+        .header(header::HOST, "docker")
+        .body(Body::empty())
+        .map_err(|err| err.to_string())?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Docker returned {}", response.status()));
+    }
+    let content = body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| err.to_string())?;
+    let summaries = serde_json::from_slice::<Vec<Summary>>(&content)
+        .map_err(|err| format!("Invalid container list: {err}"))?;
+    let mut containers = HashMap::new();
+    for summary in summaries {
+        let container = Container {
+            name: summary
+                .names
+                .first()
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_default(),
+            image: summary.image,
+        };
+        for endpoint in summary.network_settings.networks.values() {
+            // Empty with the network of the host
+            if let Ok(ip) = endpoint.ip_address.parse::<Ipv4Addr>() {
+                containers.insert(u32::from(ip), container.clone());
+            }
+        }
+    }
+    Ok(containers)
+}
+
+/// Poll the containers in the background
+pub fn spawn_poller(enricher: SharedEnricher, containers: SharedContainers) {
+    let socket = socket();
+    thread::spawn(move || loop {
+        let socket = socket.clone();
+        let containers = containers.clone();
+        enricher.spawn("docker", async move {
+            let listed = list(&socket).await;
+            if let Err(err) = &listed {
+                println!("Unable to list the containers: {err}");
+            }
+            *containers.lock().unwrap() = listed?;
+            Ok(())
+        });
+        thread::sleep(POLL_INTERVAL);
+    });
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{runtime::Handle, sync::Semaphore, time};
+
+/// Time given to a lookup before it is considered failed
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of lookups running at the same time
+const MAX_CONCURRENCY: usize = 16;
+
+/// Maximum number of lookups running or waiting to run, the extra ones
+/// being dropped
+const MAX_PENDING: usize = 256;
+
+/// Consecutive failures after which a source is disabled for a while
+const BREAKER_THRESHOLD: u32 = 5;
+
+/// How long a source stays disabled once its circuit is open
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Label value used when an enrichment is enabled but not available
+pub const UNKNOWN: &str = "unknown";
+
+#[derive(Debug, Clone, Default)]
+pub struct SourceState {
+    pub errors: u64,
+    /// Lookups dropped while too many were pending
+    pub dropped: u64,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl SourceState {
+    pub fn is_open(&self) -> bool {
+        self.open_until
+            .map(|until| Instant::now() < until)
+            .unwrap_or(false)
+    }
+}
+
+/// Shared executor for enrichment lookups (DNS, device descriptions,
+/// external commands, ...)
+///
+/// Lookups run in the background with a timeout and a bounded
+/// concurrency, so that they can never stall the capture or the export
+/// path. A source failing repeatedly is skipped for a while (circuit
+/// breaker), and its errors are counted for the self-metrics.
+pub struct Enricher {
+    handle: Handle,
+    semaphore: Arc<Semaphore>,
+    pending: Arc<AtomicUsize>,
+    sources: Mutex<HashMap<&'static str, SourceState>>,
+}
+
+/// Count of a pending lookup, released once the lookup is over,
+/// whatever its outcome
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Enricher {
+    /// Create an executor running the lookups on the given runtime
+    pub fn new(handle: Handle) -> Arc<Self> {
+        Arc::new(Self {
+            handle,
+            semaphore: Arc::new(Semaphore::new(MAX_CONCURRENCY)),
+            pending: Arc::default(),
+            sources: Mutex::default(),
+        })
+    }
+
+    /// Record the outcome of a lookup of the given source
+    pub fn report(&self, source: &'static str, success: bool) {
+        let mut sources = self.sources.lock().unwrap();
+        let state = sources.entry(source).or_default();
+        if success {
+            state.consecutive_failures = 0;
+            state.open_until = None;
+        } else {
+            state.errors += 1;
+            state.consecutive_failures += 1;
+            if state.consecutive_failures >= BREAKER_THRESHOLD {
+                state.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+            }
+        }
+    }
+
+    /// Whether too many lookups are pending for another one to run, so
+    /// that a source can keep its work for later rather than have it
+    /// dropped
+    pub fn is_saturated(&self) -> bool {
+        self.pending.load(Ordering::Relaxed) >= MAX_PENDING
+    }
+
+    fn is_open(&self, source: &'static str) -> bool {
+        let sources = self.sources.lock().unwrap();
+        sources
+            .get(source)
+            .map(SourceState::is_open)
+            .unwrap_or(false)
+    }
+
+    /// Count a new pending lookup, unless the circuit of its source is
+    /// open or too many lookups are pending
+    fn admit(&self, source: &'static str) -> Option<Pending> {
+        if self.is_open(source) {
+            return None;
+        }
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING {
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            let mut sources = self.sources.lock().unwrap();
+            sources.entry(source).or_default().dropped += 1;
+            return None;
+        }
+        Some(Pending(self.pending.clone()))
+    }
+
+    /// Run a lookup in the background, unless the circuit of its source
+    /// is open or too many lookups are pending
+    pub fn spawn<F>(self: &Arc<Self>, source: &'static str, lookup: F)
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let Some(pending) = self.admit(source) else {
+            return;
+        };
+        let this = self.clone();
+        self.handle.spawn(async move {
+            let _pending = pending;
+            let Ok(_permit) = this.semaphore.clone().acquire_owned().await else {
+                return;
+            };
+            let success = matches!(time::timeout(TIMEOUT, lookup).await, Ok(Ok(())));
+            this.report(source, success);
+        });
+    }
+
+    /// Run a blocking lookup in the background, unless the circuit of its
+    /// source is open or too many lookups are pending
+    ///
+    /// A blocking lookup cannot be cancelled: it holds its permit until it
+    /// is over, even after it has timed out.
+    pub fn spawn_blocking<F>(self: &Arc<Self>, source: &'static str, lookup: F)
+    where
+        F: FnOnce() -> Result<(), String> + Send + 'static,
+    {
+        let Some(pending) = self.admit(source) else {
+            return;
+        };
+        let this = self.clone();
+        self.handle.spawn(async move {
+            let Ok(permit) = this.semaphore.clone().acquire_owned().await else {
+                return;
+            };
+            let task = tokio::task::spawn_blocking(move || {
+                let _held = (pending, permit);
+                lookup()
+            });
+            let success = matches!(time::timeout(TIMEOUT, task).await, Ok(Ok(Ok(()))));
+            this.report(source, success);
+        });
+    }
+
+    /// State of every source that has been used
+    pub fn sources(&self) -> Vec<(&'static str, SourceState)> {
+        let sources = self.sources.lock().unwrap();
+        let mut sources = sources
+            .iter()
+            .map(|(source, state)| (*source, state.clone()))
+            .collect::<Vec<_>>();
+        sources.sort_by_key(|(source, _)| *source);
+        sources
+    }
+}
+
+pub type SharedEnricher = Arc<Enricher>;
@@ -0,0 +1,323 @@
+use std::{fmt, net::Ipv4Addr, time::Duration};
+
+use hyper::Uri;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::broadcast::{self, error::RecvError},
+    time,
+};
+
+use crate::notify;
+
+/// Events kept for the subscribers lagging behind
+const CAPACITY: usize = 1024;
+
+/// Time given to the MQTT broker to accept the connection
+const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something worth telling, delivered to every subscriber
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// A local device announced itself for the first time
+    NewDevice {
+        ip: Ipv4Addr,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        server: Option<String>,
+    },
+    /// The configuration was read again
+    Reloaded {
+        hosts: bool,
+    },
+    ReloadFailed {
+        error: String,
+    },
+    /// The wall clock moved by this many seconds more (or less, when
+    /// negative) than the monotonic clock
+    ClockJump {
+        seconds: f64,
+    },
+}
+
+/// Names of the events, as used in the payloads and the filters
+pub const NAMES: [&str; 4] = ["new-device", "reloaded", "reload-failed", "clock-jump"];
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::NewDevice { .. } => NAMES[0],
+            Event::Reloaded { .. } => NAMES[1],
+            Event::ReloadFailed { .. } => NAMES[2],
+            Event::ClockJump { .. } => NAMES[3],
+        }
+    }
+
+    /// Whether the event reports a problem
+    fn is_warning(&self) -> bool {
+        matches!(self, Event::ReloadFailed { .. })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::NewDevice { ip, server: None } => write!(f, "New device {ip}"),
+            Event::NewDevice {
+                ip,
+                server: Some(server),
+            } => write!(f, "New device {ip} ({server})"),
+            Event::Reloaded { hosts } => {
+                write!(
+                    f,
+                    "Reloaded the subnets, the exclusions and the maximum number of tracked IPs"
+                )?;
+                if *hosts {
+                    write!(f, ", and the hosts file")?;
+                }
+                Ok(())
+            }
+            Event::ReloadFailed { error } => write!(f, "Reload failed: {error}"),
+            Event::ClockJump { seconds } if *seconds >= 0.0 => write!(
+                f,
+                "Wall clock jumped forward by {:?} (suspend or clock step)",
+                Duration::from_secs_f64(*seconds)
+            ),
+            Event::ClockJump { seconds } => write!(
+                f,
+                "Wall clock jumped backward by {:?} (clock step)",
+                Duration::from_secs_f64(-seconds)
+            ),
+        }
+    }
+}
+
+/// Channel where the events are published, from any thread
+#[derive(Debug, Clone)]
+pub struct Bus(broadcast::Sender<Event>);
+
+impl Default for Bus {
+    fn default() -> Self {
+        Bus::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus(broadcast::channel(CAPACITY).0)
+    }
+
+    /// Publish an event, lost when nobody listens
+    pub fn emit(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    /// Receive the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+/// A delivery channel of the events (configuration file only)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Subscriber {
+    /// Names of the events delivered, all of them when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<String>,
+    /// Webhook URL (Slack or Matrix hookshot)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    /// Syslog server (HOST:PORT), over UDP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub syslog: Option<String>,
+    /// MQTT broker (HOST:PORT), used without TLS nor authentication
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<String>,
+    /// Prefix of the MQTT topics, followed by the name of the event
+    #[serde(default = "default_topic")]
+    pub topic: String,
+}
+
+fn default_topic() -> String {
+    "txne".to_string()
+}
+
+impl Subscriber {
+    /// Check that exactly one channel is configured, and the names of
+    /// the events
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.webhook, &self.syslog, &self.mqtt) {
+            (Some(url), None, None) => {
+                url.parse::<Uri>()
+                    .map_err(|err| format!("Invalid webhook URL: {err}"))?;
+            }
+            (None, Some(_), None) | (None, None, Some(_)) => {}
+            _ => {
+                return Err(
+                    "Event subscribers need either \"webhook\", \"syslog\" or \"mqtt\"".to_string(),
+                )
+            }
+        }
+        if let Some(name) = self
+            .events
+            .iter()
+            .find(|name| !NAMES.contains(&name.as_str()))
+        {
+            return Err(format!(
+                "Unknown event {name:?} (expected {})",
+                NAMES.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Deliver the events, until the bus is dropped
+    pub async fn run(self, mut receiver: broadcast::Receiver<Event>) {
+        let mut mqtt = None;
+        while let Some(event) = receive(&mut receiver).await {
+            if !self.events.is_empty() && !self.events.iter().any(|name| name == event.name()) {
+                continue;
+            }
+            let result = if let Some(url) = &self.webhook {
+                notify::post(url, &json!({ "text": event.to_string() })).await
+            } else if let Some(server) = &self.syslog {
+                send_syslog(server, &event).await
+            } else if let Some(broker) = &self.mqtt {
+                publish(&mut mqtt, broker, &self.topic, &event).await
+            } else {
+                Ok(())
+            };
+            if let Err(err) = result {
+                println!("Event delivery failed: {err}");
+            }
+        }
+    }
+}
+
+/// Print the events, until the bus is dropped
+pub async fn log(mut receiver: broadcast::Receiver<Event>) {
+    while let Some(event) = receive(&mut receiver).await {
+        println!("{event}");
+    }
+}
+
+/// Next event, skipping the ones lost by a lagging subscriber
+async fn receive(receiver: &mut broadcast::Receiver<Event>) -> Option<Event> {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => return Some(event),
+            Err(RecvError::Lagged(count)) => println!("{count} events lost by a subscriber"),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Send a RFC 5424 message (facility daemon)
+async fn send_syslog(server: &str, event: &Event) -> Result<(), String> {
+    let severity = if event.is_warning() { 4 } else { 5 };
+    let host = hostname().unwrap_or_else(|| "-".to_string());
+    let message = format!(
+        "<{}>1 - {host} txne {} {} - {event}",
+        3 * 8 + severity,
+        std::process::id(),
+        event.name(),
+    );
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|err| err.to_string())?;
+    socket
+        .send_to(message.as_bytes(), server)
+        .await
+        .map_err(|err| format!("{server}: {err}"))?;
+    Ok(())
+}
+
+/// Publish the event as JSON with MQTT 3.1.1 (QoS 0), connecting first
+/// when needed
+async fn publish(
+    connection: &mut Option<TcpStream>,
+    broker: &str,
+    topic: &str,
+    event: &Event,
+) -> Result<(), String> {
+    let mut stream = match connection.take() {
+        Some(stream) => stream,
+        None => time::timeout(MQTT_TIMEOUT, mqtt_connect(broker))
+            .await
+            .map_err(|_| format!("{broker}: Timeout"))??,
+    };
+    let topic = format!("{topic}/{}", event.name());
+    let payload = serde_json::to_vec(event).unwrap();
+    let mut body = mqtt_string(&topic);
+    body.extend_from_slice(&payload);
+    stream
+        .write_all(&mqtt_packet(0x30, &body))
+        .await
+        .map_err(|err| format!("{broker}: {err}"))?;
+    // Kept for the next event, unless broken
+    *connection = Some(stream);
+    Ok(())
+}
+
+async fn mqtt_connect(broker: &str) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(broker)
+        .await
+        .map_err(|err| format!("{broker}: {err}"))?;
+    let client_id = format!("txne-{}", std::process::id());
+    let mut body = mqtt_string("MQTT");
+    // Level 4 (3.1.1), clean session, no keep alive
+    body.extend_from_slice(&[4, 0x02, 0, 0]);
+    body.extend_from_slice(&mqtt_string(&client_id));
+    stream
+        .write_all(&mqtt_packet(0x10, &body))
+        .await
+        .map_err(|err| format!("{broker}: {err}"))?;
+    let mut connack = [0; 4];
+    stream
+        .read_exact(&mut connack)
+        .await
+        .map_err(|err| format!("{broker}: {err}"))?;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        return Err(format!(
+            "{broker}: Connection refused (code {})",
+            connack[3]
+        ));
+    }
+    Ok(stream)
+}
+
+/// A control packet, with its remaining length
+fn mqtt_packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        if length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// A length prefixed UTF-8 string
+fn mqtt_string(s: &str) -> Vec<u8> {
+    let mut result = (s.len() as u16).to_be_bytes().to_vec();
+    result.extend_from_slice(s.as_bytes());
+    result
+}
+
+/// Get the hostname of the machine
+pub fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
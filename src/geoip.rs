@@ -0,0 +1,81 @@
+//! Country and autonomous system of the remote IPs, from MaxMind
+//! databases (GeoLite2 or GeoIP2, Country or City, and ASN)
+
+use std::{collections::HashMap, net::IpAddr, net::Ipv4Addr, path::Path, sync::Arc};
+
+use maxminddb::{geoip2, Reader};
+
+use crate::enrich;
+
+/// Remote IPs whose origin is remembered by a capture, beyond which
+/// they are all forgotten
+const MAX_CACHED: usize = 65536;
+
+pub struct Geo {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+/// Country (ISO code) and AS number of an IP, "unknown" when not in the
+/// database, `None` when the database is not given
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub country: Option<Arc<str>>,
+    pub asn: Option<Arc<str>>,
+}
+
+fn open(path: &Path) -> Result<Reader<Vec<u8>>, String> {
+    Reader::open_readfile(path).map_err(|err| format!("Invalid database {}: {err}", path.display()))
+}
+
+impl Geo {
+    pub fn open(country: Option<&Path>, asn: Option<&Path>) -> Result<Self, String> {
+        Ok(Self {
+            country: country.map(open).transpose()?,
+            asn: asn.map(open).transpose()?,
+        })
+    }
+
+    fn lookup(&self, ip: u32) -> Origin {
+        let address = IpAddr::V4(Ipv4Addr::from(ip));
+        let country = self.country.as_ref().map(|reader| {
+            let record = reader.lookup::<geoip2::Country>(address).ok();
+            let code = record
+                .and_then(|record| record.country.or(record.registered_country))
+                .and_then(|country| country.iso_code);
+            Arc::from(code.unwrap_or(enrich::UNKNOWN))
+        });
+        let asn = self.asn.as_ref().map(|reader| {
+            let number = reader
+                .lookup::<geoip2::Asn>(address)
+                .ok()
+                .and_then(|record| record.autonomous_system_number);
+            match number {
+                Some(number) => Arc::from(number.to_string()),
+                None => Arc::from(enrich::UNKNOWN),
+            }
+        });
+        Origin { country, asn }
+    }
+}
+
+/// Origins of the remote IPs seen by a capture
+#[derive(Default)]
+pub struct Cache {
+    entries: HashMap<u32, Origin>,
+}
+
+impl Cache {
+    /// Origin of a remote IP
+    pub fn origin(&mut self, geo: &Geo, ip: u32) -> Origin {
+        if let Some(origin) = self.entries.get(&ip) {
+            return origin.clone();
+        }
+        if self.entries.len() >= MAX_CACHED {
+            self.entries.clear();
+        }
+        let origin = geo.lookup(ip);
+        self.entries.insert(ip, origin.clone());
+        origin
+    }
+}
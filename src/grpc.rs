@@ -0,0 +1,349 @@
+//! gRPC query service (see `proto/txne.proto`)
+//!
+//! The snapshots are the metric families of /metrics, converted to
+//! their protobuf counterparts.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rustls::ServerConfig;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture},
+    server::NamedService,
+    transport::{
+        server::{TcpConnectInfo, TlsConnectInfo},
+        Body, Server,
+    },
+    Request, Response, Status,
+};
+
+use crate::{
+    auth::{self, Allowlist, Auth, Check},
+    model, report,
+    server::{collect, MetricsParams, ServerState},
+    tls,
+};
+
+tonic::include_proto!("txne.v1");
+
+use query_server::{Query, QueryServer};
+
+/// Number of talkers when not given
+const DEFAULT_LIMIT: u32 = 10;
+
+/// Interval between deltas (in seconds) when not given
+const DEFAULT_INTERVAL: u32 = 10;
+
+/// Deltas waiting to be sent to a slow client
+const STREAM_BUFFER: usize = 4;
+
+struct Service {
+    state: ServerState,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl From<model::Family> for Family {
+    fn from(family: model::Family) -> Self {
+        let kind = match family.kind {
+            model::Kind::Counter => Kind::Counter,
+            model::Kind::Gauge => Kind::Gauge,
+            model::Kind::Histogram => Kind::Histogram,
+        };
+        Family {
+            name: family.name,
+            help: family.help,
+            kind: kind.into(),
+            samples: family.samples.into_iter().map(Sample::from).collect(),
+        }
+    }
+}
+
+impl From<model::Sample> for Sample {
+    fn from(sample: model::Sample) -> Self {
+        let value = match sample.value {
+            model::Value::Int(value) => sample::Value::Int(value),
+            model::Value::Float(value) => sample::Value::Float(value),
+            model::Value::Histogram {
+                buckets,
+                sum,
+                count,
+            } => sample::Value::Histogram(Histogram {
+                buckets: buckets
+                    .into_iter()
+                    .map(|(upper_bound, count)| Bucket { upper_bound, count })
+                    .collect(),
+                sum,
+                count,
+            }),
+        };
+        Sample {
+            labels: sample
+                .labels
+                .into_iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value,
+                })
+                .collect(),
+            value: Some(value),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Query for Service {
+    async fn snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let params = MetricsParams {
+            aggregate: Some(request.aggregate).filter(|aggregate| !aggregate.is_empty()),
+            min_bytes: request.min_bytes,
+        };
+        let families = collect(&self.state, &params)
+            .map_err(|err| Status::invalid_argument(err.trim_end()))?;
+        Ok(Response::new(SnapshotResponse {
+            timestamp: now(),
+            families: families.into_iter().map(Family::from).collect(),
+        }))
+    }
+
+    async fn top_talkers(
+        &self,
+        request: Request<TopTalkersRequest>,
+    ) -> Result<Response<TopTalkersResponse>, Status> {
+        let limit = match request.into_inner().limit {
+            0 => DEFAULT_LIMIT,
+            limit => limit,
+        };
+        // A standby instance has no talkers to report
+        if !self.state.is_leader() {
+            return Ok(Response::new(TopTalkersResponse::default()));
+        }
+        let counters = self.state.stats.lock().unwrap().counters.clone();
+        let talkers = report::per_ip(counters)
+            .into_iter()
+            .map(|(ip, counters)| report::Talker {
+                ip,
+                name: ip.and_then(|ip| self.state.names.resolve(ip)),
+                counters: counters.get(None),
+            })
+            .collect();
+        let talkers = report::top_talkers(talkers, limit as usize)
+            .into_iter()
+            .map(|talker| Talker {
+                ip: crate::server::format_ip(talker.ip),
+                name: talker.name.unwrap_or_default(),
+                inbound_packets: talker.counters.inbound.pkts,
+                inbound_bytes: talker.counters.inbound.bytes,
+                outbound_packets: talker.counters.outbound.pkts,
+                outbound_bytes: talker.counters.outbound.bytes,
+            })
+            .collect();
+        Ok(Response::new(TopTalkersResponse { talkers }))
+    }
+
+    type StreamDeltasStream = Pin<Box<dyn Stream<Item = Result<Delta, Status>> + Send>>;
+
+    async fn stream_deltas(
+        &self,
+        request: Request<StreamDeltasRequest>,
+    ) -> Result<Response<Self::StreamDeltasStream>, Status> {
+        let interval = match request.into_inner().interval_seconds {
+            0 => DEFAULT_INTERVAL,
+            interval => interval,
+        };
+        let state = self.state.clone();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval as u64));
+            let mut previous = HashMap::<(String, model::Labels), u64>::new();
+            loop {
+                interval.tick().await;
+                let Ok(families) = collect(&state, &MetricsParams::default()) else {
+                    return;
+                };
+                let delta = Delta {
+                    timestamp: now(),
+                    families: deltas(families, &mut previous)
+                        .into_iter()
+                        .map(Family::from)
+                        .collect(),
+                };
+                if sender.send(Ok(delta)).await.is_err() {
+                    // The client is gone
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
+
+/// Increases of the counters since the previous values, which are
+/// replaced by the current ones
+///
+/// Only the samples that changed are kept (all of them the first time),
+/// and the families without any.
+fn deltas(
+    families: Vec<model::Family>,
+    previous: &mut HashMap<(String, model::Labels), u64>,
+) -> Vec<model::Family> {
+    let first = previous.is_empty();
+    let mut current = HashMap::new();
+    let mut result = Vec::new();
+    for mut family in families {
+        if family.kind != model::Kind::Counter {
+            continue;
+        }
+        let name = family.name.clone();
+        family.samples.retain_mut(|sample| {
+            let model::Value::Int(value) = sample.value else {
+                return false;
+            };
+            let key = (name.clone(), sample.labels.clone());
+            let last = previous.get(&key).copied().unwrap_or(0);
+            current.insert(key, value);
+            sample.value = model::Value::Int(value.saturating_sub(last));
+            first || value != last
+        });
+        if !family.samples.is_empty() {
+            result.push(family);
+        }
+    }
+    *previous = current;
+    result
+}
+
+/// Service restricted as the HTTP API: to the allowed clients, then to
+/// the authenticated ones (the credentials being in the metadata)
+#[derive(Clone)]
+struct Restricted<S> {
+    inner: S,
+    auth: Option<Arc<Auth>>,
+    allowed: Option<Allowlist>,
+}
+
+impl<S: NamedService> NamedService for Restricted<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> tonic::codegen::Service<http::Request<Body>> for Restricted<S>
+where
+    S: tonic::codegen::Service<
+            http::Request<Body>,
+            Response = http::Response<BoxBody>,
+            Error = Infallible,
+        > + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        // The service polled ready takes the request, its clone taking
+        // its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let auth = self.auth.clone();
+        let allowed = self.allowed.clone();
+        Box::pin(async move {
+            if let Some(allowed) = &allowed {
+                let peer = remote_addr(&request);
+                if !peer.is_some_and(|peer| auth::is_allowed(allowed, peer.ip())) {
+                    return Ok(Status::permission_denied("Forbidden").to_http());
+                }
+            }
+            if let Some(auth) = &auth {
+                let authorization = request
+                    .headers()
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                match auth.check(authorization.as_deref()).await {
+                    Check::Valid => {}
+                    Check::Invalid => {
+                        return Ok(Status::unauthenticated("Unauthorized").to_http());
+                    }
+                    Check::Busy => {
+                        let status = Status::unavailable("Too many authentications in progress");
+                        return Ok(status.to_http());
+                    }
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+/// Address of the client of a request, over TCP or TLS
+fn remote_addr(request: &http::Request<Body>) -> Option<SocketAddr> {
+    let extensions = request.extensions();
+    extensions
+        .get::<TcpConnectInfo>()
+        .and_then(TcpConnectInfo::remote_addr)
+        .or_else(|| {
+            extensions
+                .get::<TlsConnectInfo<TcpConnectInfo>>()
+                .and_then(|info| info.get_ref().remote_addr())
+        })
+}
+
+/// Serve the query service, over TLS when a configuration is given,
+/// and only to the allowed and authenticated clients when restricted,
+/// forever
+pub async fn serve(
+    address: SocketAddr,
+    tls: Option<Arc<ServerConfig>>,
+    auth: Option<Arc<Auth>>,
+    allowed: Option<Allowlist>,
+    state: ServerState,
+) {
+    let service = Restricted {
+        inner: QueryServer::new(Service { state }),
+        auth,
+        allowed,
+    };
+    let router = Server::builder().add_service(service);
+    let result = match tls {
+        Some(config) => {
+            let incoming = std::net::TcpListener::bind(address)
+                .map_err(|err| format!("Unable to listen on {address}: {err}"))
+                .and_then(|listener| tls::incoming(listener, tls::http2(&config)))
+                .unwrap_or_else(|err| {
+                    println!("{err}");
+                    std::process::exit(1);
+                });
+            router.serve_with_incoming(incoming).await
+        }
+        None => router.serve(address).await,
+    };
+    if let Err(err) = result {
+        println!("gRPC service failed: {err}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,332 @@
+//! Classification hook, a WASM module given the metadata of each packet
+//! and deciding its category, or dropping it
+//!
+//! The module has no imports, and exports its `memory` and a function:
+//!
+//! ```text
+//! classify(protocol: i32, local: i32, remote: i32, local_port: i32,
+//!          remote_port: i32, outbound: i32, bytes: i32) -> i64
+//! ```
+//!
+//! The IPs are given as numbers (big endian order, `10.0.0.1` being
+//! `0x0a000001`), the ports are -1 for the protocols without ports, and
+//! `outbound` is 1 when the local IP is the source. The result is -1 to
+//! ignore the packet, 0 to leave it to the categories of the
+//! configuration file, or else the name of its category, as the offset
+//! of the name in the memory in the high 32 bits and its length in the
+//! low 32 bits.
+//!
+//! Without the `wasm` feature, the exporter does not embed the runtime
+//! and no hook can be loaded.
+
+use std::sync::Arc;
+
+/// Decision of the hook on a packet
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    Drop,
+    Category(Arc<str>),
+}
+
+/// Metadata of a packet given to the hook
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+#[derive(Debug, Clone, Copy)]
+pub struct Meta {
+    pub protocol: u8,
+    pub local: u32,
+    pub remote: u32,
+    pub ports: Option<(u16, u16)>,
+    pub outbound: bool,
+    pub bytes: u32,
+}
+
+impl Meta {
+    /// Metadata of a packet, from its IP protocol and its transport
+    /// header
+    pub fn new(
+        protocol: u8,
+        transport: &[u8],
+        local: u32,
+        remote: u32,
+        outbound: bool,
+        bytes: u32,
+    ) -> Self {
+        let ports = matches!(protocol, 6 | 17)
+            .then(|| transport.get(..4))
+            .flatten()
+            .map(|ports| {
+                let source = u16::from_be_bytes([ports[0], ports[1]]);
+                let dest = u16::from_be_bytes([ports[2], ports[3]]);
+                if outbound {
+                    (source, dest)
+                } else {
+                    (dest, source)
+                }
+            });
+        Self {
+            protocol,
+            local,
+            remote,
+            ports,
+            outbound,
+            bytes,
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+pub use runtime::Hook;
+
+#[cfg(not(feature = "wasm"))]
+pub use unsupported::Hook;
+
+#[cfg(feature = "wasm")]
+mod runtime {
+    use std::{collections::HashMap, path::Path, sync::Arc};
+
+    use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+    use super::{Meta, Verdict};
+
+    /// Result of `classify()` dropping the packet
+    const DROP: i64 = -1;
+
+    /// Result of `classify()` leaving the packet to the categories
+    const KEEP: i64 = 0;
+
+    /// Instructions (roughly) allowed to each call of `classify()`,
+    /// beyond which it is interrupted and the packet kept
+    const FUEL: u64 = 100_000;
+
+    /// Names of categories remembered by a capture, beyond which they
+    /// are all forgotten
+    const MAX_NAMES: usize = 1024;
+
+    type ClassifyFunc = TypedFunc<(i32, i32, i32, i32, i32, i32, i32), i64>;
+
+    /// A compiled module, shared by the captures
+    pub struct Hook {
+        engine: Engine,
+        module: Module,
+    }
+
+    impl Hook {
+        /// Load a module, from its binary or text format
+        pub fn load(path: &Path) -> Result<Self, String> {
+            let bytes = std::fs::read(path)
+                .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+            Self::new(&bytes).map_err(|err| format!("Invalid hook {}: {err}", path.display()))
+        }
+
+        pub fn new(bytes: &[u8]) -> Result<Self, String> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config).map_err(|err| err.to_string())?;
+            let module = Module::new(&engine, bytes).map_err(|err| err.to_string())?;
+            let hook = Self { engine, module };
+            // Check the exports once for all
+            hook.instantiate()?;
+            Ok(hook)
+        }
+
+        /// Instance of the module for a capture
+        pub fn instantiate(&self) -> Result<Classifier, String> {
+            let mut store = Store::new(&self.engine, ());
+            let instance =
+                Instance::new(&mut store, &self.module, &[]).map_err(|err| err.to_string())?;
+            let classify = instance
+                .get_typed_func(&mut store, "classify")
+                .map_err(|err| err.to_string())?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or("Missing the \"memory\" export")?;
+            Ok(Classifier {
+                store,
+                classify,
+                memory,
+                names: HashMap::new(),
+                failed: false,
+            })
+        }
+    }
+
+    /// An instance of the module, owned by a capture
+    pub struct Classifier {
+        store: Store<()>,
+        classify: ClassifyFunc,
+        memory: Memory,
+        names: HashMap<Vec<u8>, Arc<str>>,
+        /// Whether a call already failed, only the first failure being
+        /// reported
+        failed: bool,
+    }
+
+    impl Classifier {
+        /// Decision of the hook on a packet, which is kept when the call
+        /// fails (trap, fuel exhausted, invalid name)
+        pub fn classify(&mut self, meta: &Meta) -> Verdict {
+            match self.call(meta) {
+                Ok(verdict) => verdict,
+                Err(err) => {
+                    if !self.failed {
+                        self.failed = true;
+                        println!("Hook failed: {err}");
+                    }
+                    Verdict::Keep
+                }
+            }
+        }
+
+        fn call(&mut self, meta: &Meta) -> Result<Verdict, String> {
+            self.store.set_fuel(FUEL).map_err(|err| err.to_string())?;
+            let (local_port, remote_port) = match meta.ports {
+                Some((local, remote)) => (local as i32, remote as i32),
+                None => (-1, -1),
+            };
+            let result = self
+                .classify
+                .call(
+                    &mut self.store,
+                    (
+                        meta.protocol as i32,
+                        meta.local as i32,
+                        meta.remote as i32,
+                        local_port,
+                        remote_port,
+                        meta.outbound as i32,
+                        meta.bytes as i32,
+                    ),
+                )
+                .map_err(|err| err.to_string())?;
+            match result {
+                DROP => return Ok(Verdict::Drop),
+                KEEP => return Ok(Verdict::Keep),
+                _ => {}
+            }
+            let offset = (result as u64 >> 32) as usize;
+            let len = (result as u64 & 0xffff_ffff) as usize;
+            let name = self
+                .memory
+                .data(&self.store)
+                .get(offset..offset + len)
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| format!("Invalid name at {offset} ({len} bytes)"))?;
+            if let Some(name) = self.names.get(name) {
+                return Ok(Verdict::Category(name.clone()));
+            }
+            let category = Arc::<str>::from(String::from_utf8_lossy(name));
+            if self.names.len() >= MAX_NAMES {
+                self.names.clear();
+            }
+            self.names.insert(name.to_vec(), category.clone());
+            Ok(Verdict::Category(category))
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod unsupported {
+    use std::path::Path;
+
+    use super::{Meta, Verdict};
+
+    /// A compiled module, none being available without the runtime
+    pub enum Hook {}
+
+    impl Hook {
+        pub fn load(_: &Path) -> Result<Self, String> {
+            Err("built without WASM support (the wasm feature)".to_string())
+        }
+
+        pub fn instantiate(&self) -> Result<Classifier, String> {
+            match *self {}
+        }
+    }
+
+    pub enum Classifier {}
+
+    impl Classifier {
+        pub fn classify(&mut self, _: &Meta) -> Verdict {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::*;
+
+    /// Drop the UDP packets, name the HTTPS ones "web", and leave the
+    /// others to the categories
+    const MODULE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 16) "web")
+          (func (export "classify")
+                (param $protocol i32) (param $local i32) (param $remote i32)
+                (param $local_port i32) (param $remote_port i32)
+                (param $outbound i32) (param $bytes i32)
+                (result i64)
+            (if (i32.eq (local.get $protocol) (i32.const 17))
+              (then (return (i64.const -1))))
+            (if (i32.eq (local.get $remote_port) (i32.const 443))
+              (then (return (i64.const 0x0000001000000003))))
+            (i64.const 0)))
+    "#;
+
+    fn tcp(source: u16, dest: u16) -> Vec<u8> {
+        let mut header = source.to_be_bytes().to_vec();
+        header.extend(dest.to_be_bytes());
+        header.extend([0; 16]);
+        header
+    }
+
+    #[test]
+    fn classify() {
+        let hook = Hook::new(MODULE.as_bytes()).unwrap();
+        let mut classifier = hook.instantiate().unwrap();
+        let local = 0x0a00_0001;
+        let remote = 0x5db8_d822;
+
+        let meta = Meta::new(6, &tcp(50000, 443), local, remote, true, 60);
+        assert_eq!(meta.ports, Some((50000, 443)));
+        assert_eq!(classifier.classify(&meta), Verdict::Category("web".into()));
+        // Inbound, the ports being swapped
+        let meta = Meta::new(6, &tcp(443, 50000), local, remote, false, 1500);
+        assert_eq!(classifier.classify(&meta), Verdict::Category("web".into()));
+
+        let meta = Meta::new(6, &tcp(50000, 22), local, remote, true, 60);
+        assert_eq!(classifier.classify(&meta), Verdict::Keep);
+        let meta = Meta::new(17, &tcp(5353, 5353), local, remote, true, 60);
+        assert_eq!(classifier.classify(&meta), Verdict::Drop);
+        let meta = Meta::new(1, &[8, 0, 0, 0], local, remote, true, 84);
+        assert_eq!(meta.ports, None);
+        assert_eq!(classifier.classify(&meta), Verdict::Keep);
+    }
+
+    #[test]
+    fn failures() {
+        // Missing export
+        assert!(Hook::new(br#"(module (memory (export "memory") 1))"#).is_err());
+        // Endless loop, and a name out of the memory: the packets are kept
+        let hook = Hook::new(
+            br#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "classify")
+                    (param i32 i32 i32 i32 i32 i32 i32) (result i64)
+                (if (i32.eq (local.get 0) (i32.const 6))
+                  (then (loop (br 0))))
+                (i64.const 0x0001000000000010)))
+            "#,
+        )
+        .unwrap();
+        let mut classifier = hook.instantiate().unwrap();
+        for protocol in [6, 17] {
+            let meta = Meta::new(protocol, &tcp(1, 2), 1, 2, true, 60);
+            assert_eq!(classifier.classify(&meta), Verdict::Keep);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    fs,
+    net::Ipv4Addr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::naming::Resolver;
+
+/// Names of IPs and subnets, from a static file
+///
+/// The most specific subnet containing an IP gives its name.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    /// Address, mask and name, the longest masks first
+    entries: Vec<(u32, u32, String)>,
+}
+
+pub type SharedHosts = Arc<Mutex<Hosts>>;
+
+impl Resolver for SharedHosts {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        let hosts = self.lock().unwrap();
+        hosts
+            .entries
+            .iter()
+            .find(|(addr, mask, _)| ip & mask == *addr)
+            .map(|(_, _, name)| name.clone())
+    }
+}
+
+/// Read a hosts file
+///
+/// Each line is either `IP[/SIZE] NAME [ALIASES...]` (as in
+/// `/etc/hosts`, the aliases being ignored) or `IP[/SIZE],NAME[,...]`
+/// (CSV, the other columns being ignored).
+/// Empty lines and comments (`#`) are skipped.
+pub fn load(path: &Path) -> Result<Hosts, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    let mut entries = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || format!("Invalid entry {line:?} in {}:{}", path.display(), index + 1);
+        let (subnet, name) = match line.split_once(',') {
+            // Header of a CSV file
+            Some((subnet, _))
+                if index == 0 && !subnet.trim().starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                continue
+            }
+            Some((subnet, rest)) => {
+                let name = rest.split(',').next().unwrap_or_default();
+                (subnet.trim(), name.trim().trim_matches('"'))
+            }
+            None => {
+                let mut fields = line.split_whitespace();
+                let subnet = fields.next().ok_or_else(invalid)?;
+                (subnet, fields.next().ok_or_else(invalid)?)
+            }
+        };
+        // IPv6 entries of /etc/hosts are not relevant
+        if subnet.contains(':') {
+            continue;
+        }
+        let subnets = parse_subnets(subnet).ok_or_else(invalid)?;
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        for (addr, mask) in subnets {
+            entries.push((addr, mask, name.to_string()));
+        }
+    }
+    // Stable, so that the first of the entries of a subnet wins
+    entries.sort_by_key(|(_, mask, _)| std::cmp::Reverse(mask.count_ones()));
+    Ok(Hosts { entries })
+}
+
+/// Parse a comma separated list of IPv4 subnets
+pub fn parse_subnets(subnets: &str) -> Option<Vec<(u32, u32)>> {
+    let mut result = Vec::new();
+    for part in subnets.split(',') {
+        let (address, size) = part.split_once('/').unwrap_or((part, "32"));
+        let address: Ipv4Addr = address.parse().ok()?;
+        let address = u32::from_be_bytes(address.octets());
+        let size = size.parse::<u8>().ok()?;
+        if size > 32 {
+            return None;
+        }
+        let mask = if size == 32 { !0 } else { !(!0 >> size) };
+        result.push((address & mask, mask));
+    }
+    Some(result)
+}
@@ -0,0 +1,254 @@
+//! HTTP API of the exporter, over statistics given by the caller
+//!
+//! The router is the one served by the binary, middlewares included, but
+//! its state is built here rather than from the command line, and its
+//! statistics are synthetic rather than captured. This is what the
+//! integration tests (`tests/http.rs`) go through.
+
+use std::{
+    net::Ipv4Addr,
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::Duration,
+};
+
+use axum::Router;
+
+use crate::{
+    burst,
+    dhcp::SharedLeases,
+    enrich::Enricher,
+    icmp,
+    naming::{Chain, Resolver, Source},
+    probe::ProbeResults,
+    server::{CaptureHealth, ServerState, SharedHealth, ThreadGuard, Threads, DEFAULT_NAMESPACE},
+    ssdp::{Device, SharedDevices},
+    stats::{throughput_totals, BaseCounters, Key, Stats, DEFAULT_MAX},
+    wifi::Wifi,
+};
+pub use crate::{server::Compat, shm::Counters, stats::Protocol};
+
+/// State of the HTTP API, without any optional feature unless enabled
+/// by its builder methods
+#[derive(Clone)]
+pub struct State(ServerState);
+
+/// Labels of the counters of a local IP
+#[derive(Debug, Clone, Default)]
+pub struct Series {
+    /// `None` for the overflow entry
+    pub ip: Option<Ipv4Addr>,
+    pub interface: Option<String>,
+    /// Name of the service
+    pub port: Option<String>,
+    pub vlan: Option<u16>,
+    /// SSID and BSSID
+    pub wifi: Option<(String, String)>,
+}
+
+/// Capture counted by the readiness and liveness checks, stopped when
+/// dropped
+pub struct Capture {
+    _guard: ThreadGuard,
+}
+
+impl State {
+    /// Empty statistics, for an exporter started at `started` (seconds
+    /// since the UNIX epoch), within a Tokio runtime
+    pub fn new(started: u64) -> Self {
+        State(ServerState {
+            stats: Arc::new(Mutex::new(Stats::default())),
+            leader: None,
+            probes: ProbeResults::default(),
+            enricher: Enricher::new(tokio::runtime::Handle::current()),
+            names: Arc::new(Chain::new(Vec::new(), &[])),
+            reverse_dns: None,
+            pods: None,
+            containers: None,
+            leases: SharedLeases::default(),
+            dhcp: false,
+            devices: SharedDevices::default(),
+            host: None,
+            mirror_dropped: None,
+            samples: None,
+            started,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            legacy_names: false,
+            compat: None,
+            script: None,
+            health: SharedHealth::default(),
+            threads: None,
+        })
+    }
+
+    /// As `--host-label`
+    pub fn host(mut self, host: &str) -> Self {
+        self.0.host = Some(host.to_string());
+        self
+    }
+
+    /// As `--namespace`
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.0.namespace = namespace.to_string();
+        self
+    }
+
+    /// As `--legacy-names`
+    pub fn legacy_names(mut self) -> Self {
+        self.0.legacy_names = true;
+        self
+    }
+
+    /// As `--compat`
+    pub fn compat(mut self, compat: Compat) -> Self {
+        self.0.compat = Some(compat);
+        self
+    }
+
+    /// As `--percentiles`, the throughput being sampled by
+    /// `sample_throughput()`
+    pub fn percentiles(mut self) -> Self {
+        self.0.samples = Some(Arc::new(Mutex::new(burst::Samples::default())));
+        self
+    }
+
+    /// Packets that could not be mirrored, as with `--mirror`
+    pub fn mirror_dropped(mut self, packets: u64) -> Self {
+        self.0.mirror_dropped = Some(Arc::new(AtomicU64::new(packets)));
+        self
+    }
+
+    /// Name the IPs after their SSDP devices (see `add_device()`)
+    pub fn device_names(mut self) -> Self {
+        let resolvers: Vec<(Source, Box<dyn Resolver>)> =
+            vec![(Source::Ssdp, Box::new(self.0.devices.clone()))];
+        self.0.names = Arc::new(Chain::new(resolvers, &[]));
+        self
+    }
+
+    /// Wait for the captures (see `start_capture()`) to be ready, as
+    /// when not serving an archive
+    pub fn captures(mut self) -> Self {
+        self.0.threads = Some(Arc::new(Threads::default()));
+        self
+    }
+
+    /// Start of the counters (seconds since the UNIX epoch)
+    pub fn created(&self, created: u64) {
+        self.0.stats.lock().unwrap().created = Some(created);
+    }
+
+    /// Count traffic of a series
+    pub fn add(&self, series: &Series, protocol: Protocol, inbound: Counters, outbound: Counters) {
+        let key = Key {
+            ip: series.ip.map(u32::from),
+            wifi: series.wifi.as_ref().map(|(ssid, bssid)| {
+                Arc::new(Wifi {
+                    ssid: ssid.clone(),
+                    bssid: bssid.clone(),
+                })
+            }),
+            vlan: series.vlan,
+            interface: series.interface.as_deref().map(Arc::from),
+            port: series.port.as_deref().map(Arc::from),
+        };
+        let mut stats = self.0.stats.lock().unwrap();
+        let entry = stats.counters.entry(key).or_default();
+        let entry = match protocol {
+            Protocol::Icmp => &mut entry.icmp,
+            Protocol::Tcp => &mut entry.tcp,
+            Protocol::Udp => &mut entry.udp,
+            Protocol::Other => &mut entry.other,
+        };
+        entry.inbound.add(&base(inbound));
+        entry.outbound.add(&base(outbound));
+    }
+
+    /// Count traffic of a local IP (`None` for the overflow entry) under
+    /// a category
+    pub fn add_category(
+        &self,
+        ip: Option<Ipv4Addr>,
+        category: &str,
+        inbound: Counters,
+        outbound: Counters,
+    ) {
+        let mut stats = self.0.stats.lock().unwrap();
+        let entry = stats
+            .categories
+            .entry((ip.map(u32::from), Arc::from(category)))
+            .or_default();
+        entry.inbound.add(&base(inbound));
+        entry.outbound.add(&base(outbound));
+    }
+
+    /// Count IPs that could not be tracked
+    pub fn add_evictions(&self, evictions: u64) {
+        self.0.stats.lock().unwrap().evictions += evictions;
+    }
+
+    /// Record the round-trip time of an ICMP echo to a peer, the peers
+    /// being bounded as with the default `--max`
+    pub fn add_rtt(&self, peer: Ipv4Addr, rtt: Duration) {
+        let mut stats = self.0.stats.lock().unwrap();
+        icmp::record(&mut stats.latencies, peer.into(), rtt, DEFAULT_MAX);
+    }
+
+    /// Sample the throughput since the previous sample, at a time
+    /// (seconds since the UNIX epoch), as every 5 minutes
+    pub fn sample_throughput(&self, time: u64) {
+        let totals = throughput_totals(&self.0.stats.lock().unwrap());
+        let samples = self.0.samples.as_ref().expect("percentiles() not called");
+        samples.lock().unwrap().record(time, totals);
+    }
+
+    /// Record a SSDP device, as described by its announcements
+    pub fn add_device(&self, ip: Ipv4Addr, friendly_name: Option<&str>, model: Option<&str>) {
+        self.0.devices.lock().unwrap().insert(
+            ip.into(),
+            Device {
+                friendly_name: friendly_name.map(str::to_string),
+                model: model.map(str::to_string),
+                ..Device::default()
+            },
+        );
+    }
+
+    /// Start a capture, once `captures()` enabled
+    pub fn start_capture(&self, interface: &str) -> Capture {
+        let threads = self.0.threads.as_ref().expect("Captures not enabled");
+        Capture {
+            _guard: threads.start(interface),
+        }
+    }
+
+    /// Report the health of a capture, as after its first flush
+    pub fn report_capture(
+        &self,
+        interface: &str,
+        flush_interval: Duration,
+        dropped: Option<u64>,
+        unparsed: u64,
+    ) {
+        self.0.health.lock().unwrap().insert(
+            interface.to_string(),
+            CaptureHealth {
+                flush_interval,
+                dropped,
+                unparsed,
+                lag: None,
+            },
+        );
+    }
+}
+
+fn base(counters: Counters) -> BaseCounters {
+    BaseCounters {
+        pkts: counters.packets,
+        bytes: counters.bytes,
+    }
+}
+
+/// Routes of the HTTP API, over the state shared with the caller
+pub fn router(state: &State) -> Router {
+    crate::server::router(state.0.clone())
+}
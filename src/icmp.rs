@@ -0,0 +1,128 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds (in seconds) of the RTT histogram buckets
+pub const BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Echo requests older than this are considered lost
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of echo requests waiting for a reply
+const MAX_PENDING: usize = 4096;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Histogram {
+    /// Cumulative count per bucket of `BUCKETS`
+    pub buckets: [u64; BUCKETS.len()],
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn add(&mut self, other: &Histogram) {
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Match echo requests leaving the network with the replies coming back
+#[derive(Default)]
+pub struct EchoTracker {
+    /// Timestamp of the requests by (remote, identifier, sequence)
+    pending: HashMap<(u32, u16, u16), Duration>,
+}
+
+impl EchoTracker {
+    /// Process an ICMP message, returning the remote peer and the RTT
+    /// when it is the reply to a known request
+    ///
+    /// `ts` is the capture timestamp of the packet, and `remote` the
+    /// address of the peer outside the network.
+    pub fn process(
+        &mut self,
+        icmp: &[u8],
+        remote: u32,
+        outbound: bool,
+        ts: Duration,
+    ) -> Option<(u32, Duration)> {
+        if icmp.len() < 8 {
+            return None;
+        }
+        let id = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+        match (icmp[0], outbound) {
+            (ECHO_REQUEST, true) => {
+                if self.pending.len() >= MAX_PENDING {
+                    self.expire(ts);
+                }
+                if self.pending.len() < MAX_PENDING {
+                    self.pending.insert((remote, id, seq), ts);
+                }
+                None
+            }
+            (ECHO_REPLY, false) => {
+                let sent = self.pending.remove(&(remote, id, seq))?;
+                Some((remote, ts.checked_sub(sent)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Forget every pending request, after a jump of the wall clock
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Forget the requests that have not been answered in time
+    pub fn expire(&mut self, now: Duration) {
+        self.pending
+            .retain(|_, sent| now.saturating_sub(*sent) < TIMEOUT);
+    }
+}
+
+/// RTT histograms per remote peer (`None` once too many are tracked)
+pub type Latencies = HashMap<Option<u32>, Histogram>;
+
+/// Record a RTT, bounding the number of tracked peers
+pub fn record(latencies: &mut Latencies, peer: u32, rtt: Duration, max_tracking: usize) {
+    let peer = if !latencies.contains_key(&Some(peer)) && latencies.len() >= max_tracking {
+        None
+    } else {
+        Some(peer)
+    };
+    latencies
+        .entry(peer)
+        .or_default()
+        .observe(rtt.as_secs_f64());
+}
+
+/// Add the latencies measured since the last sync, the new peers taken
+/// in the order of their addresses
+pub fn merge(latencies: &mut Latencies, delta: Latencies, max_tracking: usize) {
+    let mut delta: Vec<_> = delta.into_iter().collect();
+    delta.sort_by_key(|(peer, _)| *peer);
+    for (peer, histogram) in delta {
+        let peer = peer
+            .filter(|peer| latencies.contains_key(&Some(*peer)) || latencies.len() < max_tracking);
+        latencies.entry(peer).or_default().add(&histogram);
+    }
+}
@@ -0,0 +1,285 @@
+//! Pods of the Kubernetes cluster the exporter runs in, a source of
+//! names (and of the pod, namespace and node labels with
+//! --source-labels)
+//!
+//! The pods of the node are listed, then watched through the API
+//! server, with the service account of the exporter (which needs to
+//! list and watch the pods). The name of the node is given by the
+//! downward API, in `NODE_NAME`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::BufReader,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{
+    body::HttpBody,
+    client::{connect::HttpConnector, Client},
+    header, Body, Request,
+};
+use hyper_rustls::HttpsConnector;
+use serde::Deserialize;
+
+use crate::{enrich::SharedEnricher, naming::Resolver};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Variable set to `spec.nodeName` with the downward API
+const NODE_VARIABLE: &str = "NODE_NAME";
+
+/// Delay before listing the pods again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Duration of a watch, after which it is resumed (the connection may
+/// have silently died meanwhile)
+const WATCH_TIMEOUT: u64 = 300;
+
+const SOURCE: &str = "kubernetes";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pod {
+    pub name: String,
+    pub namespace: String,
+    pub node: String,
+}
+
+pub type SharedPods = Arc<Mutex<HashMap<u32, Pod>>>;
+
+impl Resolver for SharedPods {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        let pods = self.lock().unwrap();
+        let pod = pods.get(&ip)?;
+        Some(format!("{}/{}", pod.namespace, pod.name))
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Metadata {
+    name: String,
+    namespace: String,
+    resource_version: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PodSpec {
+    node_name: String,
+    host_network: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PodStatus {
+    phase: String,
+    #[serde(rename = "podIPs")]
+    pod_ips: Vec<PodIp>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PodIp {
+    ip: String,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PodObject {
+    metadata: Metadata,
+    spec: PodSpec,
+    status: PodStatus,
+}
+
+impl PodObject {
+    /// The IPv4 addresses of the pod, except for the pods sharing the
+    /// network of their node
+    fn entries(&self) -> Vec<(u32, Pod)> {
+        if self.spec.host_network {
+            return Vec::new();
+        }
+        let pod = Pod {
+            name: self.metadata.name.clone(),
+            namespace: self.metadata.namespace.clone(),
+            node: self.spec.node_name.clone(),
+        };
+        self.status
+            .pod_ips
+            .iter()
+            .filter_map(|ip| ip.ip.parse::<Ipv4Addr>().ok())
+            .map(|ip| (u32::from(ip), pod.clone()))
+            .collect()
+    }
+
+    /// Whether the pod is over, its IPs being released
+    fn is_terminated(&self) -> bool {
+        matches!(self.status.phase.as_str(), "Succeeded" | "Failed")
+    }
+}
+
+#[derive(Deserialize)]
+struct PodList {
+    metadata: Metadata,
+    items: Vec<PodObject>,
+}
+
+#[derive(Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+/// Update the pods from a watched change
+fn apply(pods: &SharedPods, kind: &str, object: &PodObject) {
+    let mut pods = pods.lock().unwrap();
+    for (ip, pod) in object.entries() {
+        if kind == "DELETED" || object.is_terminated() {
+            // The IP may have been given to another pod meanwhile
+            if pods.get(&ip) == Some(&pod) {
+                pods.remove(&ip);
+            }
+        } else {
+            pods.insert(ip, pod);
+        }
+    }
+}
+
+/// Client of the API server, from the service account of the pod
+struct ApiServer {
+    client: Client<HttpsConnector<HttpConnector>>,
+    base: String,
+    /// Node of the exporter, the only one whose pods are watched
+    node: String,
+}
+
+impl ApiServer {
+    fn in_cluster() -> Result<Self, String> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| "Not running in a Kubernetes pod (KUBERNETES_SERVICE_HOST)".to_string())?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let node = std::env::var(NODE_VARIABLE)
+            .ok()
+            .filter(|node| !node.is_empty())
+            .ok_or_else(|| format!("Unknown node of the pod ({NODE_VARIABLE})"))?;
+        let host = if host.contains(':') {
+            format!("[{host}]")
+        } else {
+            host
+        };
+        let path = format!("{SERVICE_ACCOUNT}/ca.crt");
+        let file = fs::File::open(&path).map_err(|err| format!("Unable to read {path}: {err}"))?;
+        let mut roots = rustls::RootCertStore::empty();
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .map_err(|err| format!("Invalid certificates {path}: {err}"))?;
+        for cert in certs {
+            roots
+                .add(&rustls::Certificate(cert))
+                .map_err(|err| format!("Invalid certificate in {path}: {err}"))?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_only()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            client: Client::builder().build(connector),
+            base: format!("https://{host}:{port}"),
+            node,
+        })
+    }
+
+    async fn get(&self, path: &str) -> Result<Body, String> {
+        // Read each time, since the token is rotated
+        let token_path = format!("{SERVICE_ACCOUNT}/token");
+        let token = fs::read_to_string(&token_path)
+            .map_err(|err| format!("Unable to read {token_path}: {err}"))?;
+        let request = Request::get(format!("{}{path}", self.base))
+            .header(header::AUTHORIZATION, format!("Bearer {}", token.trim()))
+            .body(Body::empty())
+            .map_err(|err| err.to_string())?;
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "API server returned {} for {path}",
+                response.status()
+            ));
+        }
+        Ok(response.into_body())
+    }
+
+    /// List the pods of the node, then follow their changes, until the
+    /// watch fails
+    async fn watch(&self, enricher: &SharedEnricher, pods: &SharedPods) -> Result<(), String> {
+        // Node names are DNS subdomains, only "=" needs escaping
+        let selector = format!("fieldSelector=spec.nodeName%3D{}", self.node);
+        let body = self.get(&format!("/api/v1/pods?{selector}")).await?;
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|err| err.to_string())?;
+        let list = serde_json::from_slice::<PodList>(&body)
+            .map_err(|err| format!("Invalid pod list: {err}"))?;
+        *pods.lock().unwrap() = list
+            .items
+            .iter()
+            .filter(|object| !object.is_terminated())
+            .flat_map(PodObject::entries)
+            .collect();
+        enricher.report(SOURCE, true);
+        let mut version = list.metadata.resource_version;
+        loop {
+            let mut body = self
+                .get(&format!(
+                    "/api/v1/pods?{selector}&watch=1&allowWatchBookmarks=true\
+                     &timeoutSeconds={WATCH_TIMEOUT}&resourceVersion={version}"
+                ))
+                .await?;
+            let mut buffer = Vec::new();
+            while let Some(chunk) = body.data().await {
+                buffer.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+                while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line = buffer.drain(..=end).collect::<Vec<_>>();
+                    let event = serde_json::from_slice::<WatchEvent>(&line)
+                        .map_err(|err| format!("Invalid watch event: {err}"))?;
+                    if event.kind == "ERROR" {
+                        // Usually expired (410 Gone), listed again
+                        return Err(format!("Watch failed: {}", event.object["message"]));
+                    }
+                    let object = serde_json::from_value::<PodObject>(event.object)
+                        .map_err(|err| format!("Invalid pod: {err}"))?;
+                    version = object.metadata.resource_version.clone();
+                    if event.kind != "BOOKMARK" {
+                        apply(pods, &event.kind, &object);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Check that the exporter runs in a cluster, and keep the pods up to
+/// date in the background
+pub fn spawn_watcher(enricher: SharedEnricher, pods: SharedPods) -> Result<(), String> {
+    let api = ApiServer::in_cluster()?;
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = api.watch(&enricher, &pods).await {
+                println!("Kubernetes: {err}");
+                enricher.report(SOURCE, false);
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    });
+    Ok(())
+}
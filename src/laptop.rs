@@ -0,0 +1,58 @@
+use std::{fs, net::IpAddr};
+
+use crate::packet;
+
+/// Name of the interface holding the default IPv4 route
+///
+/// When several default routes exist, the one with the lowest metric
+/// wins.
+pub fn default_route_interface() -> Option<String> {
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (name, destination, flags, metric) = (
+                fields.first()?,
+                fields.get(1)?,
+                fields.get(3)?,
+                fields.get(6)?,
+            );
+            let flags = u16::from_str_radix(flags, 16).ok()?;
+            // RTF_UP
+            if *destination != "00000000" || flags & 0x1 == 0 {
+                return None;
+            }
+            Some((metric.parse::<u32>().ok()?, name.to_string()))
+        })
+        .min()
+        .map(|(_, name)| name)
+}
+
+/// IPv4 addresses assigned to the given device
+pub fn local_addresses(device: &packet::Device) -> Vec<u32> {
+    device
+        .addresses
+        .iter()
+        .filter_map(|address| match address.addr {
+            IpAddr::V4(addr) => Some(u32::from_be_bytes(addr.octets())),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// IPv4 networks (address and mask) the given device is attached to
+pub fn local_networks(device: &packet::Device) -> Vec<(u32, u32)> {
+    device
+        .addresses
+        .iter()
+        .filter_map(|address| match (address.addr, address.netmask) {
+            (IpAddr::V4(addr), Some(IpAddr::V4(netmask))) => {
+                let mask = u32::from_be_bytes(netmask.octets());
+                Some((u32::from_be_bytes(addr.octets()) & mask, mask))
+            }
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,33 @@
+//! Interfaces for the local consumers of the txne exporter (`shm`), its
+//! HTTP API over synthetic statistics (`http`), and the modules of the
+//! exporter shared with its binary
+
+pub mod auth;
+pub mod burst;
+pub mod cluster;
+pub mod compress;
+pub mod dhcp;
+pub mod discovery;
+pub mod docker;
+pub mod enrich;
+pub mod events;
+pub mod hosts;
+pub mod http;
+pub mod icmp;
+pub mod kubernetes;
+pub mod laptop;
+pub mod model;
+pub mod naming;
+pub mod notify;
+pub mod packet;
+pub mod probe;
+pub mod protobuf;
+pub mod rdns;
+pub mod report;
+pub mod script;
+pub mod server;
+pub mod shm;
+pub mod ssdp;
+pub mod stats;
+pub mod store;
+pub mod wifi;
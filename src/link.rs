@@ -0,0 +1,297 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::Linktype;
+
+const ETHER_IPV4: u16 = 0x0800;
+
+/// 802.1Q tag, and 802.1ad (QinQ) outer tags
+const ETHER_VLAN: [u16; 3] = [0x8100, 0x88a8, 0x9100];
+
+/// Packet type of the packets sent by this machine, in Linux cooked
+/// captures
+const PACKET_OUTGOING: u16 = 4;
+
+/// `AF_INET` in the header of BSD loopback captures
+const AF_INET: u32 = 2;
+
+/// Bytes counted for each packet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Count {
+    /// The frame on the wire, with its link layer header
+    #[default]
+    Wire,
+    /// The IP packet (from its total length), without the link layer
+    /// header and padding
+    Ip,
+}
+
+/// Framing of the packets, from the link type of the capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    Ethernet,
+    /// Linux "cooked" capture (`any` device, PPP, ...)
+    LinuxSll,
+    LinuxSll2,
+    /// Bare IP packets (tun devices, WireGuard, ...)
+    Raw,
+    /// BSD loopback, with the address family in the byte order of the
+    /// machine that captured the packets
+    Null,
+    /// OpenBSD loopback, with the address family in network byte order
+    Loop,
+}
+
+impl Framing {
+    /// Framing of a link type, if supported
+    pub fn new(linktype: Linktype) -> Option<Self> {
+        match linktype {
+            Linktype::ETHERNET => Some(Framing::Ethernet),
+            Linktype::LINUX_SLL => Some(Framing::LinuxSll),
+            Linktype::LINUX_SLL2 => Some(Framing::LinuxSll2),
+            Linktype::RAW | Linktype::IPV4 => Some(Framing::Raw),
+            Linktype::NULL => Some(Framing::Null),
+            Linktype::LOOP => Some(Framing::Loop),
+            _ => None,
+        }
+    }
+
+    /// Whether the packet was sent by this machine (only known for Linux
+    /// cooked captures)
+    pub fn is_outgoing(self, data: &[u8]) -> bool {
+        match self {
+            Framing::LinuxSll => read_u16(data, 0) == Some(PACKET_OUTGOING),
+            Framing::LinuxSll2 => data.get(10).map(|&kind| kind as u16) == Some(PACKET_OUTGOING),
+            _ => false,
+        }
+    }
+
+    /// Skip the link layer header and the VLAN tags (if any) of a frame
+    ///
+    /// Returns the VLAN (the inner one for stacked tags, 0 when untagged)
+    /// and the IPv4 packet, which is at least as long as its header
+    /// (options included), and stops at its total length (without the
+    /// padding of the frame).
+    pub fn parse(self, data: &[u8]) -> Option<(u16, &[u8])> {
+        let (vlan, ip) = match self {
+            Framing::Ethernet => skip_tags(data, read_u16(data, 12)?, 14)?,
+            Framing::LinuxSll => skip_tags(data, read_u16(data, 14)?, 16)?,
+            // The protocol comes first, the tags (reinserted by libpcap)
+            // after the header
+            Framing::LinuxSll2 => skip_tags(data, read_u16(data, 0)?, 20)?,
+            Framing::Raw => (data.first()? >> 4 == 4).then_some((0, data))?,
+            Framing::Null => {
+                // As libpcap, whatever the byte order of this machine
+                let family = u32::from_ne_bytes(data.get(..4)?.try_into().unwrap());
+                (family == AF_INET || family == AF_INET.swap_bytes()).then_some((0, &data[4..]))?
+            }
+            Framing::Loop => {
+                let family = u32::from_be_bytes(data.get(..4)?.try_into().unwrap());
+                (family == AF_INET).then_some((0, &data[4..]))?
+            }
+        };
+        if ip.first()? >> 4 != 4 {
+            return None;
+        }
+        let header = header_len(ip);
+        if header < 20 || ip.len() < header {
+            return None;
+        }
+        match total_len(ip) {
+            // Offloaded (TSO/GRO), the total length is not set
+            0 => Some((vlan, ip)),
+            total if total < header => None,
+            total => Some((vlan, &ip[..total.min(ip.len())])),
+        }
+    }
+}
+
+/// Length of the header of an IPv4 packet, options included (IHL)
+pub fn header_len(ip: &[u8]) -> usize {
+    (ip[0] & 0x0f) as usize * 4
+}
+
+/// Total length of an IPv4 packet, 0 when offloaded (TSO/GRO)
+pub fn total_len(ip: &[u8]) -> usize {
+    u16::from_be_bytes([ip[2], ip[3]]) as usize
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Skip the VLAN tags of a payload of the given protocol, starting at
+/// `offset`
+fn skip_tags(data: &[u8], mut protocol: u16, mut offset: usize) -> Option<(u16, &[u8])> {
+    let mut vlan = 0;
+    while ETHER_VLAN.contains(&protocol) {
+        vlan = read_u16(data, offset)? & 0x0fff;
+        protocol = read_u16(data, offset + 2)?;
+        offset += 4;
+    }
+    (protocol == ETHER_IPV4).then_some((vlan, data.get(offset..)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 header of a TCP packet of `total` bytes, with `options`
+    /// words of options
+    fn ip(total: u16, options: u8) -> Vec<u8> {
+        let mut ip = vec![0x45 + options, 0];
+        ip.extend(total.to_be_bytes());
+        ip.extend([0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 1, 1, 1, 1, 1]);
+        ip.extend(vec![1; options as usize * 4]);
+        ip
+    }
+
+    /// Payload of a frame: 802.1Q and 802.1ad tags (outer first) if
+    /// any, the IPv4 ethertype, then the packet
+    fn payload(tags: &[(u16, u16)], packet: &[u8]) -> (u16, Vec<u8>) {
+        let mut protocols = tags.iter().map(|(protocol, _)| *protocol);
+        let first = protocols.next().unwrap_or(ETHER_IPV4);
+        let mut data = Vec::new();
+        for ((_, vlan), next) in tags.iter().zip(protocols.chain([ETHER_IPV4])) {
+            data.extend(vlan.to_be_bytes());
+            data.extend(next.to_be_bytes());
+        }
+        data.extend(packet);
+        (first, data)
+    }
+
+    fn ethernet(tags: &[(u16, u16)], packet: &[u8]) -> Vec<u8> {
+        let (protocol, payload) = payload(tags, packet);
+        let mut frame = vec![0xaa; 12];
+        frame.extend(protocol.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn linux_sll(tags: &[(u16, u16)], packet: &[u8], packet_type: u16) -> Vec<u8> {
+        let (protocol, payload) = payload(tags, packet);
+        let mut frame = packet_type.to_be_bytes().to_vec();
+        frame.extend([0, 1, 0, 6, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0]);
+        frame.extend(protocol.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn linux_sll2(tags: &[(u16, u16)], packet: &[u8], packet_type: u8) -> Vec<u8> {
+        let (protocol, payload) = payload(tags, packet);
+        let mut frame = protocol.to_be_bytes().to_vec();
+        frame.extend([0, 0, 0, 0, 0, 2, 0, 1, packet_type, 6]);
+        frame.extend([0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0]);
+        frame.extend(payload);
+        frame
+    }
+
+    fn loopback(family: [u8; 4], packet: &[u8]) -> Vec<u8> {
+        let mut frame = family.to_vec();
+        frame.extend(packet);
+        frame
+    }
+
+    #[test]
+    fn link_types() {
+        let packet = ip(20, 0);
+        let parsed = Some((0, &packet[..]));
+        let family_le = 2u32.to_le_bytes();
+        let family_be = 2u32.to_be_bytes();
+        for (framing, frame) in [
+            (Framing::Ethernet, ethernet(&[], &packet)),
+            (Framing::LinuxSll, linux_sll(&[], &packet, 0)),
+            (Framing::LinuxSll2, linux_sll2(&[], &packet, 0)),
+            (Framing::Raw, packet.clone()),
+            // Captured on a little-endian or a big-endian machine
+            (Framing::Null, loopback(family_le, &packet)),
+            (Framing::Null, loopback(family_be, &packet)),
+            (Framing::Loop, loopback(family_be, &packet)),
+        ] {
+            assert_eq!(framing.parse(&frame), parsed, "{framing:?}");
+        }
+        // Other protocols and address families
+        let mut frame = ethernet(&[], &packet);
+        frame[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+        assert_eq!(Framing::Ethernet.parse(&frame), None);
+        let mut frame = linux_sll2(&[], &packet, 0);
+        frame[0..2].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(Framing::LinuxSll2.parse(&frame), None);
+        let frame = loopback(24u32.to_ne_bytes(), &packet);
+        assert_eq!(Framing::Null.parse(&frame), None);
+        let frame = loopback(family_le, &packet);
+        assert_eq!(Framing::Loop.parse(&frame), None);
+        let mut frame = packet.clone();
+        frame[0] = 0x60;
+        assert_eq!(Framing::Raw.parse(&frame), None);
+    }
+
+    #[test]
+    fn vlan_tags() {
+        let packet = ip(20, 0);
+        let single = [(0x8100, 10)];
+        let stacked = [(0x88a8, 100), (0x8100, 0x2000 | 20)];
+        for (tags, vlan) in [(&single[..], 10), (&stacked[..], 20)] {
+            for (framing, frame) in [
+                (Framing::Ethernet, ethernet(tags, &packet)),
+                (Framing::LinuxSll, linux_sll(tags, &packet, 0)),
+                (Framing::LinuxSll2, linux_sll2(tags, &packet, 0)),
+            ] {
+                assert_eq!(
+                    framing.parse(&frame),
+                    Some((vlan, &packet[..])),
+                    "{framing:?}"
+                );
+                // Cut in the tags
+                let cut = frame.len() - packet.len() - 3;
+                assert_eq!(framing.parse(&frame[..cut]), None, "{framing:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn outgoing() {
+        let packet = ip(20, 0);
+        assert!(Framing::LinuxSll.is_outgoing(&linux_sll(&[], &packet, 4)));
+        assert!(!Framing::LinuxSll.is_outgoing(&linux_sll(&[], &packet, 0)));
+        assert!(Framing::LinuxSll2.is_outgoing(&linux_sll2(&[], &packet, 4)));
+        assert!(!Framing::LinuxSll2.is_outgoing(&linux_sll2(&[], &packet, 0)));
+        assert!(!Framing::Ethernet.is_outgoing(&ethernet(&[], &packet)));
+    }
+
+    #[test]
+    fn header_and_total_lengths() {
+        // Options, then a payload and the padding of the frame
+        let mut packet = ip(32, 2);
+        packet.extend([7; 4]);
+        packet.extend([0; 6]);
+        let (_, parsed) = Framing::Raw.parse(&packet).unwrap();
+        assert_eq!((header_len(parsed), parsed.len()), (28, 32));
+
+        // Truncated by the snapshot length, within the payload or the
+        // options
+        let (_, parsed) = Framing::Raw.parse(&packet[..30]).unwrap();
+        assert_eq!(parsed.len(), 30);
+        assert_eq!(Framing::Raw.parse(&packet[..27]), None);
+        assert_eq!(Framing::Raw.parse(&packet[..19]), None);
+
+        // Offloaded, without a total length
+        let mut offloaded = packet.clone();
+        offloaded[2..4].copy_from_slice(&[0, 0]);
+        let (_, parsed) = Framing::Raw.parse(&offloaded).unwrap();
+        assert_eq!(parsed.len(), packet.len());
+
+        // Shorter than its header
+        let mut short = packet.clone();
+        short[2..4].copy_from_slice(&27u16.to_be_bytes());
+        assert_eq!(Framing::Raw.parse(&short), None);
+
+        // Header shorter than the minimum
+        let mut invalid = ip(20, 0);
+        invalid[0] = 0x44;
+        assert_eq!(Framing::Raw.parse(&invalid), None);
+    }
+}
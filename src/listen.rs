@@ -0,0 +1,176 @@
+//! Listeners of the HTTP server, live or over an archived snapshot
+
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::middleware;
+use hyper::server::accept;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+
+use crate::{
+    auth::{self, Allowlist, Auth, Peer},
+    dhcp::SharedLeases,
+    enrich::Enricher,
+    naming::{Chain, Resolver, Source},
+    probe::ProbeResults,
+    server::{router, ServerState, SharedHealth, DEFAULT_NAMESPACE},
+    ssdp::SharedDevices,
+    store, tls, SNAPSHOT_INTERVAL,
+};
+
+/// Serve the statistics of the latest snapshot of a state directory,
+/// reloading it periodically
+pub async fn serve_archive(dir: PathBuf, bind: &str, port: u16) {
+    let load = |dir: &PathBuf| {
+        store::load_latest(dir).and_then(|snapshot| {
+            snapshot.ok_or_else(|| format!("No snapshot in {}", dir.display()))
+        })
+    };
+    let snapshot = load(&dir).unwrap_or_else(|err| {
+        println!("{err}");
+        std::process::exit(1);
+    });
+    let stats = Arc::new(Mutex::new(snapshot.stats()));
+    let devices = Arc::new(Mutex::new(snapshot.devices.iter().cloned().collect()));
+    let mut resolvers = Vec::<(Source, Box<dyn Resolver>)>::new();
+    if !snapshot.names.is_empty() {
+        resolvers.push((Source::Discovery, Box::new(stats.clone())));
+    }
+    if !snapshot.devices.is_empty() {
+        resolvers.push((Source::Ssdp, Box::new(SharedDevices::clone(&devices))));
+    }
+    let names = Arc::new(Chain::new(resolvers, &[]));
+    let state = ServerState {
+        stats,
+        leader: None,
+        probes: ProbeResults::default(),
+        enricher: Enricher::new(tokio::runtime::Handle::current()),
+        names,
+        reverse_dns: None,
+        pods: None,
+        containers: None,
+        leases: SharedLeases::default(),
+        dhcp: false,
+        devices,
+        host: snapshot.host,
+        mirror_dropped: None,
+        samples: None,
+        namespace: DEFAULT_NAMESPACE.to_string(),
+        legacy_names: false,
+        compat: None,
+        script: None,
+        health: SharedHealth::default(),
+        threads: None,
+        started: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    let reloaded = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match load(&dir) {
+                Ok(snapshot) => {
+                    *reloaded.stats.lock().unwrap() = snapshot.stats();
+                    *reloaded.devices.lock().unwrap() = snapshot.devices.into_iter().collect();
+                }
+                Err(err) => println!("{err}"),
+            }
+        }
+    });
+    let listener = tcp_listener(bind, port).unwrap_or_else(|err| {
+        println!("{err}");
+        std::process::exit(1);
+    });
+    serve(Listen::Tcp(listener), None, None, None, state).await;
+}
+
+/// Listen on a Unix domain socket, replacing the one left by a previous
+/// run
+pub fn unix_listener(path: &Path) -> Result<std::os::unix::net::UnixListener, String> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()));
+        }
+        fs::remove_file(path)
+            .map_err(|err| format!("Unable to remove {}: {err}", path.display()))?;
+    }
+    std::os::unix::net::UnixListener::bind(path)
+        .map_err(|err| format!("Unable to listen on {}: {err}", path.display()))
+}
+
+/// Parse the listen address (`--bind`)
+pub fn parse_bind(bind: &str) -> Result<IpAddr, String> {
+    bind.parse()
+        .map_err(|_| format!("Invalid listen address {bind:?} (--bind)"))
+}
+
+/// Listen on a TCP port
+pub fn tcp_listener(bind: &str, port: u16) -> Result<std::net::TcpListener, String> {
+    let addr = SocketAddr::new(parse_bind(bind)?, port);
+    std::net::TcpListener::bind(addr).map_err(|err| format!("Unable to listen on {addr}: {err}"))
+}
+
+/// Socket where the HTTP API is served
+pub enum Listen {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Serve the HTTP API, over HTTPS when a TLS configuration is given,
+/// and only to the allowed and authenticated clients when restricted
+pub async fn serve(
+    listen: Listen,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    auth: Option<Arc<Auth>>,
+    allowed: Option<Allowlist>,
+    state: ServerState,
+) {
+    let mut app = router(state);
+    if let Some(auth) = auth {
+        app = app.layer(middleware::from_fn_with_state(auth, auth::require));
+    }
+    // Checked before the credentials
+    if let Some(allowed) = allowed {
+        app = app.layer(middleware::from_fn_with_state(allowed, auth::allow));
+    }
+
+    fn fail<T>(err: String) -> T {
+        println!("{err}");
+        std::process::exit(1);
+    }
+    match (listen, tls) {
+        (Listen::Unix(listener), _) => {
+            let listener = listener
+                .set_nonblocking(true)
+                .and_then(|()| UnixListener::from_std(listener))
+                .unwrap_or_else(|err| fail(err.to_string()));
+            axum::Server::builder(accept::from_stream(UnixListenerStream::new(listener)))
+                .serve(app.into_make_service())
+                .await
+                .unwrap();
+        }
+        (Listen::Tcp(listener), Some(config)) => {
+            let incoming = tls::listen(listener, config).unwrap_or_else(fail);
+            axum::Server::builder(incoming)
+                .serve(app.into_make_service_with_connect_info::<Peer>())
+                .await
+                .unwrap();
+        }
+        (Listen::Tcp(listener), None) => axum::Server::from_tcp(listener)
+            .unwrap_or_else(|err| fail(err.to_string()))
+            .serve(app.into_make_service_with_connect_info::<Peer>())
+            .await
+            .unwrap(),
+    }
+}
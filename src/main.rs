@@ -1,354 +1,1290 @@
 use std::{
-    collections::HashMap,
-    fmt,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use axum::{extract::State, routing::get, Router};
-use clap::Parser;
-use pcap::{Active, Capture, Linktype};
+use category::Rules;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+
+use auth::Auth;
+use capture::{
+    is_stream, open_capture, open_stream, pcap_devices, run, Networks, RunOptions, SharedNetworks,
+    ANY_DEVICE, ROUTE_CHECK_INTERVAL,
+};
+use config::Config;
+use dhcp::SharedLeases;
+use docker::SharedContainers;
+use enrich::Enricher;
+use events::{hostname, Bus, Event};
+use geoip::Geo;
+use hook::Hook;
+use hosts::{parse_subnets, SharedHosts};
+use kubernetes::SharedPods;
+use link::Count;
+use listen::{parse_bind, serve, serve_archive, tcp_listener, unix_listener, Listen};
+use mirror::Mirror;
+use naming::{Chain, Resolver, Source};
+use poll::PollMode;
+use probe::{ProbeResults, Target};
+use profile::Profile;
+use rdns::ReverseDns;
+use script::Script;
+use server::{Compat, ServerState, SharedHealth, Threads, DEFAULT_NAMESPACE};
+use service::Ports;
+use shard::Shard;
+use ssdp::SharedDevices;
+use stats::{throughput_totals, Stats, DEFAULT_MAX};
+use tokio::signal::unix::{signal, SignalKind};
+use txne::{
+    auth, burst, cluster, dhcp, discovery, docker, enrich, events, hosts, icmp, kubernetes, laptop,
+    model, naming, notify, packet, probe, rdns, report, script, server, shm, ssdp, stats, store,
+    wifi,
+};
+use wifi::SharedWifi;
+use window::Schedule;
+
+mod capture;
+mod category;
+mod clock;
+mod config;
+mod geoip;
+mod grpc;
+mod hook;
+mod link;
+mod listen;
+mod mirror;
+mod poll;
+mod profile;
+mod push;
+mod rawsock;
+mod service;
+mod shard;
+mod stream;
+mod systemd;
+mod tls;
+mod window;
 
 /// Prometheus node exporter with per IP traffic statistics
 #[derive(Parser, Debug)]
 struct Args {
-    /// Interface to listen
+    /// Configuration file (TOML, or YAML with a .yaml or .yml
+    /// extension), overridden by the command line
     #[arg(short, long)]
-    interface: String,
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Presets for a deployment topology, overridden by the other
+    /// options
+    #[arg(long)]
+    profile: Option<Profile>,
+
+    /// Interface to listen, may be repeated (the series then get an
+    /// "interface" label), or a pcap or pcapng stream ("-" for the
+    /// standard input, or the path of a file or named pipe)
+    #[arg(short, long)]
+    interface: Vec<String>,
 
     /// Exporter listen address (use "0.0.0.0" or "::" to bind on
     /// every interfaces, but this is not recommended)
     #[arg(short, long)]
-    bind: String,
+    bind: Option<String>,
 
     /// Exporter port
     #[arg(short, long)]
-    port: u16,
+    port: Option<u16>,
+
+    /// Serve on this Unix domain socket instead of --bind and --port
+    #[arg(long)]
+    bind_unix: Option<PathBuf>,
 
-    /// Subnet(s) to consider as local
+    /// Serve over HTTPS with this certificate chain (PEM)
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the certificate (PEM)
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Require a client certificate signed by these CAs (PEM)
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Require HTTP basic authentication, for the users of this file
+    /// (one "USER:HASH" per line, the bcrypt HASH given by txne
+    /// hash-password or htpasswd -B)
+    #[arg(long)]
+    basic_auth_users: Option<PathBuf>,
+
+    /// Require this bearer token (read from the file), or basic
+    /// authentication when both are enabled
+    #[arg(long)]
+    bearer_token_file: Option<PathBuf>,
+
+    /// Subnet(s) of the clients allowed to scrape (the others get a
+    /// 403), every client by default
+    #[arg(long)]
+    allow_scrape_from: Option<String>,
+
+    /// Subnet(s) to consider as local ("auto" for the networks of the
+    /// interface, "self" for its addresses only)
     #[arg(short, long)]
-    subnets: String,
+    subnets: Option<String>,
 
     /// Subnet(s) to ignore
     #[arg(short, long)]
     exclude: Option<String>,
 
     /// Maximum number of IP to track
-    #[arg(short, long, default_value_t = 1024)]
+    #[arg(short, long, default_value_t = DEFAULT_MAX)]
     max: usize,
-}
 
-const ETHER_IPV4: u16 = 0x0800;
+    /// Capture in promiscuous mode (required for mirrored traffic)
+    #[arg(long)]
+    promisc: bool,
 
-#[derive(Clone, Copy)]
-enum Protocol {
-    Icmp,
-    Tcp,
-    Udp,
-    Other,
-}
+    /// Interval at which the counters of the captures become visible
+    /// (to /metrics and the other outputs), in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    flush_interval: u64,
 
-impl fmt::Display for Protocol {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Protocol::Icmp => write!(f, "icmp"),
-            Protocol::Tcp => write!(f, "tcp"),
-            Protocol::Udp => write!(f, "udp"),
-            Protocol::Other => write!(f, "other"),
-        }
-    }
-}
+    /// How the captures wait for the packets: "immediate" (each packet
+    /// as it arrives), "blocking" (never waking up when idle),
+    /// "timeout:MS" (buffered by libpcap for at most MS milliseconds)
+    /// or "busy" (polling without blocking, keeping a CPU busy)
+    #[arg(long, default_value_t = PollMode::Immediate)]
+    poll_mode: PollMode,
 
-#[derive(Clone, Copy)]
-enum Direction {
-    Inbound,
-    Outbound,
-}
+    /// Add a "host" label to every series (defaults to the machine
+    /// hostname when no value is given)
+    #[arg(long, num_args = 0..=1, default_missing_value = "")]
+    host_label: Option<String>,
 
-impl fmt::Display for Direction {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Direction::Inbound => write!(f, "inbound"),
-            Direction::Outbound => write!(f, "outbound"),
-        }
-    }
-}
+    /// Prefix of the metric names (none when empty)
+    #[arg(long, default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
 
-#[derive(Clone, Copy)]
-enum ValueType {
-    Packets,
-    Bytes,
-}
+    /// Also export the metrics under their former names and labels (a
+    /// family per direction, such as txne_inbound_bytes_total with an
+    /// "ip_dest" label), while migrating dashboards and alerts
+    #[arg(long)]
+    legacy_names: bool,
 
-impl fmt::Display for ValueType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ValueType::Packets => write!(f, "packets"),
-            ValueType::Bytes => write!(f, "bytes"),
-        }
-    }
-}
+    /// Export the metrics exactly as an older version did, for the
+    /// existing dashboards (excludes --namespace and --legacy-names)
+    #[arg(long)]
+    compat: Option<Compat>,
 
-#[derive(Debug, Clone, Default)]
-struct BaseCounters {
-    pkts: u64,
-    bytes: u64,
-}
+    /// Monitor this machine against everything else, following the
+    /// interface of the default route across suspends and docks
+    #[arg(long)]
+    laptop: bool,
 
-#[derive(Debug, Clone, Default)]
-struct DirectionCounters {
-    inbound: BaseCounters,
-    outbound: BaseCounters,
-}
+    /// Add "ssid" and "bssid" labels when capturing on a wireless uplink
+    /// (needs the iw command)
+    #[arg(long)]
+    wifi: bool,
+
+    /// Export a histogram of the RTT of ICMP echo requests leaving the
+    /// network, per remote peer
+    #[arg(long)]
+    icmp_rtt: bool,
+
+    /// Target to probe actively (icmp:HOST or tcp:HOST:PORT), may be
+    /// repeated
+    #[arg(long)]
+    probe: Vec<Target>,
+
+    /// Interval between active probes, in seconds
+    #[arg(long, default_value_t = 30)]
+    probe_interval: u64,
 
-#[derive(Debug, Clone, Default)]
-struct ProtocolCounters {
-    icmp: DirectionCounters,
-    tcp: DirectionCounters,
-    udp: DirectionCounters,
-    other: DirectionCounters,
+    /// DHCP lease file (dnsmasq, Kea CSV or ISC dhcpd) used to label
+    /// local IPs with their hostname and MAC address, may be repeated
+    #[arg(long)]
+    dhcp_leases: Vec<PathBuf>,
+
+    /// Label local IPs with the names announced over mDNS and NetBIOS
+    #[arg(long)]
+    discover_names: bool,
+
+    /// Build an inventory of the local devices announcing themselves
+    /// over SSDP (UPnP), served at /api/v1/devices
+    #[arg(long)]
+    ssdp: bool,
+
+    /// Label local IPs with the names of this file (lines of `IP[/SIZE]
+    /// NAME` as in /etc/hosts, or `IP[/SIZE],NAME`), read again on SIGHUP
+    #[arg(long)]
+    hosts_file: Option<PathBuf>,
+
+    /// Sources of the "name" label by priority (comma separated, among
+    /// hosts, dhcp, discovery, ssdp, kubernetes, docker and
+    /// reverse-dns), every enabled one by default
+    #[arg(long, value_delimiter = ',')]
+    name_sources: Vec<naming::Source>,
+
+    /// Name local IPs from their reverse DNS name (resolved in the
+    /// background)
+    #[arg(long)]
+    reverse_dns: bool,
+
+    /// Name local IPs after the Kubernetes pods they belong to (when
+    /// running in the cluster)
+    #[arg(long)]
+    kubernetes: bool,
+
+    /// Name local IPs after the Docker containers they belong to (from
+    /// the Docker socket)
+    #[arg(long)]
+    docker: bool,
+
+    /// Also label the series of the local IPs with the details of the
+    /// sources of names ("hostname" for --reverse-dns, "pod",
+    /// "namespace" and "node" for --kubernetes, "container_name" and
+    /// "image" for --docker)
+    #[arg(long)]
+    source_labels: bool,
+
+    /// Push the metrics to this VictoriaMetrics import URL, over HTTP or
+    /// HTTPS (for example http://vm:8428/api/v1/import)
+    #[arg(long)]
+    vm_import_url: Option<String>,
+
+    /// Interval between VictoriaMetrics pushes, in seconds
+    #[arg(long, default_value_t = 30)]
+    vm_import_interval: u64,
+
+    /// Lease file shared by redundant instances watching the same
+    /// traffic, only the leader exports the per-IP series
+    #[arg(long)]
+    leader_lease: Option<PathBuf>,
+
+    /// Identity written in the lease file by the leader (defaults to
+    /// the hostname)
+    #[arg(long)]
+    leader_id: Option<String>,
+
+    /// Only track the shard of the local IPs owned by this instance
+    /// (INDEX/COUNT, e.g. 0/4), when several instances share the traffic
+    #[arg(long)]
+    shard: Option<Shard>,
+
+    /// Copy the captured packets to another tool (iface:NAME to inject
+    /// them on an interface, unix:PATH to write a pcap stream to a UNIX
+    /// socket)
+    #[arg(long)]
+    mirror: Option<mirror::Target>,
+
+    /// Only mirror the packets matching this BPF filter
+    #[arg(long)]
+    mirror_filter: Option<String>,
+
+    /// Only count the packets of these VLANs (comma separated IDs, the
+    /// inner one for stacked tags)
+    #[arg(long, value_delimiter = ',')]
+    vlan: Vec<u16>,
+
+    /// Add a "vlan" label with the ID of the VLAN (the inner one for
+    /// stacked tags, 0 when untagged)
+    #[arg(long)]
+    vlan_label: bool,
+
+    /// MaxMind country database (GeoLite2 or GeoIP2, Country or City),
+    /// to count the traffic per country of the remote side
+    #[arg(long)]
+    geoip_db: Option<PathBuf>,
+
+    /// MaxMind ASN database (GeoLite2 or GeoIP2), to count the traffic
+    /// per autonomous system of the remote side
+    #[arg(long)]
+    asn_db: Option<PathBuf>,
+
+    /// Bytes counted for each packet
+    #[arg(long, value_enum, default_value = "wire")]
+    count: Count,
+
+    /// Add a "port" label to the series, with the service of the
+    /// traffic among these ports (comma separated PORT, PORT=NAME or
+    /// well-known service names), "other" for the rest
+    #[arg(long, value_delimiter = ',')]
+    ports: Vec<service::Service>,
+
+    /// WASM module classifying the packets in categories, or dropping
+    /// them (needs the wasm feature)
+    #[arg(long)]
+    wasm_hook: Option<PathBuf>,
+
+    /// Expose the live counters in this memory-mapped file, for local
+    /// consumers (see the shm module of the library)
+    #[arg(long)]
+    shm_file: Option<PathBuf>,
+
+    /// Port of the gRPC query service (on the exporter listen address)
+    #[arg(long)]
+    grpc_port: Option<u16>,
+
+    /// Directory where the statistics are persisted, restored at startup
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+
+    /// Sample the throughput of each IP every 5 minutes, to export its
+    /// 95th percentile (burstable billing)
+    #[arg(long)]
+    percentiles: bool,
+
+    /// Scheduled summary notifications (configuration file only)
+    #[arg(skip)]
+    notify: Vec<notify::Notification>,
+
+    /// Delivery channels of the events (configuration file only)
+    #[arg(skip)]
+    subscribe: Vec<events::Subscriber>,
+
+    /// Categories of traffic (configuration file only)
+    #[arg(skip)]
+    category: Vec<category::Category>,
+
+    /// Rhai script post-processing the exported series (configuration
+    /// file only)
+    #[arg(skip)]
+    script: Option<PathBuf>,
+
+    /// Time windows of the accounting periods (configuration file only)
+    #[arg(skip)]
+    window: Vec<window::Window>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-type Stats = HashMap<Option<u32>, ProtocolCounters>;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve the statistics persisted in a state directory, without
+    /// capturing
+    ServeArchive {
+        /// State directory written by an exporter (--state-dir)
+        #[arg(long)]
+        state_dir: PathBuf,
 
-#[derive(Clone)]
-struct ServerState {
-    stats: Arc<Mutex<Stats>>,
+        /// Exporter listen address
+        #[arg(short, long)]
+        bind: String,
+
+        /// Exporter port
+        #[arg(short, long)]
+        port: u16,
+    },
+    /// Print a summary of the traffic persisted in a state directory
+    Report {
+        /// State directory written by an exporter (--state-dir)
+        #[arg(long)]
+        state_dir: PathBuf,
+
+        /// Period covered by the report
+        #[arg(long, default_value = "last-month")]
+        period: report::Period,
+
+        #[arg(long, default_value = "md")]
+        format: report::Format,
+
+        /// Number of top talkers to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Traffic allowed to each local IP over the period (bytes, in
+        /// both directions), to list the IPs over it
+        #[arg(long)]
+        quota: Option<u64>,
+    },
+    /// Print the hash of a password read from the standard input, for
+    /// --basic-auth-users
+    HashPassword,
 }
 
-fn run(
-    mut cap: Capture<Active>,
-    is_local: impl Fn(u32) -> bool,
-    is_excluded: Option<impl Fn(u32) -> bool>,
-    max_tracking: usize,
-    out_stats: Arc<Mutex<Stats>>,
-) {
-    let mut stats = Stats::default();
-    let mut sync_remaining = 0usize;
-    loop {
-        if sync_remaining == 0 {
-            *out_stats.lock().unwrap() = stats.clone();
-            sync_remaining = 64;
+/// How often the memory-mapped file is updated
+const SHM_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the statistics are persisted (or reloaded when serving an
+/// archive)
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Check the consistency of the arguments, once merged with the
+/// configuration file
+fn validate(args: &Args) -> Result<(), String> {
+    if args.grpc_port.is_some() && args.bind.is_none() {
+        return Err("The gRPC service needs a listen address (--bind)".to_string());
+    }
+    if args.bind_unix.is_some() {
+        if args.bind.is_some() || args.port.is_some() {
+            return Err("--bind-unix excludes --bind and --port".to_string());
         }
-        sync_remaining -= 1;
-
-        let pkt = cap.next_packet().ok();
-        if let Some(pkt) = pkt {
-            if pkt.header.caplen >= 14 + 20 {
-                let data = pkt.data;
-                let eth_proto = u16::from_be_bytes(data[12..14].try_into().unwrap());
-                if eth_proto == ETHER_IPV4 {
-                    let ip = &data[14..];
-                    let ip_proto = ip[9];
-                    let ip_source = u32::from_be_bytes(ip[12..16].try_into().unwrap());
-                    let ip_dest = u32::from_be_bytes(ip[16..20].try_into().unwrap());
-                    if let Some(is_excluded) = &is_excluded {
-                        if is_excluded(ip_source) || is_excluded(ip_dest) {
-                            continue;
-                        }
-                    }
-                    let from_local = is_local(ip_source);
-                    let to_local = is_local(ip_dest);
-                    if from_local != to_local {
-                        let ip_entry = if from_local { ip_source } else { ip_dest };
-                        let ip_entry = if !stats.contains_key(&Some(ip_entry))
-                            && stats.len() >= max_tracking
-                        {
-                            None
-                        } else {
-                            Some(ip_entry)
-                        };
-                        let entry = stats.entry(ip_entry);
-                        let entry = entry.or_insert(ProtocolCounters::default());
-                        let item = match ip_proto {
-                            1 => &mut entry.icmp,
-                            6 => &mut entry.tcp,
-                            17 => &mut entry.udp,
-                            _ => &mut entry.other,
-                        };
-                        let item = if from_local {
-                            &mut item.outbound
-                        } else {
-                            &mut item.inbound
-                        };
-                        item.pkts += 1;
-                        item.bytes += pkt.header.len as u64;
-                    }
-                }
-            }
+        if args.tls_cert.is_some() || args.allow_scrape_from.is_some() {
+            return Err("--bind-unix excludes --tls-cert and --allow-scrape-from".to_string());
+        }
+    } else if !systemd::activated() {
+        if args.bind.is_none() {
+            return Err("Missing the listen address (--bind)".to_string());
+        }
+        if args.port.is_none() {
+            return Err("Missing the listen port (--port)".to_string());
         }
     }
+    if let Some(bind) = &args.bind {
+        parse_bind(bind)?;
+    }
+    if args.tls_cert.is_some() != args.tls_key.is_some() {
+        return Err("HTTPS needs both --tls-cert and --tls-key".to_string());
+    }
+    if args.tls_client_ca.is_some() && args.tls_cert.is_none() {
+        return Err("Client certificates need HTTPS (--tls-cert)".to_string());
+    }
+    if let Some(subnets) = &args.allow_scrape_from {
+        parse_subnets(subnets).ok_or("Invalid subnets (--allow-scrape-from)")?;
+    }
+    if args.laptop {
+        if !args.interface.is_empty() || args.subnets.is_some() {
+            return Err("The laptop mode excludes --interface and --subnets".to_string());
+        }
+        // The default route is checked when the capture wakes up
+        if args.poll_mode == PollMode::Blocking {
+            return Err("The laptop mode excludes --poll-mode blocking".to_string());
+        }
+    } else if args.interface.is_empty() || args.subnets.is_none() {
+        return Err("Missing --interface and --subnets (or --laptop)".to_string());
+    }
+    // Each capture reports its health under its interface (or stream)
+    for (n, interface) in args.interface.iter().enumerate() {
+        if args.interface[..n].contains(interface) {
+            return Err(format!("Interface {interface:?} given more than once"));
+        }
+    }
+    if args.wifi && args.interface.len() > 1 {
+        return Err("The wireless labels need a single interface".to_string());
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':';
+    if !args.namespace.chars().all(valid)
+        || args.namespace.starts_with(|c: char| c.is_ascii_digit())
+    {
+        return Err(format!("Invalid namespace {:?}", args.namespace));
+    }
+    if args.compat.is_some() && (args.namespace != DEFAULT_NAMESPACE || args.legacy_names) {
+        return Err("--compat excludes --namespace and --legacy-names".to_string());
+    }
+    if args.flush_interval == 0 {
+        return Err("The flush interval must be at least a millisecond".to_string());
+    }
+    if let Some(url) = &args.vm_import_url {
+        let url = url
+            .parse::<hyper::Uri>()
+            .map_err(|err| format!("Invalid VictoriaMetrics import URL: {err}"))?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) || url.host().is_none() {
+            return Err(format!(
+                "Invalid VictoriaMetrics import URL: {url} (not an http or https URL)"
+            ));
+        }
+    }
+    if !args.notify.is_empty() && args.state_dir.is_none() {
+        return Err("Notifications need the persisted statistics (--state-dir)".to_string());
+    }
+    for notification in &args.notify {
+        notification.validate()?;
+    }
+    for subscriber in &args.subscribe {
+        subscriber.validate()?;
+    }
+    Ok(())
 }
 
-async fn metrics(State(state): State<ServerState>) -> String {
-    let mut result = String::new();
-
-    let stats = state.stats.lock().unwrap().clone();
-
-    let mut ips = stats.keys().collect::<Vec<_>>();
-    ips.sort();
+/// Merge the arguments with the profile and the configuration file
+fn configure(mut args: Args, matches: &ArgMatches) -> Result<Args, String> {
+    let config = args.config.as_deref().map(config::load).transpose()?;
+    let profile = args
+        .profile
+        .or_else(|| config.as_ref().and_then(|config| config.profile));
+    if let Some(profile) = profile {
+        profile.apply(&mut args, matches);
+    }
+    if let Some(config) = config {
+        config.apply(&mut args, matches);
+    }
+    Ok(args)
+}
 
-    let add_desc = |result: &mut String, direction: Direction, value_type: ValueType| {
-        let dir_name = match direction {
-            Direction::Inbound => "entering",
-            Direction::Outbound => "leaving",
-        };
-        let type_name = match value_type {
-            ValueType::Packets => "Packets",
-            ValueType::Bytes => "Bytes",
-        };
-        let series_name = format!("txne_{direction}_{value_type}_total");
-        result.push_str(&format!(
-            "# HELP {series_name} {type_name} {dir_name} the network\n",
-        ));
-        result.push_str(&format!("# TYPE {series_name} counter\n",));
+/// Networks of a capture on the devices of `scope` (none in laptop
+/// mode), named `interface`
+fn networks(args: &Args, interface: &str, scope: &[packet::Device]) -> Result<Networks, String> {
+    let local = match args.subnets.as_deref() {
+        None => Vec::new(),
+        Some("auto") => scope.iter().flat_map(laptop::local_networks).collect(),
+        Some("self") => scope
+            .iter()
+            .flat_map(laptop::local_addresses)
+            .map(|addr| (addr, !0))
+            .collect(),
+        Some(subnets) => parse_subnets(subnets).ok_or("Invalid subnets")?,
+    };
+    if args.subnets.is_some() && local.is_empty() {
+        return Err(format!("No IPv4 address found on {interface}"));
+    }
+    let excluded = match args.exclude.as_deref() {
+        Some(subnets) => parse_subnets(subnets).ok_or("Invalid subnets")?,
+        None => Vec::new(),
     };
+    Ok(Networks {
+        local,
+        excluded,
+        max_tracking: args.max,
+    })
+}
 
-    let add_metric = |result: &mut String,
-                      stats: &Stats,
-                      direction: Direction,
-                      value_type: ValueType,
-                      ip: Option<u32>,
-                      protocol: Protocol| {
-        let counter = {
-            let entry = {
-                let entry = {
-                    let entry = stats.get(&ip).unwrap();
-                    match protocol {
-                        Protocol::Icmp => &entry.icmp,
-                        Protocol::Tcp => &entry.tcp,
-                        Protocol::Udp => &entry.udp,
-                        Protocol::Other => &entry.other,
-                    }
+/// Reload the subnets, the exclusions and the maximum number of tracked
+/// IPs on SIGHUP
+///
+/// The other settings are only read at startup. The counters are kept,
+/// including the ones of the IPs that are not local anymore.
+async fn reload(
+    matches: ArgMatches,
+    captures: Vec<(String, Vec<packet::Device>, SharedNetworks)>,
+    hosts: Option<SharedHosts>,
+    events: Bus,
+) {
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangup.recv().await.is_some() {
+        let result = Args::from_arg_matches(&matches)
+            .map_err(|err| err.to_string())
+            .and_then(|args| configure(args, &matches))
+            .and_then(|args| {
+                validate(&args)?;
+                let reloaded_hosts = match (&hosts, &args.hosts_file) {
+                    (Some(_), Some(path)) => Some(hosts::load(path)?),
+                    _ => None,
                 };
-                match direction {
-                    Direction::Inbound => &entry.inbound,
-                    Direction::Outbound => &entry.outbound,
+                let networks = captures
+                    .iter()
+                    .map(|(interface, scope, shared)| {
+                        let mut networks = networks(&args, interface, scope)?;
+                        if args.laptop {
+                            // Following the addresses of the interface in use
+                            networks.local = shared.lock().unwrap().local.clone();
+                        }
+                        Ok(networks)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok((networks, reloaded_hosts))
+            });
+        match result {
+            Ok((updated, reloaded_hosts)) => {
+                for ((_, _, shared), networks) in captures.iter().zip(updated) {
+                    *shared.lock().unwrap() = Arc::new(networks);
                 }
-            };
-            match value_type {
-                ValueType::Packets => &entry.pkts,
-                ValueType::Bytes => &entry.bytes,
+                let reloaded_hosts = match (&hosts, reloaded_hosts) {
+                    (Some(hosts), Some(reloaded)) => {
+                        *hosts.lock().unwrap() = reloaded;
+                        true
+                    }
+                    _ => false,
+                };
+                events.emit(Event::Reloaded {
+                    hosts: reloaded_hosts,
+                });
             }
-        };
-        let field = match direction {
-            Direction::Inbound => "ip_dest",
-            Direction::Outbound => "ip_source",
-        };
-        let ip = ip.map(|ip| {
-            let ip = ip.to_be_bytes();
-            format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
-        });
-        let ip = ip.as_deref().unwrap_or("other");
-        let series_name = format!("txne_{direction}_{value_type}_total");
-        result.push_str(&format!(
-            "{series_name}{{ip_version=\"4\",{field}=\"{ip}\",protocol=\"{protocol}\"}} {counter}\n",
-        ));
-    };
+            Err(error) => events.emit(Event::ReloadFailed { error }),
+        }
+    }
+}
 
-    for direction in [Direction::Inbound, Direction::Outbound] {
-        for value_type in [ValueType::Packets, ValueType::Bytes] {
-            add_desc(&mut result, direction, value_type);
-
-            for ip in ips.iter() {
-                for protocol in [
-                    Protocol::Icmp,
-                    Protocol::Tcp,
-                    Protocol::Udp,
-                    Protocol::Other,
-                ] {
-                    add_metric(&mut result, &stats, direction, value_type, **ip, protocol);
+#[tokio::main]
+async fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    match args.command.take() {
+        Some(Command::ServeArchive {
+            state_dir,
+            bind,
+            port,
+        }) => {
+            serve_archive(state_dir, &bind, port).await;
+            return;
+        }
+        Some(Command::Report {
+            state_dir,
+            period,
+            format,
+            top,
+            quota,
+        }) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            match report::build(&state_dir, period, now, top, quota) {
+                Ok(report) => print!("{}", report::render(&report, format)),
+                Err(err) => {
+                    println!("{err}");
+                    std::process::exit(1);
                 }
             }
-            result.push('\n');
+            return;
+        }
+        Some(Command::HashPassword) => {
+            let mut password = String::new();
+            if let Err(err) = std::io::stdin().read_line(&mut password) {
+                println!("Unable to read the password: {err}");
+                std::process::exit(1);
+            }
+            let password = password.trim_end_matches(['\r', '\n']);
+            println!("{}", auth::hash_password(password));
+            return;
         }
+        None => {}
+    }
+    let args = configure(args, &matches).unwrap_or_else(|err| {
+        println!("{err}");
+        std::process::exit(1);
+    });
+    if args.print_config {
+        let format = args
+            .config
+            .as_deref()
+            .map(config::Format::of)
+            .unwrap_or_default();
+        print!("{}", config::print(&Config::effective(&args), format));
+        return;
+    }
+    if let Err(err) = validate(&args) {
+        println!("{err}");
+        std::process::exit(1);
     }
-    result
-}
 
-/// Parse a comma separated list of IPv4 subnets
-fn parse_subnets(subnets: &str) -> Option<Vec<(u32, u32)>> {
-    let mut result = Vec::new();
-    for part in subnets.split(',') {
-        let (address, size) = part.split_once('/').unwrap_or((part, "32"));
-        let address: Ipv4Addr = address.parse().ok()?;
-        let address = u32::from_be_bytes(address.octets());
-        let size = size.parse::<u8>().ok()?;
-        if size > 32 {
-            return None;
+    let tls = args.tls_cert.as_deref().map(|cert| {
+        let key = args.tls_key.as_deref().unwrap();
+        tls::server_config(cert, key, args.tls_client_ca.as_deref()).unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        })
+    });
+    let auth = Auth::load(
+        args.basic_auth_users.as_deref(),
+        args.bearer_token_file.as_deref(),
+    )
+    .unwrap_or_else(|err| {
+        println!("{err}");
+        std::process::exit(1);
+    });
+    let allowed = args
+        .allow_scrape_from
+        .as_deref()
+        .and_then(parse_subnets)
+        .map(Arc::new);
+
+    let host = args.host_label.clone().map(|host| {
+        if host.is_empty() {
+            hostname().unwrap_or_else(|| {
+                println!("Unable to determine the hostname");
+                std::process::exit(1);
+            })
+        } else {
+            host
         }
-        let mask = if size == 32 { !0 } else { !(!0 >> size) };
-        result.push((address & mask, mask));
+    });
+
+    let probes = ProbeResults::default();
+    if !args.probe.is_empty() {
+        probe::spawn(
+            args.probe.clone(),
+            Duration::from_secs(args.probe_interval),
+            probes.clone(),
+        );
     }
-    Some(result)
-}
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+    let enricher = Enricher::new(tokio::runtime::Handle::current());
 
-    let subnets = parse_subnets(&args.subnets).unwrap_or_else(|| {
-        println!("Invalid subnets");
-        std::process::exit(1);
-    });
-    let is_local = move |ip: u32| subnets.iter().any(|(addr, mask)| ip & mask == *addr);
+    let bus = Bus::new();
+    tokio::spawn(events::log(bus.subscribe()));
+    for subscriber in &args.subscribe {
+        tokio::spawn(subscriber.clone().run(bus.subscribe()));
+    }
 
-    let excluded_subnets = args.exclude.map(|s| {
-        parse_subnets(&s).unwrap_or_else(|| {
-            println!("Invalid subnets");
+    let leases = SharedLeases::default();
+    if !args.dhcp_leases.is_empty() {
+        dhcp::spawn_watcher(enricher.clone(), args.dhcp_leases.clone(), leases.clone());
+    }
+
+    let devices = SharedDevices::default();
+
+    let leader = args.leader_lease.clone().map(|path| {
+        let id = args.leader_id.clone().or_else(hostname).unwrap_or_default();
+        cluster::spawn(path, id).unwrap_or_else(|err| {
+            println!("{err}");
             std::process::exit(1);
         })
     });
-    let is_excluded = excluded_subnets.map(|excluded_subnets| {
-        move |ip: u32| {
-            excluded_subnets
-                .iter()
-                .any(|(addr, mask)| ip & mask == *addr)
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut restored = Stats {
+        created: Some(started),
+        ..Stats::default()
+    };
+    if let Some(dir) = &args.state_dir {
+        match store::load_latest(dir) {
+            Ok(Some(snapshot)) => {
+                restored = snapshot.stats();
+                *devices.lock().unwrap() = snapshot.devices.into_iter().collect();
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("{err}");
+                std::process::exit(1);
+            }
         }
+    }
+
+    let stats = Arc::new(Mutex::new(restored));
+    let health = SharedHealth::default();
+    let threads = Arc::new(Threads::default());
+
+    // Only streams, replayed or not: the timestamps of their packets
+    // drive the throughput samples
+    let packet_clock = !args.laptop && args.interface.iter().all(|interface| is_stream(interface));
+    let samples = args.percentiles.then(|| {
+        let samples = match &args.state_dir {
+            Some(dir) => burst::Samples::load(dir).unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            }),
+            None => burst::Samples::default(),
+        };
+        Arc::new(Mutex::new(samples))
     });
 
-    let device = pcap::Device::list()
-        .expect("device lookup failed")
-        .into_iter()
-        .find(|dev| dev.name == args.interface)
-        .expect("device not found");
-    println!("Using device {}", device.name);
-
-    let cap = pcap::Capture::from_device(device)
-        .unwrap()
-        .immediate_mode(true)
-        .snaplen(64)
-        .open()
-        .unwrap();
-
-    let link = cap.get_datalink();
-    if link != Linktype::ETHERNET {
-        println!(
-            "Interface not supported. {:?} is not an Ethernet interface.",
-            args.interface
-        );
-        std::process::exit(1);
+    let pods = args.kubernetes.then(|| {
+        let pods = SharedPods::default();
+        kubernetes::spawn_watcher(enricher.clone(), pods.clone()).unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        });
+        pods
+    });
+
+    let hosts = args.hosts_file.as_ref().map(|path| {
+        hosts::load(path)
+            .map(|hosts| Arc::new(Mutex::new(hosts)))
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            })
+    });
+
+    let containers = args.docker.then(|| {
+        let containers = SharedContainers::default();
+        docker::spawn_poller(enricher.clone(), containers.clone());
+        containers
+    });
+
+    let reverse_dns = args
+        .reverse_dns
+        .then(|| Arc::new(ReverseDns::new(enricher.clone())));
+
+    let mut resolvers = Vec::<(Source, Box<dyn Resolver>)>::new();
+    if let Some(hosts) = &hosts {
+        resolvers.push((Source::Hosts, Box::new(hosts.clone())));
+    }
+    if !args.dhcp_leases.is_empty() {
+        resolvers.push((Source::Dhcp, Box::new(leases.clone())));
+    }
+    if args.discover_names {
+        resolvers.push((Source::Discovery, Box::new(stats.clone())));
+    }
+    if args.ssdp {
+        resolvers.push((Source::Ssdp, Box::new(devices.clone())));
     }
+    if let Some(pods) = &pods {
+        resolvers.push((Source::Kubernetes, Box::new(pods.clone())));
+    }
+    if let Some(containers) = &containers {
+        resolvers.push((Source::Docker, Box::new(containers.clone())));
+    }
+    if let Some(reverse_dns) = &reverse_dns {
+        resolvers.push((Source::ReverseDns, Box::new(reverse_dns.clone())));
+    }
+    let names = Arc::new(Chain::new(resolvers, &args.name_sources));
+
+    let categories = (!args.category.is_empty()).then(|| {
+        Rules::new(&args.category)
+            .map(Arc::new)
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            })
+    });
+
+    let script = args.script.as_deref().map(|path| {
+        Script::load(path).map(Arc::new).unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        })
+    });
+
+    let hook = args.wasm_hook.as_deref().map(|path| {
+        Hook::load(path).map(Arc::new).unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        })
+    });
+
+    let geo = (args.geoip_db.is_some() || args.asn_db.is_some()).then(|| {
+        Geo::open(args.geoip_db.as_deref(), args.asn_db.as_deref())
+            .map(Arc::new)
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            })
+    });
+
+    let schedule = (!args.window.is_empty()).then(|| {
+        Schedule::new(&args.window)
+            .map(Arc::new)
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            })
+    });
+
+    let ports = (!args.ports.is_empty()).then(|| Arc::new(Ports::new(&args.ports)));
+
+    let mirror = args.mirror.clone().map(|target| {
+        Mirror::spawn(target, args.mirror_filter.as_deref())
+            .map(Arc::new)
+            .unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            })
+    });
 
-    let stats = Arc::new(Mutex::new(Stats::default()));
     let state = ServerState {
         stats: stats.clone(),
+        leader,
+        probes,
+        enricher: enricher.clone(),
+        names,
+        reverse_dns: reverse_dns.filter(|_| args.source_labels),
+        pods: pods.filter(|_| args.source_labels),
+        containers: containers.filter(|_| args.source_labels),
+        leases,
+        dhcp: !args.dhcp_leases.is_empty(),
+        devices: devices.clone(),
+        host,
+        mirror_dropped: mirror.as_deref().map(Mirror::dropped),
+        samples: samples.clone(),
+        started,
+        namespace: args.namespace.clone(),
+        legacy_names: args.legacy_names,
+        compat: args.compat,
+        script,
+        health: health.clone(),
+        threads: Some(threads.clone()),
     };
 
-    let thread_stats = stats.clone();
-    thread::spawn(move || {
-        run(cap, is_local, is_excluded, args.max, thread_stats);
+    let shared_wifi = args.wifi.then(|| {
+        wifi::check().unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        });
+        let shared_wifi = SharedWifi::default();
+        wifi::spawn_poller(
+            enricher.clone(),
+            args.interface.first().cloned(),
+            shared_wifi.clone(),
+        );
+        shared_wifi
     });
 
-    let app = Router::new()
-        .route("/metrics", get(metrics))
-        .with_state(state);
+    // Mirrored packets must be complete, name and device announcements
+    // (and server names) need the whole packet, headers are enough
+    // otherwise
+    let snaplen = if args.mirror.is_some() {
+        mirror::SNAPLEN
+    } else if args.discover_names
+        || args.ssdp
+        || categories.as_deref().is_some_and(Rules::needs_payload)
+    {
+        1500
+    } else {
+        64
+    };
+    let flush_interval = Duration::from_millis(args.flush_interval);
+
+    // Networks of each capture, with their interface and scope
+    let mut captures = Vec::<(String, Vec<packet::Device>, SharedNetworks)>::new();
+    let thread_stats = stats.clone();
+    if args.laptop {
+        let shared = SharedNetworks::new(Mutex::new(Arc::new(
+            networks(&args, "", &[]).unwrap_or_else(|err| {
+                println!("{err}");
+                std::process::exit(1);
+            }),
+        )));
+        captures.push((String::new(), Vec::new(), shared.clone()));
+        let health = health.clone();
+        let bus = bus.clone();
+        let samples = samples.clone();
+        let guard = threads.start("default route");
+        thread::spawn(move || {
+            let _guard = guard;
+            loop {
+                let Some(interface) = laptop::default_route_interface() else {
+                    thread::sleep(ROUTE_CHECK_INTERVAL);
+                    continue;
+                };
+                let (device, cap) = match open_capture(
+                    &interface,
+                    snaplen,
+                    args.promisc,
+                    args.poll_mode,
+                    flush_interval,
+                ) {
+                    Ok(result) => result,
+                    Err(err) => {
+                        println!("{err}");
+                        thread::sleep(ROUTE_CHECK_INTERVAL);
+                        continue;
+                    }
+                };
+                {
+                    let mut networks = shared.lock().unwrap();
+                    *networks = Arc::new(Networks {
+                        local: laptop::local_addresses(&device)
+                            .into_iter()
+                            .map(|addr| (addr, !0))
+                            .collect(),
+                        ..Networks::clone(&networks)
+                    });
+                }
+                run(
+                    cap,
+                    &shared,
+                    thread_stats.clone(),
+                    RunOptions {
+                        follow: Some(&interface),
+                        wifi: shared_wifi.as_ref(),
+                        icmp_rtt: args.icmp_rtt,
+                        discover_names: args.discover_names,
+                        ssdp: args.ssdp.then_some(&devices),
+                        enricher: &enricher,
+                        shard: args.shard,
+                        vlans: &args.vlan,
+                        vlan_label: args.vlan_label,
+                        mirror: mirror.as_deref(),
+                        interface: None,
+                        own_addresses: None,
+                        categories: categories.as_deref(),
+                        hook: hook.as_deref(),
+                        schedule: schedule.as_deref(),
+                        ports: ports.as_deref(),
+                        geo: geo.as_deref(),
+                        count: args.count,
+                        flush_interval,
+                        health: (&health, &interface),
+                        events: &bus,
+                        samples: samples.as_deref(),
+                        packet_clock: false,
+                    },
+                );
+                health.lock().unwrap().remove(&interface);
+            }
+        });
+    } else {
+        let label = args.interface.len() > 1;
+        for interface in &args.interface {
+            // Streams are opened by their thread, since a named pipe
+            // waits for its writer
+            let (device, cap) = if is_stream(interface) {
+                (packet::Device::from(interface.as_str()), None)
+            } else {
+                let (device, cap) = open_capture(
+                    interface,
+                    snaplen,
+                    args.promisc,
+                    args.poll_mode,
+                    flush_interval,
+                )
+                .unwrap_or_else(|err| {
+                    println!("{err}");
+                    std::process::exit(1);
+                });
+                (device, Some(cap))
+            };
+            // The "any" device stands for every interface
+            let any = device.name == ANY_DEVICE;
+            let scope = if any {
+                pcap_devices()
+                    .or_else(|_| rawsock::devices())
+                    .unwrap_or_default()
+            } else {
+                vec![device.clone()]
+            };
+            let own_addresses = any.then(|| {
+                scope
+                    .iter()
+                    .flat_map(laptop::local_addresses)
+                    .collect::<Vec<_>>()
+            });
+            let shared = SharedNetworks::new(Mutex::new(Arc::new(
+                networks(&args, &device.name, &scope).unwrap_or_else(|err| {
+                    println!("{err}");
+                    std::process::exit(1);
+                }),
+            )));
+            captures.push((device.name.clone(), scope, shared.clone()));
+            let thread_stats = stats.clone();
+            let shared_wifi = shared_wifi.clone();
+            let devices = devices.clone();
+            let enricher = enricher.clone();
+            let vlans = args.vlan.clone();
+            let mirror = mirror.clone();
+            let categories = categories.clone();
+            let hook = hook.clone();
+            let schedule = schedule.clone();
+            let ports = ports.clone();
+            let geo = geo.clone();
+            let health = health.clone();
+            let bus = bus.clone();
+            let samples = samples.clone();
+            let path = interface.clone();
+            let interface = label.then(|| Arc::from(interface.as_str()));
+            let guard = threads.start(&path);
+            thread::spawn(move || {
+                let _guard = guard;
+                let mut cap = cap;
+                loop {
+                    let cap = match cap.take().map(Ok).unwrap_or_else(|| open_stream(&path)) {
+                        Ok(cap) => cap,
+                        Err(err) => {
+                            println!("{err}");
+                            return;
+                        }
+                    };
+                    run(
+                        cap,
+                        &shared,
+                        thread_stats.clone(),
+                        RunOptions {
+                            follow: None,
+                            wifi: shared_wifi.as_ref(),
+                            icmp_rtt: args.icmp_rtt,
+                            discover_names: args.discover_names,
+                            ssdp: args.ssdp.then_some(&devices),
+                            enricher: &enricher,
+                            shard: args.shard,
+                            vlans: &vlans,
+                            vlan_label: args.vlan_label,
+                            mirror: mirror.as_deref(),
+                            interface: interface.clone(),
+                            own_addresses: own_addresses.as_deref(),
+                            categories: categories.as_deref(),
+                            hook: hook.as_deref(),
+                            schedule: schedule.as_deref(),
+                            ports: ports.as_deref(),
+                            geo: geo.as_deref(),
+                            count: args.count,
+                            flush_interval,
+                            health: (&health, &path),
+                            events: &bus,
+                            samples: samples.as_deref(),
+                            packet_clock,
+                        },
+                    );
+                    health.lock().unwrap().remove(&path);
+                    // A named pipe is opened again, for the next writer
+                    let fifo = fs::metadata(&path).is_ok_and(|meta| meta.file_type().is_fifo());
+                    if !fifo {
+                        println!("End of the stream {path}");
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    tokio::spawn(reload(matches, captures, hosts, bus));
+
+    if let Some(url) = &args.vm_import_url {
+        tokio::spawn(push::run_vm_import(
+            url.parse().unwrap(),
+            Duration::from_secs(args.vm_import_interval),
+            state.clone(),
+        ));
+    }
+
+    if let Some(path) = &args.shm_file {
+        let writer = shm::Writer::create(path, args.max + 1).unwrap_or_else(|err| {
+            println!("Unable to create {}: {err}", path.display());
+            std::process::exit(1);
+        });
+        tokio::spawn(export_shm(writer, state.clone()));
+    }
+
+    if let Some(samples) = &samples {
+        tokio::spawn(sample_throughput(
+            stats.clone(),
+            samples.clone(),
+            args.state_dir.clone(),
+            packet_clock,
+        ));
+    }
+
+    if let Some(dir) = args.state_dir {
+        if !args.notify.is_empty() {
+            tokio::spawn(notify::run(dir.clone(), args.notify));
+        }
+        tokio::spawn(persist(dir, state.clone()));
+    }
+
+    if let Some(port) = args.grpc_port {
+        let bind = parse_bind(args.bind.as_deref().unwrap_or_default()).unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        });
+        let address = SocketAddr::new(bind, port);
+        tokio::spawn(grpc::serve(
+            address,
+            tls.clone(),
+            auth.clone(),
+            allowed.clone(),
+            state.clone(),
+        ));
+    }
 
-    let bind_ip: IpAddr = args.bind.parse().unwrap();
+    // The socket passed by systemd takes precedence
+    let listen = systemd::listener()
+        .and_then(|listen| match (listen, &args.bind_unix) {
+            (Some(Listen::Unix(_)), _) if tls.is_some() || allowed.is_some() => {
+                Err("A Unix domain socket excludes --tls-cert and --allow-scrape-from".to_string())
+            }
+            (Some(listen), _) => Ok(listen),
+            (None, Some(path)) => unix_listener(path).map(Listen::Unix),
+            (None, None) => {
+                let bind = args.bind.as_deref().unwrap_or_default();
+                tcp_listener(bind, args.port.unwrap()).map(Listen::Tcp)
+            }
+        })
+        .unwrap_or_else(|err| {
+            println!("{err}");
+            std::process::exit(1);
+        });
+    systemd::notify("READY=1");
+    // A capture that ended freezes the counters, as for /healthz
+    tokio::spawn(systemd::watchdog(move || {
+        threads.stopped.lock().unwrap().is_empty()
+    }));
+    serve(listen, tls, auth, allowed, state).await;
+}
 
-    axum::Server::bind(&SocketAddr::new(bind_ip, args.port))
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+/// Update the memory-mapped file periodically, with the counters
+/// summed up per IP (none on a standby instance)
+async fn export_shm(mut writer: shm::Writer, state: ServerState) {
+    let mut interval = tokio::time::interval(SHM_INTERVAL);
+    loop {
+        interval.tick().await;
+        let counters = if state.is_leader() {
+            state.stats.lock().unwrap().counters.clone()
+        } else {
+            Default::default()
+        };
+        let mut records = report::per_ip(counters)
+            .into_iter()
+            .map(|(ip, entry)| {
+                let mut record = shm::Record {
+                    ip: ip.map(Ipv4Addr::from),
+                    ..Default::default()
+                };
+                for (index, counters) in [&entry.icmp, &entry.tcp, &entry.udp, &entry.other]
+                    .into_iter()
+                    .enumerate()
+                {
+                    record.inbound[index] = shm::Counters {
+                        packets: counters.inbound.pkts,
+                        bytes: counters.inbound.bytes,
+                    };
+                    record.outbound[index] = shm::Counters {
+                        packets: counters.outbound.pkts,
+                        bytes: counters.outbound.bytes,
+                    };
+                }
+                record
+            })
+            .collect::<Vec<_>>();
+        records.sort_by_key(|record| record.ip);
+        let updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        if let Err(err) = writer.write(updated, &records) {
+            println!("Unable to grow the shared memory file: {err}");
+        }
+    }
+}
+
+/// Sample the throughput of each IP, at the end of every interval of
+/// the wall clock (unless the captures of streams sample it), and
+/// persist the samples
+async fn sample_throughput(
+    stats: Arc<Mutex<Stats>>,
+    samples: Arc<Mutex<burst::Samples>>,
+    dir: Option<PathBuf>,
+    packet_clock: bool,
+) {
+    loop {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let next = (now.as_secs() / burst::INTERVAL + 1) * burst::INTERVAL;
+        tokio::time::sleep(Duration::from_secs(next) - now).await;
+        // Sampled by the captures, only saved here
+        if !packet_clock {
+            let totals = throughput_totals(&stats.lock().unwrap());
+            samples.lock().unwrap().record(next, totals);
+        }
+        if let Some(dir) = &dir {
+            let samples = samples.clone();
+            let dir = dir.clone();
+            let result =
+                tokio::task::spawn_blocking(move || samples.lock().unwrap().save(&dir)).await;
+            if let Ok(Err(err)) = result {
+                println!("{err}");
+            }
+        }
+    }
+}
+
+/// Persist the statistics periodically
+async fn persist(dir: PathBuf, state: ServerState) {
+    let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let snapshot = {
+            let stats = state.stats.lock().unwrap();
+            let devices = state.devices.lock().unwrap();
+            store::Snapshot::new(&stats, &devices, state.host.clone())
+        };
+        let dir = dir.clone();
+        let result = tokio::task::spawn_blocking(move || store::save(&dir, &snapshot)).await;
+        if let Ok(Err(err)) = result {
+            println!("{err}");
+        }
+    }
 }
@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    io::Write,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "pcap")]
+use pcap::{Active, BpfProgram, Capture};
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{Linktype, Packet};
+
+/// Packets waiting to be mirrored, beyond which they are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// How often the output is opened again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Snapshot length of the captures when mirroring
+pub const SNAPLEN: i32 = 65535;
+
+/// Where to mirror the captured packets
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Target {
+    /// Inject the packets on an interface
+    Interface(String),
+    /// Write a pcap stream to a UNIX socket
+    Unix(PathBuf),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    /// Parse `iface:NAME` or `unix:PATH`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("iface", name)) if !name.is_empty() => Ok(Target::Interface(name.to_string())),
+            Some(("unix", path)) if !path.is_empty() => Ok(Target::Unix(path.into())),
+            _ => Err(format!(
+                "Invalid mirror {s:?} (expected iface:NAME or unix:PATH)"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Target> for String {
+    fn from(target: Target) -> Self {
+        target.to_string()
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Interface(name) => write!(f, "iface:{name}"),
+            Target::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+struct Record {
+    linktype: Linktype,
+    ts: (u32, u32),
+    len: u32,
+    data: Vec<u8>,
+}
+
+enum Output {
+    #[cfg(feature = "pcap")]
+    Interface(Capture<Active>),
+    /// The stream, and the link type given in its header
+    Unix(UnixStream, Linktype),
+}
+
+impl Output {
+    fn open(target: &Target, linktype: Linktype) -> Result<Self, String> {
+        match target {
+            #[cfg(feature = "pcap")]
+            Target::Interface(name) => Capture::from_device(name.as_str())
+                .and_then(|cap| cap.open())
+                .map(Output::Interface)
+                .map_err(|err| format!("Unable to open {name} for mirroring: {err}")),
+            #[cfg(not(feature = "pcap"))]
+            Target::Interface(name) => Err(format!(
+                "Unable to open {name} for mirroring: built without libpcap"
+            )),
+            Target::Unix(path) => {
+                let mut stream = UnixStream::connect(path)
+                    .map_err(|err| format!("Unable to connect to {}: {err}", path.display()))?;
+                // pcap file header
+                let mut header = Vec::with_capacity(24);
+                header.extend(0xa1b2c3d4u32.to_ne_bytes());
+                header.extend(2u16.to_ne_bytes());
+                header.extend(4u16.to_ne_bytes());
+                header.extend(0u32.to_ne_bytes());
+                header.extend(0u32.to_ne_bytes());
+                header.extend((SNAPLEN as u32).to_ne_bytes());
+                header.extend((linktype.0 as u32).to_ne_bytes());
+                stream
+                    .write_all(&header)
+                    .map_err(|err| format!("Unable to write to {}: {err}", path.display()))?;
+                Ok(Output::Unix(stream, linktype))
+            }
+        }
+    }
+
+    /// Whether packets of this link type can be written, a pcap stream
+    /// having a single link type
+    fn accepts(&self, linktype: Linktype) -> bool {
+        match self {
+            #[cfg(feature = "pcap")]
+            Output::Interface(_) => true,
+            Output::Unix(_, current) => *current == linktype,
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> Result<(), String> {
+        match self {
+            #[cfg(feature = "pcap")]
+            Output::Interface(cap) => cap
+                .sendpacket(record.data.as_slice())
+                .map_err(|err| format!("Unable to inject a packet: {err}")),
+            Output::Unix(stream, _) => {
+                let mut buffer = Vec::with_capacity(16 + record.data.len());
+                buffer.extend(record.ts.0.to_ne_bytes());
+                buffer.extend(record.ts.1.to_ne_bytes());
+                buffer.extend((record.data.len() as u32).to_ne_bytes());
+                buffer.extend(record.len.to_ne_bytes());
+                buffer.extend(&record.data);
+                stream
+                    .write_all(&buffer)
+                    .map_err(|err| format!("Unable to mirror a packet: {err}"))
+            }
+        }
+    }
+}
+
+/// Copy of the captured packets (or of a subset) to another tool
+///
+/// Packets are written by a separate thread, so that a slow consumer
+/// never delays the capture: when the queue is full, or while the
+/// output is unavailable, the packets are dropped and counted.
+pub struct Mirror {
+    sender: SyncSender<Record>,
+    filter: Option<String>,
+    /// Filter compiled for each link type seen (none when it does not
+    /// apply to the link type)
+    programs: Mutex<Vec<(Linktype, Option<BpfProgram>)>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Mirror {
+    pub fn spawn(target: Target, filter: Option<&str>) -> Result<Self, String> {
+        if let Some(filter) = filter {
+            compile(filter, Linktype::ETHERNET)
+                .map_err(|err| format!("Invalid mirror filter: {err}"))?;
+        }
+        let dropped = Arc::<AtomicU64>::default();
+        let (sender, receiver) = mpsc::sync_channel::<Record>(QUEUE_SIZE);
+        let thread_dropped = dropped.clone();
+        thread::spawn(move || {
+            let mut output = None;
+            let mut last_attempt = None::<Instant>;
+            for record in receiver {
+                if !output
+                    .as_ref()
+                    .is_none_or(|output: &Output| output.accepts(record.linktype))
+                {
+                    output = None;
+                    last_attempt = None;
+                }
+                if output.is_none()
+                    && last_attempt.is_none_or(|last| last.elapsed() >= RETRY_INTERVAL)
+                {
+                    last_attempt = Some(Instant::now());
+                    match Output::open(&target, record.linktype) {
+                        Ok(opened) => output = Some(opened),
+                        Err(err) => println!("{err}"),
+                    }
+                }
+                let Some(current) = &mut output else {
+                    thread_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if let Err(err) = current.write(&record) {
+                    println!("{err}");
+                    thread_dropped.fetch_add(1, Ordering::Relaxed);
+                    output = None;
+                }
+            }
+        });
+        Ok(Self {
+            sender,
+            filter: filter.map(str::to_string),
+            programs: Mutex::default(),
+            dropped,
+        })
+    }
+
+    /// Queue a copy of the packet, if it matches the filter
+    pub fn send(&self, linktype: Linktype, packet: &Packet) {
+        if let Some(filter) = &self.filter {
+            let mut programs = self.programs.lock().unwrap();
+            let position = match programs.iter().position(|(link, _)| *link == linktype) {
+                Some(position) => position,
+                None => {
+                    let program = compile(filter, linktype)
+                        .map_err(|err| println!("Mirror filter not applicable: {err}"))
+                        .ok();
+                    programs.push((linktype, program));
+                    programs.len() - 1
+                }
+            };
+            match &programs[position].1 {
+                Some(program) if program.filter(packet.data) => {}
+                _ => return,
+            }
+        }
+        let record = Record {
+            linktype,
+            ts: (
+                packet.header.ts.tv_sec as u32,
+                packet.header.ts.tv_usec as u32,
+            ),
+            len: packet.header.len,
+            data: packet.data.to_vec(),
+        };
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of packets that could not be mirrored
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+}
+
+#[cfg(feature = "pcap")]
+fn compile(filter: &str, linktype: Linktype) -> Result<BpfProgram, String> {
+    Capture::dead(linktype)
+        .and_then(|cap| cap.compile(filter, true))
+        .map_err(|err| err.to_string())
+}
+
+/// Compiled filter, none being available without libpcap
+#[cfg(not(feature = "pcap"))]
+enum BpfProgram {}
+
+#[cfg(not(feature = "pcap"))]
+impl BpfProgram {
+    fn filter(&self, _: &[u8]) -> bool {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "pcap"))]
+fn compile(_: &str, _: Linktype) -> Result<BpfProgram, String> {
+    Err("built without libpcap".to_string())
+}
@@ -0,0 +1,375 @@
+use std::fmt::Write;
+
+/// Type of a metric family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+/// Value of a sample
+#[derive(Debug, Clone)]
+pub enum Value {
+    Int(u64),
+    Float(f64),
+    Histogram {
+        /// Upper bound and cumulative count of each bucket (without
+        /// the implicit `+Inf` bucket)
+        buckets: Vec<(f64, u64)>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Value {
+    /// Value as a float, the count for histograms
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Int(value) => *value as f64,
+            Value::Float(value) => *value,
+            Value::Histogram { count, .. } => *count as f64,
+        }
+    }
+}
+
+pub type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub labels: Labels,
+    pub value: Value,
+}
+
+/// A metric family, the unit of exposition shared by every format
+#[derive(Debug, Clone)]
+pub struct Family {
+    pub name: String,
+    pub help: String,
+    pub kind: Kind,
+    /// Time since which the counters or histograms accumulate (seconds
+    /// since the UNIX epoch), when known
+    pub created: Option<u64>,
+    pub samples: Vec<Sample>,
+}
+
+impl Family {
+    pub fn new(name: impl Into<String>, help: impl Into<String>, kind: Kind) -> Self {
+        Self {
+            name: name.into(),
+            help: help.into(),
+            kind,
+            created: None,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, mut labels: Labels, value: Value) {
+        for (_, value) in &mut labels {
+            sanitize_label(value);
+        }
+        self.samples.push(Sample { labels, value });
+    }
+}
+
+/// Replace the control characters of a label value (from hostnames,
+/// SSIDs, SNI...), except tabulations and new lines, by U+FFFD
+///
+/// Applied to every sample, whatever the format it is exposed in.
+fn sanitize_label(value: &mut String) {
+    let control = |c: char| c.is_control() && c != '\t' && c != '\n';
+    if value.contains(control) {
+        *value = value.replace(control, "\u{fffd}");
+    }
+}
+
+/// Escape a label value for the Prometheus text format
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escape a help text for the Prometheus text format, where quotes are
+/// kept as is
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn write_labels(out: &mut String, labels: &Labels, extra: Option<(&str, &str)>) {
+    if labels.is_empty() && extra.is_none() {
+        return;
+    }
+    out.push('{');
+    let labels = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra);
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(out, "{name}=\"{}\"", escape_label(value)).unwrap();
+    }
+    out.push('}');
+}
+
+/// Render the families in the Prometheus text format (version 0.0.4)
+pub fn encode_text(families: &[Family]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = &family.name;
+        writeln!(out, "# HELP {name} {}", escape_help(&family.help)).unwrap();
+        writeln!(out, "# TYPE {name} {}", family.kind.name()).unwrap();
+        for sample in &family.samples {
+            match &sample.value {
+                Value::Int(value) => {
+                    out.push_str(name);
+                    write_labels(&mut out, &sample.labels, None);
+                    writeln!(out, " {value}").unwrap();
+                }
+                Value::Float(value) => {
+                    out.push_str(name);
+                    write_labels(&mut out, &sample.labels, None);
+                    writeln!(out, " {value}").unwrap();
+                }
+                Value::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let bounds = buckets
+                        .iter()
+                        .map(|(bound, count)| (bound.to_string(), *count))
+                        .chain([("+Inf".to_string(), *count)]);
+                    for (bound, count) in bounds {
+                        write!(out, "{name}_bucket").unwrap();
+                        write_labels(&mut out, &sample.labels, Some(("le", &bound)));
+                        writeln!(out, " {count}").unwrap();
+                    }
+                    write!(out, "{name}_sum").unwrap();
+                    write_labels(&mut out, &sample.labels, None);
+                    writeln!(out, " {sum}").unwrap();
+                    write!(out, "{name}_count").unwrap();
+                    write_labels(&mut out, &sample.labels, None);
+                    writeln!(out, " {count}").unwrap();
+                }
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Float in the OpenMetrics format, canonical for the `le` label
+fn openmetrics_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        format!("{value:?}")
+    }
+}
+
+/// Render the families in the OpenMetrics text format (version 1.0.0)
+///
+/// The counters are named without their `_total` suffix, which only
+/// their samples have, and get a `_created` series when their creation
+/// time is known.
+pub fn encode_openmetrics(families: &[Family]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let name = match family.kind {
+            Kind::Counter => family.name.strip_suffix("_total").unwrap_or(&family.name),
+            _ => &family.name,
+        };
+        writeln!(out, "# TYPE {name} {}", family.kind.name()).unwrap();
+        writeln!(out, "# HELP {name} {}", escape_label(&family.help)).unwrap();
+        let sample_name = match family.kind {
+            Kind::Counter => format!("{name}_total"),
+            _ => name.to_string(),
+        };
+        for sample in &family.samples {
+            let labels = &sample.labels;
+            match &sample.value {
+                Value::Int(value) => {
+                    out.push_str(&sample_name);
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {value}").unwrap();
+                }
+                Value::Float(value) => {
+                    out.push_str(&sample_name);
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", openmetrics_float(*value)).unwrap();
+                }
+                Value::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let bounds = buckets
+                        .iter()
+                        .map(|(bound, count)| (openmetrics_float(*bound), *count))
+                        .chain([("+Inf".to_string(), *count)]);
+                    for (bound, count) in bounds {
+                        write!(out, "{name}_bucket").unwrap();
+                        write_labels(&mut out, labels, Some(("le", &bound)));
+                        writeln!(out, " {count}").unwrap();
+                    }
+                    write!(out, "{name}_count").unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {count}").unwrap();
+                    write!(out, "{name}_sum").unwrap();
+                    write_labels(&mut out, labels, None);
+                    writeln!(out, " {}", openmetrics_float(*sum)).unwrap();
+                }
+            }
+            if let Some(created) = family.created {
+                write!(out, "{name}_created").unwrap();
+                write_labels(&mut out, labels, None);
+                writeln!(out, " {created}").unwrap();
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Flatten the families into plain series (name, labels, value),
+/// expanding the histograms into their `_bucket`, `_sum` and `_count`
+/// series
+pub fn flatten(families: &[Family]) -> Vec<(String, Labels, f64)> {
+    let mut result = Vec::new();
+    for family in families {
+        let name = &family.name;
+        for sample in &family.samples {
+            match &sample.value {
+                Value::Int(_) | Value::Float(_) => {
+                    result.push((name.clone(), sample.labels.clone(), sample.value.as_f64()));
+                }
+                Value::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let bounds = buckets
+                        .iter()
+                        .map(|(bound, count)| (bound.to_string(), *count))
+                        .chain([("+Inf".to_string(), *count)]);
+                    for (bound, count) in bounds {
+                        let mut labels = sample.labels.clone();
+                        labels.push(("le", bound));
+                        result.push((format!("{name}_bucket"), labels, count as f64));
+                    }
+                    result.push((format!("{name}_sum"), sample.labels.clone(), *sum));
+                    result.push((
+                        format!("{name}_count"),
+                        sample.labels.clone(),
+                        *count as f64,
+                    ));
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the only label of a sample line, unescaped, failing when the
+    /// line is not well formed
+    fn parse_label(line: &str) -> String {
+        let rest = line
+            .strip_prefix("txne_test{value=\"")
+            .unwrap_or_else(|| panic!("Invalid line {line:?}"));
+        let mut value = String::new();
+        let mut chars = rest.chars();
+        loop {
+            match chars.next() {
+                Some('\\') => match chars.next() {
+                    Some('\\') => value.push('\\'),
+                    Some('"') => value.push('"'),
+                    Some('n') => value.push('\n'),
+                    escape => panic!("Invalid escape {escape:?} in {line:?}"),
+                },
+                Some('"') => break,
+                Some(c) => value.push(c),
+                None => panic!("Unterminated value in {line:?}"),
+            }
+        }
+        assert_eq!(chars.as_str(), "} 1", "{line:?}");
+        value
+    }
+
+    /// Random label values, rendered in both text formats, must read back
+    /// as they were, on a single line
+    #[test]
+    fn label_fuzzing() {
+        let alphabet = [
+            'a', ' ', '"', '\\', '\n', '\r', '\t', '\0', '\u{7f}', '\u{85}', '{', '}', ',', '=',
+            '#', 'n', 'é', '🦀', '\u{fffd}',
+        ];
+        // xorshift, for reproducible values
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..2000 {
+            let length = random() % 16;
+            let value: String = (0..length)
+                .map(|_| alphabet[(random() % alphabet.len() as u64) as usize])
+                .collect();
+            let mut family = Family::new(
+                "txne_test",
+                "Help with \"quotes\", \\ and\nlines",
+                Kind::Gauge,
+            );
+            family.push(vec![("value", value.clone())], Value::Int(1));
+            let expected: String = value
+                .chars()
+                .map(|c| match c {
+                    '\t' | '\n' => c,
+                    c if c.is_control() => '\u{fffd}',
+                    c => c,
+                })
+                .collect();
+            assert_eq!(family.samples[0].labels[0].1, expected);
+
+            let families = [family];
+            let text = encode_text(&families);
+            let lines: Vec<_> = text.lines().collect();
+            assert_eq!(
+                lines[0],
+                r#"# HELP txne_test Help with "quotes", \\ and\nlines"#
+            );
+            assert_eq!(lines.len(), 4, "{text:?}");
+            assert_eq!(parse_label(lines[2]), expected);
+
+            let text = encode_openmetrics(&families);
+            let lines: Vec<_> = text.lines().collect();
+            assert_eq!(
+                lines[1],
+                r#"# HELP txne_test Help with \"quotes\", \\ and\nlines"#
+            );
+            assert_eq!(lines.len(), 4, "{text:?}");
+            assert_eq!(parse_label(lines[2]), expected);
+        }
+    }
+}
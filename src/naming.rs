@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How long a resolved name (or its absence) is reused
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// A source of names for the local IPs
+///
+/// Resolving is done while exporting, so it must not block: slow
+/// lookups belong to the enrichment executor, filling what the resolver
+/// reads.
+pub trait Resolver: Send + Sync {
+    /// Name of the IP, if known to this source
+    fn resolve(&self, ip: u32) -> Option<String>;
+}
+
+/// Sources of names, declared in their default priority order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// Static mapping file (--hosts-file)
+    Hosts,
+    /// DHCP lease files (--dhcp-leases)
+    Dhcp,
+    /// mDNS and NetBIOS announcements (--discover-names)
+    Discovery,
+    /// Friendly names of the SSDP devices (--ssdp)
+    Ssdp,
+    /// Kubernetes pods, as `namespace/pod` (--kubernetes)
+    Kubernetes,
+    /// Docker containers (--docker)
+    Docker,
+    /// PTR records (--reverse-dns)
+    ReverseDns,
+}
+
+/// Chain of the enabled sources of names, by priority
+///
+/// The first source knowing an IP gives its name. Results are cached
+/// for a while, so that every series of an IP gets the same name during
+/// a scrape, and slow sources are not queried on each scrape.
+#[derive(Default)]
+pub struct Chain {
+    resolvers: Vec<(Source, Box<dyn Resolver>)>,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u32, (Option<String>, Instant)>,
+    last_prune: Option<Instant>,
+}
+
+impl Chain {
+    /// Build a chain from the enabled sources
+    ///
+    /// With an explicit priority, only the listed sources are used, in
+    /// that order. Otherwise every enabled source is used, in the
+    /// default order.
+    pub fn new(mut resolvers: Vec<(Source, Box<dyn Resolver>)>, priority: &[Source]) -> Self {
+        if priority.is_empty() {
+            resolvers.sort_by_key(|(source, _)| *source);
+        } else {
+            resolvers.retain(|(source, _)| priority.contains(source));
+            resolvers.sort_by_key(|(source, _)| priority.iter().position(|s| s == source));
+        }
+        Self {
+            resolvers,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Whether any source of names is enabled
+    pub fn is_enabled(&self) -> bool {
+        !self.resolvers.is_empty()
+    }
+
+    /// Name of an IP, from the first source knowing it
+    pub fn resolve(&self, ip: u32) -> Option<String> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((name, time)) = cache.entries.get(&ip) {
+            if now.duration_since(*time) < CACHE_TTL {
+                return name.clone();
+            }
+        }
+        let name = self
+            .resolvers
+            .iter()
+            .find_map(|(_, resolver)| resolver.resolve(ip));
+        // Forget the IPs that are not tracked anymore, once in a while
+        if cache
+            .last_prune
+            .is_none_or(|last| now.duration_since(last) >= CACHE_TTL)
+        {
+            cache
+                .entries
+                .retain(|_, (_, time)| now.duration_since(*time) < CACHE_TTL);
+            cache.last_prune = Some(now);
+        }
+        cache.entries.insert(ip, (name.clone(), now));
+        name
+    }
+}
+
+pub type SharedChain = Arc<Chain>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source knowing a single IP
+    struct Known(u32, &'static str);
+
+    impl Resolver for Known {
+        fn resolve(&self, ip: u32) -> Option<String> {
+            (ip == self.0).then(|| self.1.to_string())
+        }
+    }
+
+    fn chain(priority: &[Source]) -> Chain {
+        Chain::new(
+            vec![
+                (Source::ReverseDns, Box::new(Known(1, "nas.lan"))),
+                (Source::Dhcp, Box::new(Known(1, "nas"))),
+                (Source::ReverseDns, Box::new(Known(2, "printer.lan"))),
+            ],
+            priority,
+        )
+    }
+
+    #[test]
+    fn priority() {
+        let names = chain(&[]);
+        assert!(names.is_enabled());
+        assert_eq!(names.resolve(1).as_deref(), Some("nas"));
+        assert_eq!(names.resolve(2).as_deref(), Some("printer.lan"));
+        assert_eq!(names.resolve(3), None);
+
+        let names = chain(&[Source::ReverseDns, Source::Dhcp]);
+        assert_eq!(names.resolve(1).as_deref(), Some("nas.lan"));
+
+        let names = chain(&[Source::Dhcp]);
+        assert_eq!(names.resolve(1).as_deref(), Some("nas"));
+        assert_eq!(names.resolve(2), None);
+
+        assert!(!Chain::new(Vec::new(), &[]).is_enabled());
+    }
+}
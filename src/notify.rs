@@ -0,0 +1,252 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{header, Body, Client, Method, Request, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time;
+
+use crate::{
+    events::hostname,
+    report::{self, Format, Period},
+};
+
+const DAY: u64 = 86400;
+
+/// Delay after midnight (UTC) before sending the summaries, so that the
+/// snapshot of the new day is written first
+const DELAY: u64 = 300;
+
+/// Time given to the SMTP relay for each step of the dialog
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a summary is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Schedule {
+    /// Every day, for the previous day
+    Daily,
+    /// Every Monday, for the previous 7 days
+    Weekly,
+}
+
+/// Payload expected by the webhook
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookKind {
+    /// Slack incoming webhook
+    #[default]
+    Slack,
+    /// Matrix generic webhook (matrix-hookshot)
+    Matrix,
+}
+
+/// A scheduled summary notification (configuration file only)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Notification {
+    pub schedule: Schedule,
+    /// Number of top talkers to list
+    #[serde(default = "default_top")]
+    pub top: usize,
+    /// Traffic allowed to each local IP over the period (bytes)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<u64>,
+    /// SMTP relay (HOST:PORT), used without TLS nor authentication
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    /// Webhook URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub webhook_kind: WebhookKind,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+impl Notification {
+    /// Check that exactly one channel is configured, and completely
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.smtp, &self.webhook) {
+            (Some(_), None) => {
+                if self.from.is_none() || self.to.is_empty() {
+                    return Err("Email notifications need \"from\" and \"to\"".to_string());
+                }
+            }
+            (None, Some(url)) => {
+                url.parse::<Uri>()
+                    .map_err(|err| format!("Invalid webhook URL: {err}"))?;
+            }
+            _ => return Err("Notifications need either \"smtp\" or \"webhook\"".to_string()),
+        }
+        Ok(())
+    }
+
+    async fn send(&self, report: &report::Report) -> Result<(), String> {
+        if let Some(relay) = &self.smtp {
+            let relay = relay.clone();
+            let from = self.from.clone().unwrap_or_default();
+            let to = self.to.clone();
+            let subject = report::title(report);
+            let body = report::render(report, Format::Html);
+            return tokio::task::spawn_blocking(move || {
+                send_mail(&relay, &from, &to, &subject, &body)
+            })
+            .await
+            .map_err(|err| err.to_string())?;
+        }
+        let url = self.webhook.as_deref().unwrap_or_default();
+        let markdown = report::render(report, Format::Md);
+        let payload = match self.webhook_kind {
+            // Slack does not render Markdown tables
+            WebhookKind::Slack => json!({ "text": format!("```\n{markdown}```") }),
+            WebhookKind::Matrix => json!({
+                "text": markdown,
+                "html": report::render(report, Format::Html),
+            }),
+        };
+        post(url, &payload).await
+    }
+}
+
+/// Send the summaries on their schedule, forever
+pub async fn run(dir: PathBuf, notifications: Vec<Notification>) {
+    loop {
+        let now = now();
+        let next = (now / DAY + 1) * DAY + DELAY;
+        let wait = next.saturating_sub(now) % DAY;
+        time::sleep(Duration::from_secs(wait)).await;
+        let now = self::now();
+        // The UNIX epoch was a Thursday
+        let monday = (now / DAY + 3).is_multiple_of(7);
+        for notification in &notifications {
+            let period = match notification.schedule {
+                Schedule::Daily => Period::Day,
+                Schedule::Weekly if monday => Period::Week,
+                Schedule::Weekly => continue,
+            };
+            let result =
+                match report::build(&dir, period, now, notification.top, notification.quota) {
+                    Ok(report) => notification.send(&report).await,
+                    Err(err) => Err(err),
+                };
+            if let Err(err) = result {
+                println!("Notification failed: {err}");
+            }
+        }
+        // Do not send twice when woken up early
+        time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub async fn post(url: &str, payload: &serde_json::Value) -> Result<(), String> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build::<_, Body>(connector);
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))
+        .map_err(|err| err.to_string())?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Webhook returned {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Send an HTML email through an SMTP relay
+fn send_mail(
+    relay: &str,
+    from: &str,
+    to: &[String],
+    subject: &str,
+    body: &str,
+) -> Result<(), String> {
+    let stream = TcpStream::connect(relay).map_err(|err| format!("{relay}: {err}"))?;
+    stream
+        .set_read_timeout(Some(SMTP_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(SMTP_TIMEOUT)))
+        .map_err(|err| err.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|err| err.to_string())?);
+    let mut writer = stream;
+    let mut command = |line: &str, expected: u16| -> Result<(), String> {
+        if !line.is_empty() {
+            write!(writer, "{line}\r\n").map_err(|err| err.to_string())?;
+        }
+        let code = reply(&mut reader)?;
+        if code / 100 != expected / 100 {
+            let line = line.lines().next().unwrap_or_default();
+            return Err(format!("SMTP relay replied {code} to {line:?}"));
+        }
+        Ok(())
+    };
+
+    command("", 220)?;
+    let helo = hostname().unwrap_or_else(|| "localhost".to_string());
+    command(&format!("HELO {helo}"), 250)?;
+    command(&format!("MAIL FROM:<{from}>"), 250)?;
+    for recipient in to {
+        command(&format!("RCPT TO:<{recipient}>"), 250)?;
+    }
+    command("DATA", 354)?;
+    let mut message = format!(
+        "From: {from}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Transfer-Encoding: 8bit\r\n\r\n",
+        to.join(", "),
+        report::format_date(now()),
+    );
+    for line in body.lines() {
+        // Dot stuffing
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push('.');
+    command(&message, 250)?;
+    command("QUIT", 221)
+}
+
+/// Read a (possibly multiline) SMTP reply, returning its code
+fn reply(reader: &mut impl BufRead) -> Result<u16, String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(|err| err.to_string())? == 0 {
+            return Err("SMTP relay closed the connection".to_string());
+        }
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("Invalid SMTP reply {line:?}"))?;
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(code);
+        }
+    }
+}
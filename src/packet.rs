@@ -0,0 +1,98 @@
+//! Captured packets and interfaces, as libpcap gives them
+//!
+//! Without the `pcap` feature, the exporter does not link libpcap, and
+//! these equivalents are used by the raw socket and the streams.
+
+#[cfg(feature = "pcap")]
+pub use pcap::{Address, Device, Error, Linktype, Packet, PacketHeader};
+
+#[cfg(not(feature = "pcap"))]
+pub use standalone::*;
+
+#[cfg(not(feature = "pcap"))]
+mod standalone {
+    use std::{fmt, io, net::IpAddr};
+
+    /// Link type of the captured frames (`LINKTYPE_*` values)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Linktype(pub i32);
+
+    impl Linktype {
+        pub const NULL: Linktype = Linktype(0);
+        pub const ETHERNET: Linktype = Linktype(1);
+        pub const RAW: Linktype = Linktype(101);
+        pub const LOOP: Linktype = Linktype(108);
+        pub const LINUX_SLL: Linktype = Linktype(113);
+        pub const IPV4: Linktype = Linktype(228);
+        pub const LINUX_SLL2: Linktype = Linktype(276);
+
+        /// Name of the link type, which only libpcap knows
+        pub fn get_name(&self) -> Result<String, Error> {
+            Err(Error::IoError(io::ErrorKind::Unsupported))
+        }
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct PacketHeader {
+        pub ts: libc::timeval,
+        pub caplen: u32,
+        pub len: u32,
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct Packet<'a> {
+        pub header: &'a PacketHeader,
+        pub data: &'a [u8],
+    }
+
+    impl<'a> Packet<'a> {
+        pub fn new(header: &'a PacketHeader, data: &'a [u8]) -> Packet<'a> {
+            Packet { header, data }
+        }
+    }
+
+    /// Named as in the pcap crate
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Error {
+        /// No packet before the read timeout
+        TimeoutExpired,
+        /// End of a stream
+        NoMorePackets,
+        IoError(io::ErrorKind),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Error::TimeoutExpired => write!(f, "timeout expired"),
+                Error::NoMorePackets => write!(f, "no more packets to read from the file"),
+                Error::IoError(kind) => write!(f, "{}", io::Error::from(*kind)),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Address {
+        pub addr: IpAddr,
+        pub netmask: Option<IpAddr>,
+        pub broadcast_addr: Option<IpAddr>,
+        pub dst_addr: Option<IpAddr>,
+    }
+
+    /// A network interface and its addresses
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Device {
+        pub name: String,
+        pub addresses: Vec<Address>,
+    }
+
+    impl From<&str> for Device {
+        fn from(name: &str) -> Self {
+            Device {
+                name: name.to_string(),
+                addresses: Vec::new(),
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// How the captures wait for the packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PollMode {
+    /// Deliver each packet as it arrives, waking up at the flush
+    /// interval when idle
+    #[default]
+    Immediate,
+    /// Deliver each packet as it arrives, never waking up when idle
+    Blocking,
+    /// Let libpcap buffer the packets, delivered at the latest after
+    /// this delay (fewer wake ups on busy links)
+    Timeout(Duration),
+    /// Poll without blocking, keeping a CPU busy (the lowest latency)
+    Busy,
+}
+
+impl PollMode {
+    /// Read timeout, `None` to block (or for non-blocking reads)
+    pub fn timeout(self, flush_interval: Duration) -> Option<Duration> {
+        match self {
+            PollMode::Immediate => Some(flush_interval),
+            PollMode::Timeout(timeout) => Some(timeout),
+            PollMode::Blocking | PollMode::Busy => None,
+        }
+    }
+}
+
+impl FromStr for PollMode {
+    type Err = String;
+
+    /// Parse `immediate`, `blocking`, `timeout:MS` or `busy`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Invalid poll mode {s:?} (expected immediate, blocking, timeout:MS or busy)")
+        };
+        match s.split_once(':') {
+            Some(("timeout", ms)) => match ms.parse() {
+                Ok(0) | Err(_) => Err(invalid()),
+                Ok(ms) => Ok(PollMode::Timeout(Duration::from_millis(ms))),
+            },
+            Some(_) => Err(invalid()),
+            None => match s {
+                "immediate" => Ok(PollMode::Immediate),
+                "blocking" => Ok(PollMode::Blocking),
+                "busy" => Ok(PollMode::Busy),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for PollMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PollMode::Immediate => write!(f, "immediate"),
+            PollMode::Blocking => write!(f, "blocking"),
+            PollMode::Timeout(timeout) => write!(f, "timeout:{}", timeout.as_millis()),
+            PollMode::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl TryFrom<String> for PollMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PollMode> for String {
+    fn from(mode: PollMode) -> Self {
+        mode.to_string()
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Read,
+    net::{SocketAddr, SocketAddrV4},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::TcpStream, time};
+
+/// Time given to a probe to succeed
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Target of an active probe
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Target {
+    /// ICMP echo request to the host
+    Icmp(String),
+    /// TCP connection to the host and port
+    Tcp(String, u16),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    /// Parse `icmp:HOST` or `tcp:HOST:PORT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("icmp", host)) if !host.is_empty() => Ok(Target::Icmp(host.to_string())),
+            Some(("tcp", target)) => {
+                let (host, port) = target
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("Missing port in probe {s:?}"))?;
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in probe {s:?}"))?;
+                Ok(Target::Tcp(host.to_string(), port))
+            }
+            _ => Err(format!(
+                "Invalid probe {s:?} (expected icmp:HOST or tcp:HOST:PORT)"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Target> for String {
+    fn from(target: Target) -> Self {
+        format!("{}:{target}", target.module())
+    }
+}
+
+impl Target {
+    pub fn module(&self) -> &'static str {
+        match self {
+            Target::Icmp(_) => "icmp",
+            Target::Tcp(..) => "tcp",
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Icmp(host) => write!(f, "{host}"),
+            Target::Tcp(host, port) => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// Outcome of the last probe of a target
+#[derive(Debug, Clone)]
+pub struct ProbeResult {
+    pub success: bool,
+    pub duration: Duration,
+}
+
+pub type ProbeResults = Arc<Mutex<HashMap<Target, ProbeResult>>>;
+
+/// Probe every target on the given interval, in the background
+pub fn spawn(targets: Vec<Target>, interval: Duration, results: ProbeResults) {
+    for target in targets {
+        let results = results.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let start = Instant::now();
+                let success = matches!(time::timeout(TIMEOUT, probe(&target)).await, Ok(Ok(())));
+                let result = ProbeResult {
+                    success,
+                    duration: start.elapsed(),
+                };
+                results.lock().unwrap().insert(target.clone(), result);
+            }
+        });
+    }
+}
+
+async fn probe(target: &Target) -> Result<(), String> {
+    match target {
+        Target::Icmp(host) => {
+            let addr = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .map_err(|err| err.to_string())?
+                .find_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                })
+                .ok_or("No IPv4 address")?;
+            tokio::task::spawn_blocking(move || ping(addr))
+                .await
+                .map_err(|err| err.to_string())?
+        }
+        Target::Tcp(host, port) => {
+            TcpStream::connect((host.as_str(), *port))
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok(())
+        }
+    }
+}
+
+/// Send an echo request and wait for the reply
+///
+/// This uses an unprivileged ICMP socket (see `net.ipv4.ping_group_range`
+/// on Linux), which is also allowed when running as root.
+fn ping(addr: SocketAddrV4) -> Result<(), String> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::ICMPV4))
+        .map_err(|err| err.to_string())?;
+    socket
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|err| err.to_string())?;
+    // Type, code, checksum, identifier (set by the kernel), sequence
+    let mut request = [8u8, 0, 0, 0, 0, 0, 0, 1];
+    let sum = request
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    let checksum = !(((sum & 0xffff) + (sum >> 16)) as u16);
+    request[2..4].copy_from_slice(&checksum.to_be_bytes());
+    socket
+        .send_to(&request, &SocketAddr::V4(addr).into())
+        .map_err(|err| err.to_string())?;
+    let mut reply = [0u8; 64];
+    loop {
+        let len = (&socket).read(&mut reply).map_err(|err| err.to_string())?;
+        if len >= 8 && reply[0] == 0 {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use clap::{ArgMatches, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, Args};
+
+/// Presets for common deployment topologies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Capture on the LAN interface of a router, the local networks
+    /// being the ones of the interface
+    Router,
+    /// Capture on a server, accounting only for its own addresses
+    Server,
+    /// Capture mirrored traffic (SPAN port or network tap), which
+    /// requires the promiscuous mode and explicit subnets
+    Span,
+    /// Monitor this machine against everything else (see --laptop)
+    Laptop,
+}
+
+impl Profile {
+    /// Apply the presets of the profile to the settings that were not
+    /// given on the command line
+    ///
+    /// The configuration file is applied afterward, so that both can
+    /// override the profile.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches) {
+        let unset = |id| !config::given_on_command_line(matches, id);
+        match self {
+            Profile::Router => {
+                if unset("subnets") {
+                    args.subnets = Some("auto".to_string());
+                }
+            }
+            Profile::Server => {
+                if unset("subnets") {
+                    args.subnets = Some("self".to_string());
+                }
+                if unset("max") {
+                    args.max = 64;
+                }
+            }
+            Profile::Span => {
+                if unset("promisc") {
+                    args.promisc = true;
+                }
+                if unset("max") {
+                    args.max = 4096;
+                }
+            }
+            Profile::Laptop => {
+                if unset("laptop") {
+                    args.laptop = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Prometheus protobuf exposition format
+//!
+//! The `io.prometheus.client.MetricFamily` messages are encoded by hand
+//! (length delimited), the schema being small and stable.
+
+use crate::model::{Family, Kind, Value};
+
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn tag(out: &mut Vec<u8>, field: u8, wire: u8) {
+    out.push(field << 3 | wire);
+}
+
+fn uint(out: &mut Vec<u8>, field: u8, value: u64) {
+    tag(out, field, WIRE_VARINT);
+    varint(out, value);
+}
+
+fn double(out: &mut Vec<u8>, field: u8, value: f64) {
+    tag(out, field, WIRE_FIXED64);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn bytes(out: &mut Vec<u8>, field: u8, value: &[u8]) {
+    tag(out, field, WIRE_LEN);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn message(out: &mut Vec<u8>, field: u8, encode: impl FnOnce(&mut Vec<u8>)) {
+    let mut inner = Vec::new();
+    encode(&mut inner);
+    bytes(out, field, &inner);
+}
+
+fn encode_family(out: &mut Vec<u8>, family: &Family) {
+    bytes(out, 1, family.name.as_bytes());
+    bytes(out, 2, family.help.as_bytes());
+    let kind = match family.kind {
+        Kind::Counter => 0,
+        Kind::Gauge => 1,
+        Kind::Histogram => 4,
+    };
+    uint(out, 3, kind);
+    for sample in &family.samples {
+        message(out, 4, |out| {
+            for (name, value) in &sample.labels {
+                message(out, 1, |out| {
+                    bytes(out, 1, name.as_bytes());
+                    bytes(out, 2, value.as_bytes());
+                });
+            }
+            match (&sample.value, family.kind) {
+                (
+                    Value::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    },
+                    _,
+                ) => message(out, 7, |out| {
+                    uint(out, 1, *count);
+                    double(out, 2, *sum);
+                    for (bound, count) in buckets {
+                        message(out, 3, |out| {
+                            uint(out, 1, *count);
+                            double(out, 2, *bound);
+                        });
+                    }
+                }),
+                (value, Kind::Gauge) => message(out, 2, |out| double(out, 1, value.as_f64())),
+                (value, _) => message(out, 3, |out| double(out, 1, value.as_f64())),
+            }
+        });
+    }
+}
+
+/// Encode the families as a stream of length delimited messages
+pub fn encode(families: &[Family]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for family in families {
+        let mut inner = Vec::new();
+        encode_family(&mut inner, family);
+        varint(&mut out, inner.len() as u64);
+        out.extend_from_slice(&inner);
+    }
+    out
+}
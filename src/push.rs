@@ -0,0 +1,70 @@
+use std::{
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use hyper::{header, Body, Client, Method, Request, Uri};
+use serde_json::{json, Map};
+use tokio::time;
+
+use crate::{
+    model,
+    server::{collect, MetricsParams, ServerState},
+};
+
+/// Encode the current metrics for the VictoriaMetrics JSON line import
+/// API (`/api/v1/import`)
+fn encode_vm_import(state: &ServerState) -> Vec<u8> {
+    let families = collect(state, &MetricsParams::default()).unwrap_or_default();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut result = Vec::new();
+    for (name, labels, value) in model::flatten(&families) {
+        let mut metric = Map::new();
+        metric.insert("__name__".to_string(), name.into());
+        for (label, value) in labels {
+            metric.insert(label.to_string(), value.into());
+        }
+        let line = json!({
+            "metric": metric,
+            "values": [value],
+            "timestamps": [timestamp],
+        });
+        serde_json::to_writer(&mut result, &line).unwrap();
+        result.push(b'\n');
+    }
+    result
+}
+
+/// Push the metrics to VictoriaMetrics (over HTTP or HTTPS) on the
+/// given interval, forever
+pub async fn run_vm_import(url: Uri, interval: Duration, state: ServerState) {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build::<_, Body>(connector);
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&encode_vm_import(&state)).unwrap();
+        let body = encoder.finish().unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url.clone())
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_TYPE, "application/stream+json")
+            .body(Body::from(body))
+            .unwrap();
+        match client.request(request).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => println!("VictoriaMetrics import failed: {}", response.status()),
+            Err(err) => println!("VictoriaMetrics import failed: {err}"),
+        }
+    }
+}
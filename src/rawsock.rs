@@ -0,0 +1,309 @@
+//! Capture with a raw socket, when libpcap cannot open an interface
+//!
+//! A `AF_PACKET` socket (Linux only) receives the network layer of the
+//! frames, which are prefixed with a Linux "cooked" header (as libpcap
+//! does for the `any` device), and a classic BPF filter keeps the IPv4
+//! packets in the kernel. The VLAN tags are not available.
+
+use std::{
+    ffi::CString,
+    io, mem,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::packet::{self, Linktype, Packet, PacketHeader};
+
+/// Length of the Linux cooked header
+const SLL_LEN: usize = 16;
+
+/// Length returned by the filter for accepted packets, large enough to
+/// keep them whole (they are truncated to the snapshot length when
+/// received)
+const ACCEPT: u32 = 0x40000;
+
+const ETHER_IPV4: u16 = 0x0800;
+
+pub struct RawSocket {
+    fd: i32,
+    /// Packets dropped by the kernel, until the last statistics
+    dropped: u64,
+    buffer: Vec<u8>,
+    header: PacketHeader,
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn last_error(action: &str) -> String {
+    format!("{action}: {}", io::Error::last_os_error())
+}
+
+impl RawSocket {
+    /// Open a socket on an interface (every one for `any`), keeping the
+    /// first `snaplen` bytes of each packet
+    ///
+    /// Reading gives up after `timeout` without packets, if any.
+    #[cfg(target_os = "linux")]
+    pub fn open(
+        interface: &str,
+        snaplen: i32,
+        promisc: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Self, String> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be() as i32;
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, protocol) };
+        if fd < 0 {
+            return Err(last_error("Unable to open a raw socket"));
+        }
+        let socket = RawSocket {
+            fd,
+            dropped: 0,
+            buffer: vec![0; SLL_LEN + snaplen.max(0) as usize],
+            header: PacketHeader {
+                ts: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                caplen: 0,
+                len: 0,
+            },
+        };
+        // Only IPv4: ldh [protocol]; jeq #0x0800; ret #ACCEPT; ret #0
+        let mut filter = [
+            libc::sock_filter {
+                code: (libc::BPF_LD | libc::BPF_H | libc::BPF_ABS) as u16,
+                jt: 0,
+                jf: 0,
+                k: (libc::SKF_AD_OFF + libc::SKF_AD_PROTOCOL) as u32,
+            },
+            libc::sock_filter {
+                code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+                jt: 0,
+                jf: 1,
+                k: ETHER_IPV4 as u32,
+            },
+            libc::sock_filter {
+                code: (libc::BPF_RET | libc::BPF_K) as u16,
+                jt: 0,
+                jf: 0,
+                k: ACCEPT,
+            },
+            libc::sock_filter {
+                code: (libc::BPF_RET | libc::BPF_K) as u16,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            },
+        ];
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        socket.set_option(libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &program, "filter")?;
+        if let Some(timeout) = timeout {
+            // At least a millisecond, a zero timeout blocking forever
+            let timeout = timeout.max(Duration::from_millis(1));
+            let timeout = libc::timeval {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+            };
+            socket.set_option(libc::SOL_SOCKET, libc::SO_RCVTIMEO, &timeout, "timeout")?;
+        }
+        if interface != crate::ANY_DEVICE {
+            let name = CString::new(interface).map_err(|err| err.to_string())?;
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if index == 0 {
+                return Err(format!("Device {interface:?} not found"));
+            }
+            let mut address = unsafe { mem::zeroed::<libc::sockaddr_ll>() };
+            address.sll_family = libc::AF_PACKET as u16;
+            address.sll_protocol = protocol as u16;
+            address.sll_ifindex = index as i32;
+            let result = unsafe {
+                libc::bind(
+                    fd,
+                    &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_ll>() as u32,
+                )
+            };
+            if result < 0 {
+                return Err(last_error(&format!("Unable to bind to {interface:?}")));
+            }
+            if promisc {
+                let mut membership = unsafe { mem::zeroed::<libc::packet_mreq>() };
+                membership.mr_ifindex = index as i32;
+                membership.mr_type = libc::PACKET_MR_PROMISC as u16;
+                socket.set_option(
+                    libc::SOL_PACKET,
+                    libc::PACKET_ADD_MEMBERSHIP,
+                    &membership,
+                    "promiscuous mode",
+                )?;
+            }
+        }
+        Ok(socket)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_: &str, _: i32, _: bool, _: Option<Duration>) -> Result<Self, String> {
+        Err("Raw sockets are only supported on Linux".to_string())
+    }
+
+    /// Packets dropped by the kernel since the socket was opened
+    #[cfg(target_os = "linux")]
+    pub fn dropped(&mut self) -> Result<u64, String> {
+        let mut stats = unsafe { mem::zeroed::<libc::tpacket_stats>() };
+        let mut len = mem::size_of::<libc::tpacket_stats>() as u32;
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(last_error("Unable to get the statistics"));
+        }
+        // Reset by each read
+        self.dropped += stats.tp_drops as u64;
+        Ok(self.dropped)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn dropped(&mut self) -> Result<u64, String> {
+        Ok(self.dropped)
+    }
+
+    /// Make the reads return at once when no packet is pending
+    pub fn set_nonblocking(&self) -> Result<(), String> {
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(last_error("Unable to make the socket non-blocking"));
+        }
+        Ok(())
+    }
+
+    fn set_option<T>(&self, level: i32, name: i32, value: &T, what: &str) -> Result<(), String> {
+        let result = unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as u32,
+            )
+        };
+        if result < 0 {
+            return Err(last_error(&format!("Unable to set the {what}")));
+        }
+        Ok(())
+    }
+
+    pub fn datalink(&self) -> Linktype {
+        Linktype::LINUX_SLL
+    }
+
+    /// Wait for the next packet, with a Linux cooked header
+    #[cfg(target_os = "linux")]
+    pub fn next_packet(&mut self) -> Result<Packet<'_>, packet::Error> {
+        let mut address = unsafe { mem::zeroed::<libc::sockaddr_ll>() };
+        let mut address_len = mem::size_of::<libc::sockaddr_ll>() as u32;
+        let payload = &mut self.buffer[SLL_LEN..];
+        let len = unsafe {
+            libc::recvfrom(
+                self.fd,
+                payload.as_mut_ptr() as *mut libc::c_void,
+                payload.len(),
+                libc::MSG_TRUNC,
+                &mut address as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut address_len,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.kind() {
+                io::ErrorKind::WouldBlock => packet::Error::TimeoutExpired,
+                kind => packet::Error::IoError(kind),
+            });
+        }
+        let len = len as usize;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: now.as_secs() as libc::time_t,
+                tv_usec: now.subsec_micros() as libc::suseconds_t,
+            },
+            caplen: (SLL_LEN + len.min(payload.len())) as u32,
+            len: (SLL_LEN + len) as u32,
+        };
+        let header = &mut self.buffer[..SLL_LEN];
+        header[0..2].copy_from_slice(&(address.sll_pkttype as u16).to_be_bytes());
+        header[2..4].copy_from_slice(&address.sll_hatype.to_be_bytes());
+        let halen = (address.sll_halen as usize).min(8);
+        header[4..6].copy_from_slice(&(halen as u16).to_be_bytes());
+        header[6..14].fill(0);
+        header[6..6 + halen].copy_from_slice(&address.sll_addr[..halen]);
+        header[14..16].copy_from_slice(&u16::from_be(address.sll_protocol).to_be_bytes());
+        let caplen = self.header.caplen as usize;
+        Ok(Packet::new(&self.header, &self.buffer[..caplen]))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn next_packet(&mut self) -> Result<Packet<'_>, packet::Error> {
+        Err(packet::Error::IoError(io::ErrorKind::Unsupported))
+    }
+}
+
+/// The interfaces and their IPv4 addresses, from the system
+pub fn devices() -> Result<Vec<packet::Device>, String> {
+    let mut devices = Vec::<packet::Device>::new();
+    let mut addresses = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addresses) } < 0 {
+        return Err(last_error("Unable to list the interfaces"));
+    }
+    let mut current = addresses;
+    while let Some(entry) = unsafe { current.as_ref() } {
+        current = entry.ifa_next;
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) }
+            .to_string_lossy()
+            .into_owned();
+        let device = match devices.iter_mut().position(|device| device.name == name) {
+            Some(index) => &mut devices[index],
+            None => {
+                devices.push(packet::Device::from(name.as_str()));
+                devices.last_mut().unwrap()
+            }
+        };
+        let ipv4 = |address: *const libc::sockaddr| unsafe {
+            let address = address.as_ref()?;
+            if address.sa_family as i32 != libc::AF_INET {
+                return None;
+            }
+            let address = &*(address as *const libc::sockaddr as *const libc::sockaddr_in);
+            Some(IpAddr::from(
+                u32::from_be(address.sin_addr.s_addr).to_be_bytes(),
+            ))
+        };
+        if let Some(addr) = ipv4(entry.ifa_addr) {
+            device.addresses.push(packet::Address {
+                addr,
+                netmask: ipv4(entry.ifa_netmask),
+                broadcast_addr: None,
+                dst_addr: None,
+            });
+        }
+    }
+    unsafe { libc::freeifaddrs(addresses) };
+    Ok(devices)
+}
@@ -0,0 +1,128 @@
+//! Reverse DNS names of the tracked IPs, a source of names (and of the
+//! "hostname" label with --source-labels)
+//!
+//! The names are only read from a cache while exporting. Unknown and
+//! expired ones are looked up in the background by the enrichment
+//! executor, the previous name being kept meanwhile.
+
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{enrich::SharedEnricher, naming::Resolver};
+
+/// How long a name (or its absence) is kept
+const TTL: Duration = Duration::from_secs(3600);
+
+/// Delay before retrying a failed lookup
+const RETRY: Duration = Duration::from_secs(60);
+
+const SOURCE: &str = "reverse-dns";
+
+struct Entry {
+    name: Option<String>,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<u32, Entry>,
+    last_prune: Option<Instant>,
+}
+
+pub struct ReverseDns {
+    enricher: SharedEnricher,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl ReverseDns {
+    pub fn new(enricher: SharedEnricher) -> Self {
+        Self {
+            enricher,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Name of an IP, if already resolved
+    pub fn hostname(&self, ip: u32) -> Option<String> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        // Forget the IPs that are not tracked anymore, once in a while
+        if cache
+            .last_prune
+            .is_none_or(|last| now.duration_since(last) >= TTL)
+        {
+            cache.entries.retain(|_, entry| now < entry.expires + TTL);
+            cache.last_prune = Some(now);
+        }
+        let name = match cache.entries.get(&ip) {
+            Some(entry) if now < entry.expires => return entry.name.clone(),
+            Some(entry) => entry.name.clone(),
+            None => None,
+        };
+        // Looked up at a later scrape when the executor is busy
+        if self.enricher.is_saturated() {
+            return name;
+        }
+        // Not retried before the lookup gives up
+        cache.entries.insert(
+            ip,
+            Entry {
+                name: name.clone(),
+                expires: now + RETRY,
+            },
+        );
+        let cache = self.cache.clone();
+        self.enricher.spawn_blocking(SOURCE, move || {
+            let name = lookup(ip)?;
+            cache.lock().unwrap().entries.insert(
+                ip,
+                Entry {
+                    name,
+                    expires: Instant::now() + TTL,
+                },
+            );
+            Ok(())
+        });
+        name
+    }
+}
+
+impl Resolver for Arc<ReverseDns> {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        self.hostname(ip)
+    }
+}
+
+/// PTR record of an IP, `None` when there is none
+fn lookup(ip: u32) -> Result<Option<String>, String> {
+    let mut host = [0; libc::NI_MAXHOST as usize];
+    let result = unsafe {
+        let mut addr = std::mem::zeroed::<libc::sockaddr_in>();
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr.s_addr = ip.to_be();
+        libc::getnameinfo(
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    match result {
+        0 => {
+            let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+            Ok(Some(name.to_string_lossy().into_owned()))
+        }
+        libc::EAI_NONAME => Ok(None),
+        err => {
+            let message = unsafe { CStr::from_ptr(libc::gai_strerror(err)) };
+            Err(message.to_string_lossy().into_owned())
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    server::format_ip,
+    stats::{DirectionCounters, Key, NamedKey, Protocol, ProtocolCounters},
+    store,
+};
+
+const DAY: u64 = 86400;
+
+/// Period covered by a report, ending at the start of the current day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+pub enum Period {
+    /// The previous day
+    #[value(name = "last-day")]
+    #[serde(rename = "last-day")]
+    Day,
+    /// The previous 7 days
+    #[value(name = "last-week")]
+    #[serde(rename = "last-week")]
+    Week,
+    /// The previous calendar month
+    #[value(name = "last-month")]
+    #[serde(rename = "last-month")]
+    Month,
+}
+
+impl Period {
+    /// First and last day (excluded) of the period, as days since the
+    /// UNIX epoch
+    fn days(self, today: u64) -> (u64, u64) {
+        match self {
+            Period::Day => (today - 1, today),
+            Period::Week => (today - 7, today),
+            Period::Month => {
+                let (year, month, day) = civil_from_days(today);
+                let end = today - (day - 1);
+                let (year, month) = if month == 1 {
+                    (year - 1, 12)
+                } else {
+                    (year, month - 1)
+                };
+                (days_from_civil(year, month, 1), end)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    Md,
+    Html,
+}
+
+/// Traffic of a local IP over the period
+#[derive(Debug, Clone)]
+pub struct Talker {
+    pub ip: Option<u32>,
+    pub name: Option<String>,
+    pub counters: DirectionCounters,
+}
+
+/// Summary of the traffic over a period
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub host: Option<String>,
+    /// First and last day (excluded) actually covered by the snapshots
+    pub start: u64,
+    pub end: u64,
+    pub totals: Vec<(Protocol, DirectionCounters)>,
+    /// Local IPs with the most traffic, in decreasing order
+    pub top: Vec<Talker>,
+    /// Totals per traffic category and per accounting period, by name
+    pub categories: Vec<(String, DirectionCounters)>,
+    pub periods: Vec<(String, DirectionCounters)>,
+    /// Traffic allowed to each local IP over the period (bytes, in both
+    /// directions), when given
+    pub quota: Option<u64>,
+    /// Local IPs over the quota, in decreasing order
+    pub over_quota: Vec<Talker>,
+}
+
+/// Build a report from the snapshots of a state directory
+///
+/// The traffic is the difference between the first snapshot of the
+/// period and the first one following it (or the latest one when the
+/// period is not over yet).
+pub fn build(
+    dir: &Path,
+    period: Period,
+    now: u64,
+    top: usize,
+    quota: Option<u64>,
+) -> Result<Report, String> {
+    let (start_day, end_day) = period.days(now / DAY);
+    let history = store::history(dir);
+    let (start, start_path) = history
+        .iter()
+        .find(|(day, _)| (start_day..end_day).contains(day))
+        .ok_or_else(|| format!("No snapshot for the period in {}", dir.display()))?;
+    let first = store::load(start_path)?;
+    let last = match history.iter().find(|(day, _)| *day >= end_day) {
+        Some((_, path)) => store::load(path)?,
+        None => {
+            store::load_latest(dir)?.ok_or_else(|| format!("No snapshot in {}", dir.display()))?
+        }
+    };
+
+    let before = per_ip(first.counters);
+    let names = last.names.into_iter().collect::<HashMap<_, _>>();
+    let mut total = ProtocolCounters::default();
+    let talkers = per_ip(last.counters)
+        .into_iter()
+        .map(|(ip, counters)| {
+            let counters = match before.get(&ip) {
+                Some(before) => counters.sub(before),
+                None => counters,
+            };
+            total.add(&counters);
+            Talker {
+                ip,
+                name: ip.and_then(|ip| names.get(&ip).cloned()),
+                counters: counters.get(None),
+            }
+        })
+        .collect::<Vec<_>>();
+    let over_quota = match quota {
+        Some(quota) => top_talkers(
+            talkers
+                .iter()
+                .filter(|talker| {
+                    let counters = &talker.counters;
+                    talker.ip.is_some() && counters.inbound.bytes + counters.outbound.bytes > quota
+                })
+                .cloned()
+                .collect(),
+            usize::MAX,
+        ),
+        None => Vec::new(),
+    };
+
+    Ok(Report {
+        host: last.host,
+        start: *start,
+        end: (last.time / DAY).max(start + 1),
+        totals: [
+            Protocol::Icmp,
+            Protocol::Tcp,
+            Protocol::Udp,
+            Protocol::Other,
+        ]
+        .into_iter()
+        .map(|protocol| (protocol, total.get(Some(protocol))))
+        .collect(),
+        top: top_talkers(talkers, top),
+        categories: named_totals(first.categories, last.categories),
+        periods: named_totals(first.periods, last.periods),
+        quota,
+        over_quota,
+    })
+}
+
+/// Named counters over the period, summed up per name
+fn named_totals(
+    first: Vec<(NamedKey, DirectionCounters)>,
+    last: Vec<(NamedKey, DirectionCounters)>,
+) -> Vec<(String, DirectionCounters)> {
+    let before = first.into_iter().collect::<HashMap<_, _>>();
+    let mut totals = BTreeMap::<String, DirectionCounters>::new();
+    for (key, counters) in last {
+        let counters = match before.get(&key) {
+            Some(before) => counters.sub(before),
+            None => counters,
+        };
+        totals.entry(key.1.to_string()).or_default().add(&counters);
+    }
+    totals.into_iter().collect()
+}
+
+/// Counters summed up per IP
+pub fn per_ip(
+    counters: impl IntoIterator<Item = (Key, ProtocolCounters)>,
+) -> HashMap<Option<u32>, ProtocolCounters> {
+    let mut result = HashMap::<Option<u32>, ProtocolCounters>::new();
+    for (key, counters) in counters {
+        result.entry(key.ip).or_default().add(&counters);
+    }
+    result
+}
+
+/// The talkers with the most traffic (in both directions), in
+/// decreasing order
+pub fn top_talkers(mut talkers: Vec<Talker>, top: usize) -> Vec<Talker> {
+    talkers.sort_by_key(|talker| {
+        let counters = &talker.counters;
+        std::cmp::Reverse(counters.inbound.bytes + counters.outbound.bytes)
+    });
+    talkers.truncate(top);
+    talkers
+}
+
+pub fn title(report: &Report) -> String {
+    match &report.host {
+        Some(host) => format!("Traffic report of {host}"),
+        None => "Traffic report".to_string(),
+    }
+}
+
+/// Render a report as Markdown or HTML
+pub fn render(report: &Report, format: Format) -> String {
+    let title = title(report);
+    let period = format!(
+        "From {} to {}",
+        format_day(report.start),
+        format_day(report.end)
+    );
+    let quota = report
+        .quota
+        .map(|quota| format!("Quota of {} per IP", format_bytes(quota)));
+    let totals = report
+        .totals
+        .iter()
+        .map(|(protocol, counters)| {
+            vec![
+                protocol.to_string(),
+                format_bytes(counters.inbound.bytes),
+                format_bytes(counters.outbound.bytes),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let top = report
+        .top
+        .iter()
+        .map(|talker| {
+            vec![
+                format_ip(talker.ip),
+                talker.name.clone().unwrap_or_default(),
+                format_bytes(talker.counters.inbound.bytes),
+                format_bytes(talker.counters.outbound.bytes),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut sections = vec![
+        ("Totals", vec!["Protocol", "Inbound", "Outbound"], totals),
+        (
+            "Top talkers",
+            vec!["IP", "Name", "Inbound", "Outbound"],
+            top,
+        ),
+    ];
+    // Only when defined in the configuration of the exporter
+    for (name, header, totals) in [
+        ("Categories", "Category", &report.categories),
+        ("Accounting periods", "Period", &report.periods),
+    ] {
+        if totals.is_empty() {
+            continue;
+        }
+        let rows = totals
+            .iter()
+            .map(|(name, counters)| {
+                vec![
+                    name.clone(),
+                    format_bytes(counters.inbound.bytes),
+                    format_bytes(counters.outbound.bytes),
+                ]
+            })
+            .collect();
+        sections.push((name, vec![header, "Inbound", "Outbound"], rows));
+    }
+    if let Some(quota) = report.quota {
+        let rows = report
+            .over_quota
+            .iter()
+            .map(|talker| {
+                let bytes = talker.counters.inbound.bytes + talker.counters.outbound.bytes;
+                vec![
+                    format_ip(talker.ip),
+                    talker.name.clone().unwrap_or_default(),
+                    format_bytes(bytes),
+                    format!("{}%", bytes * 100 / quota.max(1)),
+                ]
+            })
+            .collect();
+        sections.push((
+            "Quota status",
+            vec!["IP", "Name", "Traffic", "Quota used"],
+            rows,
+        ));
+    }
+
+    let mut result = String::new();
+    match format {
+        Format::Md => {
+            let _ = writeln!(result, "# {title}\n\n{period}");
+            if let Some(quota) = &quota {
+                let _ = writeln!(result, "\n{quota}");
+            }
+            for (name, header, rows) in sections {
+                let _ = writeln!(result, "\n## {name}\n");
+                if rows.is_empty() {
+                    let _ = writeln!(result, "None");
+                    continue;
+                }
+                let _ = writeln!(result, "| {} |", header.join(" | "));
+                let _ = writeln!(result, "|{}", " --- |".repeat(header.len()));
+                for row in rows {
+                    let row = row.iter().map(|cell| cell.replace('|', "\\|"));
+                    let _ = writeln!(result, "| {} |", row.collect::<Vec<_>>().join(" | "));
+                }
+            }
+        }
+        Format::Html => {
+            let _ = writeln!(
+                result,
+                "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<p>{1}</p>",
+                escape_html(&title),
+                period
+            );
+            if let Some(quota) = &quota {
+                let _ = writeln!(result, "<p>{quota}</p>");
+            }
+            for (name, header, rows) in sections {
+                if rows.is_empty() {
+                    let _ = writeln!(result, "<h2>{name}</h2>\n<p>None</p>");
+                    continue;
+                }
+                let _ = writeln!(result, "<h2>{name}</h2>\n<table>");
+                let header = header.iter().map(|cell| format!("<th>{cell}</th>"));
+                let _ = writeln!(result, "<tr>{}</tr>", header.collect::<String>());
+                for row in rows {
+                    let row = row
+                        .iter()
+                        .map(|cell| format!("<td>{}</td>", escape_html(cell)));
+                    let _ = writeln!(result, "<tr>{}</tr>", row.collect::<String>());
+                }
+                let _ = writeln!(result, "</table>");
+            }
+            let _ = writeln!(result, "</body>\n</html>");
+        }
+    }
+    result
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_day(days: u64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Format a time (seconds since the UNIX epoch) as in RFC 5322
+pub fn format_date(time: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let days = time / DAY;
+    let (year, month, day) = civil_from_days(days);
+    let seconds = time % DAY;
+    format!(
+        "{}, {day} {} {year} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// First second of the month (UTC) of a time
+pub fn month_start(time: u64) -> u64 {
+    let days = time / DAY;
+    let (_, _, day) = civil_from_days(days);
+    (days - (day - 1)) * DAY
+}
+
+/// Format the month of a time, as `YYYY-MM`
+pub fn format_month(time: u64) -> String {
+    let (year, month, _) = civil_from_days(time / DAY);
+    format!("{year:04}-{month:02}")
+}
+
+/// Date (year, month, day) of a number of days since the UNIX epoch
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Number of days since the UNIX epoch of a date
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
@@ -0,0 +1,273 @@
+//! Post-processing of the exported series by a Rhai script
+//!
+//! The script defines `process(series)`, called with an array of the
+//! series about to be exported (`#{name, labels, value}`, the names
+//! being prefixed by the namespace) and returning the ones to export.
+//! It may rename them or their labels, drop some, or compute new ones.
+//! The histograms are not given, and exported as is.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::Mutex,
+};
+
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::model::{Family, Kind, Labels, Sample, Value};
+
+/// Operations allowed to each run of the script, beyond which it fails
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+/// Help of the families created by the script
+const HELP: &str = "Computed by the post-processing script";
+
+/// Names of the labels created by the script, which live as long as the
+/// exporter (as the labels of the exporter itself)
+static LABEL_NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+fn label_name(name: &str) -> Result<&'static str, String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("Invalid label name {name:?}"));
+    }
+    let mut names = LABEL_NAMES.lock().unwrap();
+    if let Some(known) = names.get(name) {
+        return Ok(known);
+    }
+    let name = Box::leak(name.to_string().into_boxed_str());
+    names.insert(name);
+    Ok(name)
+}
+
+fn metric_name(name: String) -> Result<String, String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if !valid {
+        return Err(format!("Invalid metric name {name:?}"));
+    }
+    Ok(name)
+}
+
+/// A compiled script
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+        Self::new(&source).map_err(|err| format!("Invalid script {}: {err}", path.display()))
+    }
+
+    pub fn new(source: &str) -> Result<Self, String> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        // The limits of release builds, whatever the build (the ones of
+        // debug builds rejecting nested closures)
+        engine.set_max_expr_depths(64, 32);
+        let ast = engine.compile(source).map_err(|err| err.to_string())?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == "process")
+        {
+            return Err("Missing the process(series) function".to_string());
+        }
+        Ok(Self { engine, ast })
+    }
+
+    /// Run the script over the families
+    ///
+    /// The series keep their families, the new ones being gauges, and
+    /// their labels keep their order, the new ones coming last.
+    pub fn process(&self, families: Vec<Family>) -> Result<Vec<Family>, String> {
+        let mut order = Vec::<&'static str>::new();
+        let mut series = Array::new();
+        for family in &families {
+            if family.kind == Kind::Histogram {
+                continue;
+            }
+            for sample in &family.samples {
+                let mut labels = Map::new();
+                for (name, value) in &sample.labels {
+                    if !order.contains(name) {
+                        order.push(*name);
+                    }
+                    labels.insert((*name).into(), Dynamic::from(value.clone()));
+                }
+                let value = match sample.value {
+                    Value::Int(value) => Dynamic::from(value.min(i64::MAX as u64) as i64),
+                    ref value => Dynamic::from(value.as_f64()),
+                };
+                let mut entry = Map::new();
+                entry.insert("name".into(), Dynamic::from(family.name.clone()));
+                entry.insert("labels".into(), Dynamic::from(labels));
+                entry.insert("value".into(), value);
+                series.push(Dynamic::from(entry));
+            }
+        }
+
+        let result = self
+            .engine
+            .call_fn::<Array>(&mut Scope::new(), &self.ast, "process", (series,))
+            .map_err(|err| format!("Script failed: {err}"))?;
+
+        let mut index = HashMap::new();
+        let mut families = families
+            .into_iter()
+            .enumerate()
+            .map(|(position, mut family)| {
+                if family.kind != Kind::Histogram {
+                    family.samples.clear();
+                    index.insert(family.name.clone(), position);
+                }
+                family
+            })
+            .collect::<Vec<_>>();
+        for entry in result {
+            let (name, sample) = parse(entry, &order)?;
+            let position = match index.get(&name) {
+                Some(&position) => position,
+                None if families.iter().any(|family| family.name == name) => {
+                    return Err(format!("Script failed: {name} is a histogram"));
+                }
+                None => {
+                    index.insert(name.clone(), families.len());
+                    families.push(Family::new(name, HELP, Kind::Gauge));
+                    families.len() - 1
+                }
+            };
+            families[position].push(sample.labels, sample.value);
+        }
+        // The families left without series are dropped
+        families.retain(|family| !family.samples.is_empty());
+        Ok(families)
+    }
+}
+
+/// Name and sample of a series returned by the script
+fn parse(entry: Dynamic, order: &[&'static str]) -> Result<(String, Sample), String> {
+    let invalid = |what: &str| format!("Script failed: invalid {what} in a series");
+    let mut entry = entry.try_cast::<Map>().ok_or_else(|| invalid("series"))?;
+    let name = entry
+        .remove("name")
+        .and_then(|name| name.into_string().ok())
+        .ok_or_else(|| invalid("name"))?;
+    let name = metric_name(name).map_err(|err| format!("Script failed: {err}"))?;
+    let value = entry.remove("value").ok_or_else(|| invalid("value"))?;
+    let value = match value.as_int() {
+        Ok(value) if value >= 0 => Value::Int(value as u64),
+        Ok(value) => Value::Float(value as f64),
+        Err(_) => Value::Float(value.as_float().map_err(|_| invalid("value"))?),
+    };
+    let labels = match entry.remove("labels") {
+        Some(labels) => labels.try_cast::<Map>().ok_or_else(|| invalid("labels"))?,
+        None => Map::new(),
+    };
+    let mut labels = labels
+        .into_iter()
+        .map(|(label, value)| Ok((label_name(&label)?, value.to_string())))
+        .collect::<Result<Labels, String>>()
+        .map_err(|err| format!("Script failed: {err}"))?;
+    labels.sort_by_key(|(label, _)| {
+        order
+            .iter()
+            .position(|known| known == label)
+            .unwrap_or(order.len())
+    });
+    Ok((name, Sample { labels, value }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn families() -> Vec<Family> {
+        let mut bytes = Family::new("txne_network_bytes_total", "Bytes", Kind::Counter);
+        for (ip, direction, value) in [
+            ("10.0.0.1", "inbound", 1000),
+            ("10.0.0.1", "outbound", 500),
+            ("10.0.0.2", "inbound", 2000),
+        ] {
+            let labels = vec![("direction", direction.to_string()), ("ip", ip.to_string())];
+            bytes.push(labels, Value::Int(value));
+        }
+        let mut rtt = Family::new("txne_network_icmp_rtt_seconds", "RTT", Kind::Histogram);
+        rtt.push(
+            vec![("peer", "1.1.1.1".to_string())],
+            Value::Histogram {
+                buckets: vec![(0.01, 1)],
+                sum: 0.005,
+                count: 1,
+            },
+        );
+        vec![bytes, rtt]
+    }
+
+    #[test]
+    fn process() {
+        let script = Script::new(
+            r#"
+            fn process(series) {
+                let result = [];
+                let total = 0;
+                for s in series {
+                    if s.labels.ip == "10.0.0.2" {
+                        continue;
+                    }
+                    s.labels.host = s.labels.ip;
+                    s.labels.remove("ip");
+                    total += s.value;
+                    result.push(s);
+                }
+                result.push(#{ name: "txne_total_bytes", value: total / 2.0 });
+                result
+            }
+            "#,
+        )
+        .unwrap();
+        let families = script.process(families()).unwrap();
+        let text = crate::model::encode_text(&families);
+        assert_eq!(
+            text,
+            "# HELP txne_network_bytes_total Bytes\n\
+             # TYPE txne_network_bytes_total counter\n\
+             txne_network_bytes_total{direction=\"inbound\",host=\"10.0.0.1\"} 1000\n\
+             txne_network_bytes_total{direction=\"outbound\",host=\"10.0.0.1\"} 500\n\
+             \n\
+             # HELP txne_network_icmp_rtt_seconds RTT\n\
+             # TYPE txne_network_icmp_rtt_seconds histogram\n\
+             txne_network_icmp_rtt_seconds_bucket{peer=\"1.1.1.1\",le=\"0.01\"} 1\n\
+             txne_network_icmp_rtt_seconds_bucket{peer=\"1.1.1.1\",le=\"+Inf\"} 1\n\
+             txne_network_icmp_rtt_seconds_sum{peer=\"1.1.1.1\"} 0.005\n\
+             txne_network_icmp_rtt_seconds_count{peer=\"1.1.1.1\"} 1\n\
+             \n\
+             # HELP txne_total_bytes Computed by the post-processing script\n\
+             # TYPE txne_total_bytes gauge\n\
+             txne_total_bytes 750\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn failures() {
+        assert!(Script::new("fn other(series) { series }").is_err());
+        assert!(Script::new("fn process(series) {").is_err());
+        for body in [
+            "42",
+            "[42]",
+            r#"[#{ name: "invalid name", value: 1 }]"#,
+            r#"[#{ name: "valid", value: "1" }]"#,
+            r#"[#{ name: "valid", labels: #{ "invalid-label": "a" }, value: 1 }]"#,
+            r#"[#{ name: "txne_network_icmp_rtt_seconds", value: 1 }]"#,
+        ] {
+            let script = Script::new(&format!("fn process(series) {{ {body} }}")).unwrap();
+            assert!(script.process(families()).is_err(), "{body}");
+        }
+    }
+}
@@ -0,0 +1,1073 @@
+//! HTTP API of the exporter, over the statistics shared with the
+//! captures
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    burst,
+    cluster::Leadership,
+    compress,
+    dhcp::SharedLeases,
+    docker::SharedContainers,
+    enrich::{self, SharedEnricher},
+    icmp,
+    kubernetes::SharedPods,
+    model::{self, Family, Kind, Labels, Sample, Value},
+    naming::SharedChain,
+    probe::ProbeResults,
+    protobuf,
+    rdns::ReverseDns,
+    report,
+    script::Script,
+    ssdp::{Device, SharedDevices},
+    stats::{Key, NamedCounters, Protocol, ProtocolCounters, Stats},
+};
+
+/// Prefix of the metric names
+pub const DEFAULT_NAMESPACE: &str = "txne_network";
+
+/// Metric model of an older version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compat {
+    /// The packet and byte counters of the first version only, with
+    /// their labels then (txne_inbound_bytes_total{ip_dest=...}...)
+    V0,
+}
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "inbound"),
+            Direction::Outbound => write!(f, "outbound"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ValueType {
+    Packets,
+    Bytes,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValueType::Packets => write!(f, "packets"),
+            ValueType::Bytes => write!(f, "bytes"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerState {
+    pub stats: Arc<Mutex<Stats>>,
+    /// Leadership among redundant instances, when enabled
+    pub leader: Option<Leadership>,
+    pub probes: ProbeResults,
+    pub enricher: SharedEnricher,
+    /// Sources of the "name" label
+    pub names: SharedChain,
+    /// Source of the "hostname" label, with --source-labels
+    pub reverse_dns: Option<Arc<ReverseDns>>,
+    /// Pods of the cluster, with --source-labels
+    pub pods: Option<SharedPods>,
+    /// Docker containers, with --source-labels
+    pub containers: Option<SharedContainers>,
+    pub leases: SharedLeases,
+    /// Whether lease files are used (source of the MAC addresses)
+    pub dhcp: bool,
+    pub devices: SharedDevices,
+    pub host: Option<String>,
+    /// Packets that could not be mirrored, when mirroring
+    pub mirror_dropped: Option<Arc<AtomicU64>>,
+    /// Throughput samples, when computing the percentiles
+    pub samples: Option<Arc<Mutex<burst::Samples>>>,
+    /// Start of the exporter (seconds since the UNIX epoch)
+    pub started: u64,
+    /// Prefix of the metric names
+    pub namespace: String,
+    /// Also export the metrics under their names before `--namespace`
+    pub legacy_names: bool,
+    /// Export only the metrics of an older version
+    pub compat: Option<Compat>,
+    /// Post-processing of the exported series, when given
+    pub script: Option<Arc<Script>>,
+    pub health: SharedHealth,
+    /// Capture threads, none when serving an archive
+    pub threads: Option<Arc<Threads>>,
+}
+
+impl ServerState {
+    /// Whether the per-IP series are exported, a standby instance only
+    /// exporting its own health
+    pub fn is_leader(&self) -> bool {
+        self.leader
+            .as_ref()
+            .is_none_or(|leader| leader.load(Ordering::Relaxed))
+    }
+}
+
+/// Health of a capture, for the self-metrics
+#[derive(Debug, Clone, Default)]
+pub struct CaptureHealth {
+    /// Actual interval between the last two flushes
+    pub flush_interval: Duration,
+    /// Packets dropped by the kernel since the capture was opened, when
+    /// known
+    pub dropped: Option<u64>,
+    /// Packets that are not IPv4, or malformed
+    pub unparsed: u64,
+    /// Delay between the capture of a packet and its processing,
+    /// sampled once per flush (live captures only)
+    pub lag: Option<Duration>,
+}
+
+/// Health of each capture, by interface (or stream)
+pub type SharedHealth = Arc<Mutex<HashMap<String, CaptureHealth>>>;
+
+/// Capture threads, for the liveness and readiness of the exporter
+#[derive(Debug, Default)]
+pub struct Threads {
+    pub started: AtomicUsize,
+    /// Interfaces (or streams) of the threads that ended
+    pub stopped: Mutex<Vec<String>>,
+}
+
+impl Threads {
+    /// Count a capture thread, until the guard is dropped
+    pub fn start(self: &Arc<Self>, name: &str) -> ThreadGuard {
+        self.started.fetch_add(1, Ordering::Relaxed);
+        ThreadGuard {
+            threads: self.clone(),
+            name: name.to_string(),
+        }
+    }
+}
+
+/// Held by a capture thread, recording its end when dropped, panics
+/// included
+pub struct ThreadGuard {
+    threads: Arc<Threads>,
+    name: String,
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut stopped) = self.threads.stopped.lock() {
+            stopped.push(std::mem::take(&mut self.name));
+        }
+    }
+}
+
+/// Query parameters of /metrics
+#[derive(Default, Deserialize)]
+pub struct MetricsParams {
+    /// Comma separated list of labels to aggregate away ("ip",
+    /// "group", "protocol")
+    pub aggregate: Option<String>,
+    /// Only report the IPs with at least this many bytes (in both
+    /// directions, all protocols)
+    pub min_bytes: Option<u64>,
+}
+
+/// Sum the counters of the IPs under the IP given by `group`
+fn regroup(stats: &mut Stats, group: impl Fn(Option<u32>) -> Option<u32>) {
+    let mut counters = HashMap::<Key, ProtocolCounters>::new();
+    for (key, entry) in stats.counters.drain() {
+        let key = Key {
+            ip: group(key.ip),
+            ..key
+        };
+        counters.entry(key).or_default().add(&entry);
+    }
+    stats.counters = counters;
+    for named in stats.named_mut() {
+        let mut counters = NamedCounters::new();
+        for ((ip, name), entry) in named.drain() {
+            counters.entry((group(ip), name)).or_default().add(&entry);
+        }
+        *named = counters;
+    }
+}
+
+/// Collect the metric families to export
+pub fn collect(state: &ServerState, params: &MetricsParams) -> Result<Vec<Family>, String> {
+    let mut aggregate_ip = false;
+    let mut aggregate_group = false;
+    let mut aggregate_protocol = false;
+    for label in params.aggregate.iter().flat_map(|labels| labels.split(',')) {
+        match label {
+            "ip" => aggregate_ip = true,
+            "group" => aggregate_group = true,
+            "protocol" => aggregate_protocol = true,
+            _ => return Err(format!("Cannot aggregate on {label:?}\n")),
+        }
+    }
+    if aggregate_group && !state.names.is_enabled() {
+        return Err("Cannot aggregate on \"group\" without a source of names\n".to_string());
+    }
+    // Summing up the IPs sums up their groups too
+    let aggregate_group = aggregate_group && !aggregate_ip;
+
+    let mut stats = state.stats.lock().unwrap().clone();
+    let tracked_ips = stats
+        .counters
+        .keys()
+        .filter_map(|key| key.ip)
+        .collect::<HashSet<_>>()
+        .len();
+    let leases = state.leases.lock().unwrap().clone();
+    let probes = state.probes.lock().unwrap().clone();
+
+    // The traffic of an IP is split over several keys (ports, VLANs,
+    // interfaces...), the IP is kept or dropped with all of them
+    let reported = params.min_bytes.map(|min_bytes| {
+        let mut totals = HashMap::<Option<u32>, u64>::new();
+        for (key, counters) in &stats.counters {
+            let total = counters.get(None);
+            *totals.entry(key.ip).or_default() += total.inbound.bytes + total.outbound.bytes;
+        }
+        totals
+            .into_iter()
+            .filter(|(_, total)| *total >= min_bytes)
+            .map(|(ip, _)| ip)
+            .collect::<HashSet<_>>()
+    });
+    if let Some(reported) = &reported {
+        stats.counters.retain(|key, _| reported.contains(&key.ip));
+        for named in stats.named_mut() {
+            named.retain(|(ip, _), _| reported.contains(ip));
+        }
+    }
+    // Group of each IP, when aggregating the groups
+    let mut members = HashMap::new();
+    if aggregate_ip {
+        regroup(&mut stats, |_| None);
+    } else if aggregate_group {
+        // A group is represented by its lowest IP, the IPs without a name
+        // joining the overflow entry
+        let mut ips = stats
+            .counters
+            .keys()
+            .map(|key| key.ip)
+            .chain(
+                stats
+                    .named()
+                    .into_iter()
+                    .flat_map(|named| named.keys().map(|(ip, _)| *ip)),
+            )
+            .flatten()
+            .collect::<Vec<_>>();
+        ips.sort();
+        ips.dedup();
+        let mut groups = HashMap::new();
+        for ip in ips {
+            if let Some(name) = state.names.resolve(ip) {
+                let first = *groups.entry(name).or_insert(ip);
+                members.insert(ip, first);
+            }
+        }
+        regroup(&mut stats, |ip| members.get(&ip?).copied());
+    }
+
+    let leader = state.is_leader();
+    if !leader {
+        stats.counters.clear();
+        stats.latencies.clear();
+        for named in stats.named_mut() {
+            named.clear();
+        }
+    }
+
+    let mut keys = stats.counters.keys().collect::<Vec<_>>();
+    keys.sort();
+
+    let protocols = if aggregate_protocol {
+        vec![None]
+    } else {
+        vec![
+            Some(Protocol::Icmp),
+            Some(Protocol::Tcp),
+            Some(Protocol::Udp),
+            Some(Protocol::Other),
+        ]
+    };
+
+    let base_labels = || {
+        let mut labels = Labels::new();
+        if let Some(host) = &state.host {
+            labels.push(("host", host.clone()));
+        }
+        labels
+    };
+
+    let name_labels = |labels: &mut Labels, ip: Option<u32>| {
+        if state.names.is_enabled() {
+            let name = ip.and_then(|ip| state.names.resolve(ip));
+            labels.push(("name", name.unwrap_or_else(|| enrich::UNKNOWN.to_string())));
+        }
+        if let Some(reverse_dns) = &state.reverse_dns {
+            let hostname = ip.and_then(|ip| reverse_dns.hostname(ip));
+            labels.push((
+                "hostname",
+                hostname.unwrap_or_else(|| enrich::UNKNOWN.to_string()),
+            ));
+        }
+        if let Some(pods) = &state.pods {
+            let pod = ip.and_then(|ip| pods.lock().unwrap().get(&ip).cloned());
+            let (name, namespace, node) = match pod {
+                Some(pod) => (pod.name, pod.namespace, pod.node),
+                None => {
+                    let unknown = || enrich::UNKNOWN.to_string();
+                    (unknown(), unknown(), unknown())
+                }
+            };
+            labels.push(("pod", name));
+            labels.push(("namespace", namespace));
+            labels.push(("node", node));
+        }
+        if let Some(containers) = &state.containers {
+            let container = ip.and_then(|ip| containers.lock().unwrap().get(&ip).cloned());
+            let (name, image) = match container {
+                Some(container) => (container.name, container.image),
+                None => (enrich::UNKNOWN.to_string(), enrich::UNKNOWN.to_string()),
+            };
+            labels.push(("container_name", name));
+            labels.push(("image", image));
+        }
+    };
+
+    let group_name = |ip: Option<u32>| {
+        ip.and_then(|ip| state.names.resolve(ip))
+            .unwrap_or_else(|| enrich::UNKNOWN.to_string())
+    };
+
+    let key_labels = |key: &Key, direction: Direction, protocol: Option<Protocol>| {
+        let mut labels = base_labels();
+        if let Some(interface) = &key.interface {
+            labels.push(("interface", interface.to_string()));
+        }
+        labels.push(("direction", direction.to_string()));
+        if aggregate_group {
+            labels.push(("name", group_name(key.ip)));
+        } else if !aggregate_ip {
+            labels.push(("ip", format_ip(key.ip)));
+            name_labels(&mut labels, key.ip);
+            if state.dhcp {
+                let lease = key.ip.and_then(|ip| leases.get(&ip));
+                let mac = lease.and_then(|lease| lease.mac.as_deref());
+                labels.push(("mac", mac.unwrap_or(enrich::UNKNOWN).to_string()));
+            }
+        }
+        if let Some(protocol) = protocol {
+            labels.push(("protocol", protocol.to_string()));
+        }
+        if let Some(port) = &key.port {
+            labels.push(("port", port.to_string()));
+        }
+        if let Some(wifi) = &key.wifi {
+            labels.push(("ssid", wifi.ssid.clone()));
+            labels.push(("bssid", wifi.bssid.clone()));
+        }
+        if let Some(vlan) = key.vlan {
+            labels.push(("vlan", vlan.to_string()));
+        }
+        labels
+    };
+
+    let mut families = Vec::new();
+
+    for value_type in [ValueType::Packets, ValueType::Bytes] {
+        let type_name = match value_type {
+            ValueType::Packets => "Packets",
+            ValueType::Bytes => "Bytes",
+        };
+        let mut family = Family::new(
+            format!("{value_type}_total"),
+            format!("{type_name} entering or leaving the network"),
+            Kind::Counter,
+        );
+        family.created = stats.created;
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            for key in keys.iter() {
+                for protocol in protocols.iter() {
+                    let entry = stats.counters[*key].get(*protocol);
+                    let entry = match direction {
+                        Direction::Inbound => entry.inbound,
+                        Direction::Outbound => entry.outbound,
+                    };
+                    let counter = match value_type {
+                        ValueType::Packets => entry.pkts,
+                        ValueType::Bytes => entry.bytes,
+                    };
+                    family.push(key_labels(key, direction, *protocol), Value::Int(counter));
+                }
+            }
+        }
+        families.push(family);
+    }
+
+    for (named, label, description) in [
+        (&stats.categories, "category", "category of traffic"),
+        (&stats.periods, "period", "accounting period"),
+        (&stats.countries, "country", "country of the remote side"),
+        (&stats.asns, "asn", "autonomous system of the remote side"),
+    ] {
+        if named.is_empty() {
+            continue;
+        }
+        let mut entries = named.iter().collect::<Vec<_>>();
+        entries.sort_by_key(|(key, _)| *key);
+        let mut family = Family::new(
+            format!("{label}_bytes_total"),
+            format!("Bytes entering or leaving the network, per {description}"),
+            Kind::Counter,
+        );
+        family.created = stats.created;
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            for ((ip, name), entry) in &entries {
+                let mut labels = base_labels();
+                labels.push(("direction", direction.to_string()));
+                if aggregate_group {
+                    labels.push(("name", group_name(*ip)));
+                } else if !aggregate_ip {
+                    labels.push(("ip", format_ip(*ip)));
+                    name_labels(&mut labels, *ip);
+                }
+                labels.push((label, name.to_string()));
+                let counter = match direction {
+                    Direction::Inbound => entry.inbound.bytes,
+                    Direction::Outbound => entry.outbound.bytes,
+                };
+                family.push(labels, Value::Int(counter));
+            }
+        }
+        families.push(family);
+    }
+
+    // The percentiles of several IPs cannot be summed up, the ones of a
+    // group are computed from the sums of the samples of its members
+    if let Some(samples) = state.samples.as_ref().filter(|_| leader && !aggregate_ip) {
+        let samples = samples.lock().unwrap();
+        let is_reported = |ip| {
+            reported
+                .as_ref()
+                .is_none_or(|reported| reported.contains(&ip))
+        };
+        let rolling = if aggregate_group {
+            samples.rolling_groups(|ip| is_reported(ip).then(|| members.get(&ip?).copied()))
+        } else {
+            let mut rolling = samples.rolling().to_vec();
+            rolling.retain(|(ip, _)| is_reported(*ip));
+            rolling
+        };
+        drop(samples);
+        let mut family = Family::new(
+            "throughput_p95_bytes_per_second",
+            "95th percentile of the throughput entering or leaving the network over 30 days \
+             (5 minutes samples)",
+            Kind::Gauge,
+        );
+        for direction in [Direction::Inbound, Direction::Outbound] {
+            for (ip, percentile) in &rolling {
+                let mut labels = base_labels();
+                labels.push(("direction", direction.to_string()));
+                if aggregate_group {
+                    labels.push(("name", group_name(*ip)));
+                } else {
+                    labels.push(("ip", format_ip(*ip)));
+                    name_labels(&mut labels, *ip);
+                }
+                let mbps = match direction {
+                    Direction::Inbound => percentile.inbound_mbps,
+                    Direction::Outbound => percentile.outbound_mbps,
+                };
+                family.push(labels, Value::Float(mbps * 1e6 / 8.0));
+            }
+        }
+        families.push(family);
+    }
+
+    if !stats.latencies.is_empty() {
+        let mut family = Family::new(
+            "icmp_rtt_seconds",
+            "Round trip time of ICMP echo requests leaving the network",
+            Kind::Histogram,
+        );
+        family.created = stats.created;
+        let mut peers = stats.latencies.keys().collect::<Vec<_>>();
+        peers.sort();
+        for peer in peers {
+            let histogram = &stats.latencies[peer];
+            let mut labels = base_labels();
+            labels.push(("peer", format_ip(*peer)));
+            family.push(
+                labels,
+                Value::Histogram {
+                    buckets: icmp::BUCKETS.into_iter().zip(histogram.buckets).collect(),
+                    sum: histogram.sum,
+                    count: histogram.count,
+                },
+            );
+        }
+        families.push(family);
+    }
+
+    if !probes.is_empty() {
+        let mut targets = probes.keys().collect::<Vec<_>>();
+        targets.sort();
+        let mut success = Family::new(
+            "probe_success",
+            "Whether the last active probe succeeded",
+            Kind::Gauge,
+        );
+        let mut duration = Family::new(
+            "probe_duration_seconds",
+            "Duration of the last active probe",
+            Kind::Gauge,
+        );
+        for target in targets {
+            let mut labels = base_labels();
+            labels.push(("module", target.module().to_string()));
+            labels.push(("target", target.to_string()));
+            let result = &probes[target];
+            success.push(labels.clone(), Value::Int(result.success as u64));
+            duration.push(labels, Value::Float(result.duration.as_secs_f64()));
+        }
+        families.push(success);
+        families.push(duration);
+    }
+
+    if state.leader.is_some() {
+        let mut family = Family::new(
+            "leader",
+            "Whether this instance is the leader exporting the per-IP series",
+            Kind::Gauge,
+        );
+        family.push(base_labels(), Value::Int(leader as u64));
+        families.push(family);
+    }
+
+    let sources = state.enricher.sources();
+    if !sources.is_empty() {
+        let mut errors = Family::new(
+            "enrichment_errors_total",
+            "Failed enrichment lookups",
+            Kind::Counter,
+        );
+        errors.created = Some(state.started);
+        let mut dropped = Family::new(
+            "enrichment_dropped_total",
+            "Enrichment lookups dropped while too many were pending",
+            Kind::Counter,
+        );
+        dropped.created = Some(state.started);
+        let mut open = Family::new(
+            "enrichment_circuit_open",
+            "Whether the enrichment source is disabled after repeated failures",
+            Kind::Gauge,
+        );
+        for (source, source_state) in sources {
+            let mut labels = base_labels();
+            labels.push(("source", source.to_string()));
+            errors.push(labels.clone(), Value::Int(source_state.errors));
+            dropped.push(labels.clone(), Value::Int(source_state.dropped));
+            open.push(labels, Value::Int(source_state.is_open() as u64));
+        }
+        families.push(errors);
+        families.push(dropped);
+        families.push(open);
+    }
+
+    if let Some(dropped) = &state.mirror_dropped {
+        let mut family = Family::new(
+            "mirror_dropped_packets_total",
+            "Captured packets that could not be mirrored",
+            Kind::Counter,
+        );
+        family.created = Some(state.started);
+        family.push(base_labels(), Value::Int(dropped.load(Ordering::Relaxed)));
+        families.push(family);
+    }
+
+    let mut health = state
+        .health
+        .lock()
+        .unwrap()
+        .clone()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if !health.is_empty() {
+        health.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut flushes = Family::new(
+            "flush_interval_seconds",
+            "Actual interval between the last two flushes of the counters of a capture",
+            Kind::Gauge,
+        );
+        let mut dropped = Family::new(
+            "capture_dropped_packets_total",
+            "Packets dropped by the kernel before the capture could read them",
+            Kind::Counter,
+        );
+        let mut unparsed = Family::new(
+            "capture_unparsed_packets_total",
+            "Captured packets that are not IPv4, or malformed",
+            Kind::Counter,
+        );
+        let mut lag = Family::new(
+            "capture_lag_seconds",
+            "Delay between the capture of a packet and its processing",
+            Kind::Gauge,
+        );
+        for (interface, capture) in health {
+            let mut labels = base_labels();
+            labels.push(("interface", interface));
+            flushes.push(
+                labels.clone(),
+                Value::Float(capture.flush_interval.as_secs_f64()),
+            );
+            if let Some(count) = capture.dropped {
+                dropped.push(labels.clone(), Value::Int(count));
+            }
+            unparsed.push(labels.clone(), Value::Int(capture.unparsed));
+            if let Some(delay) = capture.lag {
+                lag.push(labels, Value::Float(delay.as_secs_f64()));
+            }
+        }
+        families.push(flushes);
+        families.extend(
+            [dropped, unparsed, lag]
+                .into_iter()
+                .filter(|family| !family.samples.is_empty()),
+        );
+    }
+
+    let mut family = Family::new(
+        "tracked_ips",
+        "Local IPs with their own counters",
+        Kind::Gauge,
+    );
+    family.push(base_labels(), Value::Int(tracked_ips as u64));
+    families.push(family);
+
+    let mut family = Family::new(
+        "evictions_total",
+        "Untracked IPs whose counters were evicted to the overflow entry (at every sync of a \
+         capture), once the maximum number of tracked IPs is reached",
+        Kind::Counter,
+    );
+    family.created = Some(state.started);
+    family.push(base_labels(), Value::Int(stats.evictions));
+    families.push(family);
+
+    let mut family = Family::new("build_info", "Version of the exporter", Kind::Gauge);
+    let mut labels = base_labels();
+    labels.push(("version", env!("CARGO_PKG_VERSION").to_string()));
+    family.push(labels, Value::Int(1));
+    families.push(family);
+
+    // The names are prefixed last, the old ones being fixed
+    if state.compat == Some(Compat::V0) {
+        // Only the counters of then, the packets and bytes coming first
+        families = legacy_families(&families[..2])
+            .into_iter()
+            .map(v0_family)
+            .collect();
+        return post_process(state, families);
+    }
+    let legacy = if state.legacy_names {
+        legacy_families(&families)
+    } else {
+        Vec::new()
+    };
+    for family in &mut families {
+        if !state.namespace.is_empty() {
+            family.name = format!("{}_{}", state.namespace, family.name);
+        }
+    }
+    for family in legacy {
+        if families.iter().all(|current| current.name != family.name) {
+            families.push(family);
+        }
+    }
+
+    post_process(state, families)
+}
+
+/// Run the script over the families to export, when given
+fn post_process(state: &ServerState, families: Vec<Family>) -> Result<Vec<Family>, String> {
+    match &state.script {
+        Some(script) => script.process(families),
+        None => Ok(families),
+    }
+}
+
+/// Families under their names before `--namespace`, with their labels
+/// and units of then (a family for each direction)
+fn legacy_families(families: &[Family]) -> Vec<Family> {
+    let mut legacy = Vec::new();
+    // The packets and bytes were grouped by direction first
+    let mut outbound = Vec::new();
+    for family in families {
+        let name = family.name.as_str();
+        if !matches!(name, "packets_total" | "bytes_total") {
+            legacy.append(&mut outbound);
+        }
+        match name {
+            "packets_total" | "bytes_total" => {
+                for direction in [Direction::Inbound, Direction::Outbound] {
+                    let split = by_direction(family, direction, &format!("{direction}_{name}"));
+                    match direction {
+                        Direction::Inbound => legacy.push(split),
+                        Direction::Outbound => outbound.push(split),
+                    }
+                }
+            }
+            "category_bytes_total"
+            | "period_bytes_total"
+            | "country_bytes_total"
+            | "asn_bytes_total" => {
+                let label = name.trim_end_matches("_bytes_total");
+                for direction in [Direction::Inbound, Direction::Outbound] {
+                    let name = format!("{label}_{direction}_bytes_total");
+                    legacy.push(by_direction(family, direction, &name));
+                }
+            }
+            "throughput_p95_bytes_per_second" => {
+                for direction in [Direction::Inbound, Direction::Outbound] {
+                    let name = format!("{direction}_p95_mbps");
+                    let mut family = by_direction(family, direction, &name);
+                    family.help.push_str(", in Mbps");
+                    for sample in &mut family.samples {
+                        sample.value = Value::Float(sample.value.as_f64() * 8.0 / 1e6);
+                    }
+                    legacy.push(family);
+                }
+            }
+            "icmp_rtt_seconds" => {
+                let mut family = family.clone();
+                for sample in &mut family.samples {
+                    let peer = sample.labels.iter().position(|(label, _)| *label == "peer");
+                    let index = peer.unwrap_or(sample.labels.len());
+                    sample.labels.insert(index, ("ip_version", "4".to_string()));
+                }
+                legacy.push(family);
+            }
+            "mirror_dropped_packets_total" => {
+                let mut family = family.clone();
+                family.name = "mirror_dropped_total".to_string();
+                legacy.push(family);
+            }
+            _ => legacy.push(family.clone()),
+        }
+    }
+    legacy.append(&mut outbound);
+    for family in &mut legacy {
+        family.name = format!("txne_{}", family.name);
+    }
+    legacy
+}
+
+/// Samples of a family in one direction, the `direction` label being
+/// replaced by `ip_version`, and `ip` by `ip_dest` or `ip_source`
+fn by_direction(family: &Family, direction: Direction, name: &str) -> Family {
+    let (verb, field) = match direction {
+        Direction::Inbound => ("entering", "ip_dest"),
+        Direction::Outbound => ("leaving", "ip_source"),
+    };
+    let help = family.help.replace("entering or leaving", verb);
+    let mut result = Family::new(name, help, family.kind);
+    result.created = family.created;
+    let direction = direction.to_string();
+    for sample in &family.samples {
+        if !sample.labels.contains(&("direction", direction.clone())) {
+            continue;
+        }
+        let labels = sample
+            .labels
+            .iter()
+            .map(|(label, value)| match *label {
+                "direction" => ("ip_version", "4".to_string()),
+                "ip" => (field, value.clone()),
+                _ => (*label, value.clone()),
+            })
+            .collect();
+        result.samples.push(Sample {
+            labels,
+            value: sample.value.clone(),
+        });
+    }
+    result
+}
+
+/// Family with the labels of the first version only, the series that
+/// differ by the other labels being summed up
+fn v0_family(mut family: Family) -> Family {
+    const V0_LABELS: [&str; 4] = ["ip_version", "ip_dest", "ip_source", "protocol"];
+    let mut index = HashMap::<Labels, usize>::new();
+    for sample in std::mem::take(&mut family.samples) {
+        let labels = sample
+            .labels
+            .into_iter()
+            .filter(|(label, _)| V0_LABELS.contains(label))
+            .collect::<Labels>();
+        match index.get(&labels) {
+            Some(&position) => {
+                if let (Value::Int(total), Value::Int(value)) =
+                    (&mut family.samples[position].value, sample.value)
+                {
+                    *total += value;
+                }
+            }
+            None => {
+                index.insert(labels.clone(), family.samples.len());
+                family.samples.push(Sample {
+                    labels,
+                    value: sample.value,
+                });
+            }
+        }
+    }
+    family
+}
+
+async fn metrics(
+    State(state): State<ServerState>,
+    Query(params): Query<MetricsParams>,
+    headers: HeaderMap,
+) -> Response {
+    let families = match collect(&state, &params) {
+        Ok(families) => families,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    match negotiate(accept) {
+        Format::Protobuf => (
+            [(header::CONTENT_TYPE, protobuf::CONTENT_TYPE)],
+            protobuf::encode(&families),
+        )
+            .into_response(),
+        Format::OpenMetrics => (
+            [(header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)],
+            model::encode_openmetrics(&families),
+        )
+            .into_response(),
+        Format::Text => (
+            [(header::CONTENT_TYPE, TEXT_CONTENT_TYPE)],
+            model::encode_text(&families),
+        )
+            .into_response(),
+    }
+}
+
+/// Exposition formats of /metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    OpenMetrics,
+    Protobuf,
+}
+
+/// Format preferred by a scraper, from its `Accept` header
+///
+/// The media ranges are ranked by their quality (`q`), the first one
+/// winning a tie. The Prometheus text format is the fallback when none
+/// of them is supported.
+fn negotiate(accept: &str) -> Format {
+    let mut best = (Format::Text, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+        let mut quality = 1.0;
+        let mut delimited = false;
+        for param in params {
+            match param.split_once('=') {
+                Some(("q", value)) => quality = value.trim().parse().unwrap_or(0.0),
+                Some(("encoding", value)) => delimited = value.trim() == "delimited",
+                _ => {}
+            }
+        }
+        let format = match media_type.as_str() {
+            "application/vnd.google.protobuf" if delimited => Format::Protobuf,
+            "application/openmetrics-text" => Format::OpenMetrics,
+            "text/plain" | "text/*" | "*/*" => Format::Text,
+            _ => continue,
+        };
+        if quality > best.1 {
+            best = (format, quality);
+        }
+    }
+    best.0
+}
+
+#[derive(Serialize)]
+struct DeviceEntry {
+    ip: String,
+    #[serde(flatten)]
+    device: Device,
+}
+
+/// Liveness: failing once a capture thread ended, the counters being
+/// frozen
+async fn healthz(State(state): State<ServerState>) -> Response {
+    let stopped = match &state.threads {
+        Some(threads) => threads.stopped.lock().unwrap().clone(),
+        None => Vec::new(),
+    };
+    if stopped.is_empty() {
+        return "OK\n".into_response();
+    }
+    let message = format!("Capture stopped: {}\n", stopped.join(", "));
+    (StatusCode::SERVICE_UNAVAILABLE, message).into_response()
+}
+
+/// Readiness: every capture is open and synced its statistics at least
+/// once
+async fn readyz(State(state): State<ServerState>) -> Response {
+    let Some(threads) = &state.threads else {
+        return "OK\n".into_response();
+    };
+    let started = threads.started.load(Ordering::Relaxed);
+    let stopped = threads.stopped.lock().unwrap().len();
+    // One entry per capture, their interfaces being distinct
+    let synced = state.health.lock().unwrap().len();
+    if started == 0 || stopped > 0 || synced < started {
+        let message = format!("Captures synced: {synced} of {started}\n");
+        return (StatusCode::SERVICE_UNAVAILABLE, message).into_response();
+    }
+    "OK\n".into_response()
+}
+
+/// Inventory of the devices discovered over SSDP
+async fn devices_inventory(State(state): State<ServerState>) -> Json<Vec<DeviceEntry>> {
+    let devices = state.devices.lock().unwrap().clone();
+    let mut devices = devices.into_iter().collect::<Vec<_>>();
+    devices.sort_by_key(|(ip, _)| *ip);
+    Json(
+        devices
+            .into_iter()
+            .map(|(ip, device)| DeviceEntry {
+                ip: format_ip(Some(ip)),
+                device,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Serialize)]
+struct PercentileEntry {
+    ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    inbound_mbps: f64,
+    outbound_mbps: f64,
+    /// Highest of both directions
+    billable_mbps: f64,
+    samples: usize,
+}
+
+#[derive(Serialize)]
+struct MonthPercentiles {
+    /// YYYY-MM
+    month: String,
+    /// Whether the month is over
+    complete: bool,
+    ips: Vec<PercentileEntry>,
+}
+
+/// 95th percentiles of the throughput for the previous and current
+/// calendar months (UTC)
+async fn percentiles(State(state): State<ServerState>) -> Response {
+    let Some(samples) = &state.samples else {
+        return (StatusCode::NOT_FOUND, "Percentiles are not enabled\n").into_response();
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (previous, current) = {
+        let samples = samples.lock().unwrap();
+        (
+            samples.previous_month(now).cloned(),
+            samples.current_month(now),
+        )
+    };
+    let months = previous
+        .map(|month| (month, true))
+        .into_iter()
+        .chain([(current, false)])
+        .map(|(month, complete)| MonthPercentiles {
+            month: report::format_month(month.start),
+            complete,
+            ips: month
+                .percentiles
+                .into_iter()
+                .map(|(ip, percentile)| PercentileEntry {
+                    ip: format_ip(ip),
+                    name: ip.and_then(|ip| state.names.resolve(ip)),
+                    inbound_mbps: percentile.inbound_mbps,
+                    outbound_mbps: percentile.outbound_mbps,
+                    billable_mbps: percentile.billable_mbps(),
+                    samples: percentile.samples,
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    Json(months).into_response()
+}
+
+/// Format an IPv4 address, or "other" for the overflow entry
+pub fn format_ip(ip: Option<u32>) -> String {
+    match ip {
+        Some(ip) => {
+            let ip = ip.to_be_bytes();
+            format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3])
+        }
+        None => "other".to_string(),
+    }
+}
+
+/// Routes of the HTTP API, public through `http::router()`
+pub fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/api/v1/devices", get(devices_inventory))
+        .route("/api/v1/percentiles", get(percentiles))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .layer(middleware::from_fn(compress::compress))
+        .with_state(state)
+}
@@ -0,0 +1,139 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+/// Value of the "port" label for the ports not listed, and the
+/// protocols without ports
+pub const OTHER: &str = "other";
+
+/// Well-known services, named in the "port" label
+const WELL_KNOWN: [(u16, &str); 24] = [
+    (20, "ftp-data"),
+    (21, "ftp"),
+    (22, "ssh"),
+    (23, "telnet"),
+    (25, "smtp"),
+    (53, "dns"),
+    (80, "http"),
+    (110, "pop3"),
+    (123, "ntp"),
+    (143, "imap"),
+    (161, "snmp"),
+    (443, "https"),
+    (445, "smb"),
+    (465, "smtps"),
+    (587, "submission"),
+    (853, "dot"),
+    (993, "imaps"),
+    (995, "pop3s"),
+    (1194, "openvpn"),
+    (1883, "mqtt"),
+    (3389, "rdp"),
+    (5353, "mdns"),
+    (8883, "mqtts"),
+    (51820, "wireguard"),
+];
+
+/// A port to break the traffic down by, with the value of its label
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Service {
+    pub port: u16,
+    pub name: String,
+}
+
+impl FromStr for Service {
+    type Err = String;
+
+    /// Parse `PORT`, `PORT=NAME` or the name of a well-known service
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid port {s:?} (expected PORT, PORT=NAME or a service)");
+        if let Some((port, name)) = s.split_once('=') {
+            let port = port.parse().map_err(|_| invalid())?;
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(Service {
+                port,
+                name: name.to_string(),
+            });
+        }
+        if let Ok(port) = s.parse::<u16>() {
+            let name = WELL_KNOWN
+                .iter()
+                .find(|(known, _)| *known == port)
+                .map(|(_, name)| name.to_string())
+                .unwrap_or_else(|| port.to_string());
+            return Ok(Service { port, name });
+        }
+        WELL_KNOWN
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(port, name)| Service {
+                port: *port,
+                name: name.to_string(),
+            })
+            .ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for Service {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Service> for String {
+    fn from(service: Service) -> Self {
+        service.to_string()
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.port, self.name)
+    }
+}
+
+/// Labels of the ports of the allowlist
+pub struct Ports {
+    labels: HashMap<u16, Arc<str>>,
+    other: Arc<str>,
+}
+
+impl Ports {
+    pub fn new(services: &[Service]) -> Self {
+        Self {
+            labels: services
+                .iter()
+                .map(|service| (service.port, Arc::from(service.name.as_str())))
+                .collect(),
+            other: Arc::from(OTHER),
+        }
+    }
+
+    /// Label of a packet, from its IP protocol and its transport header
+    ///
+    /// The remote port is preferred (the one of the server, usually),
+    /// then the local one.
+    pub fn label(&self, ip_proto: u8, transport: &[u8], outbound: bool) -> Arc<str> {
+        let ports = match (ip_proto, transport.get(..4)) {
+            (6 | 17, Some(ports)) => ports,
+            _ => return self.other.clone(),
+        };
+        let source = u16::from_be_bytes([ports[0], ports[1]]);
+        let dest = u16::from_be_bytes([ports[2], ports[3]]);
+        let (local, remote) = if outbound {
+            (source, dest)
+        } else {
+            (dest, source)
+        };
+        self.labels
+            .get(&remote)
+            .or_else(|| self.labels.get(&local))
+            .unwrap_or(&self.other)
+            .clone()
+    }
+}
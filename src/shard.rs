@@ -0,0 +1,65 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Part of the local IPs owned by this instance, when several
+/// instances share the same traffic
+///
+/// The owner of an IP is chosen by rendezvous hashing, so that every
+/// instance agrees on it without coordination, and changing the number
+/// of shards only moves a minimal part of the IPs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Shard {
+    /// Index of this instance, starting at 0
+    pub index: u32,
+    pub count: u32,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    /// Parse `INDEX/COUNT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid shard {s:?} (expected INDEX/COUNT, e.g. 0/4)");
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index = index.parse().map_err(|_| invalid())?;
+        let count = count.parse().map_err(|_| invalid())?;
+        if index >= count {
+            return Err(invalid());
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl TryFrom<String> for Shard {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Shard> for String {
+    fn from(shard: Shard) -> Self {
+        format!("{}/{}", shard.index, shard.count)
+    }
+}
+
+/// Stable 64-bit mix (SplitMix64 finalizer), identical on every
+/// instance and version
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl Shard {
+    /// Whether this instance owns the given IP
+    pub fn owns(&self, ip: u32) -> bool {
+        let owner = (0..self.count)
+            .max_by_key(|shard| mix((ip as u64) << 32 | *shard as u64))
+            .unwrap_or(0);
+        owner == self.index
+    }
+}
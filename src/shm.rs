@@ -0,0 +1,355 @@
+//! Live counters in a memory-mapped file
+//!
+//! The exporter periodically writes the counters of every tracked IP
+//! to a file, that local consumers can map to read them without going
+//! through HTTP. Integers are in the native byte order (the file is
+//! meant to be read on the same machine).
+//!
+//! The file starts with a 64 bytes header:
+//!
+//! | Offset | Size | Field                                           |
+//! |--------|------|-------------------------------------------------|
+//! | 0      | 8    | Magic, `TXNESHM\0`                              |
+//! | 8      | 4    | Version of the layout (currently 1)             |
+//! | 12     | 4    | Size of a record (136)                          |
+//! | 16     | 8    | Sequence, odd while the exporter is writing     |
+//! | 24     | 8    | Time of the last update (ms since the epoch)    |
+//! | 32     | 4    | Capacity (number of records the file can hold)  |
+//! | 36     | 4    | Number of valid records                         |
+//!
+//! followed by the records, each one made of the IPv4 address (4
+//! bytes, big endian, `0.0.0.0` for the overflow entry), 4 reserved
+//! bytes, and 16 counters of 8 bytes: for each protocol (ICMP, TCP,
+//! UDP, other), the inbound packets and bytes, then the outbound
+//! packets and bytes.
+//!
+//! The header and records are guarded by the sequence (a seqlock): a
+//! reader copies the records between two reads of the sequence, and
+//! starts over when it was odd or has changed.
+//!
+//! The file is never shrunk nor truncated while it may be mapped (a
+//! reader would get a SIGBUS): the exporter creates it under a
+//! temporary name and renames it into place, so a restarted exporter
+//! (with a lower `--max` for instance) replaces the file instead of
+//! truncating it. A reader compares the inode behind the path with the
+//! one it mapped before each read, and maps the new file when it was
+//! replaced.
+//!
+//! When there are more records than the capacity (after `--max` was
+//! raised), the exporter grows the file. A reader that sees a larger
+//! capacity in the header maps the file again.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    net::Ipv4Addr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    thread,
+};
+
+use memmap2::{Mmap, MmapMut};
+
+pub const MAGIC: [u8; 8] = *b"TXNESHM\0";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 64;
+pub const RECORD_SIZE: usize = 136;
+
+/// Protocols of the counters of a record, in order
+pub const PROTOCOLS: [&str; 4] = ["icmp", "tcp", "udp", "other"];
+
+const SEQUENCE: usize = 16;
+const UPDATED: usize = 24;
+const CAPACITY: usize = 32;
+const COUNT: usize = 36;
+
+/// Attempts of a reader before giving up on a busy writer
+const MAX_ATTEMPTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Counters of an IP, by protocol (see `PROTOCOLS`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Record {
+    /// `None` for the overflow entry
+    pub ip: Option<Ipv4Addr>,
+    pub inbound: [Counters; 4],
+    pub outbound: [Counters; 4],
+}
+
+impl Record {
+    fn encode(&self, buffer: &mut [u8]) {
+        let ip = self.ip.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        buffer[..4].copy_from_slice(&ip.octets());
+        buffer[4..8].fill(0);
+        let mut offset = 8;
+        for (inbound, outbound) in self.inbound.iter().zip(&self.outbound) {
+            for value in [
+                inbound.packets,
+                inbound.bytes,
+                outbound.packets,
+                outbound.bytes,
+            ] {
+                buffer[offset..offset + 8].copy_from_slice(&value.to_ne_bytes());
+                offset += 8;
+            }
+        }
+    }
+
+    fn decode(buffer: &[u8]) -> Self {
+        let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&buffer[..4]).unwrap());
+        let value = |index: usize| {
+            let offset = 8 + index * 8;
+            u64::from_ne_bytes(buffer[offset..offset + 8].try_into().unwrap())
+        };
+        let mut record = Record {
+            ip: (!ip.is_unspecified()).then_some(ip),
+            ..Record::default()
+        };
+        for protocol in 0..PROTOCOLS.len() {
+            record.inbound[protocol] = Counters {
+                packets: value(protocol * 4),
+                bytes: value(protocol * 4 + 1),
+            };
+            record.outbound[protocol] = Counters {
+                packets: value(protocol * 4 + 2),
+                bytes: value(protocol * 4 + 3),
+            };
+        }
+        record
+    }
+}
+
+/// Consistent copy of the file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Time of the update (milliseconds since the UNIX epoch)
+    pub updated: u64,
+    pub records: Vec<Record>,
+}
+
+fn sequence(map: &[u8]) -> &AtomicU64 {
+    // The mapping is page aligned, and the sequence 8 bytes aligned in it
+    unsafe { &*(map.as_ptr().add(SEQUENCE) as *const AtomicU64) }
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+/// Copy a part of the mapping, which may be modified concurrently
+fn copy(map: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    let mut result = vec![0; len];
+    unsafe {
+        for (index, byte) in result.iter_mut().enumerate() {
+            *byte = ptr::read_volatile(map.as_ptr().add(offset + index));
+        }
+    }
+    result
+}
+
+/// Writer side, used by the exporter
+pub struct Writer {
+    file: File,
+    map: MmapMut,
+    capacity: usize,
+}
+
+impl Writer {
+    /// Create the file, sized for `capacity` records, replacing any
+    /// previous one
+    pub fn create(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}.tmp", std::process::id()));
+        let temporary = path.with_file_name(name);
+        let created = Self::initialize(&temporary, capacity)
+            .and_then(|writer| fs::rename(&temporary, path).map(|()| writer));
+        if created.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        created
+    }
+
+    fn initialize(path: &Path, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_SIZE + capacity * RECORD_SIZE) as u64)?;
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        map[..8].copy_from_slice(&MAGIC);
+        map[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+        map[12..16].copy_from_slice(&(RECORD_SIZE as u32).to_ne_bytes());
+        map[CAPACITY..CAPACITY + 4].copy_from_slice(&(capacity as u32).to_ne_bytes());
+        Ok(Self {
+            file,
+            map,
+            capacity,
+        })
+    }
+
+    /// Replace the records, growing the file when they do not fit (the
+    /// extra ones being ignored if that fails)
+    pub fn write(&mut self, updated: u64, records: &[Record]) -> io::Result<()> {
+        let grown = if records.len() > self.capacity {
+            self.grow(records.len().max(self.capacity * 2))
+        } else {
+            Ok(())
+        };
+        let records = &records[..records.len().min(self.capacity)];
+        let start = sequence(&self.map).load(Ordering::Relaxed);
+        sequence(&self.map).store(start + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.map[CAPACITY..CAPACITY + 4].copy_from_slice(&(self.capacity as u32).to_ne_bytes());
+        self.map[UPDATED..UPDATED + 8].copy_from_slice(&updated.to_ne_bytes());
+        self.map[COUNT..COUNT + 4].copy_from_slice(&(records.len() as u32).to_ne_bytes());
+        for (record, buffer) in records
+            .iter()
+            .zip(self.map[HEADER_SIZE..].chunks_exact_mut(RECORD_SIZE))
+        {
+            record.encode(buffer);
+        }
+        sequence(&self.map).store(start + 2, Ordering::Release);
+        grown
+    }
+
+    /// Extend the file and map it again, the capacity in the header
+    /// being updated by the next write
+    fn grow(&mut self, capacity: usize) -> io::Result<()> {
+        self.file
+            .set_len((HEADER_SIZE + capacity * RECORD_SIZE) as u64)?;
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+/// Reader side, for local consumers
+pub struct Reader {
+    path: PathBuf,
+    /// Device and inode of the mapped file
+    inode: (u64, u64),
+    file: File,
+    map: Mmap,
+    capacity: usize,
+}
+
+impl Reader {
+    /// Map a file written by the exporter
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let metadata = file.metadata()?;
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        if map.len() < HEADER_SIZE || map[..8] != MAGIC {
+            return Err(invalid("Not a txne shared memory file"));
+        }
+        if read_u32(&map, 8) != VERSION || read_u32(&map, 12) as usize != RECORD_SIZE {
+            return Err(invalid("Unsupported version"));
+        }
+        let capacity = read_u32(&map, CAPACITY) as usize;
+        if map.len() < HEADER_SIZE + capacity * RECORD_SIZE {
+            return Err(invalid("Truncated file"));
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            inode: (metadata.dev(), metadata.ino()),
+            file,
+            map,
+            capacity,
+        })
+    }
+
+    /// Consistent copy of the records, `None` if the writer kept
+    /// updating them (or the file could not be mapped again after it
+    /// grew or was replaced)
+    pub fn read(&mut self) -> Option<Snapshot> {
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            if (metadata.dev(), metadata.ino()) != self.inode {
+                *self = Reader::open(&self.path).ok()?;
+            }
+        }
+        for _ in 0..MAX_ATTEMPTS {
+            let start = sequence(&self.map).load(Ordering::Acquire);
+            if start % 2 == 1 {
+                thread::yield_now();
+                continue;
+            }
+            let header = copy(&self.map, 0, HEADER_SIZE);
+            let count = (read_u32(&header, COUNT) as usize).min(self.capacity);
+            let records = copy(&self.map, HEADER_SIZE, count * RECORD_SIZE);
+            fence(Ordering::Acquire);
+            if sequence(&self.map).load(Ordering::Relaxed) != start {
+                continue;
+            }
+            let capacity = read_u32(&header, CAPACITY) as usize;
+            if capacity > self.capacity {
+                self.map = unsafe { Mmap::map(&self.file).ok()? };
+                if self.map.len() < HEADER_SIZE + capacity * RECORD_SIZE {
+                    return None;
+                }
+                self.capacity = capacity;
+                continue;
+            }
+            return Some(Snapshot {
+                updated: u64::from_ne_bytes(header[UPDATED..UPDATED + 8].try_into().unwrap()),
+                records: records
+                    .chunks_exact(RECORD_SIZE)
+                    .map(Record::decode)
+                    .collect(),
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ip: [u8; 4], bytes: u64) -> Record {
+        let mut record = Record {
+            ip: Some(ip.into()),
+            ..Record::default()
+        };
+        record.inbound[1] = Counters { packets: 1, bytes };
+        record
+    }
+
+    #[test]
+    fn grow_and_replace() {
+        let directory = std::env::temp_dir().join(format!("txne-shm-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("txne");
+
+        let mut writer = Writer::create(&path, 2).unwrap();
+        let mut reader = Reader::open(&path).unwrap();
+        let records = vec![record([10, 0, 0, 1], 100), Record::default()];
+        writer.write(1, &records).unwrap();
+        let snapshot = reader.read().unwrap();
+        assert_eq!((snapshot.updated, snapshot.records), (1, records));
+
+        // More records than the capacity
+        let records: Vec<_> = (0..5).map(|n| record([10, 0, 0, n], 10)).collect();
+        writer.write(2, &records).unwrap();
+        assert_eq!(reader.read().unwrap().records, records);
+
+        // Restarted with a smaller capacity, while the file is mapped
+        let mut writer = Writer::create(&path, 1).unwrap();
+        let records = vec![record([10, 0, 0, 2], 200)];
+        writer.write(3, &records).unwrap();
+        let snapshot = reader.read().unwrap();
+        assert_eq!((snapshot.updated, snapshot.records), (3, records));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
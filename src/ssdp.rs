@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    enrich::SharedEnricher,
+    events::{Bus, Event},
+    naming::Resolver,
+};
+
+pub const SSDP_PORT: u16 = 1900;
+
+/// Time given to a device to return its description, from the
+/// connection to the end of the response
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum size of a device description
+const MAX_DESCRIPTION: u64 = 64 * 1024;
+
+/// A device that announced itself over SSDP
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Device {
+    /// `SERVER` header of the announcement (OS and UPnP stack)
+    pub server: Option<String>,
+    /// URL of the device description
+    pub location: Option<String>,
+    /// From the device description
+    pub friendly_name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    /// Whether the description is being fetched
+    #[serde(skip)]
+    pub fetching: bool,
+}
+
+/// Devices by IPv4 address
+pub type Devices = HashMap<u32, Device>;
+
+pub type SharedDevices = Arc<Mutex<Devices>>;
+
+impl Resolver for SharedDevices {
+    fn resolve(&self, ip: u32) -> Option<String> {
+        self.lock().unwrap().get(&ip)?.friendly_name.clone()
+    }
+}
+
+/// Extract the `SERVER` and `LOCATION` headers of a SSDP `NOTIFY` or
+/// search response
+pub fn parse(payload: &[u8]) -> Option<(Option<String>, Option<String>)> {
+    let payload = String::from_utf8_lossy(payload);
+    let mut lines = payload.lines();
+    let first = lines.next()?;
+    if !first.starts_with("NOTIFY ") && !first.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    let mut server = None;
+    let mut location = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = Some(value.trim().to_string());
+        if name.eq_ignore_ascii_case("server") {
+            server = value;
+        } else if name.eq_ignore_ascii_case("location") {
+            location = value;
+        }
+    }
+    Some((server, location))
+}
+
+/// Fetch of the description of a device in progress, until dropped
+struct Fetching {
+    devices: SharedDevices,
+    ip: u32,
+}
+
+impl Drop for Fetching {
+    fn drop(&mut self) {
+        if let Some(device) = self.devices.lock().unwrap().get_mut(&self.ip) {
+            device.fetching = false;
+        }
+    }
+}
+
+/// Record an announcement, fetching the device description in the
+/// background when its location is new (once at a time for a device,
+/// a location announced meanwhile being fetched at a later announcement)
+pub fn record(
+    enricher: &SharedEnricher,
+    events: &Bus,
+    devices: &SharedDevices,
+    ip: u32,
+    server: Option<String>,
+    location: Option<String>,
+    max_tracking: usize,
+) {
+    let mut devices_guard = devices.lock().unwrap();
+    if !devices_guard.contains_key(&ip) && devices_guard.len() >= max_tracking {
+        return;
+    }
+    if !devices_guard.contains_key(&ip) {
+        events.emit(Event::NewDevice {
+            ip: Ipv4Addr::from(ip),
+            server: server.clone(),
+        });
+    }
+    let device = devices_guard.entry(ip).or_default();
+    if server.is_some() {
+        device.server = server;
+    }
+    if location.is_none() || location == device.location || device.fetching {
+        return;
+    }
+    device.location = location.clone();
+    device.fetching = true;
+    // Released first, the guard locks the devices when dropped
+    drop(devices_guard);
+    let fetching = Fetching {
+        devices: devices.clone(),
+        ip,
+    };
+    enricher.spawn_blocking("ssdp", move || {
+        let description = location
+            .as_deref()
+            .and_then(|url| fetch(url, ip))
+            .ok_or("Unable to fetch the device description")?;
+        if let Some(device) = fetching.devices.lock().unwrap().get_mut(&ip) {
+            device.friendly_name = element(&description, "friendlyName");
+            device.manufacturer = element(&description, "manufacturer");
+            device.model = element(&description, "modelName");
+        }
+        Ok(())
+    });
+}
+
+/// Get the body of a `http://` URL, as long as it is served by the
+/// device itself
+fn fetch(url: &str, ip: u32) -> Option<String> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let addr: SocketAddr = if authority.contains(':') {
+        authority.parse().ok()?
+    } else {
+        format!("{authority}:80").parse().ok()?
+    };
+    if addr.ip() != IpAddr::V4(Ipv4Addr::from(ip)) {
+        return None;
+    }
+    let deadline = Instant::now() + TIMEOUT;
+    let remaining =
+        || Some(deadline.saturating_duration_since(Instant::now())).filter(|time| !time.is_zero());
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).ok()?;
+    stream.set_write_timeout(Some(remaining()?)).ok()?;
+    let path = if path.is_empty() { "/" } else { path };
+    write!(
+        stream,
+        "GET {path} HTTP/1.0\r\nHost: {authority}\r\nConnection: close\r\n\r\n"
+    )
+    .ok()?;
+    // The read timeout applies to each read, shortened to the time left
+    let mut response = Vec::new();
+    let mut buffer = [0; 4096];
+    while (response.len() as u64) < MAX_DESCRIPTION {
+        stream.set_read_timeout(Some(remaining()?)).ok()?;
+        let len = stream.read(&mut buffer).ok()?;
+        if len == 0 {
+            break;
+        }
+        response.extend_from_slice(&buffer[..len]);
+    }
+    response.truncate(MAX_DESCRIPTION as usize);
+    let response = String::from_utf8_lossy(&response);
+    let (_, body) = response.split_once("\r\n\r\n")?;
+    Some(body.to_string())
+}
+
+/// Get the text of the first `<name>` element of a XML document
+fn element(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    let text = xml[start..end]
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
@@ -0,0 +1,338 @@
+//! Traffic counters of the local IPs, as merged from the captures
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    burst,
+    discovery::{self, Names},
+    icmp::{self, Latencies},
+    report,
+    wifi::Wifi,
+};
+
+/// Maximum number of tracked IPs, when not given with `--max`
+pub const DEFAULT_MAX: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Icmp,
+    Tcp,
+    Udp,
+    Other,
+}
+
+impl Protocol {
+    /// Protocol of the IP protocol number
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            _ => Protocol::Other,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Icmp => write!(f, "icmp"),
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Other => write!(f, "other"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BaseCounters {
+    pub pkts: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DirectionCounters {
+    pub inbound: BaseCounters,
+    pub outbound: BaseCounters,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ProtocolCounters {
+    pub icmp: DirectionCounters,
+    pub tcp: DirectionCounters,
+    pub udp: DirectionCounters,
+    pub other: DirectionCounters,
+}
+
+impl BaseCounters {
+    pub fn add(&mut self, other: &BaseCounters) {
+        self.pkts += other.pkts;
+        self.bytes += other.bytes;
+    }
+
+    /// Difference with earlier counters
+    pub fn sub(&self, other: &BaseCounters) -> BaseCounters {
+        BaseCounters {
+            pkts: self.pkts.saturating_sub(other.pkts),
+            bytes: self.bytes.saturating_sub(other.bytes),
+        }
+    }
+}
+
+impl DirectionCounters {
+    pub fn add(&mut self, other: &DirectionCounters) {
+        self.inbound.add(&other.inbound);
+        self.outbound.add(&other.outbound);
+    }
+
+    pub fn sub(&self, other: &DirectionCounters) -> DirectionCounters {
+        DirectionCounters {
+            inbound: self.inbound.sub(&other.inbound),
+            outbound: self.outbound.sub(&other.outbound),
+        }
+    }
+}
+
+impl ProtocolCounters {
+    pub fn add(&mut self, other: &ProtocolCounters) {
+        self.icmp.add(&other.icmp);
+        self.tcp.add(&other.tcp);
+        self.udp.add(&other.udp);
+        self.other.add(&other.other);
+    }
+
+    pub fn sub(&self, other: &ProtocolCounters) -> ProtocolCounters {
+        ProtocolCounters {
+            icmp: self.icmp.sub(&other.icmp),
+            tcp: self.tcp.sub(&other.tcp),
+            udp: self.udp.sub(&other.udp),
+            other: self.other.sub(&other.other),
+        }
+    }
+
+    /// Counters of a protocol, or of all of them summed up
+    pub fn get(&self, protocol: Option<Protocol>) -> DirectionCounters {
+        match protocol {
+            Some(Protocol::Icmp) => self.icmp.clone(),
+            Some(Protocol::Tcp) => self.tcp.clone(),
+            Some(Protocol::Udp) => self.udp.clone(),
+            Some(Protocol::Other) => self.other.clone(),
+            None => {
+                let mut total = DirectionCounters::default();
+                for counters in [&self.icmp, &self.tcp, &self.udp, &self.other] {
+                    total.add(counters);
+                }
+                total
+            }
+        }
+    }
+}
+
+/// What the counters are tracked by
+///
+/// The IP is `None` for the overflow entry used once the maximum
+/// number of tracked IPs is reached.
+///
+/// The series are exported in the order of the keys: by IP (the
+/// overflow entry first, then numerically), wireless network, VLAN,
+/// interface and port. This order is documented, keep it when adding
+/// fields.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Key {
+    pub ip: Option<u32>,
+    pub wifi: Option<Arc<Wifi>>,
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    /// Capture interface, when capturing on several ones
+    #[serde(default)]
+    pub interface: Option<Arc<str>>,
+    /// Service of the traffic, when broken down by port
+    #[serde(default)]
+    pub port: Option<Arc<str>>,
+}
+
+/// Local IP (`None` for the overflow entry) and name of a category of
+/// traffic or of an accounting period
+pub type NamedKey = (Option<u32>, Arc<str>);
+
+pub type NamedCounters = HashMap<NamedKey, DirectionCounters>;
+
+/// Count a packet of a local IP under a name
+pub fn record_named(
+    counters: &mut NamedCounters,
+    ip: u32,
+    name: Arc<str>,
+    outbound: bool,
+    len: u32,
+) {
+    let entry = counters.entry((Some(ip), name)).or_default();
+    let item = if outbound {
+        &mut entry.outbound
+    } else {
+        &mut entry.inbound
+    };
+    item.pkts += 1;
+    item.bytes += len as u64;
+}
+
+/// Add named counters, the ones of new IPs going to the overflow entry
+/// once the maximum number of IPs is reached
+fn merge_named(counters: &mut NamedCounters, delta: NamedCounters, max_tracking: usize) {
+    let mut tracked = counters.keys().filter_map(|(ip, _)| *ip).collect();
+    let mut delta: Vec<_> = delta.into_iter().collect();
+    delta.sort_by_key(|((ip, _), _)| *ip);
+    for ((ip, name), entry) in delta {
+        let ip = ip.filter(|ip| track(&mut tracked, *ip, max_tracking));
+        counters.entry((ip, name)).or_default().add(&entry);
+    }
+}
+
+/// Whether an IP has (or can get) its own counters, adding it to the
+/// tracked ones
+fn track(tracked: &mut HashSet<u32>, ip: u32, max_tracking: usize) -> bool {
+    tracked.contains(&ip) || (tracked.len() < max_tracking && tracked.insert(ip))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub counters: HashMap<Key, ProtocolCounters>,
+    pub latencies: Latencies,
+    pub names: Names,
+    pub categories: NamedCounters,
+    pub periods: NamedCounters,
+    pub countries: NamedCounters,
+    pub asns: NamedCounters,
+    /// Time since which the counters accumulate (seconds since the UNIX
+    /// epoch), unknown for the snapshots of older versions
+    pub created: Option<u64>,
+    /// Untracked IPs added to the overflow entry (once per merge), since
+    /// the start
+    pub evictions: u64,
+}
+
+impl Stats {
+    /// Counters per category, period, country and autonomous system
+    pub fn named(&self) -> [&NamedCounters; 4] {
+        [&self.categories, &self.periods, &self.countries, &self.asns]
+    }
+
+    pub fn named_mut(&mut self) -> [&mut NamedCounters; 4] {
+        [
+            &mut self.categories,
+            &mut self.periods,
+            &mut self.countries,
+            &mut self.asns,
+        ]
+    }
+
+    /// Add the statistics gathered by a capture thread since its last
+    /// sync
+    ///
+    /// Once the maximum number of tracked IPs is reached, the counters
+    /// of new IPs go to the overflow entry. The new IPs are taken in the
+    /// order of their addresses, so that which ones overflow does not
+    /// depend on the order of the hash maps.
+    pub fn merge(&mut self, delta: Stats, max_tracking: usize) {
+        let mut tracked = self.counters.keys().filter_map(|key| key.ip).collect();
+        let mut overflowed = HashSet::new();
+        let mut counters: Vec<_> = delta.counters.into_iter().collect();
+        counters.sort_by_key(|(key, _)| key.ip);
+        for (mut key, counters) in counters {
+            if let Some(ip) = key.ip {
+                if !track(&mut tracked, ip, max_tracking) {
+                    key.ip = None;
+                    overflowed.insert(ip);
+                }
+            }
+            self.counters.entry(key).or_default().add(&counters);
+        }
+        self.evictions += overflowed.len() as u64;
+        icmp::merge(&mut self.latencies, delta.latencies, max_tracking);
+        merge_named(&mut self.categories, delta.categories, max_tracking);
+        merge_named(&mut self.periods, delta.periods, max_tracking);
+        merge_named(&mut self.countries, delta.countries, max_tracking);
+        merge_named(&mut self.asns, delta.asns, max_tracking);
+        let mut names: Vec<_> = delta.names.into_iter().collect();
+        names.sort_unstable();
+        discovery::record(&mut self.names, names, max_tracking);
+    }
+}
+
+/// Inbound and outbound bytes of each IP, for the throughput samples
+pub fn throughput_totals(stats: &Stats) -> burst::Totals {
+    report::per_ip(stats.counters.clone())
+        .into_iter()
+        .map(|(ip, counters)| {
+            let total = counters.get(None);
+            (ip, (total.inbound.bytes, total.outbound.bytes))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The maximum applies to the IPs, whatever the number of ports
+    #[test]
+    fn maximum_tracked_ips() {
+        let mut delta = Stats::default();
+        for ip in [0x0a00_0001, 0x0a00_0002, 0x0a00_0003] {
+            for port in ["http", "https"] {
+                let key = Key {
+                    ip: Some(ip),
+                    wifi: None,
+                    vlan: None,
+                    interface: None,
+                    port: Some(port.into()),
+                };
+                let mut counters = ProtocolCounters::default();
+                counters.tcp.inbound = BaseCounters {
+                    pkts: 1,
+                    bytes: 100,
+                };
+                delta.counters.insert(key, counters);
+            }
+        }
+        let mut stats = Stats::default();
+        stats.merge(delta, 2);
+        assert_eq!(stats.evictions, 1);
+        let tracked: HashSet<_> = stats.counters.keys().filter_map(|key| key.ip).collect();
+        assert_eq!(tracked.len(), 2);
+    }
+
+    /// The IPs filling the limit within a merge are always the same ones
+    #[test]
+    fn overflow_order() {
+        let ips: Vec<u32> = (1..=64).map(|n| 0x0a00_0000 | (n * 37 % 251)).collect();
+        for _ in 0..8 {
+            let mut delta = Stats::default();
+            for &ip in &ips {
+                let key = Key {
+                    ip: Some(ip),
+                    wifi: None,
+                    vlan: None,
+                    interface: None,
+                    port: None,
+                };
+                delta.counters.insert(key, ProtocolCounters::default());
+            }
+            let mut stats = Stats::default();
+            stats.merge(delta, 16);
+            assert_eq!(stats.evictions, 48);
+            let mut tracked: Vec<_> = stats.counters.keys().filter_map(|key| key.ip).collect();
+            tracked.sort_unstable();
+            let mut expected = ips.clone();
+            expected.sort_unstable();
+            expected.truncate(16);
+            assert_eq!(tracked, expected);
+        }
+    }
+}
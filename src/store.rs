@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    icmp::Histogram,
+    ssdp::{Device, Devices},
+    stats::{DirectionCounters, Key, NamedKey, ProtocolCounters, Stats},
+};
+
+/// Version of the snapshot format, increased on incompatible changes
+const VERSION: u32 = 1;
+
+const LATEST: &str = "latest.json";
+const HISTORY: &str = "history";
+
+/// Persisted state of the exporter
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    /// Time of the snapshot (seconds since the UNIX epoch)
+    pub time: u64,
+    /// Value of the "host" label of the exporter
+    pub host: Option<String>,
+    pub counters: Vec<(Key, ProtocolCounters)>,
+    pub latencies: Vec<(Option<u32>, Histogram)>,
+    pub names: Vec<(u32, String)>,
+    pub devices: Vec<(u32, Device)>,
+    #[serde(default)]
+    pub categories: Vec<(NamedKey, DirectionCounters)>,
+    #[serde(default)]
+    pub periods: Vec<(NamedKey, DirectionCounters)>,
+    #[serde(default)]
+    pub countries: Vec<(NamedKey, DirectionCounters)>,
+    #[serde(default)]
+    pub asns: Vec<(NamedKey, DirectionCounters)>,
+    /// Time since which the counters accumulate
+    #[serde(default)]
+    pub created: Option<u64>,
+}
+
+impl Snapshot {
+    pub fn new(stats: &Stats, devices: &Devices, host: Option<String>) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self {
+            version: VERSION,
+            time,
+            host,
+            counters: stats.counters.clone().into_iter().collect(),
+            latencies: stats.latencies.clone().into_iter().collect(),
+            names: stats.names.clone().into_iter().collect(),
+            devices: devices.clone().into_iter().collect(),
+            categories: stats.categories.clone().into_iter().collect(),
+            periods: stats.periods.clone().into_iter().collect(),
+            countries: stats.countries.clone().into_iter().collect(),
+            asns: stats.asns.clone().into_iter().collect(),
+            created: stats.created,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            counters: self.counters.iter().cloned().collect(),
+            latencies: self.latencies.iter().cloned().collect(),
+            names: self.names.iter().cloned().collect(),
+            categories: self.categories.iter().cloned().collect(),
+            periods: self.periods.iter().cloned().collect(),
+            countries: self.countries.iter().cloned().collect(),
+            asns: self.asns.iter().cloned().collect(),
+            created: self.created,
+            // Since the start
+            evictions: 0,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<Snapshot, String> {
+    let content =
+        fs::read(path).map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    let snapshot: Snapshot = serde_json::from_slice(&content)
+        .map_err(|err| format!("Invalid snapshot {}: {err}", path.display()))?;
+    if snapshot.version != VERSION {
+        return Err(format!(
+            "Unsupported snapshot version {} in {}",
+            snapshot.version,
+            path.display()
+        ));
+    }
+    Ok(snapshot)
+}
+
+/// Write a file atomically (through a temporary file and a rename)
+fn write(path: &Path, content: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|err| format!("Unable to write {}: {err}", path.display()))
+}
+
+/// Latest snapshot of the state directory, if any
+pub fn load_latest(dir: &Path) -> Result<Option<Snapshot>, String> {
+    let path = dir.join(LATEST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    read(&path).map(Some)
+}
+
+/// Save a snapshot as the latest one
+///
+/// The first snapshot of each day (UTC) is also kept in the history,
+/// so that the traffic over a period can be computed from the
+/// difference between two snapshots.
+pub fn save(dir: &Path, snapshot: &Snapshot) -> Result<(), String> {
+    let history = dir.join(HISTORY);
+    fs::create_dir_all(&history)
+        .map_err(|err| format!("Unable to create {}: {err}", history.display()))?;
+    let content = serde_json::to_vec(snapshot).unwrap();
+    write(&dir.join(LATEST), &content)?;
+    let day = history.join(format!("{}.json", snapshot.time / 86400));
+    if !day.exists() {
+        write(&day, &content)?;
+    }
+    Ok(())
+}
+
+/// Snapshots of the history, by day (since the UNIX epoch)
+pub fn history(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir.join(HISTORY)) else {
+        return Vec::new();
+    };
+    let mut result = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let day = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some((day, path))
+        })
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
+/// Read a snapshot of the history
+pub fn load(path: &Path) -> Result<Snapshot, String> {
+    read(path)
+}
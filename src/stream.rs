@@ -0,0 +1,660 @@
+//! Reader of pcap and pcapng streams
+//!
+//! A pcapng stream may hold several interfaces, each with its own link
+//! type, name and timestamp resolution (from its interface description
+//! block), which are kept with its packets.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    sync::Arc,
+};
+
+use crate::{
+    link::Framing,
+    packet::{self, Linktype, Packet, PacketHeader},
+};
+
+const PCAP_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_NANOS: u32 = 0xa1b23c4d;
+
+const SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BYTE_ORDER: u32 = 0x1a2b3c4d;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const OBSOLETE_PACKET: u32 = 2;
+const SIMPLE_PACKET: u32 = 3;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPTION_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const IF_TSOFFSET: u16 = 14;
+
+/// Blocks and records beyond this length are deemed corrupted
+const MAX_LEN: usize = 16 << 20;
+
+/// Interface of the packets of a stream
+#[derive(Debug, Clone)]
+pub struct Interface {
+    /// Name of the interface the packets were captured on, when given
+    /// by the stream
+    pub name: Option<Arc<str>>,
+    pub linktype: Linktype,
+    /// Framing of the packets, `None` for unsupported link types
+    pub framing: Option<Framing>,
+    /// Timestamp units per second
+    resolution: u64,
+    /// Seconds added to the timestamps
+    offset: i64,
+}
+
+impl Interface {
+    fn new(linktype: Linktype) -> Self {
+        Self {
+            name: None,
+            linktype,
+            framing: Framing::new(linktype),
+            resolution: 1_000_000,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Pcap { big_endian: bool },
+    Pcapng { big_endian: bool },
+}
+
+pub struct Reader {
+    path: String,
+    input: Box<dyn Read + Send>,
+    format: Format,
+    interfaces: Vec<Interface>,
+    /// Interface of the last packet
+    current: usize,
+    buffer: Vec<u8>,
+    header: PacketHeader,
+    ended: bool,
+}
+
+fn read_u16(data: &[u8], big_endian: bool) -> u16 {
+    let bytes = [data[0], data[1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(data: &[u8], big_endian: bool) -> u32 {
+    let bytes = [data[0], data[1], data[2], data[3]];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Read exactly `buffer.len()` bytes, `Ok(false)` at the end of the
+/// stream
+fn read_exact_or_end(input: &mut dyn Read, buffer: &mut [u8]) -> io::Result<bool> {
+    match input.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+impl Reader {
+    /// Open a stream ("-" for the standard input), reading its header
+    pub fn open(path: &str) -> Result<Self, String> {
+        let input: Box<dyn Read + Send> = if path == "-" {
+            Box::new(io::stdin())
+        } else {
+            let file = File::open(path).map_err(|err| format!("Unable to read {path:?}: {err}"))?;
+            Box::new(BufReader::new(file))
+        };
+        Self::with_input(path, input)
+    }
+
+    /// Read the header of a stream from `input`, named `path` in the
+    /// errors
+    fn with_input(path: &str, input: Box<dyn Read + Send>) -> Result<Self, String> {
+        let mut reader = Self {
+            path: path.to_string(),
+            input,
+            format: Format::Pcap { big_endian: false },
+            interfaces: Vec::new(),
+            current: 0,
+            buffer: Vec::new(),
+            header: PacketHeader {
+                ts: libc::timeval {
+                    tv_sec: 0,
+                    tv_usec: 0,
+                },
+                caplen: 0,
+                len: 0,
+            },
+            ended: false,
+        };
+        let mut magic = [0; 4];
+        match read_exact_or_end(&mut reader.input, &mut magic) {
+            Ok(true) => {}
+            Ok(false) => return Err(format!("Empty stream {path:?}")),
+            Err(err) => return Err(format!("Unable to read {path:?}: {err}")),
+        }
+        let invalid = |err: String| format!("Invalid stream {path:?}: {err}");
+        if u32::from_le_bytes(magic) == SECTION_HEADER {
+            reader.read_section().map_err(invalid)?;
+            return Ok(reader);
+        }
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MICROS, _) => (false, false),
+            (PCAP_NANOS, _) => (false, true),
+            (_, PCAP_MICROS) => (true, false),
+            (_, PCAP_NANOS) => (true, true),
+            _ => return Err(invalid("not a pcap or pcapng stream".to_string())),
+        };
+        let mut header = [0; 20];
+        if !read_exact_or_end(&mut reader.input, &mut header).map_err(|err| err.to_string())? {
+            return Err(invalid("truncated header".to_string()));
+        }
+        // The upper bits hold the FCS length, if any
+        let linktype = read_u32(&header[16..], big_endian) & 0xffff;
+        let mut interface = Interface::new(Linktype(linktype as i32));
+        if nanos {
+            interface.resolution = 1_000_000_000;
+        }
+        reader.format = Format::Pcap { big_endian };
+        reader.interfaces.push(interface);
+        Ok(reader)
+    }
+
+    /// Link type of the first interface, the one of every packet for a
+    /// pcap stream (pcapng streams tell it for each interface)
+    pub fn datalink(&self) -> Option<Linktype> {
+        match self.format {
+            Format::Pcap { .. } => self.interfaces.first().map(|interface| interface.linktype),
+            Format::Pcapng { .. } => None,
+        }
+    }
+
+    /// Read the rest of a pcapng section header block (after its type),
+    /// starting a new section with its own byte order and interfaces
+    fn read_section(&mut self) -> Result<(), String> {
+        let mut start = [0; 8];
+        if !read_exact_or_end(&mut self.input, &mut start).map_err(|err| err.to_string())? {
+            return Err("truncated section header".to_string());
+        }
+        let big_endian = match u32::from_le_bytes(start[4..].try_into().unwrap()) {
+            BYTE_ORDER => false,
+            _ if u32::from_be_bytes(start[4..].try_into().unwrap()) == BYTE_ORDER => true,
+            _ => return Err("invalid byte order magic".to_string()),
+        };
+        let len = read_u32(&start, big_endian) as usize;
+        if !(16..=MAX_LEN).contains(&len) || !len.is_multiple_of(4) {
+            return Err(format!("invalid block length {len}"));
+        }
+        let mut rest = vec![0; len - 12];
+        if !read_exact_or_end(&mut self.input, &mut rest).map_err(|err| err.to_string())? {
+            return Err("truncated section header".to_string());
+        }
+        self.format = Format::Pcapng { big_endian };
+        self.interfaces.clear();
+        Ok(())
+    }
+
+    /// Read the next packet, `Ok(false)` at the end of the stream
+    fn read_packet(&mut self) -> Result<bool, String> {
+        match self.format {
+            Format::Pcap { big_endian } => self.read_record(big_endian),
+            Format::Pcapng { big_endian } => self.read_block(big_endian),
+        }
+    }
+
+    fn read_record(&mut self, big_endian: bool) -> Result<bool, String> {
+        let mut record = [0; 16];
+        if !read_exact_or_end(&mut self.input, &mut record).map_err(|err| err.to_string())? {
+            return Ok(false);
+        }
+        let caplen = read_u32(&record[8..], big_endian) as usize;
+        if caplen > MAX_LEN {
+            return Err(format!("invalid record length {caplen}"));
+        }
+        self.buffer.resize(caplen, 0);
+        if !read_exact_or_end(&mut self.input, &mut self.buffer).map_err(|err| err.to_string())? {
+            return Err("truncated record".to_string());
+        }
+        let interface = &self.interfaces[0];
+        let seconds = read_u32(&record, big_endian) as u64;
+        let fraction = read_u32(&record[4..], big_endian) as u64;
+        self.set_header(
+            0,
+            seconds * interface.resolution + fraction,
+            read_u32(&record[12..], big_endian),
+        );
+        Ok(true)
+    }
+
+    fn read_block(&mut self, big_endian: bool) -> Result<bool, String> {
+        loop {
+            let mut kind = [0; 4];
+            if !read_exact_or_end(&mut self.input, &mut kind).map_err(|err| err.to_string())? {
+                return Ok(false);
+            }
+            // The same in both byte orders
+            if u32::from_le_bytes(kind) == SECTION_HEADER {
+                self.read_section()?;
+                return self.read_packet();
+            }
+            let kind = read_u32(&kind, big_endian);
+            let mut len = [0; 4];
+            if !read_exact_or_end(&mut self.input, &mut len).map_err(|err| err.to_string())? {
+                return Err("truncated block".to_string());
+            }
+            let len = read_u32(&len, big_endian) as usize;
+            if !(12..=MAX_LEN).contains(&len) || !len.is_multiple_of(4) {
+                return Err(format!("invalid block length {len}"));
+            }
+            let mut body = vec![0; len - 8];
+            if !read_exact_or_end(&mut self.input, &mut body).map_err(|err| err.to_string())? {
+                return Err("truncated block".to_string());
+            }
+            // Without the trailing length
+            let body = &body[..len - 12];
+            let truncated = || format!("truncated block of type {kind}");
+            match kind {
+                INTERFACE_DESCRIPTION => {
+                    if body.len() < 8 {
+                        return Err(truncated());
+                    }
+                    let linktype = Linktype(read_u16(body, big_endian) as i32);
+                    let mut interface = Interface::new(linktype);
+                    read_options(&body[8..], big_endian, &mut interface);
+                    if interface.framing.is_none() {
+                        println!(
+                            "Skipping the packets of interface {} of {:?}: link type {} not \
+                             supported",
+                            interface.name.as_deref().unwrap_or("?"),
+                            self.path,
+                            linktype
+                                .get_name()
+                                .unwrap_or_else(|_| linktype.0.to_string())
+                        );
+                    }
+                    self.interfaces.push(interface);
+                }
+                ENHANCED_PACKET | OBSOLETE_PACKET => {
+                    if body.len() < 20 {
+                        return Err(truncated());
+                    }
+                    let id = match kind {
+                        ENHANCED_PACKET => read_u32(body, big_endian) as usize,
+                        _ => read_u16(body, big_endian) as usize,
+                    };
+                    if id >= self.interfaces.len() {
+                        return Err(format!("packet of the undeclared interface {id}"));
+                    }
+                    let caplen = read_u32(&body[12..], big_endian) as usize;
+                    let data = body.get(20..20 + caplen).ok_or_else(truncated)?;
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(data);
+                    let ts = (read_u32(&body[4..], big_endian) as u64) << 32
+                        | read_u32(&body[8..], big_endian) as u64;
+                    self.set_header(id, ts, read_u32(&body[16..], big_endian));
+                    return Ok(true);
+                }
+                SIMPLE_PACKET => {
+                    if body.len() < 4 || self.interfaces.is_empty() {
+                        return Err(truncated());
+                    }
+                    let len = read_u32(body, big_endian);
+                    let caplen = (len as usize).min(body.len() - 4);
+                    self.buffer.clear();
+                    self.buffer.extend_from_slice(&body[4..4 + caplen]);
+                    // Not timestamped, as the previous packet
+                    self.current = 0;
+                    self.header.caplen = caplen as u32;
+                    self.header.len = len;
+                    return Ok(true);
+                }
+                // Statistics, name resolution, ...
+                _ => {}
+            }
+        }
+    }
+
+    fn set_header(&mut self, interface: usize, ts: u64, len: u32) {
+        let Interface {
+            resolution, offset, ..
+        } = self.interfaces[interface];
+        let fraction = ts % resolution;
+        self.current = interface;
+        self.header = PacketHeader {
+            ts: libc::timeval {
+                tv_sec: i64::try_from(ts / resolution)
+                    .unwrap_or(i64::MAX)
+                    .saturating_add(offset) as libc::time_t,
+                tv_usec: (fraction as u128 * 1_000_000 / resolution as u128) as libc::suseconds_t,
+            },
+            caplen: self.buffer.len() as u32,
+            len,
+        };
+    }
+
+    /// Read the next packet, with its interface
+    pub fn next_packet(&mut self) -> Result<(Packet<'_>, &Interface), packet::Error> {
+        if self.ended {
+            return Err(packet::Error::NoMorePackets);
+        }
+        match self.read_packet() {
+            Ok(true) => {}
+            Ok(false) => {
+                self.ended = true;
+                return Err(packet::Error::NoMorePackets);
+            }
+            Err(err) => {
+                println!("Invalid stream {:?}: {err}", self.path);
+                self.ended = true;
+                return Err(packet::Error::NoMorePackets);
+            }
+        }
+        Ok((
+            Packet::new(&self.header, &self.buffer),
+            &self.interfaces[self.current],
+        ))
+    }
+}
+
+/// Read the options of an interface description block
+fn read_options(mut options: &[u8], big_endian: bool, interface: &mut Interface) {
+    while options.len() >= 4 {
+        let code = read_u16(options, big_endian);
+        let len = read_u16(&options[2..], big_endian) as usize;
+        let Some(value) = options.get(4..4 + len) else {
+            return;
+        };
+        match code {
+            OPTION_END => return,
+            IF_NAME => {
+                let name = String::from_utf8_lossy(value);
+                let name = name.trim_end_matches('\0');
+                if !name.is_empty() {
+                    interface.name = Some(Arc::from(name));
+                }
+            }
+            IF_TSRESOL => {
+                if let Some(&resolution) = value.first() {
+                    // Negative power of 2 with the high bit, else of 10
+                    let exponent = (resolution & 0x7f) as u32;
+                    let resolution = if resolution & 0x80 != 0 {
+                        1u64.checked_shl(exponent)
+                    } else {
+                        10u64.checked_pow(exponent)
+                    };
+                    if let Some(resolution) = resolution {
+                        interface.resolution = resolution;
+                    }
+                }
+            }
+            IF_TSOFFSET if len == 8 => {
+                let bytes = value.try_into().unwrap();
+                interface.offset = if big_endian {
+                    i64::from_be_bytes(bytes)
+                } else {
+                    i64::from_le_bytes(bytes)
+                };
+            }
+            _ => {}
+        }
+        // Padded to 32 bits
+        options = options.get((4 + len).div_ceil(4) * 4..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_bytes(value: u16, big_endian: bool) -> [u8; 2] {
+        if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(value: u32, big_endian: bool) -> [u8; 4] {
+        if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    }
+
+    fn padded(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(data.len().div_ceil(4) * 4, 0);
+        data
+    }
+
+    fn block(kind: u32, body: &[u8], big_endian: bool) -> Vec<u8> {
+        let body = padded(body);
+        let len = u32_bytes(12 + body.len() as u32, big_endian);
+        let mut block = u32_bytes(kind, big_endian).to_vec();
+        block.extend(len);
+        block.extend(body);
+        block.extend(len);
+        block
+    }
+
+    fn section(big_endian: bool) -> Vec<u8> {
+        let mut body = u32_bytes(BYTE_ORDER, big_endian).to_vec();
+        body.extend(u16_bytes(1, big_endian));
+        body.extend(u16_bytes(0, big_endian));
+        // Unknown section length
+        body.extend([0xff; 8]);
+        block(SECTION_HEADER, &body, big_endian)
+    }
+
+    fn interface(linktype: u16, options: &[(u16, &[u8])], big_endian: bool) -> Vec<u8> {
+        let mut body = u16_bytes(linktype, big_endian).to_vec();
+        body.extend([0, 0]);
+        body.extend(u32_bytes(65535, big_endian));
+        for (code, value) in options {
+            body.extend(u16_bytes(*code, big_endian));
+            body.extend(u16_bytes(value.len() as u16, big_endian));
+            body.extend(padded(value));
+        }
+        body.extend([0; 4]);
+        block(INTERFACE_DESCRIPTION, &body, big_endian)
+    }
+
+    /// Enhanced packet block, or obsolete packet block when `drops` is
+    /// given
+    fn packet(id: u32, drops: Option<u16>, ts: u64, data: &[u8], big_endian: bool) -> Vec<u8> {
+        let mut body = match drops {
+            Some(drops) => {
+                let mut body = u16_bytes(id as u16, big_endian).to_vec();
+                body.extend(u16_bytes(drops, big_endian));
+                body
+            }
+            None => u32_bytes(id, big_endian).to_vec(),
+        };
+        body.extend(u32_bytes((ts >> 32) as u32, big_endian));
+        body.extend(u32_bytes(ts as u32, big_endian));
+        body.extend(u32_bytes(data.len() as u32, big_endian));
+        body.extend(u32_bytes(data.len() as u32 + 100, big_endian));
+        body.extend(data);
+        let kind = match drops {
+            Some(_) => OBSOLETE_PACKET,
+            None => ENHANCED_PACKET,
+        };
+        block(kind, &body, big_endian)
+    }
+
+    /// Interface, link type, timestamp, captured and original lengths,
+    /// and data of a packet
+    type Parsed = (
+        Option<String>,
+        i32,
+        (libc::time_t, libc::suseconds_t),
+        u32,
+        u32,
+        Vec<u8>,
+    );
+
+    fn read_all(stream: Vec<u8>) -> Vec<Parsed> {
+        let mut reader = Reader::with_input("test", Box::new(io::Cursor::new(stream))).unwrap();
+        let mut packets = Vec::new();
+        while let Ok((packet, interface)) = reader.next_packet() {
+            packets.push((
+                interface.name.as_deref().map(str::to_string),
+                interface.linktype.0,
+                (packet.header.ts.tv_sec, packet.header.ts.tv_usec),
+                packet.header.caplen,
+                packet.header.len,
+                packet.data.to_vec(),
+            ));
+        }
+        packets
+    }
+
+    #[test]
+    fn pcap() {
+        for (big_endian, magic, fraction) in [
+            (false, PCAP_MICROS, 250_000),
+            (true, PCAP_NANOS, 250_000_000),
+        ] {
+            let mut stream = u32_bytes(magic, big_endian).to_vec();
+            stream.extend(u16_bytes(2, big_endian));
+            stream.extend(u16_bytes(4, big_endian));
+            stream.extend([0; 8]);
+            stream.extend(u32_bytes(65535, big_endian));
+            // With a FCS length in the upper bits
+            stream.extend(u32_bytes(0x1000_0000 | 113, big_endian));
+            for value in [1_600_000_000, fraction, 3, 60] {
+                stream.extend(u32_bytes(value, big_endian));
+            }
+            stream.extend([1, 2, 3]);
+            let reader =
+                Reader::with_input("test", Box::new(io::Cursor::new(stream.clone()))).unwrap();
+            assert_eq!(reader.datalink(), Some(Linktype::LINUX_SLL));
+            assert_eq!(
+                read_all(stream.clone()),
+                [(None, 113, (1_600_000_000, 250_000), 3, 60, vec![1, 2, 3])]
+            );
+            // Truncated record
+            assert_eq!(read_all(stream[..stream.len() - 1].to_vec()), []);
+        }
+    }
+
+    #[test]
+    fn pcapng() {
+        let name = |name: &str| Some(name.to_string());
+        let mut stream = section(false);
+        stream.extend(interface(
+            1,
+            &[(IF_NAME, b"eth0"), (IF_TSRESOL, &[9])],
+            false,
+        ));
+        stream.extend(interface(
+            101,
+            &[
+                (IF_TSRESOL, &[0x80 | 10]),
+                (IF_TSOFFSET, &100i64.to_le_bytes()),
+            ],
+            false,
+        ));
+        // Interface statistics, skipped
+        stream.extend(block(5, &[0; 20], false));
+        stream.extend(packet(
+            0,
+            None,
+            1_500_000_000_250_000_000,
+            &[1, 2, 3, 4, 5],
+            false,
+        ));
+        stream.extend(packet(1, Some(0), 3 * 1024 + 512, &[6], false));
+        let mut simple = u32_bytes(60, false).to_vec();
+        simple.extend([7, 8]);
+        stream.extend(block(SIMPLE_PACKET, &simple, false));
+        // New section, in the other byte order and with its own
+        // interfaces
+        stream.extend(section(true));
+        stream.extend(interface(1, &[(IF_NAME, b"eth1")], true));
+        stream.extend(packet(0, None, 5_000_001, &[9], true));
+        stream.extend(packet(1, None, 0, &[10], true));
+        stream.extend(packet(0, None, 0, &[11], true));
+
+        assert_eq!(
+            read_all(stream),
+            [
+                (
+                    name("eth0"),
+                    1,
+                    (1_500_000_000, 250_000),
+                    5,
+                    105,
+                    vec![1, 2, 3, 4, 5]
+                ),
+                (None, 101, (103, 500_000), 1, 101, vec![6]),
+                // Up to the end of the block, with the timestamp of the
+                // previous packet
+                (name("eth0"), 1, (103, 500_000), 4, 60, vec![7, 8, 0, 0]),
+                (name("eth1"), 1, (5, 1), 1, 101, vec![9]),
+                // Then stopped at the packet of an undeclared interface
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_overflow() {
+        for (resolution, offset, ts, seconds) in [
+            (0, i64::MAX, 10, i64::MAX),
+            (0, 0, u64::MAX, i64::MAX),
+            (6, i64::MIN, 0, i64::MIN),
+            (0x80 | 63, -1, u64::MAX, 0),
+        ] {
+            let mut stream = section(false);
+            stream.extend(interface(
+                1,
+                &[
+                    (IF_TSRESOL, &[resolution]),
+                    (IF_TSOFFSET, &offset.to_le_bytes()),
+                ],
+                false,
+            ));
+            stream.extend(packet(0, None, ts, &[], false));
+            let packets = read_all(stream);
+            assert_eq!(packets[0].2 .0, seconds, "{resolution} {offset} {ts}");
+        }
+    }
+
+    #[test]
+    fn invalid_streams() {
+        let error = |stream: Vec<u8>| {
+            Reader::with_input("test", Box::new(io::Cursor::new(stream)))
+                .err()
+                .unwrap()
+        };
+        assert_eq!(error(Vec::new()), "Empty stream \"test\"");
+        assert!(error(b"GIF89a".to_vec()).ends_with("not a pcap or pcapng stream"));
+        assert!(error(PCAP_MICROS.to_le_bytes().to_vec()).ends_with("truncated header"));
+        let mut stream = section(false);
+        stream[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(error(stream).ends_with("invalid byte order magic"));
+        let mut stream = section(true);
+        stream[4..8].copy_from_slice(&13u32.to_be_bytes());
+        assert!(error(stream).ends_with("invalid block length 13"));
+
+        // Packet longer than its block
+        let mut stream = section(false);
+        stream.extend(interface(1, &[], false));
+        let mut epb = packet(0, None, 0, &[1, 2, 3, 4], false);
+        epb[20..24].copy_from_slice(&8u32.to_le_bytes());
+        stream.extend(epb);
+        assert_eq!(read_all(stream), []);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    env,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    time::Duration,
+};
+
+use crate::Listen;
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether systemd passed a listening socket to this process
+pub fn activated() -> bool {
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok());
+    for_us && count.is_some_and(|count: u32| count > 0)
+}
+
+/// Listening socket passed by systemd (socket activation), TCP or Unix
+///
+/// Only the first socket is used, when several are passed.
+pub fn listener() -> Result<Option<Listen>, String> {
+    if !activated() {
+        return Ok(None);
+    }
+    let fd = LISTEN_FDS_START;
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let family = unsafe {
+        // Not inherited by the commands run by the exporter
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+            let err = std::io::Error::last_os_error();
+            return Err(format!("Invalid socket passed by systemd: {err}"));
+        }
+        addr.ss_family as libc::c_int
+    };
+    // The descriptor is owned from now on
+    let listener = match family {
+        libc::AF_INET | libc::AF_INET6 => {
+            Listen::Tcp(unsafe { std::net::TcpListener::from_raw_fd(fd) })
+        }
+        libc::AF_UNIX => Listen::Unix(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) }),
+        _ => return Err("Unsupported socket passed by systemd".to_string()),
+    };
+    Ok(Some(listener))
+}
+
+/// Send a state (`READY=1`, `WATCHDOG=1`...) to systemd, when run by a
+/// `Type=notify` unit
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    // Abstract sockets start with "@"
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path),
+    };
+    let result =
+        UnixDatagram::unbound().and_then(|socket| socket.send_to_addr(state.as_bytes(), &addr?));
+    if let Err(err) = result {
+        println!("Unable to notify systemd: {err}");
+    }
+}
+
+/// Tell systemd that the exporter is alive, at half the watchdog
+/// interval, as long as `alive` holds (when the watchdog is enabled)
+pub async fn watchdog(alive: impl Fn() -> bool) {
+    let for_us = env::var("WATCHDOG_PID")
+        .map(|pid| pid == std::process::id().to_string())
+        .unwrap_or(true);
+    let usec = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec > 0);
+    let Some(usec) = usec.filter(|_| for_us) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+    loop {
+        interval.tick().await;
+        if alive() {
+            notify("WATCHDOG=1");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{fs, io::BufReader, path::Path, sync::Arc, time::Duration};
+
+use hyper::server::accept::{self, Accept};
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Time given to a client to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections accepted but not yet served
+const BACKLOG: usize = 64;
+
+/// Build the configuration of the listener, requiring a client
+/// certificate signed by `client_ca` when given
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>, String> {
+    let chain = certificates(cert)?;
+    if chain.is_empty() {
+        return Err(format!("No certificate in {}", cert.display()));
+    }
+    let key = private_key(key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certificates(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| format!("Invalid certificate in {}: {err}", path.display()))?;
+            }
+            if roots.is_empty() {
+                return Err(format!("No certificate in {}", path.display()));
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(chain, key)
+        .map_err(|err| format!("Invalid TLS certificate or key: {err}"))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Same configuration, for HTTP/2 only (gRPC)
+pub fn http2(config: &ServerConfig) -> Arc<ServerConfig> {
+    let mut config = config.clone();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    Arc::new(config)
+}
+
+/// Read the certificates of a PEM file
+fn certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file =
+        fs::File::open(path).map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|err| format!("Invalid certificates {}: {err}", path.display()))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Read the first private key (PKCS#8, RSA or EC) of a PEM file
+fn private_key(path: &Path) -> Result<PrivateKey, String> {
+    let file =
+        fs::File::open(path).map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| format!("Invalid private key {}: {err}", path.display()))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key in {}", path.display()))
+}
+
+/// Accept the TLS connections of a listener, for the HTTP server
+pub fn listen(
+    listener: std::net::TcpListener,
+    config: Arc<ServerConfig>,
+) -> Result<impl Accept<Conn = TlsStream<TcpStream>, Error = std::io::Error>, String> {
+    incoming(listener, config).map(accept::from_stream)
+}
+
+/// Stream of the TLS connections of a listener
+///
+/// The handshakes are done in the background, so that a slow client
+/// does not hold the other ones. Failed handshakes are only dropped.
+pub fn incoming(
+    listener: std::net::TcpListener,
+    config: Arc<ServerConfig>,
+) -> Result<ReceiverStream<std::io::Result<TlsStream<TcpStream>>>, String> {
+    let listener = listener
+        .set_nonblocking(true)
+        .and_then(|()| TcpListener::from_std(listener))
+        .map_err(|err| err.to_string())?;
+    let acceptor = TlsAcceptor::from(config);
+    let (sender, receiver) = mpsc::channel(BACKLOG);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    println!("Unable to accept a connection: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                if let Ok(Ok(stream)) = handshake.await {
+                    let _ = sender.send(Ok(stream)).await;
+                }
+            });
+        }
+    });
+    Ok(ReceiverStream::new(receiver))
+}
@@ -0,0 +1,83 @@
+use std::{
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{enrich::SharedEnricher, laptop};
+
+/// How often the wireless association is refreshed
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Wireless network the uplink is associated with
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct Wifi {
+    pub ssid: String,
+    pub bssid: String,
+}
+
+/// Current association, shared with the capture thread
+pub type SharedWifi = Arc<Mutex<Option<Arc<Wifi>>>>;
+
+/// Check that `iw` can be run, the associations being queried through it
+pub fn check() -> Result<(), String> {
+    match Command::new("iw").arg("--version").output() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!(
+            "The wireless labels need the iw command (from the iw package): {err}"
+        )),
+    }
+}
+
+/// Query nl80211 (through `iw`) for the current association of the
+/// given interface
+///
+/// Returns `None` when the interface is not wireless or not associated,
+/// and an error when `iw` is not available.
+pub fn query(interface: &str) -> Result<Option<Wifi>, String> {
+    let output = Command::new("iw")
+        .args(["dev", interface, "link"])
+        .output()
+        .map_err(|err| format!("Unable to run iw: {err}"))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_link(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_link(output: &str) -> Option<Wifi> {
+    let mut lines = output.lines();
+    // Connected to aa:bb:cc:dd:ee:ff (on wlan0)
+    let bssid = lines
+        .next()?
+        .strip_prefix("Connected to ")?
+        .split_whitespace()
+        .next()?
+        .to_string();
+    let ssid = lines
+        .find_map(|line| line.trim().strip_prefix("SSID: "))?
+        .to_string();
+    Some(Wifi { ssid, bssid })
+}
+
+/// Poll the association of the interface in the background
+///
+/// Without an interface, the interface of the default route is used.
+pub fn spawn_poller(enricher: SharedEnricher, interface: Option<String>, current: SharedWifi) {
+    thread::spawn(move || loop {
+        let interface = interface.clone();
+        let current = current.clone();
+        enricher.spawn_blocking("wifi", move || {
+            let wifi = match interface.or_else(laptop::default_route_interface) {
+                Some(interface) => query(&interface)?.map(Arc::new),
+                None => None,
+            };
+            *current.lock().unwrap() = wifi;
+            Ok(())
+        });
+        thread::sleep(POLL_INTERVAL);
+    });
+}
@@ -0,0 +1,290 @@
+use std::{fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+const MINUTES_PER_DAY: u32 = 1440;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+
+/// Day of the UNIX epoch (a Thursday), counted from Monday
+const EPOCH_WEEKDAY: i64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// Time of the day, `HH:MM` (up to `24:00`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u32);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let parsed = s
+            .split_once(':')
+            .and_then(|(hours, minutes)| {
+                Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+            })
+            .filter(|(hours, minutes)| *minutes < 60 && hours * 60 + minutes <= MINUTES_PER_DAY);
+        match parsed {
+            Some((hours, minutes)) => Ok(TimeOfDay(hours * 60 + minutes)),
+            None => Err(format!("Invalid time {s:?} (expected HH:MM)")),
+        }
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// A time window of the accounting periods (configuration file only)
+///
+/// The window ends the next day when `end` is not after `start`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Window {
+    /// Value of the "period" label, which may be shared by several
+    /// windows
+    pub period: String,
+    /// Days when the window starts, every day when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    #[serde(default = "midnight")]
+    pub start: TimeOfDay,
+    #[serde(default = "end_of_day")]
+    pub end: TimeOfDay,
+}
+
+fn midnight() -> TimeOfDay {
+    TimeOfDay(0)
+}
+
+fn end_of_day() -> TimeOfDay {
+    TimeOfDay(MINUTES_PER_DAY)
+}
+
+/// The windows, as ranges of minutes of the week
+pub struct Schedule {
+    /// Period, first minute (from Monday 00:00) and duration
+    ranges: Vec<(Arc<str>, u32, u32)>,
+}
+
+impl Schedule {
+    pub fn new(windows: &[Window]) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for window in windows {
+            if window.period.is_empty() {
+                return Err("Time windows need a \"period\"".to_string());
+            }
+            let period = Arc::<str>::from(window.period.as_str());
+            let (start, end) = (window.start.0, window.end.0);
+            let duration = if end > start {
+                end - start
+            } else {
+                end + MINUTES_PER_DAY - start
+            };
+            let days = if window.days.is_empty() {
+                (0..7).collect::<Vec<_>>()
+            } else {
+                window.days.iter().map(|day| *day as u32).collect()
+            };
+            for day in days {
+                ranges.push((period.clone(), day * MINUTES_PER_DAY + start, duration));
+            }
+        }
+        Ok(Self { ranges })
+    }
+
+    /// Period of a time (seconds since the UNIX epoch, shifted to the
+    /// local time), from the first matching window
+    pub fn period(&self, local_time: i64) -> Option<Arc<str>> {
+        let minute = (local_time.div_euclid(60) + EPOCH_WEEKDAY * MINUTES_PER_DAY as i64)
+            .rem_euclid(MINUTES_PER_WEEK as i64) as u32;
+        self.ranges
+            .iter()
+            .find(|(_, start, duration)| {
+                (minute + MINUTES_PER_WEEK - start) % MINUTES_PER_WEEK < *duration
+            })
+            .map(|(period, _, _)| period.clone())
+    }
+}
+
+/// Local time of the packets, the offset from UTC being looked up for
+/// their own time (DST changes, replayed captures), once a minute
+#[derive(Default)]
+pub struct LocalTime {
+    /// Minute (since the UNIX epoch) of the last lookup, and its offset
+    last: Option<(i64, i64)>,
+}
+
+impl LocalTime {
+    /// Shift a time (seconds since the UNIX epoch) to the local time
+    pub fn shift(&mut self, time: i64) -> i64 {
+        let minute = time.div_euclid(60);
+        let offset = match self.last {
+            Some((last, offset)) if last == minute => offset,
+            _ => {
+                let offset = utc_offset(time);
+                self.last = Some((minute, offset));
+                offset
+            }
+        };
+        time.saturating_add(offset)
+    }
+}
+
+/// Offset of the local time from UTC (in seconds) at a time, none when
+/// out of the range of the C library
+fn utc_offset(time: i64) -> i64 {
+    unsafe {
+        let time = time as libc::time_t;
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time of a minute of the week of Monday, January 5, 1970, in
+    /// seconds since the UNIX epoch
+    fn at(day: i64, hours: i64, minutes: i64) -> i64 {
+        ((4 + day) * MINUTES_PER_DAY as i64 + hours * 60 + minutes) * 60
+    }
+
+    fn windows(source: &str) -> Result<Vec<Window>, toml::de::Error> {
+        #[derive(Deserialize)]
+        struct Windows {
+            window: Vec<Window>,
+        }
+        toml::from_str::<Windows>(source).map(|windows| windows.window)
+    }
+
+    #[test]
+    fn time_of_day() {
+        for (text, minutes) in [("00:00", 0), ("08:30", 510), ("24:00", 1440)] {
+            let time = TimeOfDay::try_from(text.to_string()).unwrap();
+            assert_eq!((time.0, time.to_string()), (minutes, text.to_string()));
+        }
+        assert_eq!(TimeOfDay::try_from("7:05".to_string()).unwrap().0, 425);
+        for text in ["24:01", "12:60", "8h", "08:", ":30", "-1:00", ""] {
+            assert!(TimeOfDay::try_from(text.to_string()).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let parsed = windows(
+            r#"
+            [[window]]
+            period = "any"
+
+            [[window]]
+            period = "night"
+            days = ["sat", "sun"]
+            start = "22:00"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(parsed[0].days, []);
+        assert_eq!((parsed[0].start, parsed[0].end), (midnight(), end_of_day()));
+        assert_eq!(parsed[1].days, [Weekday::Sat, Weekday::Sun]);
+        assert_eq!(
+            (parsed[1].start, parsed[1].end),
+            (TimeOfDay(1320), end_of_day())
+        );
+
+        for invalid in [
+            "[[window]]\ndays = [\"mon\"]",
+            "[[window]]\nperiod = \"a\"\nday = [\"mon\"]",
+            "[[window]]\nperiod = \"a\"\ndays = [\"monday\"]",
+            "[[window]]\nperiod = \"a\"\nstart = \"25:00\"",
+        ] {
+            assert!(windows(invalid).is_err(), "{invalid}");
+        }
+        let unnamed = windows("[[window]]\nperiod = \"\"").unwrap();
+        assert!(Schedule::new(&unnamed).is_err());
+    }
+
+    #[test]
+    fn period() {
+        let schedule = Schedule::new(
+            &windows(
+                r#"
+                [[window]]
+                period = "weekend"
+                days = ["sat", "sun"]
+
+                [[window]]
+                period = "night"
+                start = "22:00"
+                end = "06:00"
+
+                [[window]]
+                period = "monday"
+                days = ["mon"]
+                start = "09:00"
+                end = "09:00"
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let period = |time| schedule.period(time).map(|period| period.to_string());
+        // The first matching window wins
+        assert_eq!(period(at(5, 23, 0)).as_deref(), Some("weekend"));
+        assert_eq!(period(at(0, 21, 59)).as_deref(), Some("monday"));
+        // Overnight, until the end excluded
+        assert_eq!(period(at(2, 22, 0)).as_deref(), Some("night"));
+        assert_eq!(period(at(3, 5, 59)).as_deref(), Some("night"));
+        assert_eq!(period(at(3, 6, 0)), None);
+        // From Sunday night to Monday morning, across the week
+        assert_eq!(period(at(7, 1, 0)).as_deref(), Some("night"));
+        assert_eq!(period(at(0, 1, 0)).as_deref(), Some("night"));
+        // Same start and end: a whole day, until the next morning
+        assert_eq!(period(at(0, 8, 59)), None);
+        assert_eq!(period(at(0, 9, 0)).as_deref(), Some("monday"));
+        assert_eq!(period(at(1, 8, 59)).as_deref(), Some("monday"));
+        assert_eq!(period(at(1, 9, 0)), None);
+        // Before the epoch, and the extreme times
+        assert_eq!(period(at(-7, 12, 0)).as_deref(), Some("monday"));
+        assert_eq!(period(at(-2, 12, 0)).as_deref(), Some("weekend"));
+        for time in [i64::MIN, i64::MAX] {
+            period(time);
+        }
+    }
+
+    #[test]
+    fn local_time() {
+        let mut local_time = LocalTime::default();
+        let offset = local_time.shift(at(0, 12, 0)) - at(0, 12, 0);
+        assert_eq!(
+            local_time.shift(at(0, 12, 0) + 59),
+            at(0, 12, 0) + 59 + offset
+        );
+        // Saturated, as the timestamps of the captures can be
+        assert!(local_time.shift(i64::MAX) >= i64::MAX - 86400);
+        assert!(local_time.shift(i64::MIN) <= i64::MIN + 86400);
+    }
+}
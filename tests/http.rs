@@ -0,0 +1,708 @@
+//! Requests to the HTTP API, with synthetic statistics
+//!
+//! The requests go through `txne::http::router()` with `oneshot`, so the
+//! routing and the middlewares (compression, ...) are exercised as when
+//! served.
+
+use std::{fs, net::Ipv4Addr, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+};
+use prost::Message;
+use tower::ServiceExt;
+use txne::http::{router, Compat, Counters, Protocol, Series, State};
+
+const LOCAL_1: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const LOCAL_2: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const LOCAL_3: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+fn series(ip: Option<Ipv4Addr>) -> Series {
+    Series {
+        ip,
+        ..Series::default()
+    }
+}
+
+/// Inbound counters, and outbound ones half of them
+fn counters(packets: u64, bytes: u64) -> (Counters, Counters) {
+    (
+        Counters { packets, bytes },
+        Counters {
+            packets: packets / 2,
+            bytes: bytes / 2,
+        },
+    )
+}
+
+/// Count TCP traffic of a series, the outbound one being half the
+/// inbound one
+fn tcp(state: &State, series: &Series, packets: u64, bytes: u64) {
+    let (inbound, outbound) = counters(packets, bytes);
+    state.add(series, Protocol::Tcp, inbound, outbound);
+}
+
+/// Count traffic of a category, as `tcp()`
+fn category(state: &State, ip: Option<Ipv4Addr>, name: &str, packets: u64, bytes: u64) {
+    let (inbound, outbound) = counters(packets, bytes);
+    state.add_category(ip, name, inbound, outbound);
+}
+
+/// State of an exporter without any optional feature
+fn state() -> State {
+    State::new(1_700_000_000)
+}
+
+/// Two local IPs, counted out of order, and the overflow entry
+fn stats(state: State) -> State {
+    state.created(1_600_000_000);
+    tcp(&state, &series(Some(LOCAL_2)), 20, 2000);
+    tcp(&state, &series(Some(LOCAL_1)), 10, 1000);
+    tcp(&state, &series(None), 4, 400);
+    state
+}
+
+/// Content type and body of the response to a GET
+async fn get(state: &State, uri: &str, accept: Option<&str>) -> (StatusCode, String, String) {
+    let mut request = Request::get(uri);
+    if let Some(accept) = accept {
+        request = request.header(header::ACCEPT, accept);
+    }
+    let response = router(state)
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (
+        status,
+        content_type,
+        String::from_utf8_lossy(&body).into_owned(),
+    )
+}
+
+/// The messages of the protobuf exposition format
+/// (`io.prometheus.client`), as far as the exporter uses them
+mod metrics {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct MetricFamily {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub help: String,
+        #[prost(int32, tag = "3")]
+        pub kind: i32,
+        #[prost(message, repeated, tag = "4")]
+        pub metric: Vec<Metric>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metric {
+        #[prost(message, repeated, tag = "1")]
+        pub label: Vec<LabelPair>,
+        #[prost(message, optional, tag = "2")]
+        pub gauge: Option<Single>,
+        #[prost(message, optional, tag = "3")]
+        pub counter: Option<Single>,
+        #[prost(message, optional, tag = "7")]
+        pub histogram: Option<Histogram>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LabelPair {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    /// A counter or a gauge
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Single {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Histogram {
+        #[prost(uint64, tag = "1")]
+        pub sample_count: u64,
+        #[prost(double, tag = "2")]
+        pub sample_sum: f64,
+        #[prost(message, repeated, tag = "3")]
+        pub bucket: Vec<Bucket>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Bucket {
+        #[prost(uint64, tag = "1")]
+        pub cumulative_count: u64,
+        #[prost(double, tag = "2")]
+        pub upper_bound: f64,
+    }
+}
+
+/// Position of a line in a body, failing when missing
+fn position(body: &str, line: &str) -> usize {
+    body.lines()
+        .position(|candidate| candidate == line)
+        .unwrap_or_else(|| panic!("Missing {line:?} in:\n{body}"))
+}
+
+#[tokio::test]
+async fn text_format() {
+    let (status, content_type, body) = get(&stats(state()), "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type, TEXT_CONTENT_TYPE);
+    let help = position(
+        &body,
+        "# HELP txne_network_bytes_total Bytes entering or leaving the network",
+    );
+    let kind = position(&body, "# TYPE txne_network_bytes_total counter");
+    let other = position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="other",protocol="tcp"} 400"#,
+    );
+    let first = position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.1",protocol="tcp"} 1000"#,
+    );
+    let second = position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.2",protocol="tcp"} 2000"#,
+    );
+    let outbound = position(
+        &body,
+        r#"txne_network_bytes_total{direction="outbound",ip="other",protocol="tcp"} 200"#,
+    );
+    assert!(help < kind && kind < other && other < first && first < second && second < outbound);
+    position(
+        &body,
+        r#"txne_network_packets_total{direction="outbound",ip="10.0.0.2",protocol="tcp"} 10"#,
+    );
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.2",protocol="udp"} 0"#,
+    );
+    position(&body, "txne_network_tracked_ips 2");
+}
+
+#[tokio::test]
+async fn same_output_for_the_same_stats() {
+    let (_, _, first) = get(&stats(state()), "/metrics", None).await;
+    let (_, _, second) = get(&stats(state()), "/metrics", None).await;
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn escaping() {
+    let state = state().device_names();
+    let series = Series {
+        wifi: Some((
+            "Café \"5G\" \\ guest\nnet".to_string(),
+            "00:11:22:33:44:55".to_string(),
+        )),
+        ..series(Some(LOCAL_1))
+    };
+    tcp(&state, &series, 1, 100);
+    state.add_device(LOCAL_1, Some("Living \"Room\""), None);
+
+    let line = concat!(
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.1","#,
+        r#"name="Living \"Room\"",protocol="tcp","#,
+        r#"ssid="Café \"5G\" \\ guest\nnet",bssid="00:11:22:33:44:55"} 100"#,
+    );
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(&body, line);
+    let accept = "application/openmetrics-text; version=1.0.0";
+    let (_, _, body) = get(&state, "/metrics", Some(accept)).await;
+    position(&body, line);
+}
+
+#[tokio::test]
+async fn label_modes() {
+    let state = state().host("router");
+    let series = Series {
+        vlan: Some(10),
+        interface: Some("eth0".to_string()),
+        port: Some("https".to_string()),
+        ..series(Some(LOCAL_1))
+    };
+    tcp(&state, &series, 6, 600);
+    category(&state, Some(LOCAL_1), "video", 6, 600);
+
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(
+        &body,
+        concat!(
+            r#"txne_network_packets_total{host="router",interface="eth0","#,
+            r#"direction="outbound",ip="10.0.0.1",protocol="tcp",port="https",vlan="10"} 3"#,
+        ),
+    );
+    position(
+        &body,
+        concat!(
+            r#"txne_network_category_bytes_total{host="router",direction="inbound","#,
+            r#"ip="10.0.0.1",category="video"} 600"#,
+        ),
+    );
+    position(&body, r#"txne_network_tracked_ips{host="router"} 1"#);
+}
+
+#[tokio::test]
+async fn aggregation() {
+    let state = stats(state());
+    let (_, _, body) = get(&state, "/metrics?aggregate=ip", None).await;
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",protocol="tcp"} 3400"#,
+    );
+    assert!(!body.contains("ip="));
+
+    let (_, _, body) = get(&state, "/metrics?aggregate=protocol", None).await;
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.1"} 1000"#,
+    );
+    assert!(!body.contains("protocol="));
+
+    let (_, _, body) = get(&state, "/metrics?aggregate=ip,protocol", None).await;
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound"} 3400"#,
+    );
+
+    let (status, _, _) = get(&state, "/metrics?aggregate=mac", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn aggregation_by_group() {
+    let (status, _, _) = get(&stats(state()), "/metrics?aggregate=group", None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let state = stats(state().device_names());
+    tcp(&state, &series(Some(LOCAL_3)), 1, 100);
+    category(&state, Some(LOCAL_2), "video", 2, 200);
+    for ip in [LOCAL_1, LOCAL_2] {
+        state.add_device(ip, Some("NAS"), None);
+    }
+
+    let (_, _, body) = get(&state, "/metrics?aggregate=group", None).await;
+    // The overflow entry and the IPs without a name
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",name="unknown",protocol="tcp"} 500"#,
+    );
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",name="NAS",protocol="tcp"} 3000"#,
+    );
+    position(
+        &body,
+        r#"txne_network_category_bytes_total{direction="inbound",name="NAS",category="video"} 200"#,
+    );
+    assert!(!body.contains("ip="));
+
+    let (_, _, body) = get(&state, "/metrics?aggregate=group,ip", None).await;
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",protocol="tcp"} 3500"#,
+    );
+}
+
+/// The percentiles of a group, from the sums of the throughputs of its
+/// members
+#[tokio::test]
+async fn percentiles_by_group() {
+    let state = stats(state().device_names().percentiles());
+    for ip in [LOCAL_1, LOCAL_2] {
+        state.add_device(ip, Some("NAS"), None);
+    }
+    state.sample_throughput(1_700_000_100);
+    // 1 and 2 Mbps inbound over 5 minutes, half of them outbound
+    tcp(&state, &series(Some(LOCAL_1)), 1, 37_500_000);
+    tcp(&state, &series(Some(LOCAL_2)), 1, 75_000_000);
+    state.sample_throughput(1_700_000_400);
+
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(
+        &body,
+        r#"txne_network_throughput_p95_bytes_per_second{direction="inbound",ip="10.0.0.1",name="NAS"} 125000"#,
+    );
+    let (_, _, body) = get(&state, "/metrics?aggregate=group", None).await;
+    position(
+        &body,
+        r#"txne_network_throughput_p95_bytes_per_second{direction="inbound",name="NAS"} 375000"#,
+    );
+    position(
+        &body,
+        r#"txne_network_throughput_p95_bytes_per_second{direction="outbound",name="NAS"} 187500"#,
+    );
+    position(
+        &body,
+        r#"txne_network_throughput_p95_bytes_per_second{direction="inbound",name="unknown"} 0"#,
+    );
+    assert!(!body.contains("ip="));
+
+    let (_, _, body) = get(&state, "/metrics?aggregate=ip", None).await;
+    assert!(!body.contains("throughput_p95"));
+}
+
+#[tokio::test]
+async fn minimum_bytes() {
+    let state = stats(state());
+    let (_, _, body) = get(&state, "/metrics?min_bytes=1500", None).await;
+    assert!(body.contains(r#"ip="10.0.0.1""#));
+    assert!(!body.contains(r#"ip="other""#));
+    // 600 bytes in both directions
+    let (_, _, body) = get(&state, "/metrics?min_bytes=601", None).await;
+    assert!(!body.contains(r#"ip="other""#));
+    let (_, _, body) = get(&state, "/metrics?min_bytes=600", None).await;
+    assert!(body.contains(r#"ip="other""#));
+
+    // 1500 bytes over two ports, with its other series
+    for (port, bytes) in [("http", 600), ("https", 400)] {
+        let series = Series {
+            port: Some(port.to_string()),
+            ..series(Some(LOCAL_3))
+        };
+        tcp(&state, &series, 1, bytes);
+    }
+    category(&state, Some(LOCAL_3), "web", 1, 100);
+    category(&state, None, "web", 1, 100);
+    let (_, _, body) = get(&state, "/metrics?min_bytes=1500", None).await;
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.3",protocol="tcp",port="http"} 600"#,
+    );
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.3",protocol="tcp",port="https"} 400"#,
+    );
+    position(
+        &body,
+        r#"txne_network_category_bytes_total{direction="inbound",ip="10.0.0.3",category="web"} 100"#,
+    );
+    assert!(!body.contains(r#"ip="other""#));
+}
+
+#[tokio::test]
+async fn evictions() {
+    let state = stats(state());
+    state.add_evictions(1);
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(&body, "txne_network_tracked_ips 2");
+    position(&body, "txne_network_evictions_total 1");
+}
+
+#[tokio::test]
+async fn namespace() {
+    let state = stats(state().namespace(""));
+    category(&state, Some(LOCAL_1), "video", 6, 600);
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(
+        &body,
+        r#"bytes_total{direction="inbound",ip="10.0.0.1",protocol="tcp"} 1000"#,
+    );
+    assert!(!body.contains("txne_"));
+
+    let state = state.legacy_names().mirror_dropped(7);
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    position(&body, "mirror_dropped_packets_total 7");
+    position(&body, "txne_mirror_dropped_total 7");
+    position(
+        &body,
+        r#"bytes_total{direction="outbound",ip="10.0.0.1",protocol="tcp"} 500"#,
+    );
+    position(
+        &body,
+        r#"txne_outbound_bytes_total{ip_version="4",ip_source="10.0.0.1",protocol="tcp"} 500"#,
+    );
+    position(
+        &body,
+        r#"txne_category_inbound_bytes_total{ip_version="4",ip_dest="10.0.0.1",category="video"} 600"#,
+    );
+    // A family per direction, as before
+    let families = [
+        "txne_inbound_packets_total Packets entering the network",
+        "txne_inbound_bytes_total Bytes entering the network",
+        "txne_outbound_packets_total Packets leaving the network",
+        "txne_outbound_bytes_total Bytes leaving the network",
+        "txne_category_inbound_bytes_total Bytes entering the network, per category of traffic",
+        "txne_category_outbound_bytes_total Bytes leaving the network, per category of traffic",
+    ]
+    .map(|family| position(&body, &format!("# HELP {family}")));
+    assert!(families.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+/// The output of the first version, whatever the labels and families
+/// added since then
+#[tokio::test]
+async fn compat_v0() {
+    let state = stats(
+        state()
+            .host("router")
+            .device_names()
+            .mirror_dropped(7)
+            .compat(Compat::V0),
+    );
+    let series = Series {
+        vlan: Some(10),
+        interface: Some("eth0".to_string()),
+        port: Some("https".to_string()),
+        ..series(Some(LOCAL_1))
+    };
+    tcp(&state, &series, 6, 600);
+    category(&state, Some(LOCAL_1), "video", 6, 600);
+    state.add_device(LOCAL_1, Some("NAS"), None);
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    assert_eq!(body, include_str!("metrics_v0.txt"));
+}
+
+#[tokio::test]
+async fn exposition_formats() {
+    let counted = stats(state());
+    let accept = "application/openmetrics-text;version=1.0.0;q=0.9,text/plain;q=0.5";
+    let (_, content_type, body) = get(&counted, "/metrics", Some(accept)).await;
+    assert_eq!(content_type, OPENMETRICS_CONTENT_TYPE);
+    position(&body, "# TYPE txne_network_bytes counter");
+    position(
+        &body,
+        r#"txne_network_bytes_total{direction="inbound",ip="10.0.0.1",protocol="tcp"} 1000"#,
+    );
+    position(
+        &body,
+        r#"txne_network_bytes_created{direction="inbound",ip="10.0.0.1",protocol="tcp"} 1600000000"#,
+    );
+    position(&body, "txne_network_evictions_created 1700000000");
+    assert!(body.ends_with("# EOF\n"));
+
+    // The same series as the text format, with the same types
+    let golden = golden_stats(state());
+    golden.report_capture("eth0", Duration::from_millis(1000), Some(1), 2);
+    let (_, _, text) = get(&golden, "/metrics", None).await;
+    let accept = "application/vnd.google.protobuf;\
+                  proto=io.prometheus.client.MetricFamily;encoding=delimited";
+    let request = Request::get("/metrics")
+        .header(header::ACCEPT, accept)
+        .body(Body::empty())
+        .unwrap();
+    let response = router(&golden).oneshot(request).await.unwrap();
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        PROTOBUF_CONTENT_TYPE
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let mut buffer = &body[..];
+    let mut families = Vec::new();
+    while !buffer.is_empty() {
+        families.push(metrics::MetricFamily::decode_length_delimited(&mut buffer).unwrap());
+    }
+    let kinds = text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .collect::<Vec<_>>();
+    assert_eq!(kinds.len(), families.len());
+    for (family, kind) in families.iter().zip(kinds) {
+        let name = &family.name;
+        let expected = match family.kind {
+            0 => "counter",
+            1 => "gauge",
+            4 => "histogram",
+            other => panic!("Unexpected type {other} of {name}"),
+        };
+        assert_eq!(kind, format!("{name} {expected}"));
+        position(&text, &format!("# HELP {name} {}", family.help));
+        for metric in &family.metric {
+            let labels = metric
+                .label
+                .iter()
+                .map(|pair| format!("{}=\"{}\"", pair.name, pair.value))
+                .collect::<Vec<_>>();
+            let braced = |labels: &[String]| match labels {
+                [] => String::new(),
+                labels => format!("{{{}}}", labels.join(",")),
+            };
+            match (&metric.counter, &metric.gauge, &metric.histogram) {
+                (Some(single), None, None) | (None, Some(single), None) => {
+                    let line = format!("{name}{} {}", braced(&labels), single.value);
+                    position(&text, &line);
+                }
+                (None, None, Some(histogram)) => {
+                    for bucket in &histogram.bucket {
+                        let mut labels = labels.clone();
+                        labels.push(format!("le=\"{}\"", bucket.upper_bound));
+                        let count = bucket.cumulative_count;
+                        position(&text, &format!("{name}_bucket{} {count}", braced(&labels)));
+                    }
+                    let (sum, count) = (histogram.sample_sum, histogram.sample_count);
+                    position(&text, &format!("{name}_sum{} {sum}", braced(&labels)));
+                    position(&text, &format!("{name}_count{} {count}", braced(&labels)));
+                }
+                _ => panic!("Not exactly one value in {name}"),
+            }
+        }
+    }
+    let bytes = families
+        .iter()
+        .find(|family| family.name == "txne_network_bytes_total")
+        .unwrap();
+    let first = &bytes.metric[5];
+    let labels = first
+        .label
+        .iter()
+        .map(|pair| (pair.name.as_str(), pair.value.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        labels,
+        [
+            ("direction", "inbound"),
+            ("ip", "10.0.0.9"),
+            ("protocol", "tcp")
+        ]
+    );
+    assert_eq!(first.counter.as_ref().unwrap().value, 500.0);
+    let rtt = families
+        .iter()
+        .find(|family| family.name == "txne_network_icmp_rtt_seconds")
+        .unwrap();
+    let histogram = rtt.metric[0].histogram.as_ref().unwrap();
+    assert_eq!((histogram.sample_count, histogram.sample_sum), (1, 0.012));
+}
+
+#[tokio::test]
+async fn compression() {
+    use std::io::Read;
+
+    let state = stats(state());
+    let (_, _, plain) = get(&state, "/metrics", None).await;
+    for (accept, expected) in [
+        ("gzip", Some("gzip")),
+        ("deflate, gzip;q=0.5", Some("deflate")),
+        ("br, *", Some("gzip")),
+        ("gzip;q=0, identity", None),
+    ] {
+        let request = Request::get("/metrics")
+            .header(header::ACCEPT_ENCODING, accept)
+            .body(Body::empty())
+            .unwrap();
+        let response = router(&state).oneshot(request).await.unwrap();
+        let encoding = response.headers().get(header::CONTENT_ENCODING).cloned();
+        assert_eq!(
+            encoding.as_ref().map(|value| value.to_str().unwrap()),
+            expected,
+            "{accept}"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut reader: Box<dyn Read> = match expected {
+            Some("gzip") => Box::new(flate2::read::GzDecoder::new(&body[..])),
+            Some(_) => Box::new(flate2::read::ZlibDecoder::new(&body[..])),
+            None => Box::new(&body[..]),
+        };
+        let mut decoded = String::new();
+        reader.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, plain, "{accept}");
+    }
+}
+
+#[tokio::test]
+async fn devices_inventory_api() {
+    let state = state();
+    state.add_device(LOCAL_2, None, Some("Sonos One"));
+    let (status, _, body) = get(&state, "/api/v1/devices", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body,
+        concat!(
+            r#"[{"ip":"10.0.0.2","server":null,"location":null,"friendly_name":null,"#,
+            r#""manufacturer":null,"model":"Sonos One"}]"#,
+        )
+    );
+}
+
+#[tokio::test]
+async fn health_endpoints() {
+    let (status, _, _) = get(&state(), "/readyz", None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let state = state().captures();
+    let (status, _, body) = get(&state, "/readyz", None).await;
+    assert_eq!(
+        (status, body.as_str()),
+        (StatusCode::SERVICE_UNAVAILABLE, "Captures synced: 0 of 0\n")
+    );
+
+    let capture = state.start_capture("eth0");
+    let (status, _, _) = get(&state, "/readyz", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    state.report_capture("eth0", Duration::from_millis(1000), None, 0);
+    let (status, _, _) = get(&state, "/readyz", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = get(&state, "/healthz", None).await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "OK\n"));
+
+    // A panic of the capture thread
+    std::thread::spawn(move || {
+        let _capture = capture;
+        panic!("capture");
+    })
+    .join()
+    .unwrap_err();
+    let (status, _, body) = get(&state, "/healthz", None).await;
+    assert_eq!(
+        (status, body.as_str()),
+        (StatusCode::SERVICE_UNAVAILABLE, "Capture stopped: eth0\n")
+    );
+    let (status, _, _) = get(&state, "/readyz", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+/// Statistics covering the main families, with IPs whose numeric and
+/// lexical orders differ
+fn golden_stats(state: State) -> State {
+    state.created(1_600_000_000);
+    state.add_evictions(3);
+    for (ip, packets) in [
+        (Ipv4Addr::new(10, 0, 0, 10), 7),
+        (Ipv4Addr::new(10, 0, 0, 9), 5),
+        (Ipv4Addr::new(192, 168, 0, 1), 2),
+    ] {
+        tcp(&state, &series(Some(ip)), packets, packets * 100);
+    }
+    tcp(&state, &series(None), 4, 400);
+    category(&state, Some(Ipv4Addr::new(10, 0, 0, 10)), "web", 2, 200);
+    category(&state, Some(Ipv4Addr::new(10, 0, 0, 9)), "web", 1, 100);
+    state.add_rtt(Ipv4Addr::new(1, 1, 1, 1), Duration::from_millis(12));
+    state
+}
+
+/// The whole output, compared with `tests/metrics.txt`
+///
+/// Run with `UPDATE_GOLDEN=1` to write the file again after an
+/// intended change of the output, for review.
+#[tokio::test]
+async fn golden_output() {
+    let state = golden_stats(state());
+    state.report_capture("eth0", Duration::from_millis(1000), Some(1), 2);
+    let (_, _, body) = get(&state, "/metrics", None).await;
+    let body = body.replace(env!("CARGO_PKG_VERSION"), "VERSION");
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/metrics.txt");
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(path, &body).unwrap();
+    }
+    assert_eq!(body, include_str!("metrics.txt"));
+}